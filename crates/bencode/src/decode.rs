@@ -0,0 +1,263 @@
+use std::borrow::Cow;
+
+use crate::{
+    error::{Error, ErrorKind},
+    value::{Dict, List, Source, Value},
+};
+
+/// default nesting limit, deep enough for any real torrent or extension message
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// zero-copy bencode decoder
+#[derive(Debug, Clone)]
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    canonical: bool,
+    max_depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            canonical: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+    /// reject leading zeros, `i-0e`, unsorted and duplicate dictionary keys
+    pub fn canonical(mut self, canonical: bool) -> Self {
+        self.canonical = canonical;
+        self
+    }
+    /// maximum list/dictionary nesting depth
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+    /// current byte offset into the input
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    /// the input not consumed yet
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+    /// decode the whole input as exactly one value
+    pub fn decode(mut self) -> Result<Value<'a>, Error> {
+        let v = self.decode_prefix()?;
+        if self.pos != self.buf.len() {
+            return Err(self.error(ErrorKind::TrailingData));
+        }
+        Ok(v)
+    }
+    /// decode one value and stop, leaving any following bytes for the caller,
+    /// as needed for messages that carry raw data after a dictionary.
+    pub fn decode_prefix(&mut self) -> Result<Value<'a>, Error> {
+        self.value(0)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value<'a>, Error> {
+        match self.peek()? {
+            b'i' => self.int().map(Value::Int),
+            b'0'..=b'9' => self.bytes().map(|b| Value::Bytes(Cow::Borrowed(b))),
+            b'l' => self.list(depth + 1).map(Value::List),
+            b'd' => self.dict(depth + 1).map(Value::Dict),
+            b => Err(self.error(ErrorKind::InvalidByte(b))),
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, Error> {
+        let start = self.pos;
+        // skip the 'i'
+        self.pos += 1;
+        let end = self.find(b'e')?;
+        let digits = &self.buf[self.pos..end];
+        let (negative, unsigned) = match digits.first() {
+            Some(b'-') => (true, &digits[1..]),
+            _ => (false, digits),
+        };
+        if unsigned.is_empty() || !unsigned.iter().all(u8::is_ascii_digit) {
+            return Err(Error::new(ErrorKind::InvalidInteger, start));
+        }
+        if self.canonical
+            && ((unsigned.len() > 1 && unsigned[0] == b'0') || (negative && unsigned == b"0"))
+        {
+            return Err(Error::new(ErrorKind::LeadingZero, start));
+        }
+        // only ascii digits and an optional sign, so this is valid utf-8
+        let n = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInteger, start))?;
+        self.pos = end + 1;
+        Ok(n)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let start = self.pos;
+        let colon = self.find(b':')?;
+        let digits = &self.buf[self.pos..colon];
+        if !digits.iter().all(u8::is_ascii_digit) {
+            return Err(Error::new(ErrorKind::InvalidLength, start));
+        }
+        if self.canonical && digits.len() > 1 && digits[0] == b'0' {
+            return Err(Error::new(ErrorKind::LeadingZero, start));
+        }
+        let len = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidLength, start))?;
+        let data_start = colon + 1;
+        let data_end = match data_start.checked_add(len) {
+            Some(e) if e <= self.buf.len() => e,
+            _ => return Err(Error::new(ErrorKind::UnexpectedEof, self.buf.len())),
+        };
+        self.pos = data_end;
+        Ok(&self.buf[data_start..data_end])
+    }
+
+    fn list(&mut self, depth: usize) -> Result<List<'a>, Error> {
+        if depth > self.max_depth {
+            return Err(self.error(ErrorKind::DepthLimit));
+        }
+        let start = self.pos;
+        self.pos += 1;
+        let mut items = Vec::new();
+        while self.peek()? != b'e' {
+            items.push(self.value(depth)?);
+        }
+        self.pos += 1;
+        Ok(List::decoded(items, self.source(start)))
+    }
+
+    fn dict(&mut self, depth: usize) -> Result<Dict<'a>, Error> {
+        if depth > self.max_depth {
+            return Err(self.error(ErrorKind::DepthLimit));
+        }
+        let start = self.pos;
+        self.pos += 1;
+        let mut entries: Vec<(Cow<'a, [u8]>, Value<'a>)> = Vec::new();
+        while self.peek()? != b'e' {
+            let key_start = self.pos;
+            let key = match self.peek()? {
+                b'0'..=b'9' => self.bytes()?,
+                b => return Err(self.error(ErrorKind::InvalidByte(b))),
+            };
+            if self.canonical
+                && let Some((last, _)) = entries.last()
+            {
+                match (**last).cmp(key) {
+                    std::cmp::Ordering::Less => {}
+                    std::cmp::Ordering::Equal => {
+                        return Err(Error::new(ErrorKind::DuplicateKey, key_start));
+                    }
+                    std::cmp::Ordering::Greater => {
+                        return Err(Error::new(ErrorKind::UnsortedKey, key_start));
+                    }
+                }
+            }
+            let v = self.value(depth)?;
+            entries.push((Cow::Borrowed(key), v));
+        }
+        self.pos += 1;
+        Ok(Dict::decoded(entries, self.source(start)))
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error(ErrorKind::UnexpectedEof))
+    }
+
+    fn find(&self, b: u8) -> Result<usize, Error> {
+        self.buf[self.pos..]
+            .iter()
+            .position(|c| *c == b)
+            .map(|i| self.pos + i)
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, self.buf.len()))
+    }
+
+    fn source(&self, start: usize) -> Source<'a> {
+        Source {
+            raw: &self.buf[start..self.pos],
+            offset: start,
+        }
+    }
+
+    fn error(&self, kind: ErrorKind) -> Error {
+        Error::new(kind, self.pos)
+    }
+}
+
+/// decode a buffer holding exactly one bencoded value
+pub fn decode(buf: &[u8]) -> Result<Value<'_>, Error> {
+    Decoder::new(buf).decode()
+}
+
+/// decode a buffer holding exactly one value in canonical form
+pub fn decode_canonical(buf: &[u8]) -> Result<Value<'_>, Error> {
+    Decoder::new(buf).canonical(true).decode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(b: &[u8]) -> (ErrorKind, usize) {
+        let e = decode_canonical(b).unwrap_err();
+        (e.kind, e.offset)
+    }
+
+    #[test]
+    fn decodes_every_type() {
+        let v = decode(b"d1:ai-12e1:bl3:xyzi0eee").unwrap();
+        let d = v.as_dict().unwrap();
+        assert_eq!(d.get_int("a"), Some(-12));
+        let l = d.get_list("b").unwrap();
+        assert_eq!(l.get(0).and_then(Value::as_str), Some("xyz"));
+        assert_eq!(l.get(1).and_then(Value::as_int), Some(0));
+        assert_eq!(l.len(), 2);
+    }
+
+    #[test]
+    fn canonical_mode_rejects_what_plain_mode_accepts() {
+        for (b, expected) in [
+            (&b"d1:bi1e1:ai2ee"[..], (ErrorKind::UnsortedKey, 7)),
+            (b"d1:ai1e1:ai2ee", (ErrorKind::DuplicateKey, 7)),
+            (b"i03e", (ErrorKind::LeadingZero, 0)),
+            (b"i-0e", (ErrorKind::LeadingZero, 0)),
+            (b"l02:abe", (ErrorKind::LeadingZero, 1)),
+        ] {
+            assert!(decode(b).is_ok());
+            assert_eq!(kind(b), expected, "{:?}", String::from_utf8_lossy(b));
+        }
+    }
+
+    #[test]
+    fn errors_carry_the_offset() {
+        for (b, expected) in [
+            (&b"i12"[..], (ErrorKind::UnexpectedEof, 3)),
+            (b"l5:abe", (ErrorKind::UnexpectedEof, 6)),
+            (b"d1:ai1ex", (ErrorKind::InvalidByte(b'x'), 7)),
+            (b"di1ei2ee", (ErrorKind::InvalidByte(b'i'), 1)),
+            (b"li1ei+2ee", (ErrorKind::InvalidInteger, 4)),
+            (b"l1x:ae", (ErrorKind::InvalidLength, 1)),
+            (b"i1ei2e", (ErrorKind::TrailingData, 3)),
+        ] {
+            assert_eq!(kind(b), expected, "{:?}", String::from_utf8_lossy(b));
+        }
+        let deep = Decoder::new(b"llleee").max_depth(2).decode().unwrap_err();
+        assert_eq!((deep.kind, deep.offset), (ErrorKind::DepthLimit, 2));
+    }
+
+    #[test]
+    fn a_prefix_leaves_the_rest() {
+        let mut d = Decoder::new(b"d1:ai1eerest");
+        d.decode_prefix().unwrap();
+        assert_eq!(d.position(), 8);
+        assert_eq!(d.remaining(), b"rest");
+    }
+}
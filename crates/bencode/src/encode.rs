@@ -0,0 +1,120 @@
+use std::io::{self, Write};
+
+use crate::value::Value;
+
+/// streaming bencode encoder.
+///
+/// containers are opened with `begin_list`/`begin_dict` and closed with `end`;
+/// dictionary keys must be written in ascending byte order to stay canonical.
+#[derive(Debug)]
+pub struct Encoder<W: Write> {
+    w: W,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(w: W) -> Self {
+        Self { w }
+    }
+    pub fn int(&mut self, i: i64) -> io::Result<&mut Self> {
+        write!(self.w, "i{}e", i)?;
+        Ok(self)
+    }
+    pub fn bytes(&mut self, b: &[u8]) -> io::Result<&mut Self> {
+        write!(self.w, "{}:", b.len())?;
+        self.w.write_all(b)?;
+        Ok(self)
+    }
+    pub fn str(&mut self, s: &str) -> io::Result<&mut Self> {
+        self.bytes(s.as_bytes())
+    }
+    pub fn begin_list(&mut self) -> io::Result<&mut Self> {
+        self.w.write_all(b"l")?;
+        Ok(self)
+    }
+    pub fn begin_dict(&mut self) -> io::Result<&mut Self> {
+        self.w.write_all(b"d")?;
+        Ok(self)
+    }
+    /// close the innermost open list or dictionary
+    pub fn end(&mut self) -> io::Result<&mut Self> {
+        self.w.write_all(b"e")?;
+        Ok(self)
+    }
+    /// write bytes that are already bencoded, such as a preserved raw span
+    pub fn raw(&mut self, b: &[u8]) -> io::Result<&mut Self> {
+        self.w.write_all(b)?;
+        Ok(self)
+    }
+    /// write a whole value tree, sorting dictionary keys
+    pub fn value(&mut self, v: &Value<'_>) -> io::Result<&mut Self> {
+        match v {
+            Value::Int(i) => self.int(*i),
+            Value::Bytes(b) => self.bytes(b),
+            Value::List(l) => {
+                self.begin_list()?;
+                for item in l.iter() {
+                    self.value(item)?;
+                }
+                self.end()
+            }
+            Value::Dict(d) => {
+                self.begin_dict()?;
+                for (k, item) in d.sorted() {
+                    self.bytes(k)?;
+                    self.value(item)?;
+                }
+                self.end()
+            }
+        }
+    }
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode::decode_canonical, value::Dict};
+
+    #[test]
+    fn canonical_input_round_trips() {
+        let b = b"d3:bar4:spam3:fooi42e4:listli-1e0:d1:k1:veee";
+        assert_eq!(decode_canonical(b).unwrap().to_bytes(), b);
+    }
+
+    #[test]
+    fn dictionaries_are_written_sorted() {
+        let mut inner = Dict::new();
+        inner.insert("z", 1);
+        inner.insert("a", "x");
+        let mut d = Dict::new();
+        d.insert("b", inner);
+        d.insert("a", -3);
+        let b = Value::from(d).to_bytes();
+        assert_eq!(b, b"d1:ai-3e1:bd1:a1:x1:zi1eee");
+        assert_eq!(decode_canonical(&b).unwrap().to_bytes(), b);
+    }
+
+    #[test]
+    fn the_encoder_writes_nested_values() {
+        let mut e = Encoder::new(Vec::new());
+        e.begin_dict()
+            .unwrap()
+            .str("l")
+            .unwrap()
+            .begin_list()
+            .unwrap()
+            .int(1)
+            .unwrap()
+            .bytes(b"\xff")
+            .unwrap()
+            .raw(b"de")
+            .unwrap()
+            .end()
+            .unwrap()
+            .end()
+            .unwrap();
+        assert_eq!(e.into_inner(), b"d1:lli1e1:\xffdeee");
+    }
+}
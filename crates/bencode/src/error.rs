@@ -0,0 +1,69 @@
+use std::fmt;
+
+/// bencode decoding error with the byte offset where decoding stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// what went wrong
+    pub kind: ErrorKind,
+    /// byte offset into the input
+    pub offset: usize,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, offset: usize) -> Self {
+        Self { kind, offset }
+    }
+}
+
+/// the class of a bencode decoding error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// input ended in the middle of a value
+    UnexpectedEof,
+    /// a byte that cannot start or continue a value
+    InvalidByte(u8),
+    /// integer with no digits, stray characters or overflow
+    InvalidInteger,
+    /// byte string length prefix that is malformed or overflows
+    InvalidLength,
+    /// `i03e`, `i-0e` or `03:abc`, rejected in canonical mode
+    LeadingZero,
+    /// dictionary keys not in ascending byte order, rejected in canonical mode
+    UnsortedKey,
+    /// the same dictionary key appears twice
+    DuplicateKey,
+    /// bytes left over after the top-level value
+    TrailingData,
+    /// lists and dictionaries nested deeper than the decoder allows
+    DepthLimit,
+    /// a lookup expected a different value type
+    UnexpectedType(&'static str),
+    /// a lookup expected a dictionary key that is absent
+    MissingKey(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnexpectedEof => f.write_str("unexpected end of input"),
+            ErrorKind::InvalidByte(b) => write!(f, "invalid byte 0x{:02x}", b),
+            ErrorKind::InvalidInteger => f.write_str("invalid integer"),
+            ErrorKind::InvalidLength => f.write_str("invalid byte string length"),
+            ErrorKind::LeadingZero => f.write_str("non-canonical leading zero"),
+            ErrorKind::UnsortedKey => f.write_str("dictionary keys are not sorted"),
+            ErrorKind::DuplicateKey => f.write_str("duplicate dictionary key"),
+            ErrorKind::TrailingData => f.write_str("trailing data after value"),
+            ErrorKind::DepthLimit => f.write_str("nesting depth limit exceeded"),
+            ErrorKind::UnexpectedType(t) => write!(f, "expected {}", t),
+            ErrorKind::MissingKey(k) => write!(f, "missing key {:?}", k),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.offset)
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,9 @@
+pub mod decode;
+pub mod encode;
+pub mod error;
+pub mod value;
+
+pub use decode::{Decoder, decode, decode_canonical};
+pub use encode::Encoder;
+pub use error::{Error, ErrorKind};
+pub use value::{Dict, List, Value};
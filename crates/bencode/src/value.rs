@@ -0,0 +1,327 @@
+use std::{borrow::Cow, ops::Range};
+
+use crate::{
+    encode::Encoder,
+    error::{Error, ErrorKind},
+};
+
+/// a decoded bencode value, borrowing byte strings from the input buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+    Int(i64),
+    Bytes(Cow<'a, [u8]>),
+    List(List<'a>),
+    Dict(Dict<'a>),
+}
+
+impl<'a> Value<'a> {
+    /// owned byte string value
+    pub fn bytes(b: impl Into<Vec<u8>>) -> Self {
+        Value::Bytes(Cow::Owned(b.into()))
+    }
+    /// byte string value from utf-8 text
+    pub fn string(s: &str) -> Self {
+        Value::Bytes(Cow::Owned(s.as_bytes().to_vec()))
+    }
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+    /// byte string as utf-8, `None` when it is not a string or not valid utf-8
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+    pub fn as_list(&self) -> Option<&List<'a>> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+    pub fn as_dict(&self) -> Option<&Dict<'a>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+    /// the exact input bytes of a decoded list or dictionary
+    pub fn raw(&self) -> Option<&'a [u8]> {
+        match self {
+            Value::List(l) => l.raw(),
+            Value::Dict(d) => d.raw(),
+            _ => None,
+        }
+    }
+    /// canonical bencoding of this value, dictionary keys sorted
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut e = Encoder::new(Vec::new());
+        // writing into a Vec cannot fail
+        let _ = e.value(self);
+        e.into_inner()
+    }
+}
+
+impl From<i64> for Value<'_> {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl From<&str> for Value<'_> {
+    fn from(s: &str) -> Self {
+        Value::string(s)
+    }
+}
+
+impl From<String> for Value<'_> {
+    fn from(s: String) -> Self {
+        Value::Bytes(Cow::Owned(s.into_bytes()))
+    }
+}
+
+impl<'a> From<List<'a>> for Value<'a> {
+    fn from(l: List<'a>) -> Self {
+        Value::List(l)
+    }
+}
+
+impl<'a> From<Dict<'a>> for Value<'a> {
+    fn from(d: Dict<'a>) -> Self {
+        Value::Dict(d)
+    }
+}
+
+/// where a decoded container sits in its input buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Source<'a> {
+    pub(crate) raw: &'a [u8],
+    pub(crate) offset: usize,
+}
+
+/// bencode list
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct List<'a> {
+    items: Vec<Value<'a>>,
+    source: Option<Source<'a>>,
+}
+
+impl<'a> List<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub(crate) fn decoded(items: Vec<Value<'a>>, source: Source<'a>) -> Self {
+        Self {
+            items,
+            source: Some(source),
+        }
+    }
+    pub fn push(&mut self, v: impl Into<Value<'a>>) {
+        self.items.push(v.into());
+        self.source = None;
+    }
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    pub fn get(&self, i: usize) -> Option<&Value<'a>> {
+        self.items.get(i)
+    }
+    pub fn iter(&self) -> std::slice::Iter<'_, Value<'a>> {
+        self.items.iter()
+    }
+    /// the exact input bytes of this list, `None` once built or modified in memory
+    pub fn raw(&self) -> Option<&'a [u8]> {
+        self.source.as_ref().map(|s| s.raw)
+    }
+    /// byte range of this list in the decoded input
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.source
+            .as_ref()
+            .map(|s| s.offset..s.offset + s.raw.len())
+    }
+}
+
+impl<'s, 'a> IntoIterator for &'s List<'a> {
+    type Item = &'s Value<'a>;
+    type IntoIter = std::slice::Iter<'s, Value<'a>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+/// bencode dictionary, entries kept in the order they were decoded or inserted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dict<'a> {
+    entries: Vec<(Cow<'a, [u8]>, Value<'a>)>,
+    source: Option<Source<'a>>,
+}
+
+impl<'a> Dict<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub(crate) fn decoded(entries: Vec<(Cow<'a, [u8]>, Value<'a>)>, source: Source<'a>) -> Self {
+        Self {
+            entries,
+            source: Some(source),
+        }
+    }
+    /// insert or replace a key
+    pub fn insert(&mut self, key: &str, v: impl Into<Value<'a>>) {
+        self.insert_bytes(key.as_bytes().to_vec(), v);
+    }
+    /// insert or replace a key that is not valid utf-8
+    pub fn insert_bytes(&mut self, key: Vec<u8>, v: impl Into<Value<'a>>) {
+        let v = v.into();
+        match self.entries.iter_mut().find(|(k, _)| **k == *key) {
+            Some(e) => e.1 = v,
+            None => self.entries.push((Cow::Owned(key), v)),
+        }
+        self.source = None;
+    }
+    pub fn remove(&mut self, key: &str) -> Option<Value<'a>> {
+        let i = self
+            .entries
+            .iter()
+            .position(|(k, _)| **k == *key.as_bytes())?;
+        self.source = None;
+        Some(self.entries.remove(i).1)
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+    pub fn get(&self, key: &str) -> Option<&Value<'a>> {
+        self.get_raw_key(key.as_bytes())
+    }
+    pub fn get_raw_key(&self, key: &[u8]) -> Option<&Value<'a>> {
+        self.entries
+            .iter()
+            .find(|(k, _)| **k == *key)
+            .map(|(_, v)| v)
+    }
+    pub fn get_int(&self, key: &str) -> Option<i64> {
+        self.get(key).and_then(Value::as_int)
+    }
+    pub fn get_bytes(&self, key: &str) -> Option<&[u8]> {
+        self.get(key).and_then(Value::as_bytes)
+    }
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(Value::as_str)
+    }
+    pub fn get_list(&self, key: &str) -> Option<&List<'a>> {
+        self.get(key).and_then(Value::as_list)
+    }
+    pub fn get_dict(&self, key: &str) -> Option<&Dict<'a>> {
+        self.get(key).and_then(Value::as_dict)
+    }
+    /// like `get`, but reports a missing key at this dictionary's offset
+    pub fn require(&self, key: &str) -> Result<&Value<'a>, Error> {
+        self.get(key)
+            .ok_or_else(|| Error::new(ErrorKind::MissingKey(key.to_string()), self.offset()))
+    }
+    pub fn require_int(&self, key: &str) -> Result<i64, Error> {
+        self.require(key)?
+            .as_int()
+            .ok_or_else(|| self.type_error("integer"))
+    }
+    pub fn require_bytes(&self, key: &str) -> Result<&[u8], Error> {
+        self.require(key)?
+            .as_bytes()
+            .ok_or_else(|| self.type_error("byte string"))
+    }
+    pub fn require_str(&self, key: &str) -> Result<&str, Error> {
+        self.require(key)?
+            .as_str()
+            .ok_or_else(|| self.type_error("utf-8 string"))
+    }
+    pub fn require_list(&self, key: &str) -> Result<&List<'a>, Error> {
+        self.require(key)?
+            .as_list()
+            .ok_or_else(|| self.type_error("list"))
+    }
+    pub fn require_dict(&self, key: &str) -> Result<&Dict<'a>, Error> {
+        self.require(key)?
+            .as_dict()
+            .ok_or_else(|| self.type_error("dictionary"))
+    }
+    /// entries in decoded or insertion order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &Value<'a>)> {
+        self.entries.iter().map(|(k, v)| (&**k, v))
+    }
+    /// the exact input bytes of this dictionary, `None` once built or modified in memory
+    pub fn raw(&self) -> Option<&'a [u8]> {
+        self.source.as_ref().map(|s| s.raw)
+    }
+    /// byte range of this dictionary in the decoded input
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.source
+            .as_ref()
+            .map(|s| s.offset..s.offset + s.raw.len())
+    }
+    /// byte offset of this dictionary in the decoded input, 0 when built in memory
+    pub fn offset(&self) -> usize {
+        self.source.as_ref().map(|s| s.offset).unwrap_or(0)
+    }
+    /// entries sorted by key, the order required on the wire
+    pub(crate) fn sorted(&self) -> Vec<(&[u8], &Value<'a>)> {
+        let mut v: Vec<(&[u8], &Value<'a>)> = self.iter().collect();
+        v.sort_by(|a, b| a.0.cmp(b.0));
+        v
+    }
+    fn type_error(&self, expected: &'static str) -> Error {
+        Error::new(ErrorKind::UnexpectedType(expected), self.offset())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decode::decode;
+
+    #[test]
+    fn nested_dictionaries_keep_their_raw_bytes() {
+        let b = b"d4:infod4:name1:x5:filesld1:ai1eeee3:zzzi0ee";
+        let v = decode(b).unwrap();
+        let root = v.as_dict().unwrap();
+        assert_eq!(root.raw(), Some(&b[..]));
+        let info = root.get_dict("info").unwrap();
+        assert_eq!(info.span(), Some(7..35));
+        assert_eq!(info.raw(), Some(&b"d4:name1:x5:filesld1:ai1eeee"[..]));
+        let files = info.get_list("files").unwrap();
+        assert_eq!(files.raw(), Some(&b"ld1:ai1eee"[..]));
+        let file = files.get(0).and_then(|f| f.as_dict()).unwrap();
+        assert_eq!((file.offset(), file.raw()), (25, Some(&b"d1:ai1ee"[..])));
+    }
+
+    #[test]
+    fn changed_dictionaries_lose_their_raw_bytes() {
+        let v = decode(b"d1:ai1ee").unwrap();
+        let mut d = v.as_dict().unwrap().clone();
+        d.insert("b", 2);
+        assert_eq!((d.raw(), d.span(), d.offset()), (None, None, 0));
+    }
+
+    #[test]
+    fn missing_keys_are_reported_at_their_dictionary() {
+        let v = decode(b"d1:ad1:bi1eee").unwrap();
+        let a = v.as_dict().unwrap().get_dict("a").unwrap();
+        let e = a.require_int("c").unwrap_err();
+        assert_eq!(e.to_string(), "missing key \"c\" at byte 4");
+        let e = a.require_str("b").unwrap_err();
+        assert_eq!(e.to_string(), "expected utf-8 string at byte 4");
+    }
+}
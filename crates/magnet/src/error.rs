@@ -0,0 +1,35 @@
+use std::fmt;
+
+/// why a magnet link could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// not a `magnet:?` uri
+    NotMagnet,
+    /// the link has no `xt` parameter
+    MissingExactTopic,
+    /// a `%` not followed by two hex digits, or text that is not utf-8
+    InvalidEncoding,
+    /// a `btih` or `btmh` hash of the wrong length or alphabet
+    InvalidInfoHash(String),
+    /// a `btmh` multihash of a hash function other than sha2-256
+    UnsupportedMultihash(String),
+    /// a parameter whose value cannot be parsed
+    InvalidParameter { name: &'static str, value: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotMagnet => f.write_str("not a magnet link"),
+            Error::MissingExactTopic => f.write_str("magnet link has no exact topic"),
+            Error::InvalidEncoding => f.write_str("invalid percent encoding"),
+            Error::InvalidInfoHash(h) => write!(f, "invalid info hash {:?}", h),
+            Error::UnsupportedMultihash(h) => write!(f, "unsupported multihash {:?}", h),
+            Error::InvalidParameter { name, value } => {
+                write!(f, "invalid {} parameter {:?}", name, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
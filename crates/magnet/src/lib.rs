@@ -1 +1,6 @@
-mod magnet;
\ No newline at end of file
+mod encoding;
+pub mod error;
+pub mod magnet;
+
+pub use error::Error;
+pub use magnet::{ExactTopic, Magnet, PeerAddress};
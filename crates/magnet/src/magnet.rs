@@ -1 +1,463 @@
-pub struct Magnet {}
+use std::{fmt, ops::RangeInclusive, str::FromStr};
+
+use crate::{
+    encoding::{base32_decode, hex_decode, hex_encode, percent_decode, percent_encode},
+    error::Error,
+};
+
+/// multihash prefix of a sha2-256 digest, function 0x12 and length 0x20
+const SHA2_256_MULTIHASH: &str = "1220";
+
+/// what a link points at, the `xt` parameter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ExactTopic {
+    /// `urn:btih:`, the sha1 info hash of a v1 or hybrid torrent
+    BtInfoHash([u8; 20]),
+    /// `urn:btmh:`, the sha2-256 info hash of a v2 or hybrid torrent
+    BtMultihash([u8; 32]),
+    /// any other urn, kept as written
+    Other(String),
+}
+
+impl ExactTopic {
+    fn parse(s: &str) -> Result<Self, Error> {
+        if let Some(h) = strip_prefix_ignore_case(s, "urn:btih:") {
+            let hash = match h.len() {
+                32 => base32_decode(h),
+                _ => hex_decode::<20>(h),
+            };
+            return match hash {
+                Some(h) => Ok(ExactTopic::BtInfoHash(h)),
+                None => Err(Error::InvalidInfoHash(h.to_string())),
+            };
+        }
+        if let Some(h) = strip_prefix_ignore_case(s, "urn:btmh:") {
+            let digest = match h.get(..4) {
+                Some(p) if p == SHA2_256_MULTIHASH => &h[4..],
+                Some(_) if h.len() > 4 => return Err(Error::UnsupportedMultihash(h.to_string())),
+                _ => return Err(Error::InvalidInfoHash(h.to_string())),
+            };
+            return match hex_decode::<32>(digest) {
+                Some(h) => Ok(ExactTopic::BtMultihash(h)),
+                None => Err(Error::InvalidInfoHash(h.to_string())),
+            };
+        }
+        Ok(ExactTopic::Other(s.to_string()))
+    }
+}
+
+impl fmt::Display for ExactTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExactTopic::BtInfoHash(h) => write!(f, "urn:btih:{}", hex_encode(h)),
+            ExactTopic::BtMultihash(h) => {
+                write!(f, "urn:btmh:{}{}", SHA2_256_MULTIHASH, hex_encode(h))
+            }
+            ExactTopic::Other(s) => f.write_str(s),
+        }
+    }
+}
+
+/// a peer to connect to directly, the BEP 9 `x.pe` parameter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerAddress {
+    /// an ip address or host name, without brackets
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for PeerAddress {
+    type Err = Error;
+
+    /// `host:port`, `1.2.3.4:port` or `[::1]:port`
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidParameter {
+            name: "x.pe",
+            value: s.to_string(),
+        };
+        let (host, port) = match s.strip_prefix('[') {
+            Some(rest) => rest.split_once("]:").ok_or_else(invalid)?,
+            None => s.rsplit_once(':').ok_or_else(invalid)?,
+        };
+        if host.is_empty() || (!s.starts_with('[') && host.contains(':')) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// a parsed magnet link. parameters this crate does not know are kept in
+/// `extra` so that a parsed link serializes back to an equivalent one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Magnet {
+    /// `xt`, in link order
+    pub exact_topics: Vec<ExactTopic>,
+    /// `dn`, a name to show until the metadata arrives
+    pub display_name: Option<String>,
+    /// `xl`, the total size in bytes
+    pub exact_length: Option<u64>,
+    /// `tr`, tracker urls
+    pub trackers: Vec<String>,
+    /// `ws`, BEP 19 web seed urls
+    pub web_seeds: Vec<String>,
+    /// `x.pe`, BEP 9 peers to connect to
+    pub peers: Vec<PeerAddress>,
+    /// `so`, BEP 53 indices of the files to download
+    pub select_only: Vec<RangeInclusive<usize>>,
+    /// `kt`, search keywords
+    pub keywords: Vec<String>,
+    /// every other parameter, decoded, in link order
+    pub extra: Vec<(String, String)>,
+}
+
+impl Magnet {
+    /// a link to the v1 or hybrid torrent with info hash `h`
+    pub fn from_info_hash(h: [u8; 20]) -> Self {
+        Self::default().with_exact_topic(ExactTopic::BtInfoHash(h))
+    }
+    /// a link to the v2 torrent with info hash `h`
+    pub fn from_info_hash_v2(h: [u8; 32]) -> Self {
+        Self::default().with_exact_topic(ExactTopic::BtMultihash(h))
+    }
+    /// parse a `magnet:?` uri, `xt` and `tr` style keys with a `.1` index
+    /// suffix are accepted
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let query = match strip_prefix_ignore_case(s, "magnet:?") {
+            Some(q) => q,
+            None => return Err(Error::NotMagnet),
+        };
+        let mut m = Magnet::default();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = percent_decode(key)?;
+            let value = percent_decode(value)?;
+            match base_key(&key) {
+                "xt" => m.exact_topics.push(ExactTopic::parse(&value)?),
+                "dn" => m.display_name = Some(value),
+                "xl" => m.exact_length = Some(parse_number("xl", &value)?),
+                "tr" => m.trackers.push(value),
+                "ws" => m.web_seeds.push(value),
+                "x.pe" => m.peers.push(value.parse()?),
+                "so" => m.select_only.extend(parse_select_only(&value)?),
+                "kt" => m
+                    .keywords
+                    .extend(value.split_whitespace().map(str::to_string)),
+                _ => m.extra.push((key, value)),
+            }
+        }
+        if m.exact_topics.is_empty() {
+            return Err(Error::MissingExactTopic);
+        }
+        Ok(m)
+    }
+    pub fn with_exact_topic(mut self, xt: ExactTopic) -> Self {
+        self.exact_topics.push(xt);
+        self
+    }
+    pub fn with_display_name(mut self, name: impl Into<String>) -> Self {
+        self.display_name = Some(name.into());
+        self
+    }
+    pub fn with_exact_length(mut self, length: u64) -> Self {
+        self.exact_length = Some(length);
+        self
+    }
+    pub fn with_tracker(mut self, url: impl Into<String>) -> Self {
+        self.trackers.push(url.into());
+        self
+    }
+    pub fn with_web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+    pub fn with_peer(mut self, peer: PeerAddress) -> Self {
+        self.peers.push(peer);
+        self
+    }
+    /// download only the files with these indices
+    pub fn with_select_only(mut self, files: RangeInclusive<usize>) -> Self {
+        self.select_only.push(files);
+        self
+    }
+    pub fn with_keyword(mut self, keyword: impl Into<String>) -> Self {
+        self.keywords.push(keyword.into());
+        self
+    }
+    /// the first v1 info hash
+    pub fn info_hash(&self) -> Option<[u8; 20]> {
+        self.exact_topics.iter().find_map(|xt| match xt {
+            ExactTopic::BtInfoHash(h) => Some(*h),
+            _ => None,
+        })
+    }
+    /// the first v2 info hash
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        self.exact_topics.iter().find_map(|xt| match xt {
+            ExactTopic::BtMultihash(h) => Some(*h),
+            _ => None,
+        })
+    }
+    /// whether file `index` is wanted, every file is when `so` is absent
+    pub fn is_selected(&self, index: usize) -> bool {
+        self.select_only.is_empty() || self.select_only.iter().any(|r| r.contains(&index))
+    }
+}
+
+impl FromStr for Magnet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for Magnet {
+    /// the link, parameters in a fixed order with `:` left readable in urns
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut params: Vec<(&str, String)> = vec![];
+        for xt in &self.exact_topics {
+            params.push(("xt", percent_encode(&xt.to_string(), b":")));
+        }
+        if let Some(dn) = &self.display_name {
+            params.push(("dn", percent_encode(dn, b"")));
+        }
+        if let Some(xl) = self.exact_length {
+            params.push(("xl", xl.to_string()));
+        }
+        for tr in &self.trackers {
+            params.push(("tr", percent_encode(tr, b"")));
+        }
+        for ws in &self.web_seeds {
+            params.push(("ws", percent_encode(ws, b"")));
+        }
+        for pe in &self.peers {
+            params.push(("x.pe", percent_encode(&pe.to_string(), b":[]")));
+        }
+        if !self.select_only.is_empty() {
+            let so: Vec<String> = self
+                .select_only
+                .iter()
+                .map(|r| match r.start() == r.end() {
+                    true => r.start().to_string(),
+                    false => format!("{}-{}", r.start(), r.end()),
+                })
+                .collect();
+            params.push(("so", so.join(",")));
+        }
+        if !self.keywords.is_empty() {
+            let kt: Vec<String> = self
+                .keywords
+                .iter()
+                .map(|k| percent_encode(k, b""))
+                .collect();
+            params.push(("kt", kt.join("+")));
+        }
+        f.write_str("magnet:?")?;
+        let extra = self
+            .extra
+            .iter()
+            .map(|(k, v)| (percent_encode(k, b""), percent_encode(v, b":")));
+        let all = params
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .chain(extra);
+        for (i, (k, v)) in all.enumerate() {
+            if i > 0 {
+                f.write_str("&")?;
+            }
+            write!(f, "{}={}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// `xt.1` and `tr.2` are `xt` and `tr`, `x.pe` stays as it is
+fn base_key(key: &str) -> &str {
+    match key.rsplit_once('.') {
+        Some((base, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => base,
+        _ => key,
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    match s.get(..prefix.len()) {
+        Some(p) if p.eq_ignore_ascii_case(prefix) => Some(&s[prefix.len()..]),
+        _ => None,
+    }
+}
+
+fn parse_number<T: FromStr>(name: &'static str, value: &str) -> Result<T, Error> {
+    value.parse().map_err(|_| Error::InvalidParameter {
+        name,
+        value: value.to_string(),
+    })
+}
+
+/// `0,2,4-6` into `0..=0`, `2..=2` and `4..=6`
+fn parse_select_only(value: &str) -> Result<Vec<RangeInclusive<usize>>, Error> {
+    let mut ranges = vec![];
+    for part in value.split(',').filter(|p| !p.is_empty()) {
+        let range = match part.split_once('-') {
+            Some((a, b)) => parse_number("so", a)?..=parse_number("so", b)?,
+            None => {
+                let i = parse_number("so", part)?;
+                i..=i
+            }
+        };
+        if range.is_empty() {
+            return Err(Error::InvalidParameter {
+                name: "so",
+                value: value.to_string(),
+            });
+        }
+        ranges.push(range);
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "0123456789abcdef0123456789abcdef01234567";
+    const V2_HEX: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn v1() -> [u8; 20] {
+        hex_decode(HEX).unwrap()
+    }
+
+    fn v2() -> [u8; 32] {
+        hex_decode(V2_HEX).unwrap()
+    }
+
+    #[test]
+    fn btih_is_read_as_hex_or_base32() {
+        let hex = Magnet::parse(&format!("magnet:?xt=urn:btih:{}", HEX.to_uppercase())).unwrap();
+        let b32 = Magnet::parse("magnet:?xt=urn:BTIH:aeruKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+        assert_eq!(hex.info_hash(), Some(v1()));
+        assert_eq!(b32.info_hash(), Some(v1()));
+        assert_eq!(
+            Magnet::parse("magnet:?xt=urn:btih:0123"),
+            Err(Error::InvalidInfoHash("0123".to_string()))
+        );
+    }
+
+    #[test]
+    fn btmh_must_be_a_sha256_multihash() {
+        let m = Magnet::parse(&format!("magnet:?xt=urn:btmh:1220{}", V2_HEX)).unwrap();
+        assert_eq!((m.info_hash(), m.info_hash_v2()), (None, Some(v2())));
+        let sha512 = format!("1340{}{}", V2_HEX, V2_HEX);
+        assert_eq!(
+            Magnet::parse(&format!("magnet:?xt=urn:btmh:{}", sha512)),
+            Err(Error::UnsupportedMultihash(sha512))
+        );
+        assert_eq!(
+            Magnet::parse("magnet:?xt=urn:btmh:1220ab"),
+            Err(Error::InvalidInfoHash("1220ab".to_string()))
+        );
+    }
+
+    #[test]
+    fn every_xt_is_kept() {
+        let m = Magnet::parse(&format!(
+            "magnet:?xt.1=urn:btih:{}&xt.2=urn:btmh:1220{}&xt=urn:sha1:abc",
+            HEX, V2_HEX
+        ))
+        .unwrap();
+        assert_eq!(
+            m.exact_topics,
+            [
+                ExactTopic::BtInfoHash(v1()),
+                ExactTopic::BtMultihash(v2()),
+                ExactTopic::Other("urn:sha1:abc".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn peers_may_be_bracketed_ipv6() {
+        let m = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&x.pe=1.2.3.4:6881&x.pe=%5B::1%5D:6882&x.pe=[fe80::2]:1",
+            HEX
+        ))
+        .unwrap();
+        let peers: Vec<(&str, u16)> = m.peers.iter().map(|p| (p.host.as_str(), p.port)).collect();
+        assert_eq!(peers, [("1.2.3.4", 6881), ("::1", 6882), ("fe80::2", 1)]);
+        assert_eq!(m.peers[1].to_string(), "[::1]:6882");
+        for bad in ["::1:6881", "[::1]6881", ":6881", "host:port"] {
+            assert!(matches!(
+                bad.parse::<PeerAddress>(),
+                Err(Error::InvalidParameter { name: "x.pe", .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn select_only_takes_indices_and_ranges() {
+        let m = Magnet::parse(&format!("magnet:?xt=urn:btih:{}&so=0,2,4-6", HEX)).unwrap();
+        assert_eq!(m.select_only, [0..=0, 2..=2, 4..=6]);
+        let selected: Vec<usize> = (0..8).filter(|i| m.is_selected(*i)).collect();
+        assert_eq!(selected, [0, 2, 4, 5, 6]);
+        assert_eq!(
+            Magnet::parse(&format!("magnet:?xt=urn:btih:{}&so=3-1", HEX)),
+            Err(Error::InvalidParameter {
+                name: "so",
+                value: "3-1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn keywords_are_split_on_plus() {
+        let m = Magnet::parse(&format!("magnet:?xt=urn:btih:{}&kt=big+buck%20bunny", HEX)).unwrap();
+        assert_eq!(m.keywords, ["big", "buck", "bunny"]);
+    }
+
+    #[test]
+    fn a_link_survives_printing_and_parsing() {
+        let mut m = Magnet::from_info_hash(v1())
+            .with_exact_topic(ExactTopic::BtMultihash(v2()))
+            .with_display_name("a name & more")
+            .with_exact_length(12345)
+            .with_tracker("udp://tracker.example:6969/announce?x=1")
+            .with_web_seed("http://seed.example/file")
+            .with_peer("[::1]:6881".parse().unwrap())
+            .with_peer("10.0.0.1:1".parse().unwrap())
+            .with_select_only(1..=1)
+            .with_select_only(3..=5)
+            .with_keyword("k1")
+            .with_keyword("k2");
+        m.extra.push(("x.custom".to_string(), "a b:c".to_string()));
+        let s = m.to_string();
+        assert_eq!(Magnet::parse(&s), Ok(m));
+    }
+
+    #[test]
+    fn malformed_links_have_typed_errors() {
+        assert_eq!(Magnet::parse("http://example"), Err(Error::NotMagnet));
+        assert_eq!(Magnet::parse("magnet:?dn=x"), Err(Error::MissingExactTopic));
+        assert_eq!(
+            Magnet::parse(&format!("magnet:?xt=urn:btih:{}&dn=%zz", HEX)),
+            Err(Error::InvalidEncoding)
+        );
+        assert_eq!(
+            Magnet::parse(&format!("magnet:?xt=urn:btih:{}&xl=big", HEX)),
+            Err(Error::InvalidParameter {
+                name: "xl",
+                value: "big".to_string()
+            })
+        );
+    }
+}
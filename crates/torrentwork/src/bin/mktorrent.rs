@@ -0,0 +1,96 @@
+use std::{env, io::Write, process};
+
+use torrentwork::{
+    creator::{TorrentBuilder, TorrentVersion},
+    file::TorrentFile,
+};
+
+const USAGE: &str = "usage: mktorrent <file or directory> [options]
+
+options:
+  -o, --output <file>        .torrent output path, default <name>.torrent
+  -a, --announce <urls>      add a tracker tier, urls in one tier separated by ','
+  -p, --piece-length <n>     piece length in bytes, picked automatically by default
+  -c, --comment <text>       comment
+  -w, --web-seed <url>       add a web seed url
+  -s, --source <text>        source tag
+      --private              set the private flag
+      --no-date              leave out the creation date
+      --meta-version <v>     1, 2 or hybrid, default 1
+      --threads <n>          number of hashing threads";
+
+fn main() {
+    if let Err(e) = run(env::args().skip(1).collect()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let mut args = args.into_iter();
+    let mut root: Option<String> = None;
+    let mut output: Option<String> = None;
+    let mut options: Vec<(String, Option<String>)> = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            "--private" | "--no-date" => options.push((arg, None)),
+            "-o" | "--output" => output = Some(value(&arg, args.next())?),
+            "-a" | "--announce" | "-p" | "--piece-length" | "-c" | "--comment" | "-w"
+            | "--web-seed" | "-s" | "--source" | "--meta-version" | "--threads" => {
+                let v = value(&arg, args.next())?;
+                options.push((arg, Some(v)));
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => root = Some(arg),
+        }
+    }
+    let root = match root {
+        Some(r) => r,
+        None => return Err(USAGE.to_string()),
+    };
+    let mut builder = TorrentBuilder::new(&root);
+    for (opt, v) in options {
+        let v = v.unwrap_or_default();
+        builder = match opt.as_str() {
+            "--private" => builder.private(true),
+            "--no-date" => builder.creation_date(None),
+            "-a" | "--announce" => {
+                builder.announce_tier(v.split(',').map(|u| u.trim().to_string()).collect())
+            }
+            "-p" | "--piece-length" => builder.piece_length(number(&opt, &v)?),
+            "-c" | "--comment" => builder.comment(&v),
+            "-w" | "--web-seed" => builder.web_seed(&v),
+            "-s" | "--source" => builder.source(&v),
+            "--meta-version" => builder.version(match v.as_str() {
+                "1" => TorrentVersion::V1,
+                "2" => TorrentVersion::V2,
+                "hybrid" => TorrentVersion::Hybrid,
+                _ => return Err(format!("unknown meta version {:?}", v)),
+            }),
+            _ => builder.threads(number(&opt, &v)? as usize),
+        };
+    }
+    let progress = |done: usize, total: usize| {
+        eprint!("\rhashing {}/{} pieces", done, total);
+        let _ = std::io::stderr().flush();
+    };
+    let tf = TorrentFile::make_with_progress(&builder, &progress).map_err(|e| e.to_string())?;
+    eprintln!();
+    let output = output.unwrap_or_else(|| format!("{}.torrent", tf.meta_data.info.name));
+    tf.storage(&output).map_err(|e| e.to_string())?;
+    println!("{} {}", tf.info_hash, output);
+    Ok(())
+}
+
+fn value(opt: &str, v: Option<String>) -> Result<String, String> {
+    v.ok_or_else(|| format!("{} needs a value", opt))
+}
+
+fn number(opt: &str, v: &str) -> Result<u64, String> {
+    v.parse()
+        .map_err(|_| format!("{} expects a number, got {:?}", opt, v))
+}
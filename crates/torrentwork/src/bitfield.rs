@@ -0,0 +1,79 @@
+use crate::error::PeerError;
+
+/// a piece bitfield as sent on the wire, the high bit of the first byte is
+/// piece 0.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// an empty bitfield for `len` pieces
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+    /// a bitfield with every piece set
+    pub fn full(len: usize) -> Self {
+        let mut b = Self::new(len);
+        for i in 0..len {
+            b.set(i);
+        }
+        b
+    }
+    /// parse a wire bitfield for `len` pieces, the spare bits must be zero
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, PeerError> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(PeerError::InvalidBitfield(format!(
+                "{} bytes for {} pieces",
+                bytes.len(),
+                len
+            )));
+        }
+        if !len.is_multiple_of(8) && bytes[bytes.len() - 1] & (0xff >> (len % 8)) != 0 {
+            return Err(PeerError::InvalidBitfield("spare bits set".to_string()));
+        }
+        Ok(Self {
+            bits: bytes.to_vec(),
+            len,
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// out of range pieces are reported as missing
+    pub fn get(&self, i: usize) -> bool {
+        i < self.len && self.bits[i / 8] & (0x80 >> (i % 8)) != 0
+    }
+    /// out of range pieces are ignored
+    pub fn set(&mut self, i: usize) {
+        if i < self.len {
+            self.bits[i / 8] |= 0x80 >> (i % 8);
+        }
+    }
+    pub fn clear(&mut self, i: usize) {
+        if i < self.len {
+            self.bits[i / 8] &= !(0x80 >> (i % 8));
+        }
+    }
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+    pub fn all(&self) -> bool {
+        self.count() == self.len
+    }
+    /// indices of the set pieces
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|i| self.get(*i))
+    }
+    /// the wire form
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
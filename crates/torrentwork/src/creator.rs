@@ -0,0 +1,712 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bencode::{Dict, List, Value};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    error::{Error, MetainfoError},
+    merkle::{self, MerkleHash},
+};
+
+/// smallest piece length picked automatically, 16 KiB
+const MIN_AUTO_PIECE_LENGTH: u64 = 16 * 1024;
+/// largest piece length picked automatically, 16 MiB
+const MAX_AUTO_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// number of pieces the automatic piece length aims for
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// the callback invoked while hashing, with (hashed pieces, total pieces).
+pub type FnCreateProgressCallBack = dyn Fn(usize, usize) + Send + Sync;
+
+/// which metadata format to create
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TorrentVersion {
+    /// sha1 pieces only
+    V1,
+    /// BEP 52 per-file sha256 merkle trees only
+    V2,
+    /// both, with padding files so v1 and v2 pieces line up
+    Hybrid,
+}
+
+/// a regular file that goes into the torrent
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    /// absolute location on disk
+    pub disk_path: PathBuf,
+    /// path components relative to the torrent root
+    pub path: Vec<String>,
+    /// file size in bytes
+    pub length: u64,
+}
+
+/// builds a .torrent from a file or a directory
+#[derive(Clone, Debug)]
+pub struct TorrentBuilder {
+    root: PathBuf,
+    piece_length: Option<u64>,
+    announce_tiers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    web_seeds: Vec<String>,
+    source: Option<String>,
+    threads: usize,
+    version: TorrentVersion,
+}
+
+impl TorrentBuilder {
+    /// start building a torrent for the file or directory at `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .ok();
+        Self {
+            root: root.into(),
+            piece_length: None,
+            announce_tiers: vec![],
+            comment: None,
+            created_by: Some(format!("torrentwork/{}", env!("CARGO_PKG_VERSION"))),
+            creation_date: now,
+            private: false,
+            web_seeds: vec![],
+            source: None,
+            threads: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            version: TorrentVersion::V1,
+        }
+    }
+    /// fixed piece length, must be a power of two and at least 16 KiB.
+    pub fn piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+    /// append a tier of tracker urls, the first url of the first tier becomes "announce".
+    pub fn announce_tier(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.announce_tiers.push(tier);
+        }
+        self
+    }
+    /// append a single tracker as its own tier.
+    pub fn announce(self, url: &str) -> Self {
+        self.announce_tier(vec![url.to_string()])
+    }
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+    /// "created by", `None` leaves the key out.
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+    /// "creation date" as unix seconds, `None` leaves the key out for reproducible output.
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+    /// add a BEP 19 web seed url ("url-list").
+    pub fn web_seed(mut self, url: &str) -> Self {
+        self.web_seeds.push(url.to_string());
+        self
+    }
+    /// "source" tag inside the info dictionary, used by private trackers to make
+    /// the info hash unique per site.
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+    /// metadata format, v1 by default.
+    pub fn version(mut self, version: TorrentVersion) -> Self {
+        self.version = version;
+        self
+    }
+    /// number of hashing threads, at least one.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+    /// hash the content and return the bencoded .torrent bytes.
+    pub fn build(&self) -> Result<Vec<u8>, Error> {
+        self.build_with_progress(&|_, _| {})
+    }
+    /// like `build`, reporting hashing progress to `progress`.
+    pub fn build_with_progress(
+        &self,
+        progress: &FnCreateProgressCallBack,
+    ) -> Result<Vec<u8>, Error> {
+        let name = match self.root.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n.to_string(),
+            None => {
+                return Err(MetainfoError::invalid(
+                    "name",
+                    format!("invalid torrent root {:?}", self.root),
+                )
+                .into());
+            }
+        };
+        let meta = fs::metadata(&self.root)?;
+        let is_dir = meta.is_dir();
+        let files = if is_dir {
+            let mut files = vec![];
+            collect_files(&self.root, &mut vec![], &mut files)?;
+            if files.is_empty() {
+                return Err(MetainfoError::invalid(
+                    "files",
+                    format!("directory {:?} has no files", self.root),
+                )
+                .into());
+            }
+            files
+        } else {
+            vec![SourceFile {
+                disk_path: self.root.clone(),
+                path: vec![name.clone()],
+                length: meta.len(),
+            }]
+        };
+        let total: u64 = files.iter().map(|f| f.length).sum();
+        let piece_length = match self.piece_length {
+            Some(pl) if pl >= MIN_AUTO_PIECE_LENGTH && pl.is_power_of_two() => pl,
+            Some(pl) => {
+                return Err(MetainfoError::invalid(
+                    "piece length",
+                    format!("{} is not a power of two of at least 16 KiB", pl),
+                )
+                .into());
+            }
+            None => auto_piece_length(total),
+        };
+        let mut info = Dict::new();
+        let mut piece_layers = Dict::new();
+        match self.version {
+            TorrentVersion::V1 => {
+                let pieces = hash_v1(&files, total, piece_length, self.threads, progress)?;
+                insert_v1_files(&mut info, &files, is_dir, 0);
+                info.insert("pieces", Value::bytes(pieces));
+            }
+            TorrentVersion::V2 | TorrentVersion::Hybrid => {
+                let hybrid = self.version == TorrentVersion::Hybrid;
+                let hashed = hash_v2(&files, piece_length, hybrid, self.threads, progress)?;
+                let mut tree = TreeNode::Dir(BTreeMap::new());
+                for (f, h) in files.iter().zip(hashed.iter()) {
+                    tree.insert(&f.path, f.length, h.root);
+                    if let (Some(root), true) = (h.root, h.layer.len() > 1) {
+                        piece_layers.insert_bytes(root.to_vec(), Value::bytes(h.layer.concat()));
+                    }
+                }
+                info.insert("file tree", tree.into_value());
+                info.insert("meta version", 2);
+                if hybrid {
+                    insert_v1_files(&mut info, &files, is_dir, piece_length);
+                    let pieces: Vec<u8> = hashed.iter().flat_map(|h| h.v1.concat()).collect();
+                    info.insert("pieces", Value::bytes(pieces));
+                }
+            }
+        }
+        info.insert("name", name);
+        info.insert("piece length", piece_length as i64);
+        if self.private {
+            info.insert("private", 1);
+        }
+        if let Some(source) = &self.source {
+            info.insert("source", source.as_str());
+        }
+
+        let mut root = Dict::new();
+        if let Some(first) = self.announce_tiers.first().and_then(|t| t.first()) {
+            root.insert("announce", first.as_str());
+        }
+        if self.announce_tiers.len() > 1 || self.announce_tiers.iter().any(|t| t.len() > 1) {
+            let mut tiers = List::new();
+            for tier in self.announce_tiers.iter() {
+                let mut l = List::new();
+                for url in tier.iter() {
+                    l.push(url.as_str());
+                }
+                tiers.push(l);
+            }
+            root.insert("announce-list", tiers);
+        }
+        if let Some(comment) = &self.comment {
+            root.insert("comment", comment.as_str());
+        }
+        if let Some(created_by) = &self.created_by {
+            root.insert("created by", created_by.as_str());
+        }
+        if let Some(date) = self.creation_date {
+            root.insert("creation date", date);
+        }
+        root.insert("info", info);
+        if !piece_layers.is_empty() {
+            root.insert("piece layers", piece_layers);
+        }
+        match self.web_seeds.len() {
+            0 => {}
+            1 => root.insert("url-list", self.web_seeds[0].as_str()),
+            _ => {
+                let mut l = List::new();
+                for url in self.web_seeds.iter() {
+                    l.push(url.as_str());
+                }
+                root.insert("url-list", l);
+            }
+        }
+        Ok(Value::Dict(root).to_bytes())
+    }
+}
+
+/// pick a power-of-two piece length giving roughly `TARGET_PIECE_COUNT` pieces.
+pub fn auto_piece_length(total: u64) -> u64 {
+    let target = total / TARGET_PIECE_COUNT;
+    let mut pl = MIN_AUTO_PIECE_LENGTH;
+    while pl < target && pl < MAX_AUTO_PIECE_LENGTH {
+        pl *= 2;
+    }
+    pl
+}
+
+/// walk a directory in sorted order so the same tree always yields the same torrent.
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    out: &mut Vec<SourceFile>,
+) -> Result<(), Error> {
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(n) => {
+                return Err(MetainfoError::invalid(
+                    "path",
+                    format!("file name is not valid utf-8 {:?}", n),
+                )
+                .into());
+            }
+        };
+        let meta = entry.metadata()?;
+        prefix.push(name);
+        if meta.is_dir() {
+            collect_files(&entry.path(), prefix, out)?;
+        } else if meta.is_file() {
+            out.push(SourceFile {
+                disk_path: entry.path(),
+                path: prefix.clone(),
+                length: meta.len(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// add the v1 "files" list or "length" to `info`. a non-zero `align` inserts
+/// BEP 47 padding files so every file starts on a piece boundary, as hybrid
+/// torrents need for their v1 and v2 pieces to line up.
+fn insert_v1_files(info: &mut Dict<'_>, files: &[SourceFile], is_dir: bool, align: u64) {
+    if !is_dir {
+        info.insert("length", files[0].length as i64);
+        return;
+    }
+    let last = files.iter().rposition(|f| f.length > 0);
+    let mut list = List::new();
+    for (i, f) in files.iter().enumerate() {
+        let mut fd = Dict::new();
+        fd.insert("length", f.length as i64);
+        let mut path = List::new();
+        for p in f.path.iter() {
+            path.push(p.as_str());
+        }
+        fd.insert("path", path);
+        list.push(fd);
+        let pad = if align > 0 && f.length % align != 0 {
+            align - f.length % align
+        } else {
+            0
+        };
+        if pad > 0 && Some(i) != last {
+            let mut fd = Dict::new();
+            fd.insert("attr", "p");
+            fd.insert("length", pad as i64);
+            let mut path = List::new();
+            path.push(".pad");
+            path.push(pad.to_string());
+            fd.insert("path", path);
+            list.push(fd);
+        }
+    }
+    info.insert("files", list);
+}
+
+/// a directory level of a v2 "file tree" under construction
+enum TreeNode {
+    Dir(BTreeMap<String, TreeNode>),
+    File(u64, Option<MerkleHash>),
+}
+
+impl TreeNode {
+    fn insert(&mut self, path: &[String], length: u64, root: Option<MerkleHash>) {
+        if let TreeNode::Dir(children) = self {
+            match path {
+                [name] => {
+                    children.insert(name.clone(), TreeNode::File(length, root));
+                }
+                [name, rest @ ..] => children
+                    .entry(name.clone())
+                    .or_insert_with(|| TreeNode::Dir(BTreeMap::new()))
+                    .insert(rest, length, root),
+                [] => {}
+            }
+        }
+    }
+    fn into_value(self) -> Value<'static> {
+        let mut d = Dict::new();
+        match self {
+            TreeNode::Dir(children) => {
+                for (name, child) in children {
+                    d.insert(&name, child.into_value());
+                }
+            }
+            TreeNode::File(length, root) => {
+                let mut attrs = Dict::new();
+                attrs.insert("length", length as i64);
+                if let Some(root) = root {
+                    attrs.insert("pieces root", Value::bytes(root.to_vec()));
+                }
+                d.insert("", attrs);
+            }
+        }
+        Value::Dict(d)
+    }
+}
+
+/// run `task` over every item on `threads` workers, splitting the items into
+/// contiguous ranges so each worker reads sequentially, and keep the input order.
+fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    files: &[SourceFile],
+    threads: usize,
+    progress: &FnCreateProgressCallBack,
+    task: impl Fn(&T, &mut PieceReader) -> Result<R, Error> + Sync,
+) -> Result<Vec<R>, Error> {
+    let done = AtomicUsize::new(0);
+    let per_worker = items.len().div_ceil(threads.max(1)).max(1);
+    let results: Vec<Result<Vec<R>, Error>> = thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(per_worker)
+            .map(|chunk| {
+                let done = &done;
+                let task = &task;
+                s.spawn(move || {
+                    let mut reader = PieceReader::new(files);
+                    let mut out = Vec::with_capacity(chunk.len());
+                    for item in chunk {
+                        out.push(task(item, &mut reader)?);
+                        let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+                        progress(n, items.len());
+                    }
+                    Ok(out)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| match h.join() {
+                Ok(r) => r,
+                Err(_) => Err(io::Error::other("hashing thread panicked").into()),
+            })
+            .collect()
+    });
+    let mut out = Vec::with_capacity(items.len());
+    for r in results {
+        out.extend(r?);
+    }
+    Ok(out)
+}
+
+/// sha1 every piece of the concatenated files.
+fn hash_v1(
+    files: &[SourceFile],
+    total: u64,
+    piece_length: u64,
+    threads: usize,
+    progress: &FnCreateProgressCallBack,
+) -> Result<Vec<u8>, Error> {
+    let indices: Vec<u64> = (0..total.div_ceil(piece_length)).collect();
+    let hashes = parallel_map(&indices, files, threads, progress, |index, reader| {
+        let start = index * piece_length;
+        let mut buf = vec![0; piece_length.min(total - start) as usize];
+        reader.read_at(start, &mut buf)?;
+        Ok(Sha1::digest(&buf))
+    })?;
+    Ok(hashes.concat())
+}
+
+/// per-file result of v2 hashing
+struct FileHashes {
+    /// "pieces root", `None` for empty files
+    root: Option<MerkleHash>,
+    /// one node per piece
+    layer: Vec<MerkleHash>,
+    /// v1 sha1 of each piece, zero padded to the piece length, hybrid only
+    v1: Vec<[u8; 20]>,
+}
+
+/// build each file's merkle tree, and for hybrid torrents the piece-aligned sha1 hashes.
+fn hash_v2(
+    files: &[SourceFile],
+    piece_length: u64,
+    hybrid: bool,
+    threads: usize,
+    progress: &FnCreateProgressCallBack,
+) -> Result<Vec<FileHashes>, Error> {
+    let last = files.iter().rposition(|f| f.length > 0);
+    let mut file_start = 0;
+    // (file index, file offset in the unpadded concatenation, piece in file)
+    let mut tasks = vec![];
+    for (i, f) in files.iter().enumerate() {
+        for p in 0..f.length.div_ceil(piece_length) {
+            tasks.push((i, file_start, p));
+        }
+        file_start += f.length;
+    }
+    let pieces = parallel_map(&tasks, files, threads, progress, |(i, start, p), reader| {
+        let offset = p * piece_length;
+        let len = piece_length.min(files[*i].length - offset) as usize;
+        let mut buf = vec![0; len];
+        reader.read_at(start + offset, &mut buf)?;
+        let leaves = merkle::block_hashes(&buf);
+        let v1 = if hybrid {
+            // every file but the last is followed by padding up to the piece boundary
+            if Some(*i) != last {
+                buf.resize(piece_length as usize, 0);
+            }
+            Some(<[u8; 20]>::from(Sha1::digest(&buf)))
+        } else {
+            None
+        };
+        Ok((*i, leaves, v1))
+    })?;
+    let mut out: Vec<FileHashes> = files
+        .iter()
+        .map(|_| FileHashes {
+            root: None,
+            layer: vec![],
+            v1: vec![],
+        })
+        .collect();
+    let mut leaves: Vec<Vec<MerkleHash>> = files.iter().map(|_| vec![]).collect();
+    for (i, piece_leaves, v1) in pieces {
+        out[i]
+            .layer
+            .push(merkle::piece_layer(&piece_leaves, piece_length as usize)[0]);
+        leaves[i].extend(piece_leaves);
+        out[i].v1.extend(v1);
+    }
+    for (h, l) in out.iter_mut().zip(leaves.iter()) {
+        if !l.is_empty() {
+            h.root = Some(merkle::file_root(l));
+        }
+    }
+    Ok(out)
+}
+
+/// reads byte ranges of the concatenated file list, keeping the current file open.
+struct PieceReader<'a> {
+    files: &'a [SourceFile],
+    open: Option<(usize, fs::File)>,
+}
+
+impl<'a> PieceReader<'a> {
+    fn new(files: &'a [SourceFile]) -> Self {
+        Self { files, open: None }
+    }
+    fn read_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        let mut file_start = 0;
+        for (i, f) in self.files.iter().enumerate() {
+            if buf.is_empty() {
+                break;
+            }
+            let file_end = file_start + f.length;
+            if offset < file_end {
+                let n = ((file_end - offset) as usize).min(buf.len());
+                let file = self.file(i)?;
+                let res = file
+                    .seek(SeekFrom::Start(offset - file_start))
+                    .and_then(|_| file.read_exact(&mut buf[..n]));
+                if let Err(e) = res {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("{} reading {:?}", e, f.disk_path),
+                    ));
+                }
+                offset += n as u64;
+                buf = &mut buf[n..];
+            }
+            file_start = file_end;
+        }
+        Ok(())
+    }
+    fn file(&mut self, i: usize) -> io::Result<&mut fs::File> {
+        if self.open.as_ref().map(|(idx, _)| *idx) != Some(i) {
+            match fs::File::open(&self.files[i].disk_path) {
+                Ok(f) => self.open = Some((i, f)),
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("{} opening {:?}", e, self.files[i].disk_path),
+                    ));
+                }
+            }
+        }
+        match self.open.as_mut() {
+            Some((_, f)) => Ok(f),
+            None => Err(io::Error::other("no open file")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::TorrentFile;
+
+    /// a directory `d` with files of 20000, 40000, 0 and 100 bytes
+    fn source(tag: &str) -> PathBuf {
+        let root = std::env::temp_dir()
+            .join(format!(
+                "torrentwork-creator-{}-{}",
+                tag,
+                std::process::id()
+            ))
+            .join("d");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let data = |n: usize| (0..n).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs::write(root.join("a"), data(20000)).unwrap();
+        fs::write(root.join("sub").join("b"), data(40000)).unwrap();
+        fs::write(root.join("sub").join("c"), []).unwrap();
+        fs::write(root.join("z"), data(100)).unwrap();
+        root
+    }
+
+    fn build(root: &Path, version: TorrentVersion, threads: usize) -> Vec<u8> {
+        TorrentBuilder::new(root)
+            .version(version)
+            .threads(threads)
+            .creation_date(None)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn every_version_parses_back() {
+        let root = source("parse");
+        for version in [
+            TorrentVersion::V1,
+            TorrentVersion::V2,
+            TorrentVersion::Hybrid,
+        ] {
+            let tf = TorrentFile::from_bytes(build(&root, version, 2)).unwrap();
+            let t = &tf.meta_data;
+            assert_eq!(t.info.name, "d");
+            assert_eq!(t.info.piece_length, 16384);
+            assert_eq!(
+                (t.is_v1(), t.is_v2()),
+                (version != TorrentVersion::V2, version != TorrentVersion::V1),
+                "{:?}",
+                version
+            );
+            assert_eq!(tf.info_hash_v2.is_some(), t.is_v2());
+            if t.is_v2() {
+                let tree = t.info.file_tree.as_ref().unwrap();
+                let lengths: Vec<i64> = tree.iter().map(|f| f.length).collect();
+                assert_eq!(lengths, [20000, 40000, 0, 100]);
+                // only files longer than a piece have a layer
+                let layers = t.piece_layers.as_ref().unwrap();
+                assert_eq!(layers.len(), 2);
+                assert_eq!(layers[&tree[1].pieces_root.unwrap()].len(), 3);
+            }
+        }
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn hybrid_files_are_padded_to_piece_boundaries() {
+        let root = source("pad");
+        let tf = TorrentFile::from_bytes(build(&root, TorrentVersion::Hybrid, 1)).unwrap();
+        let files: Vec<(String, i64, bool)> = tf
+            .meta_data
+            .info
+            .files
+            .unwrap()
+            .iter()
+            .map(|f| (f.path.join("/"), f.length, f.is_padding()))
+            .collect();
+        let file = |p: &str, l, pad| (p.to_string(), l, pad);
+        // empty files need no padding and the last file is not padded
+        assert_eq!(
+            files,
+            [
+                file("a", 20000, false),
+                file(".pad/12768", 12768, true),
+                file("sub/b", 40000, false),
+                file(".pad/9152", 9152, true),
+                file("sub/c", 0, false),
+                file("z", 100, false),
+            ]
+        );
+        // two v1 pieces for a and its padding, three for b and one for z
+        assert_eq!(tf.meta_data.info.pieces.len(), 20 * 6);
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn threads_do_not_change_the_torrent() {
+        let root = source("threads");
+        for version in [
+            TorrentVersion::V1,
+            TorrentVersion::V2,
+            TorrentVersion::Hybrid,
+        ] {
+            assert_eq!(
+                build(&root, version, 1),
+                build(&root, version, 4),
+                "{:?}",
+                version
+            );
+        }
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn piece_length_grows_with_the_content() {
+        assert_eq!(auto_piece_length(0), MIN_AUTO_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(TARGET_PIECE_COUNT * 16384), 16384);
+        assert_eq!(auto_piece_length(TARGET_PIECE_COUNT * 65536), 65536);
+        assert_eq!(auto_piece_length(TARGET_PIECE_COUNT * 65537), 131072);
+        assert_eq!(auto_piece_length(u64::MAX), MAX_AUTO_PIECE_LENGTH);
+        let root = source("length");
+        let err = TorrentBuilder::new(&root).piece_length(20000).build();
+        assert!(err.is_err());
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use bencode::{Dict, List, Value};
+
+use crate::{
+    dht::routing::{NODE_ID_LEN, NodeId},
+    error::DhtError,
+    hash::InfoHash,
+    peer::{COMPACT_PEER_V4_LEN, Peer},
+};
+
+/// a node id followed by a compact ipv4 address
+pub const COMPACT_NODE_LEN: usize = NODE_ID_LEN + COMPACT_PEER_V4_LEN;
+
+/// KRPC error codes of BEP 5
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// a BEP 5 query, every one names the querying node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping {
+        id: NodeId,
+    },
+    FindNode {
+        id: NodeId,
+        target: NodeId,
+    },
+    GetPeers {
+        id: NodeId,
+        info_hash: InfoHash,
+    },
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: InfoHash,
+        port: u16,
+        /// the peer listens on the port the query came from
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    pub fn id(&self) -> NodeId {
+        match self {
+            Query::Ping { id }
+            | Query::FindNode { id, .. }
+            | Query::GetPeers { id, .. }
+            | Query::AnnouncePeer { id, .. } => *id,
+        }
+    }
+    pub fn method(&self) -> &'static str {
+        match self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// the reply to any query, which keys are set depends on the query
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    /// nodes closer to the target or info hash
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    /// peers of the info hash
+    pub values: Vec<SocketAddr>,
+    /// what a get_peers answer hands out for announcing later
+    pub token: Option<Vec<u8>>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// a KRPC message, a bencoded dictionary sent in one udp datagram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcMessage {
+    /// chosen by the querying node and echoed in the answer
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+impl KrpcMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut d = Dict::new();
+        d.insert("t", Value::bytes(self.transaction.clone()));
+        match &self.body {
+            Body::Query(q) => {
+                d.insert("y", "q");
+                d.insert("q", q.method());
+                let mut a = Dict::new();
+                a.insert("id", Value::bytes(q.id().as_bytes().to_vec()));
+                match q {
+                    Query::Ping { .. } => {}
+                    Query::FindNode { target, .. } => {
+                        a.insert("target", Value::bytes(target.as_bytes().to_vec()));
+                    }
+                    Query::GetPeers { info_hash, .. } => {
+                        a.insert("info_hash", Value::bytes(info_hash.as_bytes().to_vec()));
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                        ..
+                    } => {
+                        a.insert("info_hash", Value::bytes(info_hash.as_bytes().to_vec()));
+                        a.insert("port", *port as i64);
+                        a.insert("implied_port", *implied_port as i64);
+                        a.insert("token", Value::bytes(token.clone()));
+                    }
+                }
+                d.insert("a", a);
+            }
+            Body::Response(r) => {
+                d.insert("y", "r");
+                let mut rd = Dict::new();
+                rd.insert("id", Value::bytes(r.id.as_bytes().to_vec()));
+                if !r.nodes.is_empty() {
+                    rd.insert("nodes", Value::bytes(compact_nodes(&r.nodes)));
+                }
+                if !r.values.is_empty() {
+                    let mut values = List::new();
+                    for addr in &r.values {
+                        values.push(Value::bytes(Peer::new(*addr).to_compact()));
+                    }
+                    rd.insert("values", values);
+                }
+                if let Some(token) = &r.token {
+                    rd.insert("token", Value::bytes(token.clone()));
+                }
+                d.insert("r", rd);
+            }
+            Body::Error { code, message } => {
+                d.insert("y", "e");
+                let mut e = List::new();
+                e.push(*code);
+                e.push(message.as_str());
+                d.insert("e", e);
+            }
+        }
+        Value::from(d).to_bytes()
+    }
+    pub fn from_bytes(b: &[u8]) -> Result<Self, DhtError> {
+        let root = bencode::decode(b).map_err(|e| invalid(e.to_string()))?;
+        let d = root
+            .as_dict()
+            .ok_or_else(|| invalid("message is not a dictionary"))?;
+        let transaction = d
+            .get_bytes("t")
+            .ok_or_else(|| invalid("missing transaction id"))?
+            .to_vec();
+        let body = match d.get_bytes("y") {
+            Some(b"q") => Body::Query(parse_query(d)?),
+            Some(b"r") => Body::Response(parse_response(
+                d.get_dict("r").ok_or_else(|| invalid("missing response"))?,
+            )?),
+            Some(b"e") => {
+                let e = d.get_list("e").ok_or_else(|| invalid("missing error"))?;
+                Body::Error {
+                    code: e.get(0).and_then(Value::as_int).unwrap_or(ERROR_GENERIC),
+                    message: e
+                        .get(1)
+                        .and_then(Value::as_bytes)
+                        .map(|m| String::from_utf8_lossy(m).into_owned())
+                        .unwrap_or_default(),
+                }
+            }
+            _ => return Err(invalid("unknown message type")),
+        };
+        Ok(Self { transaction, body })
+    }
+}
+
+/// the transaction id of a query that failed to parse, to answer it with
+/// an error
+pub fn query_transaction(b: &[u8]) -> Option<Vec<u8>> {
+    let root = bencode::decode(b).ok()?;
+    let d = root.as_dict()?;
+    match d.get_bytes("y") {
+        Some(b"q") => d.get_bytes("t").map(|t| t.to_vec()),
+        _ => None,
+    }
+}
+
+fn parse_query(d: &Dict<'_>) -> Result<Query, DhtError> {
+    let a = d
+        .get_dict("a")
+        .ok_or_else(|| invalid("missing arguments"))?;
+    let id = node_id(a, "id")?;
+    let q = match d.get_bytes("q") {
+        Some(b"ping") => Query::Ping { id },
+        Some(b"find_node") => Query::FindNode {
+            id,
+            target: node_id(a, "target")?,
+        },
+        Some(b"get_peers") => Query::GetPeers {
+            id,
+            info_hash: node_id(a, "info_hash")?.into(),
+        },
+        Some(b"announce_peer") => Query::AnnouncePeer {
+            id,
+            info_hash: node_id(a, "info_hash")?.into(),
+            port: a
+                .get_int("port")
+                .and_then(|p| u16::try_from(p).ok())
+                .ok_or_else(|| invalid("invalid port"))?,
+            implied_port: a.get_int("implied_port").is_some_and(|p| p != 0),
+            token: a
+                .get_bytes("token")
+                .ok_or_else(|| invalid("missing token"))?
+                .to_vec(),
+        },
+        Some(q) => return Err(DhtError::UnknownMethod(String::from_utf8_lossy(q).into())),
+        None => return Err(invalid("missing method")),
+    };
+    Ok(q)
+}
+
+fn parse_response(r: &Dict<'_>) -> Result<Response, DhtError> {
+    let mut values = vec![];
+    if let Some(list) = r.get_list("values") {
+        for v in list.iter().filter_map(Value::as_bytes) {
+            values.extend(
+                Peer::from_compact_list(v, COMPACT_PEER_V4_LEN)
+                    .into_iter()
+                    .map(|p| p.addr),
+            );
+        }
+    }
+    Ok(Response {
+        id: node_id(r, "id")?,
+        nodes: parse_compact_nodes(r.get_bytes("nodes").unwrap_or_default()),
+        values,
+        token: r.get_bytes("token").map(|t| t.to_vec()),
+    })
+}
+
+/// ids and addresses of `nodes` in the 26 bytes per node form
+pub fn compact_nodes(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for (id, addr) in nodes.iter().filter(|(_, a)| a.is_ipv4()) {
+        b.extend_from_slice(id.as_bytes());
+        b.extend(Peer::new(*addr).to_compact());
+    }
+    b
+}
+
+/// read 26 bytes per node, a trailing partial node is ignored
+pub fn parse_compact_nodes(b: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    b.chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|c| {
+            let id = NodeId::from_slice(&c[..NODE_ID_LEN])?;
+            let ip = Ipv4Addr::new(c[20], c[21], c[22], c[23]);
+            let port = u16::from_be_bytes([c[24], c[25]]);
+            Some((id, SocketAddr::V4(SocketAddrV4::new(ip, port))))
+        })
+        .filter(|(_, a)| a.port() != 0 && a.ip() != IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        .collect()
+}
+
+fn node_id(d: &Dict<'_>, key: &str) -> Result<NodeId, DhtError> {
+    d.get_bytes(key)
+        .and_then(NodeId::from_slice)
+        .ok_or_else(|| invalid(format!("invalid {}", key)))
+}
+
+fn invalid(reason: impl Into<String>) -> DhtError {
+    DhtError::InvalidMessage(reason.into())
+}
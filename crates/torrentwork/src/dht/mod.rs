@@ -0,0 +1,736 @@
+pub mod krpc;
+pub mod routing;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bencode::{Dict, Value};
+use futures_util::future::join_all;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    sync::oneshot,
+    time::{interval, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+pub use krpc::{Body, KrpcMessage, Query, Response};
+pub use routing::{Insert, K, NodeEntry, NodeId, RoutingTable};
+
+use crate::{
+    error::{DhtError, Error},
+    hash::InfoHash,
+};
+
+/// the routers a node without saved state joins the network through
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+/// how long a node gets to answer a query
+pub const DHT_QUERY_TIMEOUT_SEC: u64 = 5;
+/// queries of one lookup in flight at a time
+const ALPHA: usize = 3;
+/// how often the node refreshes buckets, rotates its token secret and
+/// forgets expired peers
+const DHT_MAINTENANCE_SEC: u64 = 60;
+/// a bucket nothing happened in for this long is looked up again
+const BUCKET_REFRESH_SEC: u64 = 15 * 60;
+/// tokens stay valid for one to two rotations
+const TOKEN_ROTATE_SEC: u64 = 5 * 60;
+const TOKEN_LEN: usize = 8;
+/// how long an announced peer is handed out
+const PEER_TTL_SEC: u64 = 30 * 60;
+/// peers in one get_peers answer, keeps it in one datagram
+const DHT_MAX_VALUES: usize = 50;
+/// peers kept per info hash
+const DHT_MAX_PEERS_PER_TORRENT: usize = 1000;
+/// info hashes peers are kept for
+const DHT_MAX_TORRENTS: usize = 2000;
+/// larger than any KRPC message we send or expect
+const DHT_RECV_BUFFER_LEN: usize = 4096;
+
+/// how a DHT node is set up
+#[derive(Debug, Clone)]
+pub struct DhtSettings {
+    /// `host:port` of the nodes to join through
+    pub bootstrap: Vec<String>,
+    /// where the node id and routing table are kept between sessions
+    pub state_path: Option<PathBuf>,
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtSettings {
+    fn default() -> Self {
+        Self {
+            bootstrap: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|n| n.to_string())
+                .collect(),
+            state_path: None,
+            query_timeout: Duration::from_secs(DHT_QUERY_TIMEOUT_SEC),
+        }
+    }
+}
+
+impl DhtSettings {
+    /// join through these nodes instead of the default routers
+    pub fn with_bootstrap(mut self, nodes: Vec<String>) -> Self {
+        self.bootstrap = nodes;
+        self
+    }
+    /// load the routing table from `path` and save it there on shutdown
+    pub fn with_state_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_path = Some(path.into());
+        self
+    }
+    pub fn with_query_timeout(mut self, query_timeout: Duration) -> Self {
+        self.query_timeout = query_timeout;
+        self
+    }
+}
+
+/// a BEP 5 mainline DHT node over ipv4. it answers the queries of other
+/// nodes, keeps the peers announced to it and finds the peers of info
+/// hashes. clones share the node, which runs until `shutdown`.
+#[derive(Debug, Clone)]
+pub struct Dht {
+    node: Arc<DhtNode>,
+}
+
+#[derive(Debug)]
+struct DhtNode {
+    id: NodeId,
+    socket: UdpSocket,
+    settings: DhtSettings,
+    /// cancelled on shutdown, the receive and maintenance loops exit
+    stop: CancellationToken,
+    state: Mutex<DhtState>,
+}
+
+/// where the answer to one of our queries goes
+type Answer = oneshot::Sender<Result<Response, DhtError>>;
+
+#[derive(Debug)]
+struct DhtState {
+    table: RoutingTable,
+    /// our queries waiting for an answer by transaction id, and the node
+    /// they went to
+    pending: HashMap<Vec<u8>, (SocketAddr, Answer)>,
+    next_transaction: u16,
+    /// tokens are derived from the secret, the previous one is still
+    /// accepted
+    secret: [u8; 16],
+    previous_secret: [u8; 16],
+    rotated: Instant,
+    /// peers announced to us and when
+    peers: HashMap<InfoHash, HashMap<SocketAddr, Instant>>,
+    /// nodes we are pinging to see whether they can be replaced
+    pinging: HashSet<NodeId>,
+}
+
+/// what an iterative lookup found
+#[derive(Debug, Default)]
+struct Lookup {
+    /// the nodes that answered, closest first, with their get_peers token
+    nodes: Vec<(NodeId, SocketAddr, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    /// unknown for bootstrap nodes until they answer
+    id: Option<NodeId>,
+    addr: SocketAddr,
+    probe: Probe,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Probe {
+    New,
+    Answered(Option<Vec<u8>>),
+    Failed,
+}
+
+impl Dht {
+    /// bind the node to udp `port` on every ipv4 interface, 0 lets the
+    /// system pick. the id and nodes saved at `state_path` are restored,
+    /// `bootstrap` joins the network.
+    pub async fn bind(port: u16, settings: DhtSettings) -> Result<Self, Error> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        let saved = settings.state_path.as_deref().and_then(load_state);
+        let id = saved.as_ref().map_or_else(NodeId::random, |(id, _)| *id);
+        let mut table = RoutingTable::new(id);
+        for (node, addr) in saved.into_iter().flat_map(|(_, nodes)| nodes) {
+            table.insert(node, addr);
+        }
+        let mut rng = rand::thread_rng();
+        let secret = rng.r#gen();
+        let dht = Self {
+            node: Arc::new(DhtNode {
+                id,
+                socket,
+                settings,
+                stop: CancellationToken::new(),
+                state: Mutex::new(DhtState {
+                    table,
+                    pending: HashMap::new(),
+                    next_transaction: rng.r#gen(),
+                    secret,
+                    previous_secret: secret,
+                    rotated: Instant::now(),
+                    peers: HashMap::new(),
+                    pinging: HashSet::new(),
+                }),
+            }),
+        };
+        tokio::spawn(dht.clone().receive_loop());
+        tokio::spawn(dht.clone().maintenance_loop());
+        Ok(dht)
+    }
+    pub fn id(&self) -> NodeId {
+        self.node.id
+    }
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.node.socket.local_addr()?)
+    }
+    /// nodes in the routing table
+    pub fn len(&self) -> usize {
+        self.node.state.lock().unwrap().table.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// a snapshot of the routing table
+    pub fn nodes(&self) -> Vec<NodeEntry> {
+        let state = self.node.state.lock().unwrap();
+        state.table.nodes().cloned().collect()
+    }
+    /// look up our own id through the saved nodes and the bootstrap nodes,
+    /// filling the routing table. returns its size.
+    pub async fn bootstrap(&self) -> usize {
+        let mut start = self.start_nodes(&self.id());
+        start.extend(self.bootstrap_addrs().await.into_iter().map(|a| (None, a)));
+        self.lookup(self.id(), None, start).await;
+        self.len()
+    }
+    /// ping a node, it is added to the routing table if it answers
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId, Error> {
+        let r = self.query(addr, Query::Ping { id: self.id() }).await?;
+        Ok(r.id)
+    }
+    /// the nodes closest to `target` that answered a lookup, closest first
+    pub async fn find_node(&self, target: NodeId) -> Vec<(NodeId, SocketAddr)> {
+        let start = self.joined_start_nodes(&target).await;
+        let l = self.lookup(target, None, start).await;
+        l.nodes
+            .into_iter()
+            .map(|(id, addr, _)| (id, addr))
+            .collect()
+    }
+    /// the peers the nodes closest to `info_hash` know of
+    pub async fn get_peers(&self, info_hash: InfoHash) -> Vec<SocketAddr> {
+        let target = NodeId::from(info_hash);
+        let start = self.joined_start_nodes(&target).await;
+        self.lookup(target, Some(info_hash), start).await.peers
+    }
+    /// look up the peers of `info_hash` and announce that we accept
+    /// connections for it on `port` to the closest nodes
+    pub async fn announce(&self, info_hash: InfoHash, port: u16) -> Vec<SocketAddr> {
+        let target = NodeId::from(info_hash);
+        let start = self.joined_start_nodes(&target).await;
+        let l = self.lookup(target, Some(info_hash), start).await;
+        let announces = l
+            .nodes
+            .into_iter()
+            .filter_map(|(_, addr, token)| Some((addr, token?)))
+            .take(K)
+            .map(|(addr, token)| {
+                let q = Query::AnnouncePeer {
+                    id: self.id(),
+                    info_hash,
+                    port,
+                    implied_port: false,
+                    token,
+                };
+                self.query(addr, q)
+            });
+        join_all(announces).await;
+        l.peers
+    }
+    /// the node id and the routing table as a bencoded dictionary
+    pub fn state_bytes(&self) -> Vec<u8> {
+        let state = self.node.state.lock().unwrap();
+        let nodes: Vec<(NodeId, SocketAddr)> = state
+            .table
+            .nodes()
+            .filter(|n| !n.is_bad())
+            .map(|n| (n.id, n.addr))
+            .collect();
+        let mut d = Dict::new();
+        d.insert("id", Value::bytes(self.id().as_bytes().to_vec()));
+        d.insert("nodes", Value::bytes(krpc::compact_nodes(&nodes)));
+        Value::from(d).to_bytes()
+    }
+    /// write `state_bytes` to `path`, `bind` restores it
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        Ok(fs::write(path, self.state_bytes())?)
+    }
+    /// stop answering and querying, the routing table is saved to the
+    /// `state_path` if one is set
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.node.stop.cancel();
+        for (_, (_, tx)) in self.node.state.lock().unwrap().pending.drain() {
+            let _ = tx.send(Err(DhtError::Closed));
+        }
+        match &self.node.settings.state_path {
+            Some(path) => self.save(path),
+            None => Ok(()),
+        }
+    }
+    /// send a query and wait for the answer, the routing table learns
+    /// whether the node answered
+    async fn query(&self, addr: SocketAddr, q: Query) -> Result<Response, DhtError> {
+        if self.node.stop.is_cancelled() {
+            return Err(DhtError::Closed);
+        }
+        let (tx, rx) = oneshot::channel();
+        let transaction = {
+            let mut state = self.node.state.lock().unwrap();
+            let t = state.next_transaction.to_be_bytes().to_vec();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            state.pending.insert(t.clone(), (addr, tx));
+            t
+        };
+        let m = KrpcMessage {
+            transaction: transaction.clone(),
+            body: Body::Query(q),
+        };
+        let result = match self.node.socket.send_to(&m.to_bytes(), addr).await {
+            Ok(_) => match timeout(self.node.settings.query_timeout, rx).await {
+                Ok(Ok(r)) => r,
+                Ok(Err(_)) => Err(DhtError::Closed),
+                Err(_) => Err(DhtError::Timeout),
+            },
+            Err(e) => Err(DhtError::Send(e.to_string())),
+        };
+        let mut state = self.node.state.lock().unwrap();
+        state.pending.remove(&transaction);
+        match &result {
+            Ok(r) => {
+                drop(state);
+                self.node_seen(r.id, addr);
+            }
+            Err(DhtError::Timeout) => {
+                let failed = state.table.nodes().find(|n| n.addr == addr).map(|n| n.id);
+                if let Some(id) = failed {
+                    state.table.failed(&id);
+                }
+            }
+            Err(_) => {}
+        }
+        result
+    }
+    /// offer a node that answered or queried us to the routing table. when
+    /// its bucket is full of live looking nodes the least recently seen
+    /// one is pinged and replaced if it does not answer.
+    fn node_seen(&self, id: NodeId, addr: SocketAddr) {
+        let old = {
+            let mut state = self.node.state.lock().unwrap();
+            match state.table.insert(id, addr) {
+                // one ping at a time per node, whoever else wants its slot
+                Insert::PingFirst(old) if state.pinging.insert(old.id) => old,
+                _ => return,
+            }
+        };
+        let dht = self.clone();
+        tokio::spawn(async move {
+            let answer = dht.query(old.addr, Query::Ping { id: dht.id() }).await;
+            let mut state = dht.node.state.lock().unwrap();
+            state.pinging.remove(&old.id);
+            if let Err(DhtError::Timeout) = answer {
+                state.table.remove(&old.id);
+                state.table.insert(id, addr);
+            }
+        });
+    }
+    /// the nodes of the routing table closest to `target`
+    fn start_nodes(&self, target: &NodeId) -> Vec<(Option<NodeId>, SocketAddr)> {
+        let state = self.node.state.lock().unwrap();
+        let closest = state.table.closest(target, K);
+        closest.into_iter().map(|n| (Some(n.id), n.addr)).collect()
+    }
+    /// like `start_nodes`, going through the bootstrap nodes while the
+    /// routing table is empty
+    async fn joined_start_nodes(&self, target: &NodeId) -> Vec<(Option<NodeId>, SocketAddr)> {
+        let start = self.start_nodes(target);
+        match start.is_empty() {
+            true => self
+                .bootstrap_addrs()
+                .await
+                .into_iter()
+                .map(|a| (None, a))
+                .collect(),
+            false => start,
+        }
+    }
+    /// the ipv4 addresses of the configured bootstrap nodes, the ones that
+    /// do not resolve in time are skipped
+    async fn bootstrap_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = vec![];
+        for node in &self.node.settings.bootstrap {
+            let wait = self.node.settings.query_timeout;
+            if let Ok(Ok(resolved)) = timeout(wait, lookup_host(node.as_str())).await {
+                addrs.extend(resolved.filter(SocketAddr::is_ipv4));
+            }
+        }
+        addrs
+    }
+    /// Kademlia's iterative lookup, querying the closest nodes not asked
+    /// yet, `ALPHA` at a time, until the `K` closest nodes heard of have
+    /// all answered or failed. asks for the peers of `info_hash` if given,
+    /// otherwise for the nodes closest to `target`.
+    async fn lookup(
+        &self,
+        target: NodeId,
+        info_hash: Option<InfoHash>,
+        start: Vec<(Option<NodeId>, SocketAddr)>,
+    ) -> Lookup {
+        let mut candidates: Vec<Candidate> = vec![];
+        for (id, addr) in start {
+            if !candidates.iter().any(|c| c.addr == addr) {
+                candidates.push(Candidate {
+                    id,
+                    addr,
+                    probe: Probe::New,
+                });
+            }
+        }
+        let mut peers = vec![];
+        let mut seen_peers = HashSet::new();
+        loop {
+            // nodes of unknown id are asked first
+            candidates.sort_by_key(|c| (c.id.is_some(), c.id.map(|id| target.distance(&id))));
+            let batch: Vec<usize> = candidates
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.probe != Probe::Failed)
+                .take(K)
+                .filter(|(_, c)| c.probe == Probe::New)
+                .take(ALPHA)
+                .map(|(i, _)| i)
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let queries = batch.iter().map(|i| {
+                let q = match info_hash {
+                    Some(info_hash) => Query::GetPeers {
+                        id: self.id(),
+                        info_hash,
+                    },
+                    None => Query::FindNode {
+                        id: self.id(),
+                        target,
+                    },
+                };
+                self.query(candidates[*i].addr, q)
+            });
+            let results = join_all(queries).await;
+            for (i, r) in batch.into_iter().zip(results) {
+                let r = match r {
+                    Ok(r) => r,
+                    Err(_) => {
+                        candidates[i].probe = Probe::Failed;
+                        continue;
+                    }
+                };
+                candidates[i].id = Some(r.id);
+                candidates[i].probe = Probe::Answered(r.token);
+                peers.extend(r.values.into_iter().filter(|p| seen_peers.insert(*p)));
+                for (id, addr) in r.nodes {
+                    if id != self.id() && !candidates.iter().any(|c| c.addr == addr) {
+                        candidates.push(Candidate {
+                            id: Some(id),
+                            addr,
+                            probe: Probe::New,
+                        });
+                    }
+                }
+            }
+            if self.node.stop.is_cancelled() {
+                break;
+            }
+        }
+        self.node.state.lock().unwrap().table.touch(&target);
+        let nodes = candidates
+            .into_iter()
+            .filter_map(|c| match (c.id, c.probe) {
+                (Some(id), Probe::Answered(token)) => Some((id, c.addr, token)),
+                _ => None,
+            })
+            .collect();
+        Lookup { nodes, peers }
+    }
+    async fn receive_loop(self) {
+        let mut buf = vec![0; DHT_RECV_BUFFER_LEN];
+        loop {
+            let (len, from) = tokio::select! {
+                r = self.node.socket.recv_from(&mut buf) => match r {
+                    Ok(r) => r,
+                    Err(_) => continue,
+                },
+                _ = self.node.stop.cancelled() => return,
+            };
+            let reply = match KrpcMessage::from_bytes(&buf[..len]) {
+                Ok(KrpcMessage {
+                    transaction,
+                    body: Body::Query(q),
+                }) => KrpcMessage {
+                    transaction,
+                    body: self.answer(from, q),
+                },
+                Ok(KrpcMessage {
+                    transaction,
+                    body: Body::Response(r),
+                }) => {
+                    self.resolve(from, &transaction, Ok(r));
+                    continue;
+                }
+                Ok(KrpcMessage {
+                    transaction,
+                    body: Body::Error { code, message },
+                }) => {
+                    self.resolve(from, &transaction, Err(DhtError::Remote { code, message }));
+                    continue;
+                }
+                // queries we cannot parse are answered with an error
+                Err(e) => match krpc::query_transaction(&buf[..len]) {
+                    Some(transaction) => {
+                        let code = match e {
+                            DhtError::UnknownMethod(_) => krpc::ERROR_METHOD_UNKNOWN,
+                            _ => krpc::ERROR_PROTOCOL,
+                        };
+                        KrpcMessage {
+                            transaction,
+                            body: Body::Error {
+                                code,
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    None => continue,
+                },
+            };
+            let _ = self.node.socket.send_to(&reply.to_bytes(), from).await;
+        }
+    }
+    /// hand an answer to the query waiting for it, answers from another
+    /// address than the query went to are dropped
+    fn resolve(&self, from: SocketAddr, transaction: &[u8], r: Result<Response, DhtError>) {
+        let mut state = self.node.state.lock().unwrap();
+        if state
+            .pending
+            .get(transaction)
+            .is_some_and(|(addr, _)| *addr == from)
+            && let Some((_, tx)) = state.pending.remove(transaction)
+        {
+            let _ = tx.send(r);
+        }
+    }
+    /// the answer to a query from `from`
+    fn answer(&self, from: SocketAddr, q: Query) -> Body {
+        self.node_seen(q.id(), from);
+        let mut state = self.node.state.lock().unwrap();
+        let mut r = Response::new(self.id());
+        match q {
+            Query::Ping { .. } => {}
+            Query::FindNode { target, .. } => r.nodes = closest(&state.table, &target),
+            Query::GetPeers { info_hash, .. } => {
+                r.token = Some(token(&state.secret, from.ip()));
+                r.values = state
+                    .peers
+                    .get(&info_hash)
+                    .map(|p| p.keys().take(DHT_MAX_VALUES).copied().collect())
+                    .unwrap_or_default();
+                if r.values.is_empty() {
+                    r.nodes = closest(&state.table, &info_hash.into());
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token: t,
+                ..
+            } => {
+                if t != token(&state.secret, from.ip())
+                    && t != token(&state.previous_secret, from.ip())
+                {
+                    return Body::Error {
+                        code: krpc::ERROR_PROTOCOL,
+                        message: "bad token".to_string(),
+                    };
+                }
+                let peer = match implied_port {
+                    true => from,
+                    false => SocketAddr::new(from.ip(), port),
+                };
+                if state.peers.len() < DHT_MAX_TORRENTS || state.peers.contains_key(&info_hash) {
+                    let peers = state.peers.entry(info_hash).or_default();
+                    if peers.len() < DHT_MAX_PEERS_PER_TORRENT || peers.contains_key(&peer) {
+                        peers.insert(peer, Instant::now());
+                    }
+                }
+            }
+        }
+        Body::Response(r)
+    }
+    async fn maintenance_loop(self) {
+        let mut tick = interval(Duration::from_secs(DHT_MAINTENANCE_SEC));
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {}
+                _ = self.node.stop.cancelled() => return,
+            }
+            let stale = {
+                let mut state = self.node.state.lock().unwrap();
+                if state.rotated.elapsed() >= Duration::from_secs(TOKEN_ROTATE_SEC) {
+                    state.previous_secret = state.secret;
+                    state.secret = rand::thread_rng().r#gen();
+                    state.rotated = Instant::now();
+                }
+                let ttl = Duration::from_secs(PEER_TTL_SEC);
+                for peers in state.peers.values_mut() {
+                    peers.retain(|_, at| at.elapsed() < ttl);
+                }
+                state.peers.retain(|_, peers| !peers.is_empty());
+                state
+                    .table
+                    .stale_targets(Duration::from_secs(BUCKET_REFRESH_SEC))
+            };
+            for target in stale {
+                self.find_node(target).await;
+            }
+            if self.is_empty() {
+                self.bootstrap().await;
+            }
+        }
+    }
+}
+
+/// the nodes of `table` closest to `target` as sent in answers
+fn closest(table: &RoutingTable, target: &NodeId) -> Vec<(NodeId, SocketAddr)> {
+    table
+        .closest(target, K)
+        .into_iter()
+        .filter(|n| !n.is_bad())
+        .map(|n| (n.id, n.addr))
+        .collect()
+}
+
+/// the token handed to `ip`, a hash of its address and our secret
+fn token(secret: &[u8; 16], ip: IpAddr) -> Vec<u8> {
+    let mut h = Sha1::new();
+    h.update(secret);
+    match ip {
+        IpAddr::V4(ip) => h.update(ip.octets()),
+        IpAddr::V6(ip) => h.update(ip.octets()),
+    }
+    h.finalize()[..TOKEN_LEN].to_vec()
+}
+
+/// the id and nodes saved by `Dht::save`, `None` when missing or malformed
+fn load_state(path: &Path) -> Option<(NodeId, Vec<(NodeId, SocketAddr)>)> {
+    let b = fs::read(path).ok()?;
+    let root = bencode::decode(&b).ok()?;
+    let d = root.as_dict()?;
+    let id = NodeId::from_slice(d.get_bytes("id")?)?;
+    let nodes = krpc::parse_compact_nodes(d.get_bytes("nodes").unwrap_or_default());
+    Some((id, nodes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a node on a free port joining through `bootstrap`
+    async fn node(bootstrap: Option<&Dht>) -> Dht {
+        let settings = DhtSettings::default()
+            .with_bootstrap(
+                bootstrap
+                    .map(|b| vec![loopback(b).to_string()])
+                    .unwrap_or_default(),
+            )
+            .with_query_timeout(Duration::from_millis(500));
+        Dht::bind(0, settings).await.unwrap()
+    }
+
+    /// where the other nodes reach `dht`
+    fn loopback(dht: &Dht) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], dht.local_addr().unwrap().port()))
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_found_by_other_nodes() {
+        let router = node(None).await;
+        let mut nodes = vec![];
+        for _ in 0..3 {
+            let n = node(Some(&router)).await;
+            assert!(n.bootstrap().await > 0);
+            nodes.push(n);
+        }
+        // the last node to join learned of every earlier one
+        assert_eq!(nodes[2].len(), 3);
+        let info_hash = InfoHash::new([7; 20]);
+        assert!(nodes[0].announce(info_hash, 6881).await.is_empty());
+        let peers = nodes[2].get_peers(info_hash).await;
+        assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 6881))]);
+        for n in nodes.iter().chain([&router]) {
+            n.shutdown().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_needs_a_token_handed_out_to_us() {
+        let (a, b) = (node(None).await, node(None).await);
+        let info_hash = InfoHash::new([9; 20]);
+        let announce = |token: Vec<u8>| Query::AnnouncePeer {
+            id: a.id(),
+            info_hash,
+            port: 6881,
+            implied_port: true,
+            token,
+        };
+        let rejected = a.query(loopback(&b), announce(vec![0; TOKEN_LEN])).await;
+        assert!(matches!(
+            rejected,
+            Err(DhtError::Remote {
+                code: krpc::ERROR_PROTOCOL,
+                ..
+            })
+        ));
+        let get_peers = Query::GetPeers {
+            id: a.id(),
+            info_hash,
+        };
+        let r = a.query(loopback(&b), get_peers.clone()).await.unwrap();
+        assert!(r.values.is_empty());
+        a.query(loopback(&b), announce(r.token.unwrap()))
+            .await
+            .unwrap();
+        // an implied port is the port the announce came from
+        let r = a.query(loopback(&b), get_peers).await.unwrap();
+        assert_eq!(r.values, vec![loopback(&a)]);
+        a.shutdown().unwrap();
+        b.shutdown().unwrap();
+    }
+}
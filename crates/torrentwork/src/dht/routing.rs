@@ -0,0 +1,405 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::hash::InfoHash;
+
+/// length of a node id in bytes
+pub const NODE_ID_LEN: usize = 20;
+/// nodes per bucket
+pub const K: usize = 8;
+/// a node not heard from for this long is questionable
+pub const NODE_QUESTIONABLE_SEC: u64 = 15 * 60;
+/// queries a node may fail in a row before it is bad
+const NODE_MAX_FAILURES: u32 = 2;
+
+/// a 160 bit DHT node id, also the key space info hashes live in
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId([u8; NODE_ID_LEN]);
+
+impl NodeId {
+    pub fn new(bytes: [u8; NODE_ID_LEN]) -> Self {
+        Self(bytes)
+    }
+    pub fn random() -> Self {
+        Self(rand::thread_rng().r#gen())
+    }
+    /// build from a byte slice, which must be exactly 20 bytes long
+    pub fn from_slice(b: &[u8]) -> Option<Self> {
+        <[u8; NODE_ID_LEN]>::try_from(b).ok().map(Self)
+    }
+    pub fn as_bytes(&self) -> &[u8; NODE_ID_LEN] {
+        &self.0
+    }
+    /// the XOR metric of Kademlia
+    pub fn distance(&self, other: &NodeId) -> [u8; NODE_ID_LEN] {
+        let mut d = [0; NODE_ID_LEN];
+        for (i, b) in d.iter_mut().enumerate() {
+            *b = self.0[i] ^ other.0[i];
+        }
+        d
+    }
+    /// which of `a` and `b` is closer to us
+    pub fn cmp_distance(&self, a: &NodeId, b: &NodeId) -> Ordering {
+        self.distance(a).cmp(&self.distance(b))
+    }
+    /// bits shared with `other` from the top, 160 for the same id
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let d = self.distance(other);
+        match d.iter().position(|b| *b != 0) {
+            Some(i) => i * 8 + d[i].leading_zeros() as usize,
+            None => NODE_ID_LEN * 8,
+        }
+    }
+    /// a random id sharing exactly `prefix` leading bits with us
+    fn random_with_prefix(&self, prefix: usize) -> NodeId {
+        let mut id = NodeId::random();
+        for bit in 0..=prefix.min(NODE_ID_LEN * 8 - 1) {
+            let (byte, mask) = (bit / 8, 0x80 >> (bit % 8));
+            // the bit after the prefix is the one that differs
+            let ours = self.0[byte] & mask != 0;
+            let set = if bit == prefix { !ours } else { ours };
+            match set {
+                true => id.0[byte] |= mask,
+                false => id.0[byte] &= !mask,
+            }
+        }
+        id
+    }
+}
+
+impl From<InfoHash> for NodeId {
+    fn from(h: InfoHash) -> Self {
+        Self(*h.as_bytes())
+    }
+}
+
+impl From<NodeId> for InfoHash {
+    fn from(id: NodeId) -> Self {
+        InfoHash::new(id.0)
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", hex::encode(self.0))
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// a node of the routing table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeEntry {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    /// when the node last answered or queried us
+    pub last_seen: Instant,
+    /// queries it failed to answer since
+    pub failures: u32,
+}
+
+impl NodeEntry {
+    fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Self {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        }
+    }
+    /// recently heard from
+    pub fn is_good(&self) -> bool {
+        self.failures == 0 && self.last_seen.elapsed() < Duration::from_secs(NODE_QUESTIONABLE_SEC)
+    }
+    pub fn is_bad(&self) -> bool {
+        self.failures >= NODE_MAX_FAILURES
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    /// least recently seen first
+    nodes: Vec<NodeEntry>,
+    /// when a node in the bucket was last added or heard from
+    last_changed: Instant,
+}
+
+/// what became of a node offered to the routing table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Insert {
+    /// added, or already known and refreshed
+    Added,
+    /// the bucket is full of good nodes, the node is us, or a good node
+    /// with another id has the address
+    Ignored,
+    /// the bucket is full and its least recently seen node is
+    /// questionable, ping it and call `failed` if it does not answer
+    PingFirst(NodeEntry),
+}
+
+/// Kademlia routing table of k-buckets, bucket `i` holds the nodes whose id
+/// shares exactly `i` leading bits with ours
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        let bucket = Bucket {
+            nodes: vec![],
+            last_changed: Instant::now(),
+        };
+        Self {
+            id,
+            buckets: vec![bucket; NODE_ID_LEN * 8],
+        }
+    }
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// every node, closest buckets last
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeEntry> {
+        self.buckets.iter().flat_map(|b| b.nodes.iter())
+    }
+    /// note that a node answered or queried us. a full bucket makes room by
+    /// dropping a bad node, otherwise the newcomer waits for a
+    /// questionable one to fail a ping. an address is held by one id, a
+    /// new id takes it over only once the old one is no longer good.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> Insert {
+        let previous = match self.nodes().find(|n| n.addr == addr && n.id != id) {
+            Some(n) if n.is_good() => return Insert::Ignored,
+            Some(n) => Some(n.id),
+            None => None,
+        };
+        let bucket = match self.bucket_mut(&id) {
+            Some(b) => b,
+            None => return Insert::Ignored,
+        };
+        if let Some(i) = bucket.nodes.iter().position(|n| n.id == id) {
+            let mut node = bucket.nodes.remove(i);
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            bucket.nodes.push(node);
+        } else {
+            if bucket.nodes.len() >= K {
+                match bucket.nodes.iter().position(NodeEntry::is_bad) {
+                    Some(i) => {
+                        bucket.nodes.remove(i);
+                    }
+                    None => {
+                        return match bucket.nodes.iter().find(|n| !n.is_good()) {
+                            Some(n) => Insert::PingFirst(n.clone()),
+                            None => Insert::Ignored,
+                        };
+                    }
+                }
+            }
+            bucket.nodes.push(NodeEntry::new(id, addr));
+        }
+        bucket.last_changed = Instant::now();
+        if let Some(previous) = previous {
+            self.remove(&previous);
+        }
+        Insert::Added
+    }
+    /// note that a node did not answer a query, bad nodes are dropped once
+    /// their bucket is full
+    pub fn failed(&mut self, id: &NodeId) {
+        if let Some(bucket) = self.bucket_mut(id)
+            && let Some(node) = bucket.nodes.iter_mut().find(|n| n.id == *id)
+        {
+            node.failures += 1;
+            if node.is_bad() && bucket.nodes.len() >= K {
+                bucket.nodes.retain(|n| n.id != *id);
+            }
+        }
+    }
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(bucket) = self.bucket_mut(id) {
+            bucket.nodes.retain(|n| n.id != *id);
+        }
+    }
+    /// up to `n` nodes closest to `target`, bad nodes last
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<NodeEntry> {
+        let mut nodes: Vec<NodeEntry> = self.nodes().cloned().collect();
+        nodes.sort_by(|a, b| {
+            (a.is_bad(), target.distance(&a.id)).cmp(&(b.is_bad(), target.distance(&b.id)))
+        });
+        nodes.truncate(n);
+        nodes
+    }
+    /// a random target in every bucket nothing happened in for `after`, a
+    /// lookup of it refreshes the bucket. empty buckets past the deepest
+    /// non-empty one are skipped, no node can fill them.
+    pub fn stale_targets(&self, after: Duration) -> Vec<NodeId> {
+        let deepest = match self.buckets.iter().rposition(|b| !b.nodes.is_empty()) {
+            Some(i) => i,
+            None => return vec![],
+        };
+        self.buckets[..=deepest]
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.last_changed.elapsed() >= after)
+            .map(|(i, _)| self.id.random_with_prefix(i))
+            .collect()
+    }
+    /// mark the bucket of `target` as refreshed
+    pub fn touch(&mut self, target: &NodeId) {
+        if let Some(bucket) = self.bucket_mut(target) {
+            bucket.last_changed = Instant::now();
+        }
+    }
+    fn bucket_mut(&mut self, id: &NodeId) -> Option<&mut Bucket> {
+        self.buckets.get_mut(self.id.common_prefix(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an id whose first two bytes are `a` and `b`
+    fn id(a: u8, b: u8) -> NodeId {
+        let mut bytes = [0; NODE_ID_LEN];
+        bytes[0] = a;
+        bytes[1] = b;
+        NodeId::new(bytes)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn bucket_of(table: &RoutingTable, id: &NodeId) -> Option<usize> {
+        table
+            .buckets
+            .iter()
+            .position(|b| b.nodes.iter().any(|n| n.id == *id))
+    }
+
+    #[test]
+    fn nodes_go_to_the_bucket_of_their_common_prefix() {
+        let mut table = RoutingTable::new(NodeId::default());
+        for (n, (a, b)) in [(0x80, 0), (0x40, 0), (0x01, 0), (0, 0x80), (0, 0x01)]
+            .into_iter()
+            .enumerate()
+        {
+            assert_eq!(table.insert(id(a, b), addr(n as u16 + 1)), Insert::Added);
+        }
+        assert_eq!(bucket_of(&table, &id(0x80, 0)), Some(0));
+        assert_eq!(bucket_of(&table, &id(0x40, 0)), Some(1));
+        assert_eq!(bucket_of(&table, &id(0x01, 0)), Some(7));
+        assert_eq!(bucket_of(&table, &id(0, 0x80)), Some(8));
+        assert_eq!(bucket_of(&table, &id(0, 0x01)), Some(15));
+        // we are not a node of our own table
+        assert_eq!(table.insert(NodeId::default(), addr(9)), Insert::Ignored);
+        assert_eq!(table.len(), 5);
+    }
+
+    #[test]
+    fn known_node_is_refreshed_and_moves_to_the_back() {
+        let mut table = RoutingTable::new(NodeId::default());
+        table.insert(id(0x80, 1), addr(1));
+        table.insert(id(0x80, 2), addr(2));
+        table.failed(&id(0x80, 1));
+        assert_eq!(table.insert(id(0x80, 1), addr(1)), Insert::Added);
+        let nodes: Vec<&NodeEntry> = table.nodes().collect();
+        assert_eq!(nodes[1].id, id(0x80, 1));
+        assert_eq!(nodes[1].failures, 0);
+    }
+
+    #[test]
+    fn full_bucket_asks_to_ping_a_questionable_node() {
+        let mut table = RoutingTable::new(NodeId::default());
+        for i in 0..K as u8 {
+            assert_eq!(table.insert(id(0x80, i), addr(i as u16)), Insert::Added);
+        }
+        // a full bucket of good nodes keeps them
+        assert_eq!(table.insert(id(0x80, 0xff), addr(100)), Insert::Ignored);
+        table.failed(&id(0x80, 3));
+        let old = match table.insert(id(0x80, 0xff), addr(100)) {
+            Insert::PingFirst(old) => old,
+            other => panic!("expected a ping, got {:?}", other),
+        };
+        assert_eq!(old.id, id(0x80, 3));
+        // the questionable node fails the ping and goes bad, making room
+        table.failed(&old.id);
+        assert_eq!(bucket_of(&table, &old.id), None);
+        assert_eq!(table.insert(id(0x80, 0xff), addr(100)), Insert::Added);
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn closest_orders_by_xor_distance() {
+        let mut table = RoutingTable::new(NodeId::default());
+        table.insert(id(0x80, 0), addr(1));
+        table.insert(id(0xc0, 0), addr(2));
+        table.insert(id(0x01, 0), addr(3));
+        let closest: Vec<NodeId> = table
+            .closest(&id(0xc1, 0), 2)
+            .into_iter()
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(closest, vec![id(0xc0, 0), id(0x80, 0)]);
+    }
+
+    #[test]
+    fn bucket_changes_only_when_a_node_is_added() {
+        let mut table = RoutingTable::new(NodeId::default());
+        for i in 0..K as u8 {
+            table.insert(id(0x80, i), addr(i as u16));
+        }
+        let hour = Duration::from_secs(3600);
+        table.buckets[0].last_changed = Instant::now().checked_sub(hour).unwrap();
+        assert_eq!(table.insert(id(0x80, 0xff), addr(100)), Insert::Ignored);
+        assert_eq!(table.stale_targets(hour).len(), 1);
+        table.failed(&id(0x80, 3));
+        assert!(matches!(
+            table.insert(id(0x80, 0xff), addr(100)),
+            Insert::PingFirst(_)
+        ));
+        assert_eq!(table.stale_targets(hour).len(), 1);
+        assert_eq!(table.insert(id(0x80, 3), addr(3)), Insert::Added);
+        assert!(table.stale_targets(hour).is_empty());
+    }
+
+    #[test]
+    fn address_of_a_good_node_is_not_taken_over() {
+        let mut table = RoutingTable::new(NodeId::default());
+        table.insert(id(0x80, 0), addr(1));
+        assert_eq!(table.insert(id(0x01, 0), addr(1)), Insert::Ignored);
+        assert_eq!(table.len(), 1);
+        // a known node moving onto the address is refused too
+        table.insert(id(0x40, 0), addr(2));
+        assert_eq!(table.insert(id(0x40, 0), addr(1)), Insert::Ignored);
+        assert_eq!(
+            table.nodes().find(|n| n.id == id(0x40, 0)).unwrap().addr,
+            addr(2)
+        );
+        // once the old node stops answering, the new id replaces it
+        table.failed(&id(0x80, 0));
+        assert_eq!(table.insert(id(0x01, 0), addr(1)), Insert::Added);
+        assert_eq!(bucket_of(&table, &id(0x80, 0)), None);
+        assert_eq!(bucket_of(&table, &id(0x01, 0)), Some(7));
+        assert_eq!(table.len(), 2);
+    }
+}
@@ -1,27 +1,64 @@
 use std::{
-    process,
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
+    sync::{Notify, broadcast, mpsc},
+    task::{JoinHandle, JoinSet},
+    time::{Interval, MissedTickBehavior, interval, interval_at},
 };
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    peer::{Handshake, Peer},
-    torrent::File,
-    tracker::tracker::Tracker,
+    bitfield::Bitfield,
+    dht::Dht,
+    error::{Error, PeerError, StorageError, TrackerError},
+    event::{Event, Events, TorrentStats},
+    file::TorrentFile,
+    hash::InfoHash,
+    limiter::RateLimiter,
+    metadata::MetadataFetch,
+    peer::{
+        ExtendedHandshake, Extension, ExtensionContext, ExtensionRegistry, Handshake,
+        HandshakeResult, Message, MetadataMessage, Peer, PexMessage, codec,
+        extension::{
+            EXTENDED_HANDSHAKE_ID, LT_DONTHAVE, METADATA_PIECE_SIZE, PEX_CONNECTABLE,
+            PEX_MAX_PEERS, PEX_SEED, UT_METADATA, UT_PEX,
+        },
+    },
+    picker::{Block, PiecePicker, Priority},
+    resume::ResumeData,
+    server::{IncomingPeer, SessionContext},
+    storage::{
+        DEFAULT_RECHECK_THREADS, DiskStorage, FnRecheckProgressCallBack, PieceCheck, Storage,
+        StorageLayout, TorrentStorage, disk::move_files,
+    },
+    torrent::Node,
+    tracker::{manager::AnnounceStats, tracker::Tracker},
 };
 
-/// the callback function type when the download task progress is updated.
-pub type FnUpdateProgressCallBack = fn(process: u16);
+/// how often the task checks on its peers and the tracker
+const SWARM_TICK_SEC: u64 = 5;
+/// keep-alive interval on idle connections
+const KEEP_ALIVE_SEC: u64 = 120;
+/// BEP 11 allows one ut_pex message a minute per connection
+const PEX_INTERVAL_SEC: u64 = 60;
+/// how often a torrent is looked up and announced in the DHT
+const DHT_ANNOUNCE_SEC: u64 = 15 * 60;
+/// how often a magnet link is looked up until its metadata arrives
+const DHT_METADATA_LOOKUP_SEC: u64 = 60;
 
 /// download task abstract structure
 #[derive(Clone, Debug)]
 pub struct DownloadTask {
-    // files to be downloaded
-    file: File,
     // tracker
     tracker: Tracker,
     // the peer list at the current progress
@@ -30,71 +67,1652 @@ pub struct DownloadTask {
     pub progress: Arc<Mutex<u16>>,
     // the number of bytes downloaded.
     pub downloaded: Arc<Mutex<u64>>,
+    // the number of bytes uploaded.
+    pub uploaded: Arc<Mutex<u64>>,
     // total downloads
     pub total_download: i64,
     // file download status
-    pub status: DownloadStatus,
+    status: Arc<Mutex<DownloadStatus>>,
     // info hash
-    pub info_hash: String,
+    pub info_hash: InfoHash,
+    // directory the torrent's files are stored below
+    pub storage_path: PathBuf,
+    // backend taken by `start`, files below `storage_path` when unset
+    storage: Arc<Mutex<Option<Box<dyn Storage>>>>,
+    // the backend was set by the caller and is not ours to move
+    custom_storage: bool,
+    // resume data applied by `start`
+    resume: Option<ResumeData>,
+    // hash the existing data on the next `start` instead of trusting resume data
+    recheck: bool,
+    // the running swarm, source of fresh resume data
+    swarm: Arc<Mutex<Option<Arc<Swarm>>>>,
+    // peer id, limits and bandwidth shared with the other torrents of a session
+    session: SessionContext,
+    // cancelled to make a running `start` drain its peers and return
+    stop: Arc<Mutex<CancellationToken>>,
+    // keep serving peers after the download finished, until stopped
+    seed: bool,
+    // bytes missing when `start` last returned
+    left: u64,
+    // transfer rates measured over the last tick
+    rates: Arc<Mutex<Rates>>,
+}
+
+/// bytes per second, measured by the running task
+#[derive(Debug, Clone, Copy, Default)]
+struct Rates {
+    download: u64,
+    upload: u64,
+}
+
+/// peers waiting to be connected to, in the order they were found. an
+/// address is queued once until it is taken out again.
+#[derive(Debug, Default)]
+struct PeerQueue {
+    peers: VecDeque<Peer>,
+    queued: HashSet<SocketAddr>,
+}
+
+impl PeerQueue {
+    fn extend(&mut self, peers: impl IntoIterator<Item = Peer>) {
+        for p in peers {
+            if self.queued.insert(p.addr) {
+                self.peers.push_back(p);
+            }
+        }
+    }
+    fn pop(&mut self) -> Option<Peer> {
+        let p = self.peers.pop_front()?;
+        self.queued.remove(&p.addr);
+        Some(p)
+    }
+    /// put back a peer that could not be connected to yet, it goes first
+    fn put_back(&mut self, p: Peer) {
+        if self.queued.insert(p.addr) {
+            self.peers.push_front(p);
+        }
+    }
 }
 
 impl DownloadTask {
-    pub fn new(info_hash: String, file: File, tracker: Tracker) -> Self {
+    pub fn new(tf: &TorrentFile, tracker: Tracker) -> Self {
+        let total_download = tf.total_length();
         Self {
-            file: file.clone(),
             peers: None,
             progress: Arc::new(Mutex::new(0)),
-            status: DownloadStatus::WAITING,
-            tracker: tracker,
+            status: Arc::new(Mutex::new(DownloadStatus::Queued)),
+            tracker,
             downloaded: Arc::new(Mutex::new(0)),
-            total_download: file.clone().length,
-            info_hash: info_hash,
+            uploaded: Arc::new(Mutex::new(0)),
+            total_download,
+            info_hash: tf.info_hash,
+            storage_path: tf.storage_path.clone(),
+            storage: Arc::new(Mutex::new(None)),
+            custom_storage: false,
+            resume: None,
+            recheck: false,
+            swarm: Arc::new(Mutex::new(None)),
+            session: SessionContext::default(),
+            stop: Arc::new(Mutex::new(CancellationToken::new())),
+            seed: false,
+            left: total_download.max(0) as u64,
+            rates: Arc::new(Mutex::new(Rates::default())),
+        }
+    }
+    /// run as part of a session, sharing its peer id and limits
+    pub fn with_session(mut self, session: SessionContext) -> Self {
+        self.session = session;
+        self
+    }
+    /// receive the events of this task, and of every other task when it
+    /// runs in a session
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.session.events.subscribe()
+    }
+    /// offer a BEP 10 extension to peers, returning the message id peers
+    /// send it with. takes effect on the next `start`.
+    pub fn register_extension(&mut self, ext: Arc<dyn Extension>) -> Result<u8, Error> {
+        self.session.extensions.register(ext)
+    }
+    /// keep uploading to peers once every piece is verified, `start` then
+    /// only returns when the task is stopped
+    pub fn with_seeding(mut self, seed: bool) -> Self {
+        self.seed = seed;
+        self
+    }
+    /// start from saved state instead of from nothing
+    pub fn set_resume_data(&mut self, r: ResumeData) -> Result<(), StorageError> {
+        if r.info_hash != self.info_hash {
+            return Err(StorageError::ResumeMismatch);
         }
+        self.storage_path = r.save_path.clone();
+        self.resume = Some(r);
+        Ok(())
+    }
+    /// hash whatever is already in storage when the task starts, so data
+    /// left by an earlier run or another client is seeded and not downloaded
+    pub fn force_recheck(&mut self) {
+        self.recheck = true;
     }
-    pub async fn start(&mut self) {
-        self.status = DownloadStatus::DOWNLOADING;
-        self.peers = Some(self.peers().await.unwrap());
-        for (a, b) in self.peers.clone().unwrap().iter().enumerate() {
-            let add = b.to_address();
-            b.handshake().await;
+    /// the current state, to be saved and handed to `set_resume_data`
+    /// after a restart. storage is flushed first so mtimes are final.
+    pub fn resume_data(&self) -> Result<ResumeData, Error> {
+        let swarm = match self.swarm.lock().unwrap().clone() {
+            Some(s) => s,
+            None => {
+                return match &self.resume {
+                    Some(r) => Ok(ResumeData {
+                        paused: self.status() == DownloadStatus::Paused,
+                        ..r.clone()
+                    }),
+                    None => Err(Error::InvalidState(
+                        "download task has not started".to_string(),
+                    )),
+                };
+            }
+        };
+        let file_sizes = {
+            let mut storage = swarm.storage.lock().unwrap();
+            storage.flush()?;
+            storage.file_sizes()?.unwrap_or_default()
+        };
+        let state = swarm.state.lock().unwrap();
+        let tf = &self.tracker.torrent_file;
+        Ok(ResumeData {
+            info_hash: self.info_hash,
+            info_hash_v2: tf.info_hash_v2,
+            name: tf.meta_data.info.display_name().to_string(),
+            save_path: self.storage_path.clone(),
+            pieces: state.picker.have().clone(),
+            file_priorities: state.file_priorities.clone(),
+            file_sizes,
+            peers: state.connections.keys().copied().collect(),
+            trackers: tf.announce_tiers.clone(),
+            total_uploaded: self.uploaded(),
+            total_downloaded: self.downloaded(),
+            paused: self.status() == DownloadStatus::Paused,
+        })
+    }
+    /// keep the payload somewhere other than files below `storage_path`
+    pub fn set_storage(&mut self, backend: Box<dyn Storage>) {
+        *self.storage.lock().unwrap() = Some(backend);
+        self.custom_storage = true;
+    }
+    /// download every piece from the peers the trackers, the DHT and other
+    /// peers hand out and from peers connecting to the session, then seed
+    /// if asked to. returns once stopped, with peers disconnected and
+    /// storage flushed, and puts the task in the error state when it fails.
+    pub async fn start(&mut self) -> Result<(), Error> {
+        let stop = self.stop.lock().unwrap().clone();
+        if stop.is_cancelled() {
+            return Ok(());
+        }
+        let status = self.status();
+        if matches!(status, DownloadStatus::Paused | DownloadStatus::Error(_)) {
+            return Err(Error::InvalidState(format!(
+                "cannot start a task in the {:?} state, resume it first",
+                status
+            )));
         }
+        let r = self.run(stop).await;
+        if let Err(e) = &r {
+            let _ = self.set_status(DownloadStatus::Error(e.to_string()));
+        }
+        r
     }
-    // get the peer list based on the current download progress
-    pub async fn peers(&mut self) -> Result<Vec<Peer>, String> {
-        let mut peers = Vec::<Peer>::new();
-        match self
-            .tracker
-            .get_peers(self.file.clone(), self.downloaded(), 0)
+    async fn run(&mut self, stop: CancellationToken) -> Result<(), Error> {
+        let mut peers = PeerQueue::default();
+        if !self.tracker.torrent_file.has_metadata() {
+            match self.fetch_metadata(&stop).await? {
+                Some(found) => peers.extend(found),
+                None => return self.set_status(DownloadStatus::Paused),
+            }
+        }
+        if self.recheck || self.resume.is_some() {
+            self.set_status(DownloadStatus::CheckingFiles)?;
+        }
+        let layout = StorageLayout::from_torrent(&self.tracker.torrent_file.meta_data)?;
+        let mut picker = PiecePicker::new(
+            layout.piece_count(),
+            layout.piece_length as u32,
+            layout.total_length,
+        );
+        let file_count = layout.files.len();
+        let file_priorities = match &self.resume {
+            Some(r) if r.file_priorities.len() == file_count => r.file_priorities.clone(),
+            _ => vec![Priority::Normal; file_count],
+        };
+        let priorities: Vec<(u64, Priority)> = layout
+            .files
+            .iter()
+            .zip(file_priorities.iter())
+            .map(|(f, p)| match f.padding {
+                true => (f.length, Priority::Skip),
+                false => (f.length, *p),
+            })
+            .collect();
+        picker.set_file_priorities(&priorities);
+        let backend = match self.storage.lock().unwrap().take() {
+            Some(b) => b,
+            None => Box::new(DiskStorage::new(&self.storage_path)),
+        };
+        let mut storage = TorrentStorage::new(layout, backend)?;
+        if self.recheck {
+            // hashing blocks, keep it off the async workers
+            let progress = Arc::clone(&self.progress);
+            let (s, have) = match tokio::task::spawn_blocking(move || {
+                let have = storage.recheck(DEFAULT_RECHECK_THREADS, &move |n, total| {
+                    *progress.lock().unwrap() = (n * 100 / total.max(1)) as u16;
+                });
+                (storage, have)
+            })
+            .await
+            {
+                Ok(r) => r,
+                Err(_) => return Err(StorageError::WorkerPanicked.into()),
+            };
+            storage = s;
+            for i in have?.iter_set() {
+                picker.set_have(i as u32);
+            }
+            self.recheck = false;
+        } else if let Some(r) = self.resume.clone() {
+            *self.downloaded.lock().unwrap() = r.total_downloaded;
+            *self.uploaded.lock().unwrap() = r.total_uploaded;
+            let progress = Arc::clone(&self.progress);
+            let (s, have) = match tokio::task::spawn_blocking(move || {
+                let have = check_resume(&mut storage, &r, &move |n, total| {
+                    *progress.lock().unwrap() = (n * 100 / total.max(1)) as u16;
+                });
+                (storage, have)
+            })
             .await
+            {
+                Ok(r) => r,
+                Err(_) => return Err(StorageError::WorkerPanicked.into()),
+            };
+            storage = s;
+            for i in have? {
+                picker.set_have(i);
+            }
+        }
+        if let Some(r) = &self.resume {
+            peers.extend(r.peers.iter().map(|a| Peer::new(*a)));
+        }
+        let have = picker.have();
+        *self.progress.lock().unwrap() = (have.count() * 100 / have.len().max(1)) as u16;
+        if !picker.is_complete() {
+            self.set_status(DownloadStatus::Downloading)?;
+        }
+        // cancelled when this run ends, for whatever reason
+        let peers_stop = stop.child_token();
+        let tf = &self.tracker.torrent_file;
+        let handshake = self
+            .session
+            .extensions
+            .handshake(tf, self.session.listen_port);
+        let swarm = Arc::new(Swarm {
+            info_hash: self.info_hash,
+            metadata: match handshake.id_of(UT_METADATA) {
+                Some(_) => tf.info_bytes().map(Bytes::copy_from_slice),
+                None => None,
+            },
+            extensions: self.session.extensions.clone(),
+            handshake,
+            dht: self.dht(),
+            peer_id: self.session.peer_id,
+            download_limit: self.session.download_limit.clone(),
+            upload_limit: self.session.upload_limit.clone(),
+            seed: self.seed,
+            events: self.session.events.clone(),
+            stop: peers_stop.clone(),
+            failure: Mutex::new(None),
+            completed: Notify::new(),
+            layout: storage.layout().clone(),
+            storage: Arc::new(Mutex::new(storage)),
+            state: Mutex::new(SwarmState {
+                picker,
+                file_priorities,
+                connections: HashMap::new(),
+                listen_addrs: HashMap::new(),
+                learned: vec![],
+                banned: HashSet::new(),
+                writing: HashMap::new(),
+                completing: HashSet::new(),
+            }),
+            downloaded: Arc::clone(&self.downloaded),
+            uploaded: Arc::clone(&self.uploaded),
+            progress: Arc::clone(&self.progress),
+        });
+        *self.swarm.lock().unwrap() = Some(Arc::clone(&swarm));
+        // peers connecting to us are routed here by any hash they may use
+        let (incoming_tx, mut incoming) = mpsc::unbounded_channel::<IncomingPeer>();
+        {
+            let mut router = self.session.router.lock().unwrap();
+            for h in self.tracker.torrent_file.wire_hashes() {
+                router.insert(h, incoming_tx.clone());
+            }
+        }
+        let max_peers = self.session.max_connections_per_torrent;
+        let mut tasks = JoinSet::new();
+        let mut tick = interval(Duration::from_secs(SWARM_TICK_SEC));
+        let (dht_tx, mut dht_peers) = mpsc::unbounded_channel();
+        let mut dht_due = Instant::now();
+        let (tracker_tx, mut tracker_peers) = mpsc::unbounded_channel::<Vec<Peer>>();
+        let mut announcing: Option<JoinHandle<()>> = None;
+        self.add_dht_nodes();
+        let mut finished = false;
+        let mut failure = None;
+        // totals at the previous tick, for the rates
+        let mut last = (Instant::now(), self.downloaded(), self.uploaded());
+        loop {
+            if peers_stop.is_cancelled() {
+                break;
+            }
+            if !finished && swarm.is_complete() {
+                finished = true;
+                if let Err(e) = swarm.with_storage(|s| s.flush()).await {
+                    failure = Some(e.into());
+                    break;
+                }
+                let (tracker, stats) = (self.tracker.clone(), self.announce_stats());
+                tokio::spawn(async move { tracker.completed(stats).await });
+                if !self.seed {
+                    break;
+                }
+                if let Err(e) = self.set_status(DownloadStatus::Seeding) {
+                    failure = Some(e);
+                    break;
+                }
+            }
+            tokio::select! {
+                _ = tick.tick() => {}
+                Some(found) = tracker_peers.recv() => {
+                    self.peers = Some(found.clone());
+                    peers.extend(found);
+                }
+                _ = swarm.completed.notified() => continue,
+                Some(p) = incoming.recv() => {
+                    let connected = swarm.state.lock().unwrap().connections.len();
+                    if connected < max_peers && !swarm.is_banned(p.addr) {
+                        let swarm = Arc::clone(&swarm);
+                        let IncomingPeer { addr, theirs, stream, permit } = p;
+                        tasks.spawn(async move {
+                            swarm.run_peer(addr, theirs, stream, false).await;
+                            drop(permit);
+                        });
+                    }
+                    continue;
+                }
+                // stopped, or a peer hit a storage error
+                _ = peers_stop.cancelled() => break,
+            }
+            self.measure_rates(&mut last);
+            self.session.events.send(Event::Stats(self.stats()));
+            peers.extend(std::mem::take(&mut swarm.state.lock().unwrap().learned));
+            while let Ok(found) = dht_peers.try_recv() {
+                peers.extend(found);
+            }
+            // seeds announce too, for peers to find them
+            if Instant::now() >= dht_due {
+                self.dht_announce(&dht_tx, &peers_stop);
+                dht_due = Instant::now() + Duration::from_secs(DHT_ANNOUNCE_SEC);
+            }
+            // seeds keep announcing and wait for peers to come to them, only
+            // a download short of peers asks the trackers early
+            let connected = swarm.state.lock().unwrap().connections.len();
+            if announcing.as_ref().is_none_or(JoinHandle::is_finished) {
+                let short = !finished && connected < max_peers;
+                let stats = self.announce_stats();
+                announcing = Some(self.tracker_announce(stats, short, &tracker_tx, &peers_stop));
+            }
+            if finished || connected >= max_peers {
+                continue;
+            }
+            let mut slots = max_peers - connected;
+            while slots > 0
+                && let Some(p) = peers.pop()
+            {
+                if swarm.is_connected(p.addr) || swarm.is_banned(p.addr) {
+                    continue;
+                }
+                // the session wide connection limit, the peer waits for the
+                // next tick
+                let permit = match Arc::clone(&self.session.connections).try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        peers.put_back(p);
+                        break;
+                    }
+                };
+                slots -= 1;
+                let swarm = Arc::clone(&swarm);
+                tasks.spawn(async move {
+                    let _ = swarm.connect(p).await;
+                    drop(permit);
+                });
+            }
+        }
+        self.session
+            .router
+            .lock()
+            .unwrap()
+            .retain(|_, tx| !tx.same_channel(&incoming_tx));
+        // drain the peers before touching storage for the last time, the
+        // resume data flushes it
+        peers_stop.cancel();
+        while tasks.join_next().await.is_some() {}
+        self.left = swarm.left();
+        let complete = swarm.is_complete();
+        let resume = self.resume_data();
+        let peer_failure = swarm.failure.lock().unwrap().take();
+        *self.swarm.lock().unwrap() = None;
+        // hand the backend back so the next `start` continues with it
+        if let Ok(swarm) = Arc::try_unwrap(swarm)
+            && let Ok(storage) = Arc::try_unwrap(swarm.storage)
+            && let Ok(storage) = storage.into_inner()
         {
-            Ok(r_vec) => {
-                r_vec.iter().for_each(|r| {
-                    if !r.peers.is_none() {
-                        peers.append(&mut r.peers.clone().unwrap());
+            *self.storage.lock().unwrap() = Some(storage.into_backend());
+        }
+        *self.rates.lock().unwrap() = Rates::default();
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        if let Some(e) = peer_failure {
+            return Err(Error::Storage(e));
+        }
+        self.resume = Some(resume?);
+        match stop.is_cancelled() || !complete {
+            true => self.set_status(DownloadStatus::Paused),
+            false => self.set_status(DownloadStatus::Finished),
+        }
+    }
+    /// fetch the info dictionary of a torrent made from a magnet link from
+    /// the peers its trackers, `x.pe` parameters and the DHT name, and
+    /// continue with the torrent it describes. returns the peers found, or
+    /// `None` when stopped first.
+    async fn fetch_metadata(
+        &mut self,
+        stop: &CancellationToken,
+    ) -> Result<Option<Vec<Peer>>, Error> {
+        let magnet = match &self.tracker.torrent_file.magnet {
+            Some(m) => m.clone(),
+            None => {
+                return Err(Error::InvalidState(
+                    "torrent has neither metadata nor a magnet link".to_string(),
+                ));
+            }
+        };
+        self.set_status(DownloadStatus::DownloadingMetadata)?;
+        let fetch = Arc::new(MetadataFetch::new(
+            magnet.clone(),
+            self.session.peer_id,
+            self.session.listen_port,
+            stop.child_token(),
+        ));
+        let mut found = magnet.peers().await;
+        let mut candidates = PeerQueue::default();
+        candidates.extend(found.iter().cloned());
+        // trackers skip seeds for peers that claim to have everything
+        let left = magnet
+            .link
+            .exact_length
+            .unwrap_or(METADATA_PIECE_SIZE as u64);
+        let max_peers = self.session.max_connections_per_torrent;
+        let mut tasks = JoinSet::new();
+        let mut tick = interval(Duration::from_secs(SWARM_TICK_SEC));
+        let (dht_tx, mut dht_peers) = mpsc::unbounded_channel();
+        let mut dht_due = Instant::now();
+        let (tracker_tx, mut tracker_peers) = mpsc::unbounded_channel();
+        let mut announcing: Option<JoinHandle<()>> = None;
+        let stats = AnnounceStats {
+            left,
+            ..Default::default()
+        };
+        loop {
+            let mut announced = vec![];
+            tokio::select! {
+                _ = tick.tick() => {}
+                Some(found) = tracker_peers.recv() => announced = found,
+                _ = fetch.finished() => break,
+            }
+            if Instant::now() >= dht_due {
+                self.dht_announce(&dht_tx, stop);
+                dht_due = Instant::now() + Duration::from_secs(DHT_METADATA_LOOKUP_SEC);
+            }
+            if announcing.as_ref().is_none_or(JoinHandle::is_finished) {
+                announcing = Some(self.tracker_announce(stats, true, &tracker_tx, stop));
+            }
+            while let Ok(p) = dht_peers.try_recv() {
+                announced.extend(p);
+            }
+            found.extend(announced.iter().cloned());
+            candidates.extend(announced);
+            let mut slots = max_peers.saturating_sub(fetch.connections());
+            while slots > 0
+                && let Some(p) = candidates.pop()
+            {
+                if fetch.is_known(p.addr) {
+                    continue;
+                }
+                let permit = match Arc::clone(&self.session.connections).try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        candidates.put_back(p);
+                        break;
                     }
+                };
+                slots -= 1;
+                let fetch = Arc::clone(&fetch);
+                tasks.spawn(async move {
+                    let _ = fetch.connect(p).await;
+                    drop(permit);
                 });
             }
-            Err(e) => return Err(e),
         }
+        fetch.stop();
+        while tasks.join_next().await.is_some() {}
+        let info = match fetch.take_info() {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let tf = self.tracker.torrent_file.with_metadata(&info)?;
+        self.total_download = tf.total_length();
+        self.left = self.total_download.max(0) as u64;
+        self.tracker.torrent_file = tf;
+        self.session.events.send(Event::MetadataReceived {
+            info_hash: self.info_hash,
+        });
+        found.dedup_by_key(|p| p.addr);
+        Ok(Some(found))
+    }
+    /// the session's DHT node, `None` for private torrents
+    fn dht(&self) -> Option<Dht> {
+        match self.tracker.torrent_file.is_private() {
+            true => None,
+            false => self.session.dht.clone(),
+        }
+    }
+    /// look the torrent up in the DHT in the background and announce it,
+    /// the peers found are sent to `tx`
+    fn dht_announce(&self, tx: &mpsc::UnboundedSender<Vec<Peer>>, stop: &CancellationToken) {
+        let dht = match self.dht() {
+            Some(dht) => dht,
+            None => return,
+        };
+        let (info_hash, port) = (self.info_hash, self.session.listen_port);
+        let (tx, stop) = (tx.clone(), stop.clone());
+        tokio::spawn(async move {
+            tokio::select! {
+                found = dht.announce(info_hash, port) => {
+                    let _ = tx.send(found.into_iter().map(Peer::new).collect());
+                }
+                _ = stop.cancelled() => {}
+            }
+        });
+    }
+    /// re-announce to the trackers that are due in the background, making
+    /// them due sooner when `short` of peers. the peers they return are
+    /// sent to `tx`.
+    fn tracker_announce(
+        &self,
+        stats: AnnounceStats,
+        short: bool,
+        tx: &mpsc::UnboundedSender<Vec<Peer>>,
+        stop: &CancellationToken,
+    ) -> JoinHandle<()> {
+        let mut tracker = self.tracker.clone();
+        let (tx, stop) = (tx.clone(), stop.clone());
+        tokio::spawn(async move {
+            if short {
+                tracker.request_peers().await;
+            }
+            tokio::select! {
+                found = tracker.get_peers(stats.left, stats.downloaded, stats.uploaded) => {
+                    let _ = tx.send(found.unwrap_or_default());
+                }
+                _ = stop.cancelled() => {}
+            }
+        })
+    }
+    /// ping the DHT nodes the torrent's `nodes` name, BEP 5 has them join
+    /// the routing table
+    fn add_dht_nodes(&self) {
+        let (dht, nodes) = match (self.dht(), &self.tracker.torrent_file.meta_data.nodes) {
+            (Some(dht), Some(nodes)) => (dht, nodes.clone()),
+            _ => return,
+        };
+        tokio::spawn(async move {
+            for Node(host, port) in nodes {
+                let port = match u16::try_from(port) {
+                    Ok(p) => p,
+                    Err(_) => continue,
+                };
+                if let Ok(addrs) = tokio::net::lookup_host((host.as_str(), port)).await {
+                    for addr in addrs.filter(SocketAddr::is_ipv4) {
+                        let _ = dht.ping(addr).await;
+                    }
+                }
+            }
+        });
+    }
+    /// the current state
+    pub fn status(&self) -> DownloadStatus {
+        self.status.lock().unwrap().clone()
+    }
+    /// move to `next` if the current state allows it
+    pub(crate) fn set_status(&self, next: DownloadStatus) -> Result<(), Error> {
+        let mut status = self.status.lock().unwrap();
+        if !status.can_become(&next) {
+            return Err(Error::InvalidState(format!(
+                "cannot go from {:?} to {:?}",
+                *status, next
+            )));
+        }
+        if *status != next {
+            *status = next.clone();
+            drop(status);
+            self.session.events.send(Event::StateChanged {
+                info_hash: self.info_hash,
+                state: next,
+            });
+        }
+        Ok(())
+    }
+    /// update the rates from the totals since the `last` tick
+    fn measure_rates(&self, last: &mut (Instant, u64, u64)) {
+        let now = Instant::now();
+        let (downloaded, uploaded) = (self.downloaded(), self.uploaded());
+        let secs = now.duration_since(last.0).as_secs_f64();
+        if secs > 0.0 {
+            *self.rates.lock().unwrap() = Rates {
+                download: (downloaded.saturating_sub(last.1) as f64 / secs) as u64,
+                upload: (uploaded.saturating_sub(last.2) as f64 / secs) as u64,
+            };
+        }
+        *last = (now, downloaded, uploaded);
+    }
+    /// make a running `start` disconnect its peers, flush storage and
+    /// return paused. a task stopped before it starts does nothing when
+    /// started.
+    pub fn stop(&self) {
+        self.stop.lock().unwrap().cancel();
+    }
+    /// queue a paused or failed task so that `start` runs it again
+    pub fn resume(&self) -> Result<(), Error> {
+        if !matches!(
+            self.status(),
+            DownloadStatus::Paused | DownloadStatus::Error(_)
+        ) {
+            return Ok(());
+        }
+        self.set_status(DownloadStatus::Queued)?;
+        let mut stop = self.stop.lock().unwrap();
+        if stop.is_cancelled() {
+            *stop = CancellationToken::new();
+        }
+        Ok(())
+    }
+    /// move the files to a new storage path, for a task that is not running
+    /// and keeps its files below `storage_path`. files not written yet are
+    /// skipped and directories left empty are removed.
+    pub async fn move_storage(&mut self, to: impl Into<PathBuf>) -> Result<(), Error> {
+        let to = to.into();
+        if self.swarm.lock().unwrap().is_some() {
+            return Err(Error::InvalidState(
+                "cannot move the storage of a running task".to_string(),
+            ));
+        }
+        if self.custom_storage {
+            return Err(Error::InvalidState(
+                "the storage backend was set by the caller".to_string(),
+            ));
+        }
+        let files = match self.tracker.torrent_file.has_metadata() {
+            true => StorageLayout::from_torrent(&self.tracker.torrent_file.meta_data)?.files,
+            // nothing is written before the metadata arrives
+            false => vec![],
+        };
+        let before = self.status();
+        self.set_status(DownloadStatus::MovingStorage)?;
+        // close the files before they move
+        *self.storage.lock().unwrap() = None;
+        let from = self.storage_path.clone();
+        let dest = to.clone();
+        let moved =
+            match tokio::task::spawn_blocking(move || move_files(&files, &from, &dest)).await {
+                Ok(r) => r.map_err(|e| StorageError::Io(e).into()),
+                Err(_) => Err(Error::from(StorageError::WorkerPanicked)),
+            };
+        if let Err(e) = moved {
+            let _ = self.set_status(DownloadStatus::Error(e.to_string()));
+            return Err(e);
+        }
+        self.storage_path = to.clone();
+        if let Some(r) = &mut self.resume {
+            r.save_path = to;
+        }
+        match before {
+            DownloadStatus::Paused => self.set_status(DownloadStatus::Paused),
+            _ => self.set_status(DownloadStatus::Queued),
+        }
+    }
+    /// tell the trackers we are gone, once the task is stopped or removed
+    pub async fn announce_stopped(&self) {
+        self.tracker.stopped(self.announce_stats()).await;
+    }
+    // get the peer list based on the current download progress
+    pub async fn peers(&mut self) -> Result<Vec<Peer>, TrackerError> {
+        let stats = self.announce_stats();
+        let peers = self
+            .tracker
+            .get_peers(stats.left, stats.downloaded, stats.uploaded)
+            .await?;
+        self.peers = Some(peers.clone());
         Ok(peers)
     }
     // set progress
-    pub async fn update_progress(&mut self, new_progress: u16) {
-        let progress = Arc::clone(&self.progress);
-        let mut m_progress = progress.lock().unwrap();
-        *m_progress += new_progress;
+    pub fn update_progress(&mut self, new_progress: u16) {
+        *self.progress.lock().unwrap() = new_progress.min(100);
     }
     pub fn downloaded(&self) -> u64 {
         let arc = Arc::clone(&self.downloaded);
         let m = arc.lock().unwrap();
         *m
     }
+    pub fn uploaded(&self) -> u64 {
+        *self.uploaded.lock().unwrap()
+    }
+    /// a snapshot of the transfer, also sent as `Event::Stats` every tick
+    pub fn stats(&self) -> TorrentStats {
+        let announce = self.announce_stats();
+        let peers = match self.swarm.lock().unwrap().as_ref() {
+            Some(swarm) => swarm.state.lock().unwrap().connections.len(),
+            None => 0,
+        };
+        let rates = *self.rates.lock().unwrap();
+        TorrentStats {
+            info_hash: self.info_hash,
+            state: self.status(),
+            progress: *self.progress.lock().unwrap(),
+            downloaded: announce.downloaded,
+            uploaded: announce.uploaded,
+            left: announce.left,
+            peers,
+            download_rate: rates.download,
+            upload_rate: rates.upload,
+        }
+    }
+    fn announce_stats(&self) -> AnnounceStats {
+        let downloaded = self.downloaded();
+        // pieces found by a recheck or resume were never downloaded
+        let left = match self.swarm.lock().unwrap().as_ref() {
+            Some(swarm) => swarm.left(),
+            None => self.left,
+        };
+        AnnounceStats {
+            uploaded: self.uploaded(),
+            downloaded,
+            left,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-/// enumeration identifying the download status
+/// state shared by every peer connection of one torrent
+#[derive(Debug)]
+struct Swarm {
+    info_hash: InfoHash,
+    /// the raw info dictionary served to peers fetching the metadata, `None`
+    /// when ut_metadata is not offered
+    metadata: Option<Bytes>,
+    extensions: ExtensionRegistry,
+    /// our extended handshake, without `yourip`
+    handshake: ExtendedHandshake,
+    /// the session's DHT node, told about the nodes of peers. `None` for
+    /// private torrents.
+    dht: Option<Dht>,
+    peer_id: [u8; 20],
+    download_limit: RateLimiter,
+    upload_limit: RateLimiter,
+    /// stay connected to peers that still need pieces once complete
+    seed: bool,
+    events: Events,
+    /// cancelled when the task stops or finishes, peers disconnect
+    stop: CancellationToken,
+    /// the storage error that made a peer cancel `stop`
+    failure: Mutex<Option<StorageError>>,
+    /// woken when the last piece passes its hash check
+    completed: Notify,
+    layout: StorageLayout,
+    /// only locked on blocking threads, never while `state` is held
+    storage: Arc<Mutex<TorrentStorage>>,
+    state: Mutex<SwarmState>,
+    downloaded: Arc<Mutex<u64>>,
+    uploaded: Arc<Mutex<u64>>,
+    progress: Arc<Mutex<u16>>,
+}
+
+/// what one connection knows about its peer
+#[derive(Debug)]
+struct PeerConn {
+    /// the peer does not answer our requests
+    choked: bool,
+    /// we do not answer the peer's requests
+    choking: bool,
+    /// the peer's extended handshake
+    extended: Option<ExtendedHandshake>,
+    /// the listen addresses the peer knows from our ut_pex messages
+    pex_sent: HashSet<SocketAddr>,
+}
+
+#[derive(Debug)]
+struct SwarmState {
+    picker: PiecePicker,
+    file_priorities: Vec<Priority>,
+    /// outgoing message queues of the connected peers
+    connections: HashMap<SocketAddr, mpsc::UnboundedSender<Message>>,
+    /// where the connected peers accept connections, the address we dialed
+    /// or the `p` of an incoming peer's extended handshake
+    listen_addrs: HashMap<SocketAddr, SocketAddr>,
+    /// peers learned over ut_pex, connected to on the next tick
+    learned: Vec<Peer>,
+    /// peers that alone sent a piece failing its hash check
+    banned: HashSet<IpAddr>,
+    /// blocks received and not written yet, by piece
+    writing: HashMap<u32, usize>,
+    /// pieces with every block received, verified once the last write is done
+    completing: HashSet<u32>,
+}
+
+/// disk work left by a message, done once the state lock is released
+#[derive(Debug)]
+enum DiskJob {
+    /// read a block the peer requested
+    Read { index: u32, begin: u32, length: u32 },
+    /// write a block the peer sent
+    Write {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+}
+
+impl Swarm {
+    fn is_complete(&self) -> bool {
+        self.state.lock().unwrap().picker.is_complete()
+    }
+    fn is_connected(&self, addr: SocketAddr) -> bool {
+        self.state.lock().unwrap().connections.contains_key(&addr)
+    }
+    fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.state.lock().unwrap().banned.contains(&addr.ip())
+    }
+    /// bytes of the pieces we do not have yet
+    fn left(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        let picker = &state.picker;
+        (0..picker.piece_count() as u32)
+            .filter(|i| !picker.has_piece(*i))
+            .map(|i| picker.piece_size(i) as u64)
+            .sum()
+    }
+    /// nothing more to exchange with the peer
+    fn is_done_with(&self, addr: SocketAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        state.banned.contains(&addr.ip())
+            || (state.picker.is_complete() && (!self.seed || state.picker.peer_is_seed(addr)))
+    }
+    /// dial a peer and exchange messages until either side is done
+    async fn connect(&self, peer: Peer) -> Result<(), Error> {
+        let mut ours = Handshake::new(self.info_hash, self.peer_id).with_extension_protocol();
+        if self.dht.is_some() {
+            ours = ours.with_dht();
+        }
+        let (theirs, stream) = peer.handshake(&ours).await?;
+        self.run_peer(peer.addr, theirs, stream, true).await;
+        Ok(())
+    }
+    /// drive one handshaken connection, reporting how it went as events.
+    /// `outgoing` connections were dialed by us.
+    async fn run_peer(
+        &self,
+        addr: SocketAddr,
+        theirs: HandshakeResult,
+        stream: TcpStream,
+        outgoing: bool,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let have = {
+            let mut state = self.state.lock().unwrap();
+            if state.connections.contains_key(&addr) {
+                return;
+            }
+            state.connections.insert(addr, tx);
+            if outgoing {
+                state.listen_addrs.insert(addr, addr);
+            }
+            state.picker.have().clone()
+        };
+        self.events.send(Event::PeerConnected {
+            info_hash: self.info_hash,
+            addr,
+        });
+        let result = self.exchange(addr, theirs, stream, have, rx).await;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.picker.remove_peer(addr);
+            state.connections.remove(&addr);
+            state.listen_addrs.remove(&addr);
+        }
+        let error = match result {
+            Err(Error::Storage(StorageError::Io(e))) => {
+                // a broken disk fails the whole torrent, not just this peer.
+                // the task returns the error, the events get a copy.
+                let copy = io::Error::new(e.kind(), e.to_string());
+                let error = Arc::new(Error::Storage(StorageError::Io(copy)));
+                self.failure
+                    .lock()
+                    .unwrap()
+                    .get_or_insert(StorageError::Io(e));
+                self.stop.cancel();
+                self.events.send(Event::StorageError {
+                    info_hash: self.info_hash,
+                    error: Arc::clone(&error),
+                });
+                Some(error)
+            }
+            r => r.err().map(Arc::new),
+        };
+        self.events.send(Event::PeerDisconnected {
+            info_hash: self.info_hash,
+            addr,
+            error,
+        });
+    }
+    /// exchange messages with a registered peer until either side is done
+    async fn exchange(
+        &self,
+        addr: SocketAddr,
+        theirs: HandshakeResult,
+        stream: TcpStream,
+        have: Bitfield,
+        mut rx: mpsc::UnboundedReceiver<Message>,
+    ) -> Result<(), Error> {
+        let (mut sink, mut messages) = codec::framed(stream).split();
+        let mut keep_alive = interval(Duration::from_secs(KEEP_ALIVE_SEC));
+        let mut pex = pex_timer(Duration::from_secs(PEX_INTERVAL_SEC));
+        let mut conn = PeerConn {
+            choked: true,
+            choking: true,
+            extended: None,
+            pex_sent: HashSet::new(),
+        };
+        let mut outgoing = vec![];
+        if theirs.extension_protocol {
+            let ours = self.handshake.clone().with_your_ip(addr.ip());
+            outgoing.push(Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload: ours.to_bytes(),
+            });
+        }
+        if theirs.dht
+            && let Some(Ok(local)) = self.dht.as_ref().map(Dht::local_addr)
+        {
+            outgoing.push(Message::Port(local.port()));
+        }
+        if have.count() > 0 {
+            outgoing.push(Message::Bitfield(have.as_bytes().to_vec().into()));
+        }
+        if !have.all() {
+            outgoing.push(Message::Interested);
+        }
+        'conn: loop {
+            for m in outgoing.drain(..) {
+                let uploading = match &m {
+                    Message::Piece { block, .. } => block.len() as u64,
+                    _ => 0,
+                };
+                if uploading > 0 {
+                    self.upload_limit.acquire(uploading).await;
+                }
+                if let Err(e) = sink.send(m).await {
+                    break 'conn Err(e.into());
+                }
+                *self.uploaded.lock().unwrap() += uploading;
+            }
+            if self.is_done_with(addr) {
+                break Ok(());
+            }
+            tokio::select! {
+                m = messages.next() => match m {
+                    Some(Ok(m)) => {
+                        if let Message::Piece { block, .. } = &m {
+                            self.download_limit.acquire(block.len() as u64).await;
+                        }
+                        match self.handle(addr, m, &mut conn) {
+                            Ok((out, None)) => outgoing = out,
+                            Ok((out, Some(job))) => match self.disk(addr, job).await {
+                                Ok(sent) => outgoing = sent.into_iter().chain(out).collect(),
+                                Err(e) => break Err(e),
+                            },
+                            Err(e) => break Err(e),
+                        }
+                    }
+                    Some(Err(e)) => break Err(e.into()),
+                    None => break Ok(()),
+                },
+                m = rx.recv() => match m {
+                    Some(m) => outgoing.push(m),
+                    None => break Ok(()),
+                },
+                _ = keep_alive.tick() => outgoing.push(Message::KeepAlive),
+                _ = pex.tick() => outgoing.extend(self.pex_message(addr, &mut conn)),
+                _ = self.stop.cancelled() => break Ok(()),
+            }
+        }
+    }
+    /// apply one message from `addr`, returning what to send back and the
+    /// disk work it leaves
+    fn handle(
+        &self,
+        addr: SocketAddr,
+        m: Message,
+        conn: &mut PeerConn,
+    ) -> Result<(Vec<Message>, Option<DiskJob>), Error> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut out = vec![];
+        let mut job = None;
+        match m {
+            Message::Bitfield(b) => {
+                let b = Bitfield::from_bytes(&b, state.picker.piece_count())?;
+                state.picker.add_peer(addr, b);
+            }
+            Message::Have(i) => state.picker.peer_have(addr, i),
+            // the peer's DHT node joins our routing table if it answers
+            Message::Port(port) => {
+                if let Some(dht) = self.dht.clone() {
+                    tokio::spawn(async move { dht.ping(SocketAddr::new(addr.ip(), port)).await });
+                }
+            }
+            Message::Unchoke => conn.choked = false,
+            Message::Choke => {
+                conn.choked = true;
+                state.picker.release_requests(addr);
+            }
+            // every interested peer is served, the upload limit decides how fast
+            Message::Interested if conn.choking => {
+                conn.choking = false;
+                out.push(Message::Unchoke);
+            }
+            Message::NotInterested if !conn.choking => {
+                conn.choking = true;
+                out.push(Message::Choke);
+            }
+            // only verified pieces are served
+            Message::Request {
+                index,
+                begin,
+                length,
+            } if !conn.choking && state.picker.has_piece(index) => {
+                // a bad request is the peer's fault, not the disk's
+                let end = begin as u64 + length as u64;
+                if end > self.layout.piece_size(index) {
+                    return Err(PeerError::InvalidRequest {
+                        index,
+                        begin,
+                        length,
+                    }
+                    .into());
+                }
+                job = Some(DiskJob::Read {
+                    index,
+                    begin,
+                    length,
+                });
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let b = Block {
+                    piece: index,
+                    begin,
+                    length: block.len() as u32,
+                };
+                // unrequested or duplicate blocks are dropped
+                if let Some(r) = state.picker.block_received(addr, b) {
+                    for (peer, b) in r.cancels {
+                        if let Some(tx) = state.connections.get(&peer) {
+                            let _ = tx.send(Message::Cancel {
+                                index: b.piece,
+                                begin: b.begin,
+                                length: b.length,
+                            });
+                        }
+                    }
+                    *state.writing.entry(index).or_default() += 1;
+                    if r.piece_complete {
+                        state.completing.insert(index);
+                    }
+                    job = Some(DiskJob::Write {
+                        index,
+                        begin,
+                        block,
+                    });
+                }
+            }
+            Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload,
+            } => {
+                let theirs = ExtendedHandshake::from_bytes(&payload)?;
+                for e in self.extensions.iter() {
+                    if let Some(ext) = &e.handler
+                        && self.handshake.id_of(&e.name).is_some()
+                        && theirs.id_of(&e.name).is_some()
+                    {
+                        let mut ctx =
+                            ExtensionContext::new(self.info_hash, addr, &theirs, &e.name, &mut out);
+                        ext.on_handshake(&mut ctx)?;
+                    }
+                }
+                if let Some(port) = theirs.listen_port {
+                    state
+                        .listen_addrs
+                        .entry(addr)
+                        .or_insert_with(|| SocketAddr::new(addr.ip(), port));
+                }
+                conn.extended = Some(theirs);
+            }
+            Message::Extended { id, payload } => {
+                self.handle_extended(state, addr, conn, id, payload, &mut out)?
+            }
+            _ => {}
+        }
+        if !conn.choked {
+            for b in state.picker.pick(addr) {
+                out.push(Message::Request {
+                    index: b.piece,
+                    begin: b.begin,
+                    length: b.length,
+                });
+            }
+        }
+        Ok((out, job))
+    }
+    /// do the disk work of a message from `addr` on a blocking thread,
+    /// returning what to send back. a piece is verified once the last of
+    /// its blocks is written.
+    async fn disk(&self, addr: SocketAddr, job: DiskJob) -> Result<Vec<Message>, Error> {
+        match job {
+            DiskJob::Read {
+                index,
+                begin,
+                length,
+            } => {
+                let block = self
+                    .with_storage(move |s| s.read_block(index, begin, length))
+                    .await?;
+                Ok(vec![Message::Piece {
+                    index,
+                    begin,
+                    block: block.into(),
+                }])
+            }
+            DiskJob::Write {
+                index,
+                begin,
+                block,
+            } => {
+                let length = block.len() as u64;
+                let written = self
+                    .with_storage(move |s| s.write_block(index, begin, &block, addr))
+                    .await;
+                let verify = {
+                    let mut state = self.state.lock().unwrap();
+                    let left = match state.writing.get_mut(&index) {
+                        Some(n) => {
+                            *n -= 1;
+                            *n
+                        }
+                        None => 0,
+                    };
+                    if left == 0 {
+                        state.writing.remove(&index);
+                    }
+                    left == 0 && state.completing.remove(&index)
+                };
+                written?;
+                *self.downloaded.lock().unwrap() += length;
+                if verify {
+                    let check = self.with_storage(move |s| s.verify_piece(index)).await?;
+                    self.piece_checked(index, check)?;
+                }
+                Ok(vec![])
+            }
+        }
+    }
+    /// run `f` on the storage on a blocking thread
+    async fn with_storage<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut TorrentStorage) -> Result<T, StorageError> + Send + 'static,
+    {
+        let storage = Arc::clone(&self.storage);
+        match tokio::task::spawn_blocking(move || f(&mut storage.lock().unwrap())).await {
+            Ok(r) => r,
+            Err(_) => Err(StorageError::WorkerPanicked),
+        }
+    }
+    /// apply a message sent to the extension we registered as `id`,
+    /// messages before the peer's extended handshake or to extensions we
+    /// did not offer are ignored
+    fn handle_extended(
+        &self,
+        state: &mut SwarmState,
+        addr: SocketAddr,
+        conn: &PeerConn,
+        id: u8,
+        payload: Bytes,
+        out: &mut Vec<Message>,
+    ) -> Result<(), Error> {
+        let theirs = match &conn.extended {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        let e = match self.extensions.get(id) {
+            Some(e) if self.handshake.id_of(&e.name) == Some(id) => e,
+            _ => return Ok(()),
+        };
+        match (&e.handler, e.name.as_str()) {
+            (Some(ext), name) => {
+                let mut ctx = ExtensionContext::new(self.info_hash, addr, theirs, name, out);
+                ext.on_message(&mut ctx, payload)?;
+            }
+            (None, UT_METADATA) => {
+                if let Some(id) = theirs.id_of(UT_METADATA)
+                    && let MetadataMessage::Request { piece } =
+                        MetadataMessage::from_bytes(payload)?
+                {
+                    out.push(Message::Extended {
+                        id,
+                        payload: self.metadata_piece(piece).to_bytes(),
+                    });
+                }
+            }
+            (None, LT_DONTHAVE) => match <[u8; 4]>::try_from(&payload[..]) {
+                Ok(i) => state.picker.peer_dont_have(addr, u32::from_be_bytes(i)),
+                Err(_) => {
+                    return Err(PeerError::InvalidExtension(format!(
+                        "lt_donthave of {} bytes",
+                        payload.len()
+                    ))
+                    .into());
+                }
+            },
+            (None, UT_PEX) => {
+                let m = PexMessage::from_bytes(&payload)?;
+                let complete = state.picker.is_complete();
+                for (a, flags) in m.added.into_iter().take(PEX_MAX_PEERS) {
+                    // a seed has nothing for us once we are complete
+                    if complete && flags & PEX_SEED != 0 {
+                        continue;
+                    }
+                    if !state.connections.contains_key(&a) && !state.banned.contains(&a.ip()) {
+                        state.learned.push(Peer::new(a));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    /// the peers connected or dropped since the last ut_pex message to
+    /// `addr`, `None` when there is no change or either side does not
+    /// offer ut_pex
+    fn pex_message(&self, addr: SocketAddr, conn: &mut PeerConn) -> Option<Message> {
+        self.handshake.id_of(UT_PEX)?;
+        let id = conn.extended.as_ref()?.id_of(UT_PEX)?;
+        let state = self.state.lock().unwrap();
+        let current: HashMap<SocketAddr, u8> = state
+            .listen_addrs
+            .iter()
+            .filter(|(c, _)| **c != addr)
+            .map(|(c, l)| {
+                let mut flags = 0;
+                // we reached it, so others can
+                if c == l {
+                    flags |= PEX_CONNECTABLE;
+                }
+                if state.picker.peer_is_seed(*c) {
+                    flags |= PEX_SEED;
+                }
+                (*l, flags)
+            })
+            .collect();
+        let m = PexMessage {
+            added: current
+                .iter()
+                .filter(|(l, _)| !conn.pex_sent.contains(l))
+                .take(PEX_MAX_PEERS)
+                .map(|(l, f)| (*l, *f))
+                .collect(),
+            dropped: conn
+                .pex_sent
+                .iter()
+                .filter(|l| !current.contains_key(l))
+                .take(PEX_MAX_PEERS)
+                .copied()
+                .collect(),
+        };
+        if m.is_empty() {
+            return None;
+        }
+        conn.pex_sent.extend(m.added.iter().map(|(l, _)| *l));
+        for l in &m.dropped {
+            conn.pex_sent.remove(l);
+        }
+        Some(Message::Extended {
+            id,
+            payload: m.to_bytes(),
+        })
+    }
+    /// a 16 KiB piece of the info dictionary, rejected when out of range
+    fn metadata_piece(&self, piece: u32) -> MetadataMessage {
+        let info = match &self.metadata {
+            Some(info) => info,
+            None => return MetadataMessage::Reject { piece },
+        };
+        let begin = piece as usize * METADATA_PIECE_SIZE;
+        if begin >= info.len() {
+            return MetadataMessage::Reject { piece };
+        }
+        let end = info.len().min(begin + METADATA_PIECE_SIZE);
+        MetadataMessage::Data {
+            piece,
+            total_size: info.len() as u64,
+            data: info.slice(begin..end),
+        }
+    }
+    /// apply the hash check of a fully written piece and tell every peer
+    /// about it, a piece that fails is downloaded again and a peer that
+    /// alone sent it is banned
+    fn piece_checked(&self, i: u32, check: PieceCheck) -> Result<(), StorageError> {
+        let mut state = self.state.lock().unwrap();
+        match check {
+            PieceCheck::Passed => {
+                state.picker.set_have(i);
+                self.events.send(Event::PieceFinished {
+                    info_hash: self.info_hash,
+                    piece: i,
+                });
+                let layout = &self.layout;
+                for s in layout.map(i, 0, layout.piece_size(i))? {
+                    let f = &layout.files[s.file];
+                    if !f.padding
+                        && layout
+                            .file_pieces(s.file)
+                            .all(|p| state.picker.has_piece(p))
+                    {
+                        self.events.send(Event::FileCompleted {
+                            info_hash: self.info_hash,
+                            file: s.file,
+                            path: f.path.clone(),
+                        });
+                    }
+                }
+                let complete = state.picker.is_complete();
+                if complete {
+                    self.completed.notify_one();
+                }
+                for tx in state.connections.values() {
+                    let _ = tx.send(Message::Have(i));
+                    if complete {
+                        let _ = tx.send(Message::NotInterested);
+                    }
+                }
+                let have = state.picker.have();
+                *self.progress.lock().unwrap() = (have.count() * 100 / have.len().max(1)) as u16;
+            }
+            PieceCheck::Failed { peers } => {
+                state.picker.piece_failed(i);
+                if let [peer] = peers[..] {
+                    state.banned.insert(peer.ip());
+                }
+                self.events.send(Event::HashFailed {
+                    info_hash: self.info_hash,
+                    piece: i,
+                    peers,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the pieces resume data claims that storage still has. files untouched
+/// since it was saved are trusted, otherwise the claimed pieces are hashed
+/// again, reporting progress as (checked pieces, claimed pieces).
+fn check_resume(
+    storage: &mut TorrentStorage,
+    r: &ResumeData,
+    progress: &FnRecheckProgressCallBack,
+) -> Result<Vec<u32>, StorageError> {
+    let piece_count = storage.layout().piece_count();
+    let claimed: Vec<u32> = r
+        .pieces
+        .iter_set()
+        .filter(|i| *i < piece_count)
+        .map(|i| i as u32)
+        .collect();
+    if storage.file_sizes()?.as_ref() == Some(&r.file_sizes) {
+        return Ok(claimed);
+    }
+    let mut have = vec![];
+    for (n, i) in claimed.iter().enumerate() {
+        if storage.verify_piece(*i)? == PieceCheck::Passed {
+            have.push(*i);
+        }
+        progress(n + 1, claimed.len());
+    }
+    Ok(have)
+}
+
+/// ticks every `period`, the first time one period from now. ticks missed
+/// while the connection was busy are not made up for, so a peer never gets
+/// ut_pex messages closer together than `period`.
+fn pex_timer(period: Duration) -> Interval {
+    let mut timer = interval_at(tokio::time::Instant::now() + period, period);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    timer
+}
+
+/// where a download task stands, `can_become` tells which state may follow
+/// which
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DownloadStatus {
-    WAITING,
-    DOWNLOADING,
-    FINISH,
+    /// added, waiting for `start`
+    Queued,
+    /// hashing existing data before any peer is contacted
+    CheckingFiles,
+    /// fetching the info dictionary from peers
+    DownloadingMetadata,
+    Downloading,
+    /// complete and uploading to peers
+    Seeding,
+    /// complete and not seeding
+    Finished,
+    /// stopped, no peers connected and storage flushed
+    Paused,
+    /// the files are moving to a new storage path
+    MovingStorage,
+    /// stopped by a failure, `resume` queues it again
+    Error(String),
+}
+
+impl DownloadStatus {
+    /// whether a task in this state may move to `next`
+    pub fn can_become(&self, next: &DownloadStatus) -> bool {
+        use DownloadStatus::*;
+        match (self, next) {
+            _ if self == next => true,
+            // any task can fail or be paused
+            (_, Error(_) | Paused) => true,
+            // paused and failed tasks only run again through the queue, and
+            // a move queues the task again
+            (Paused | Error(_) | MovingStorage, Queued) => true,
+            // files only move while no peer is connected
+            (Queued | Paused | Finished, MovingStorage) => true,
+            (Queued, DownloadingMetadata) => true,
+            // a finished task started again checks its files first
+            (Queued | DownloadingMetadata | Finished, CheckingFiles) => true,
+            (Queued | DownloadingMetadata | CheckingFiles, Downloading) => true,
+            (Queued | DownloadingMetadata | CheckingFiles | Downloading, Seeding) => true,
+            (Queued | DownloadingMetadata | CheckingFiles | Downloading | Seeding, Finished) => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::storage::{FileEntry, PieceHash};
+
+    #[test]
+    fn only_listed_transitions_are_allowed() {
+        use DownloadStatus::*;
+        let path = [
+            Queued,
+            DownloadingMetadata,
+            CheckingFiles,
+            Downloading,
+            Seeding,
+            Finished,
+            MovingStorage,
+            Queued,
+        ];
+        for w in path.windows(2) {
+            assert!(w[0].can_become(&w[1]), "{:?} to {:?}", w[0], w[1]);
+        }
+        let failed = Error("disk full".to_string());
+        for s in path.iter().chain([&Paused, &failed]) {
+            assert!(s.can_become(&Paused) && s.can_become(&failed));
+        }
+        assert!(failed.can_become(&Queued));
+        assert!(!failed.can_become(&Downloading));
+        assert!(!Paused.can_become(&Downloading));
+        assert!(!Seeding.can_become(&Downloading));
+        assert!(!Finished.can_become(&Downloading));
+        assert!(!Downloading.can_become(&Queued));
+        assert!(!Downloading.can_become(&CheckingFiles));
+        assert!(!Downloading.can_become(&MovingStorage));
+        assert!(!MovingStorage.can_become(&Downloading));
+        assert!(!Finished.can_become(&DownloadingMetadata));
+    }
+
+    /// a 32 byte file in 16 byte pieces on disk below a fresh directory,
+    /// the first piece intact and the second one not
+    fn stored(tag: &str) -> (PathBuf, TorrentStorage, ResumeData) {
+        let root =
+            std::env::temp_dir().join(format!("torrentwork-resume-{}-{}", tag, std::process::id()));
+        let layout = StorageLayout {
+            files: vec![FileEntry {
+                path: PathBuf::from("a"),
+                length: 32,
+                offset: 0,
+                padding: false,
+            }],
+            piece_length: 16,
+            total_length: 32,
+            piece_hashes: [[1; 16], [2; 16]]
+                .iter()
+                .map(|p| PieceHash::V1(Sha1::digest(p).into()))
+                .collect(),
+        };
+        let mut storage = TorrentStorage::new(layout, Box::new(DiskStorage::new(&root))).unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+        storage.write_block(0, 0, &[1; 16], peer).unwrap();
+        storage.write_block(1, 0, &[9; 16], peer).unwrap();
+        storage.flush().unwrap();
+        let mut pieces = Bitfield::new(2);
+        pieces.set(0);
+        pieces.set(1);
+        let resume = ResumeData {
+            info_hash: InfoHash::new([1; 20]),
+            info_hash_v2: None,
+            name: "a".to_string(),
+            save_path: root.clone(),
+            pieces,
+            file_priorities: vec![Priority::Normal],
+            file_sizes: storage.file_sizes().unwrap().unwrap(),
+            peers: vec![],
+            trackers: vec![],
+            total_uploaded: 0,
+            total_downloaded: 32,
+            paused: false,
+        };
+        (root, storage, resume)
+    }
+
+    #[test]
+    fn untouched_files_are_not_hashed_again() {
+        let (root, mut storage, resume) = stored("same");
+        let hashed = Arc::new(AtomicUsize::new(0));
+        let counter = hashed.clone();
+        let progress = move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        };
+        // the claim is trusted, even for the piece that would fail
+        assert_eq!(
+            check_resume(&mut storage, &resume, &progress).unwrap(),
+            [0, 1]
+        );
+        assert_eq!(hashed.load(Ordering::SeqCst), 0);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn a_changed_mtime_forces_a_recheck() {
+        let (root, mut storage, resume) = stored("mtime");
+        let file = std::fs::File::options()
+            .write(true)
+            .open(root.join("a"))
+            .unwrap();
+        let earlier = std::time::SystemTime::now() - Duration::from_secs(3600);
+        file.set_modified(earlier).unwrap();
+        let hashed = Arc::new(AtomicUsize::new(0));
+        let counter = hashed.clone();
+        let progress = move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        };
+        assert_eq!(check_resume(&mut storage, &resume, &progress).unwrap(), [0]);
+        assert_eq!(hashed.load(Ordering::SeqCst), 2);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pex_is_sent_at_most_once_a_period() {
+        let period = Duration::from_millis(100);
+        let start = tokio::time::Instant::now();
+        let mut timer = pex_timer(period);
+        // not right away when the connection starts
+        timer.tick().await;
+        assert!(start.elapsed() >= period);
+        // a busy connection does not catch up with a burst of messages
+        tokio::time::sleep(period * 3).await;
+        timer.tick().await;
+        let late = tokio::time::Instant::now();
+        timer.tick().await;
+        assert!(late.elapsed() >= period);
+    }
 }
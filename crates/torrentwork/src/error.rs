@@ -0,0 +1,381 @@
+use std::{fmt, io, net::SocketAddr};
+
+use crate::peer::{handshake::HandshakeError, message::MessageError};
+
+/// result type of the crate's public api
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// any error the crate returns, grouped by where it came from
+#[derive(Debug)]
+pub enum Error {
+    /// a .torrent file, info dictionary or info hash is malformed
+    Metainfo(MetainfoError),
+    /// a tracker could not be reached or rejected the announce
+    Tracker(TrackerError),
+    /// a peer connection failed or misbehaved
+    Peer(PeerError),
+    /// reading, writing or verifying torrent data failed
+    Storage(StorageError),
+    /// a magnet link is malformed
+    Magnet(MagnetError),
+    /// a DHT query failed
+    Dht(DhtError),
+    /// a local file could not be read or written
+    Io(io::Error),
+    /// the operation is not allowed in the current state, for example
+    /// asking a task that has not started for its resume data
+    InvalidState(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Metainfo(e) => write!(f, "metainfo error: {}", e),
+            Error::Tracker(e) => write!(f, "tracker error: {}", e),
+            Error::Peer(e) => write!(f, "peer error: {}", e),
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+            Error::Magnet(e) => write!(f, "magnet error: {}", e),
+            Error::Dht(e) => write!(f, "dht error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::InvalidState(s) => write!(f, "invalid state: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Metainfo(e) => Some(e),
+            Error::Tracker(e) => Some(e),
+            Error::Peer(e) => Some(e),
+            Error::Storage(e) => Some(e),
+            Error::Magnet(e) => Some(e),
+            Error::Dht(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::InvalidState(_) => None,
+        }
+    }
+}
+
+impl From<MetainfoError> for Error {
+    fn from(e: MetainfoError) -> Self {
+        Error::Metainfo(e)
+    }
+}
+
+impl From<TrackerError> for Error {
+    fn from(e: TrackerError) -> Self {
+        Error::Tracker(e)
+    }
+}
+
+impl From<PeerError> for Error {
+    fn from(e: PeerError) -> Self {
+        Error::Peer(e)
+    }
+}
+
+impl From<StorageError> for Error {
+    fn from(e: StorageError) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<MagnetError> for Error {
+    fn from(e: MagnetError) -> Self {
+        Error::Magnet(e)
+    }
+}
+
+impl From<DhtError> for Error {
+    fn from(e: DhtError) -> Self {
+        Error::Dht(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// a malformed .torrent file or info hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetainfoError {
+    /// not valid bencoding, or not laid out like a torrent
+    Decode(String),
+    /// the torrent has no "info" dictionary
+    MissingInfo,
+    /// the torrent has neither v1 pieces nor a v2 file tree
+    NoPieces,
+    /// a field has a value the spec does not allow
+    InvalidField { field: &'static str, reason: String },
+    /// a file path that would escape the download directory
+    UnsafePath(String),
+    /// an info hash of the wrong length or encoding
+    InvalidInfoHash(String),
+}
+
+impl fmt::Display for MetainfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetainfoError::Decode(s) => write!(f, "malformed torrent: {}", s),
+            MetainfoError::MissingInfo => f.write_str("torrent has no info dictionary"),
+            MetainfoError::NoPieces => {
+                f.write_str("torrent has neither v1 pieces nor a v2 file tree")
+            }
+            MetainfoError::InvalidField { field, reason } => {
+                write!(f, "invalid {:?}: {}", field, reason)
+            }
+            MetainfoError::UnsafePath(s) => write!(f, "unsafe file path: {}", s),
+            MetainfoError::InvalidInfoHash(s) => write!(f, "invalid info hash: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for MetainfoError {}
+
+impl MetainfoError {
+    pub(crate) fn invalid(field: &'static str, reason: impl Into<String>) -> Self {
+        MetainfoError::InvalidField {
+            field,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// why an announce or scrape failed. transport errors keep only their
+/// message so the error can be stored in the tracker status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerError {
+    /// the tracker url does not parse or lacks a host or port
+    InvalidUrl(String),
+    /// neither http(s) nor udp
+    UnsupportedScheme(String),
+    /// the tracker host did not resolve
+    Resolve(String),
+    /// sending or receiving failed
+    Connection(String),
+    /// the tracker did not answer in time
+    Timeout,
+    /// an http status other than 200
+    HttpStatus(u16),
+    /// the response could not be parsed
+    InvalidResponse(String),
+    /// the tracker answered with a "failure reason" or an error action
+    Failure(String),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerError::InvalidUrl(u) => write!(f, "invalid tracker url {}", u),
+            TrackerError::UnsupportedScheme(u) => write!(f, "unsupported tracker url {}", u),
+            TrackerError::Resolve(h) => write!(f, "tracker host {} did not resolve", h),
+            TrackerError::Connection(s) => write!(f, "tracker connection failed: {}", s),
+            TrackerError::Timeout => f.write_str("tracker did not respond"),
+            TrackerError::HttpStatus(s) => write!(f, "tracker answered with http status {}", s),
+            TrackerError::InvalidResponse(s) => write!(f, "malformed tracker response: {}", s),
+            TrackerError::Failure(s) => write!(f, "tracker failure {}", s),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+/// a peer connection that failed or misbehaved
+#[derive(Debug)]
+pub enum PeerError {
+    Handshake(HandshakeError),
+    Message(MessageError),
+    /// a bitfield of the wrong length or with spare bits set
+    InvalidBitfield(String),
+    /// a malformed BEP 10 extension message
+    InvalidExtension(String),
+    /// a request for a block outside its piece
+    InvalidRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AlreadyConnected(SocketAddr),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::Handshake(e) => e.fmt(f),
+            PeerError::Message(e) => e.fmt(f),
+            PeerError::InvalidBitfield(s) => write!(f, "invalid bitfield: {}", s),
+            PeerError::InvalidExtension(s) => write!(f, "invalid extension message: {}", s),
+            PeerError::InvalidRequest {
+                index,
+                begin,
+                length,
+            } => write!(f, "request {}+{} is outside piece {}", begin, length, index),
+            PeerError::AlreadyConnected(addr) => write!(f, "already connected to {}", addr),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PeerError::Handshake(e) => Some(e),
+            PeerError::Message(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<HandshakeError> for PeerError {
+    fn from(e: HandshakeError) -> Self {
+        PeerError::Handshake(e)
+    }
+}
+
+impl From<MessageError> for PeerError {
+    fn from(e: MessageError) -> Self {
+        PeerError::Message(e)
+    }
+}
+
+impl From<HandshakeError> for Error {
+    fn from(e: HandshakeError) -> Self {
+        Error::Peer(e.into())
+    }
+}
+
+impl From<MessageError> for Error {
+    fn from(e: MessageError) -> Self {
+        Error::Peer(e.into())
+    }
+}
+
+/// reading, writing or checking torrent data failed
+#[derive(Debug)]
+pub enum StorageError {
+    /// the backend failed
+    Io(io::Error),
+    /// a block or piece outside the torrent
+    OutOfRange { piece: u32, begin: u64, length: u64 },
+    /// resume data that does not parse
+    InvalidResume(String),
+    /// resume data saved for another torrent
+    ResumeMismatch,
+    /// a hashing worker panicked
+    WorkerPanicked,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "storage io error: {}", e),
+            StorageError::OutOfRange {
+                piece,
+                begin,
+                length,
+            } => write!(f, "range {}+{} is outside piece {}", begin, length, piece),
+            StorageError::InvalidResume(s) => write!(f, "malformed resume data: {}", s),
+            StorageError::ResumeMismatch => f.write_str("resume data is for another torrent"),
+            StorageError::WorkerPanicked => f.write_str("hashing worker panicked"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+/// a malformed magnet link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MagnetError {
+    /// not a `magnet:?` uri
+    NotMagnet,
+    /// no `xt=urn:btih:` or `xt=urn:btmh:` parameter
+    MissingInfoHash,
+    InvalidInfoHash(String),
+    /// only a v2 info hash, which is not supported
+    V2Only,
+    /// any other malformed parameter
+    Link(magnet::Error),
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MagnetError::NotMagnet => f.write_str("not a magnet link"),
+            MagnetError::MissingInfoHash => f.write_str("magnet link has no info hash"),
+            MagnetError::InvalidInfoHash(s) => write!(f, "invalid magnet info hash: {}", s),
+            MagnetError::V2Only => {
+                f.write_str("magnet links with only a v2 info hash are not supported")
+            }
+            MagnetError::Link(e) => write!(f, "invalid magnet link: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MagnetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MagnetError::Link(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<magnet::Error> for MagnetError {
+    fn from(e: magnet::Error) -> Self {
+        match e {
+            magnet::Error::NotMagnet => MagnetError::NotMagnet,
+            magnet::Error::MissingExactTopic => MagnetError::MissingInfoHash,
+            magnet::Error::InvalidInfoHash(h) => MagnetError::InvalidInfoHash(h),
+            e => MagnetError::Link(e),
+        }
+    }
+}
+
+/// a DHT query that failed, or a malformed KRPC message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DhtError {
+    /// the node did not answer in time
+    Timeout,
+    /// not a valid KRPC message
+    InvalidMessage(String),
+    /// a query we do not implement
+    UnknownMethod(String),
+    /// the node answered with a KRPC error
+    Remote { code: i64, message: String },
+    /// sending failed
+    Send(String),
+    /// the DHT node was shut down
+    Closed,
+}
+
+impl fmt::Display for DhtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DhtError::Timeout => f.write_str("dht node did not respond"),
+            DhtError::InvalidMessage(s) => write!(f, "malformed krpc message: {}", s),
+            DhtError::UnknownMethod(m) => write!(f, "unknown dht method {}", m),
+            DhtError::Remote { code, message } => {
+                write!(f, "dht node answered error {}: {}", code, message)
+            }
+            DhtError::Send(s) => write!(f, "sending to dht node failed: {}", s),
+            DhtError::Closed => f.write_str("dht node is shut down"),
+        }
+    }
+}
+
+impl std::error::Error for DhtError {}
@@ -0,0 +1,146 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use tokio::sync::broadcast;
+
+use crate::{
+    download::DownloadStatus,
+    error::{Error, TrackerError},
+    hash::InfoHash,
+};
+
+/// events kept for a subscriber that falls behind, older ones are dropped
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+/// something that happened to a torrent
+#[derive(Debug, Clone)]
+pub enum Event {
+    TorrentAdded {
+        info_hash: InfoHash,
+    },
+    TorrentRemoved {
+        info_hash: InfoHash,
+    },
+    StateChanged {
+        info_hash: InfoHash,
+        state: DownloadStatus,
+    },
+    /// the info dictionary of a torrent added by magnet link arrived and
+    /// matched the link
+    MetadataReceived {
+        info_hash: InfoHash,
+    },
+    /// a piece passed its hash check
+    PieceFinished {
+        info_hash: InfoHash,
+        piece: u32,
+    },
+    /// a piece failed its hash check and is downloaded again, `peers` sent
+    /// its blocks
+    HashFailed {
+        info_hash: InfoHash,
+        piece: u32,
+        peers: Vec<SocketAddr>,
+    },
+    /// a tracker answered an announce
+    TrackerReply {
+        info_hash: InfoHash,
+        url: String,
+        peers: usize,
+    },
+    TrackerError {
+        info_hash: InfoHash,
+        url: String,
+        error: TrackerError,
+    },
+    PeerConnected {
+        info_hash: InfoHash,
+        addr: SocketAddr,
+    },
+    /// the connection ended, with the error that ended it if any
+    PeerDisconnected {
+        info_hash: InfoHash,
+        addr: SocketAddr,
+        error: Option<Arc<Error>>,
+    },
+    /// every piece of a file passed its hash check, `path` is relative to
+    /// the storage path
+    FileCompleted {
+        info_hash: InfoHash,
+        file: usize,
+        path: PathBuf,
+    },
+    /// reading or writing the torrent's data failed
+    StorageError {
+        info_hash: InfoHash,
+        error: Arc<Error>,
+    },
+    /// sent for every running torrent once per tick
+    Stats(TorrentStats),
+}
+
+impl Event {
+    /// the torrent the event is about
+    pub fn info_hash(&self) -> InfoHash {
+        match self {
+            Event::TorrentAdded { info_hash }
+            | Event::TorrentRemoved { info_hash }
+            | Event::StateChanged { info_hash, .. }
+            | Event::MetadataReceived { info_hash }
+            | Event::PieceFinished { info_hash, .. }
+            | Event::HashFailed { info_hash, .. }
+            | Event::TrackerReply { info_hash, .. }
+            | Event::TrackerError { info_hash, .. }
+            | Event::PeerConnected { info_hash, .. }
+            | Event::PeerDisconnected { info_hash, .. }
+            | Event::FileCompleted { info_hash, .. }
+            | Event::StorageError { info_hash, .. } => *info_hash,
+            Event::Stats(s) => s.info_hash,
+        }
+    }
+}
+
+/// a snapshot of a torrent's transfer
+#[derive(Debug, Clone)]
+pub struct TorrentStats {
+    pub info_hash: InfoHash,
+    pub state: DownloadStatus,
+    /// percentage of the wanted pieces we have
+    pub progress: u16,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// bytes of the pieces we do not have yet
+    pub left: u64,
+    /// open peer connections
+    pub peers: usize,
+    /// bytes per second over the last tick
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+/// the sending side of an event stream, clones send to the same subscribers
+#[derive(Debug, Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        Self { tx }
+    }
+    /// receive every event sent from now on. a subscriber that falls more
+    /// than the capacity behind gets `RecvError::Lagged` and skips ahead.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+    /// events nobody subscribed to are dropped
+    pub(crate) fn send(&self, e: Event) {
+        let _ = self.tx.send(e);
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
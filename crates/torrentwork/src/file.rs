@@ -1,15 +1,17 @@
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, path::PathBuf};
 
 use crate::{
+    bitfield::Bitfield,
+    creator::{FnCreateProgressCallBack, TorrentBuilder},
     download::DownloadTask,
-    peer::Peer,
+    error::{Error, MetainfoError},
+    hash::{InfoHash, InfoHashV2, info_bytes},
+    magnet::{ExactTopic, Magnet, MagnetLink},
+    storage::{DiskStorage, FnRecheckProgressCallBack, StorageLayout, TorrentStorage},
     torrent::Torrent,
     tracker::{http::HttpTrackerResponse, tracker::Tracker},
 };
-use magnet_url::Magnet;
-use serde_bencode::de;
-use sha1::{Digest, Sha1};
-use urlencoding::encode;
+use bencode::Encoder;
 
 /// torrent file abstract
 #[derive(Clone, Debug)]
@@ -18,8 +20,13 @@ pub struct TorrentFile {
     pub meta_data: Torrent,
     /// torrent file all tracker server address
     pub announces: Vec<String>,
-    /// torrent file info hash
-    pub info_hash: String,
+    /// BEP 12 tracker tiers, `announce-list` if present, else `announce`
+    pub announce_tiers: Vec<Vec<String>>,
+    /// torrent file info hash, sha1 of the raw "info" dictionary bytes,
+    /// or the truncated v2 hash for pure v2 torrents
+    pub info_hash: InfoHash,
+    /// sha256 info hash of v2 and hybrid torrents
+    pub info_hash_v2: Option<InfoHashV2>,
     /// multiple file tags
     pub is_multiple_files: bool,
     /// the peer list corresponding to the file
@@ -27,104 +34,287 @@ pub struct TorrentFile {
     /// download task list
     pub downloads: Option<Vec<DownloadTask>>,
     /// downloaded file storage path, by default, it is placed in the current directory
-    pub storage_path: String,
+    pub storage_path: PathBuf,
+    /// the bencoded .torrent file content as read or created, empty until
+    /// the metadata of a magnet link arrived
+    pub raw_data: Vec<u8>,
+    /// the link a torrent without metadata was made from
+    pub magnet: Option<Magnet>,
 }
 
 impl TorrentFile {
     /// constructs a TorrentFile instance using the path to a local .torrent file.
-    pub fn new(file_path: String) -> Result<Self, String> {
-        match std::fs::read(file_path) {
-            Ok(buf) => match de::from_bytes::<Torrent>(&buf) {
-                Ok(t) => {
-                    // Save the result of sha1(bencode(info)) to send Tracker request.
-                    let mut info_bytes = serde_bencode::to_bytes(&t.info).unwrap();
-                    let mut s1 = Sha1::new();
-                    s1.update(info_bytes);
-                    let mut info_sha1_hash = hex::encode(s1.finalize().to_vec());
-                    // trim 20 bytes
-                    info_sha1_hash = (&info_sha1_hash[..20]).to_string();
-                    Ok(Self {
-                        meta_data: t.clone(),
-                        announces: Self::get_all_announce(&t),
-                        info_hash: encode(&info_sha1_hash).to_string(),
-                        is_multiple_files: Self::is_multiple_files(&t),
-                        peers: None,
-                        downloads: Some(vec![]),
-                        storage_path: ".".to_string(),
-                    })
-                }
-                Err(e) => Err(format!("ERROR: {:?}", e).to_string()),
+    pub fn new(file_path: String) -> Result<Self, Error> {
+        Self::from_bytes(fs::read(file_path)?)
+    }
+    /// constructs a TorrentFile instance from the bencoded content of a .torrent file.
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self, Error> {
+        // decoded once, errors report the byte offset where the file is
+        // malformed
+        let root = match bencode::decode(&buf) {
+            Ok(v) => v,
+            Err(e) => return Err(MetainfoError::Decode(e.to_string()).into()),
+        };
+        // hash the original "info" bytes, re-encoding the parsed struct
+        // would drop unknown keys and change the hash
+        let info = match root.as_dict().and_then(|d| d.get_dict("info")) {
+            Some(info) => info.raw().unwrap_or_default(),
+            None => return Err(MetainfoError::MissingInfo.into()),
+        };
+        let t = Torrent::from_value(&root)?;
+        if !t.is_v1() && !t.is_v2() {
+            return Err(MetainfoError::NoPieces.into());
+        }
+        let info_hash = InfoHash::from_info_bytes(info);
+        let info_hash_v2 = match t.is_v2() {
+            true => Some(InfoHashV2::from_info_bytes(info)),
+            false => None,
+        };
+        Ok(Self {
+            announces: Self::get_all_announce(&t),
+            announce_tiers: Self::get_announce_tiers(&t),
+            // pure v2 torrents are identified on the wire by the truncated v2 hash
+            info_hash: match info_hash_v2 {
+                Some(v2) if !t.is_v1() => v2.truncated(),
+                _ => info_hash,
             },
-            Err(e) => Err(format!("ERROR: {:?}", e).to_string()),
+            info_hash_v2,
+            is_multiple_files: Self::is_multiple_files(&t),
+            meta_data: t,
+            peers: None,
+            downloads: Some(vec![]),
+            storage_path: PathBuf::from("."),
+            raw_data: buf,
+            magnet: None,
+        })
+    }
+    /// constructs a TorrentFile instance from a magnet link. it has no
+    /// metadata, a download task fetches the info dictionary from peers
+    /// before it downloads the files.
+    pub fn from_magnet(magnet: Magnet) -> Self {
+        let mut meta_data = Torrent::default();
+        meta_data.info.name = match &magnet.link.display_name {
+            Some(name) => name.clone(),
+            None => magnet.info_hash.to_hex(),
+        };
+        let announce_tiers = magnet.announce_tiers();
+        Self {
+            announces: announce_tiers.iter().flatten().cloned().collect(),
+            announce_tiers,
+            info_hash: magnet.info_hash,
+            info_hash_v2: magnet.info_hash_v2,
+            is_multiple_files: false,
+            meta_data,
+            peers: None,
+            downloads: Some(vec![]),
+            storage_path: PathBuf::from("."),
+            raw_data: vec![],
+            magnet: Some(magnet),
+        }
+    }
+    /// whether the info dictionary is known, it is not for a torrent made
+    /// from a magnet link until its metadata was fetched
+    pub fn has_metadata(&self) -> bool {
+        !self.raw_data.is_empty()
+    }
+    /// a BEP 27 private torrent, its peers come from its trackers only
+    pub fn is_private(&self) -> bool {
+        self.meta_data.info.private == Some(1)
+    }
+    /// the raw bencoded info dictionary, as served to peers fetching the
+    /// metadata
+    pub fn info_bytes(&self) -> Option<&[u8]> {
+        info_bytes(&self.raw_data).ok()
+    }
+    /// the torrent a magnet link names, from its fetched info dictionary.
+    /// the dictionary is checked against every hash of the link and the
+    /// link's trackers and the storage path are kept.
+    pub fn with_metadata(&self, info: &[u8]) -> Result<Self, Error> {
+        let magnet = match &self.magnet {
+            Some(m) => m,
+            None => {
+                return Err(Error::InvalidState(
+                    "torrent was not made from a magnet link".to_string(),
+                ));
+            }
+        };
+        if !magnet.verify_info(info) {
+            return Err(MetainfoError::InvalidInfoHash(
+                "info dictionary does not match the magnet link".to_string(),
+            )
+            .into());
         }
+        let mut e = Encoder::new(vec![]);
+        e.begin_dict()?;
+        if let Some(tracker) = self.announces.first() {
+            e.str("announce")?.str(tracker)?;
+            e.str("announce-list")?.begin_list()?;
+            for tier in &self.announce_tiers {
+                e.begin_list()?;
+                for tracker in tier {
+                    e.str(tracker)?;
+                }
+                e.end()?;
+            }
+            e.end()?;
+        }
+        e.str("info")?.raw(info)?.end()?;
+        let mut tf = Self::from_bytes(e.into_inner())?;
+        tf.storage_path = self.storage_path.clone();
+        Ok(tf)
     }
     /// ready to download
     pub async fn ready_to_download(&mut self) -> &mut Self {
-        let mut tracker = Tracker::new(self.clone());
-        let mut download_list = Vec::<DownloadTask>::new();
-        self.meta_data.info.files.iter().for_each(|e| {
-            e.clone().iter().for_each(|f| {
-                let download =
-                    DownloadTask::new(self.info_hash.clone(), f.clone(), tracker.clone());
-                download_list.push(download);
-            });
-        });
-        self.downloads = Some(download_list);
+        let tracker = Tracker::new(self.clone());
+        // pieces span file boundaries, one task downloads the whole torrent
+        self.downloads = Some(vec![DownloadTask::new(self, tracker)]);
         self
     }
     /// download
-    pub async fn download(&mut self) -> Result<String, String> {
-        if self.is_multiple_files {
-            self.storage_path.push_str("\\");
-            self.storage_path.push_str(&self.meta_data.info.name);
-            for (a, b) in self.downloads.clone().unwrap().iter().enumerate() {
-                b.clone().start().await;
-            }
-            fs::create_dir_all(self.storage_path.clone());
-        } else {
+    pub async fn download(&mut self) -> Result<String, Error> {
+        for b in self.downloads.iter_mut().flatten() {
+            b.start().await?;
         }
         Ok("done".to_string())
     }
     /// get .torrent file all announce url
     fn get_all_announce(torrent: &Torrent) -> Vec<String> {
         let mut announces: Vec<String> = vec![];
-        if !torrent.announce.is_none() {
-            announces.push(torrent.announce.clone().unwrap());
-        }
-        for (_i, v) in torrent.announce_list.clone().unwrap().iter().enumerate() {
-            for (_index, value) in v.iter().enumerate() {
-                announces.push(value.to_string());
+        for value in Self::get_announce_tiers(torrent).into_iter().flatten() {
+            if !announces.contains(&value) {
+                announces.push(value);
             }
         }
         announces
     }
-    /// make a magnet url
-    pub fn make_magnet_url(&self) -> Result<String, String> {
-        let m = Magnet {
-            dn: Some(self.meta_data.info.name.clone()),
-            hash_type: Some("btih".to_string()),
-            xt: Some(self.info_hash.clone()),
-            xs: None,
-            kt: None,
-            ws: None,
-            acceptable_source: None,
-            mt: None,
-            xl: None,
-            tr: vec![],
+    /// get .torrent file tracker tiers, clients supporting BEP 12 ignore
+    /// `announce` when `announce-list` is present
+    fn get_announce_tiers(torrent: &Torrent) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = torrent
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|t| !t.is_empty())
+            .cloned()
+            .collect();
+        match &torrent.announce {
+            Some(announce) if tiers.is_empty() => vec![vec![announce.clone()]],
+            _ => tiers,
+        }
+    }
+    /// make a magnet url naming every info hash of the torrent, with its
+    /// name, size and trackers
+    pub fn make_magnet_url(&self) -> Result<String, Error> {
+        let mut m = MagnetLink::default();
+        if self.meta_data.is_v1() {
+            m = m.with_exact_topic(ExactTopic::BtInfoHash(*self.info_hash.as_bytes()));
+        }
+        if let Some(v2) = self.info_hash_v2 {
+            m = m.with_exact_topic(ExactTopic::BtMultihash(*v2.as_bytes()));
+        }
+        let info = &self.meta_data.info;
+        // the content only, without the padding files of hybrid torrents
+        let length = match &info.files {
+            Some(files) => files
+                .iter()
+                .filter(|f| !f.is_padding())
+                .map(|f| f.length)
+                .sum(),
+            None => self.total_length(),
         };
+        m = m
+            .with_display_name(info.display_name())
+            .with_exact_length(length.max(0) as u64);
+        for tr in &self.announces {
+            m = m.with_tracker(tr.clone());
+        }
         Ok(m.to_string())
     }
-    /// make a .torrent file
-    pub fn make() {}
+    /// bytes of the files of the torrent, padding included, zero until its
+    /// metadata is known
+    pub fn total_length(&self) -> i64 {
+        let info = &self.meta_data.info;
+        match (&info.files, &info.file_tree) {
+            (Some(files), _) => files.iter().map(|f| f.length).sum(),
+            (None, Some(tree)) if !self.meta_data.is_v1() => tree.iter().map(|f| f.length).sum(),
+            _ => info.length.unwrap_or_default(),
+        }
+    }
+    /// make a .torrent file from the file or directory described by `builder`
+    pub fn make(builder: &TorrentBuilder) -> Result<Self, Error> {
+        Self::from_bytes(builder.build()?)
+    }
+    /// make a .torrent file, reporting hashing progress as (hashed pieces, total pieces)
+    pub fn make_with_progress(
+        builder: &TorrentBuilder,
+        progress: &FnCreateProgressCallBack,
+    ) -> Result<Self, Error> {
+        Self::from_bytes(builder.build_with_progress(progress)?)
+    }
     /// storage the .torrent to disk
-    pub fn storage(&self) {}
+    pub fn storage(&self, file_path: &str) -> Result<(), Error> {
+        Ok(fs::write(file_path, &self.raw_data)?)
+    }
     /// is multiple file
     pub fn is_multiple_files(tf: &Torrent) -> bool {
-        if tf.info.files.is_none() { false } else { true }
+        match &tf.info.file_tree {
+            Some(tree) if !tf.is_v1() => tree.len() > 1 || tree.iter().any(|f| f.path.len() > 1),
+            _ => tf.info.files.is_some(),
+        }
+    }
+    /// every 20-byte hash peers may use for this torrent in handshakes,
+    /// hybrid torrents are reachable through both the v1 and the truncated v2 hash
+    pub fn wire_hashes(&self) -> Vec<InfoHash> {
+        let mut hashes = vec![self.info_hash];
+        if let Some(v2) = self.info_hash_v2
+            && v2.truncated() != self.info_hash
+        {
+            hashes.push(v2.truncated());
+        }
+        hashes
+    }
+    /// hash the data below `storage_path` against the piece hashes with up
+    /// to `threads` workers, returning the pieces already present
+    pub fn recheck(
+        &self,
+        threads: usize,
+        progress: &FnRecheckProgressCallBack,
+    ) -> Result<Bitfield, Error> {
+        let layout = StorageLayout::from_torrent(&self.meta_data)?;
+        let backend = Box::new(DiskStorage::new(&self.storage_path));
+        Ok(TorrentStorage::new(layout, backend)?.recheck(threads, progress)?)
     }
     /// set storage path
-    pub fn set_storage_path(&mut self, storage_path: String) -> Result<&mut Self, String> {
-        self.storage_path = storage_path;
+    pub fn set_storage_path(
+        &mut self,
+        storage_path: impl Into<PathBuf>,
+    ) -> Result<&mut Self, Error> {
+        self.storage_path = storage_path.into();
         Ok(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::creator::TorrentVersion;
+
+    #[test]
+    fn magnet_url_has_the_length_of_v2_torrents() {
+        let root = std::env::temp_dir().join(format!("torrentwork-xl-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a"), [1; 100]).unwrap();
+        fs::write(root.join("sub").join("b"), [2; 50]).unwrap();
+        for version in [
+            TorrentVersion::V1,
+            TorrentVersion::V2,
+            TorrentVersion::Hybrid,
+        ] {
+            let tf = TorrentFile::make(&TorrentBuilder::new(&root).version(version)).unwrap();
+            let url = tf.make_magnet_url().unwrap();
+            let link = MagnetLink::parse(&url).unwrap();
+            assert_eq!(link.exact_length, Some(150), "{:?}", version);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::error::MetainfoError;
+
+/// length of a v1 info hash in bytes
+pub const INFO_HASH_V1_LEN: usize = 20;
+/// length of a v2 info hash in bytes
+pub const INFO_HASH_V2_LEN: usize = 32;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// sha1 hash of the bencoded "info" dictionary, 20 raw bytes.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHash([u8; INFO_HASH_V1_LEN]);
+
+impl InfoHash {
+    /// wrap 20 raw hash bytes.
+    pub fn new(bytes: [u8; INFO_HASH_V1_LEN]) -> Self {
+        Self(bytes)
+    }
+    /// hash the exact bytes of a bencoded "info" dictionary.
+    pub fn from_info_bytes(info: &[u8]) -> Self {
+        let mut s1 = Sha1::new();
+        s1.update(info);
+        Self(s1.finalize().into())
+    }
+    /// hash the "info" dictionary found inside a raw .torrent buffer,
+    /// using its original byte span so unknown keys and key order are preserved.
+    pub fn from_torrent_bytes(buf: &[u8]) -> Result<Self, MetainfoError> {
+        Ok(Self::from_info_bytes(info_bytes(buf)?))
+    }
+    /// build from a byte slice, which must be exactly 20 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        match <[u8; INFO_HASH_V1_LEN]>::try_from(bytes) {
+            Ok(b) => Ok(Self(b)),
+            Err(_) => Err(MetainfoError::InvalidInfoHash(format!(
+                "must be 20 bytes, got {}",
+                bytes.len()
+            ))),
+        }
+    }
+    /// parse a 40 character hex string.
+    pub fn from_hex(s: &str) -> Result<Self, MetainfoError> {
+        match hex::decode(s) {
+            Ok(b) => Self::from_slice(&b),
+            Err(e) => Err(MetainfoError::InvalidInfoHash(format!("invalid hex {}", e))),
+        }
+    }
+    /// parse a 32 character rfc 4648 base32 string, as used by some magnet links.
+    pub fn from_base32(s: &str) -> Result<Self, MetainfoError> {
+        let mut out = Vec::with_capacity(INFO_HASH_V1_LEN);
+        let mut buffer: u64 = 0;
+        let mut bits = 0;
+        for c in s.trim_end_matches('=').bytes() {
+            let v = match BASE32_ALPHABET
+                .iter()
+                .position(|a| *a == c.to_ascii_uppercase())
+            {
+                Some(v) => v as u64,
+                None => {
+                    return Err(MetainfoError::InvalidInfoHash(format!(
+                        "invalid base32 character {:?}",
+                        c as char
+                    )));
+                }
+            };
+            buffer = (buffer << 5) | v;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                out.push((buffer >> bits) as u8);
+                buffer &= (1 << bits) - 1;
+            }
+        }
+        Self::from_slice(&out)
+    }
+    /// raw hash bytes
+    pub fn as_bytes(&self) -> &[u8; INFO_HASH_V1_LEN] {
+        &self.0
+    }
+    /// lowercase hex view, 40 characters.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+    /// rfc 4648 base32 view without padding, 32 characters.
+    pub fn to_base32(&self) -> String {
+        let mut out = String::with_capacity(32);
+        let mut buffer: u64 = 0;
+        let mut bits = 0;
+        for b in self.0 {
+            buffer = (buffer << 8) | b as u64;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+        }
+        out
+    }
+    /// percent-encoded view of the raw bytes, as sent in a tracker announce query.
+    pub fn to_url_encoded(&self) -> String {
+        percent_encode_bytes(&self.0)
+    }
+}
+
+/// sha256 hash of the bencoded "info" dictionary of a v2 or hybrid torrent, 32 raw bytes.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHashV2([u8; INFO_HASH_V2_LEN]);
+
+impl InfoHashV2 {
+    /// wrap 32 raw hash bytes.
+    pub fn new(bytes: [u8; INFO_HASH_V2_LEN]) -> Self {
+        Self(bytes)
+    }
+    /// hash the exact bytes of a bencoded "info" dictionary.
+    pub fn from_info_bytes(info: &[u8]) -> Self {
+        Self(Sha256::digest(info).into())
+    }
+    /// hash the "info" dictionary found inside a raw .torrent buffer.
+    pub fn from_torrent_bytes(buf: &[u8]) -> Result<Self, MetainfoError> {
+        Ok(Self::from_info_bytes(info_bytes(buf)?))
+    }
+    /// build from a byte slice, which must be exactly 32 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        match <[u8; INFO_HASH_V2_LEN]>::try_from(bytes) {
+            Ok(b) => Ok(Self(b)),
+            Err(_) => Err(MetainfoError::InvalidInfoHash(format!(
+                "v2 hash must be 32 bytes, got {}",
+                bytes.len()
+            ))),
+        }
+    }
+    /// parse a 64 character hex string.
+    pub fn from_hex(s: &str) -> Result<Self, MetainfoError> {
+        match hex::decode(s) {
+            Ok(b) => Self::from_slice(&b),
+            Err(e) => Err(MetainfoError::InvalidInfoHash(format!("invalid hex {}", e))),
+        }
+    }
+    /// raw hash bytes
+    pub fn as_bytes(&self) -> &[u8; INFO_HASH_V2_LEN] {
+        &self.0
+    }
+    /// the first 20 bytes, used in handshakes, tracker announces and the DHT.
+    pub fn truncated(&self) -> InfoHash {
+        let mut b = [0; INFO_HASH_V1_LEN];
+        b.copy_from_slice(&self.0[..INFO_HASH_V1_LEN]);
+        InfoHash(b)
+    }
+    /// lowercase hex view, 64 characters.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+    /// sha2-256 multihash in hex ("1220" prefix), the form used by magnet "btmh".
+    pub fn to_multihash_hex(&self) -> String {
+        format!("1220{}", self.to_hex())
+    }
+}
+
+impl fmt::Display for InfoHashV2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for InfoHashV2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InfoHashV2({})", self.to_hex())
+    }
+}
+
+/// the exact bytes of the top-level "info" value in a bencoded torrent.
+pub(crate) fn info_bytes(buf: &[u8]) -> Result<&[u8], MetainfoError> {
+    let root = match bencode::decode(buf) {
+        Ok(v) => v,
+        Err(e) => return Err(MetainfoError::Decode(e.to_string())),
+    };
+    match root
+        .as_dict()
+        .and_then(|d| d.get_dict("info"))
+        .and_then(|i| i.raw())
+    {
+        Some(info) => Ok(info),
+        None => Err(MetainfoError::MissingInfo),
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InfoHash({})", self.to_hex())
+    }
+}
+
+/// percent-encode arbitrary bytes, leaving only rfc 3986 unreserved characters as-is.
+pub fn percent_encode_bytes(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3);
+    for b in bytes {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(*b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
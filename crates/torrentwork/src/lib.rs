@@ -1,6 +1,19 @@
-pub mod file;
+pub mod bitfield;
+pub mod creator;
+pub mod dht;
 pub mod download;
+pub mod error;
+pub mod event;
+pub mod file;
+pub mod hash;
+pub mod limiter;
 pub mod magnet;
-pub mod tracker;
+pub mod merkle;
+pub mod metadata;
+pub mod peer;
+pub mod picker;
+pub mod resume;
+pub mod server;
+pub mod storage;
 pub mod torrent;
-pub mod peer;
\ No newline at end of file
+pub mod tracker;
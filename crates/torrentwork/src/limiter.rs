@@ -0,0 +1,76 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+
+/// a bandwidth limit in bytes per second shared by every connection it
+/// throttles. callers take what they need up front and sleep off the debt,
+/// so a burst of at most one second's worth passes without waiting.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// `None` is unlimited
+    rate: Option<u64>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// `rate` in bytes per second, `None` for no limit
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: rate.filter(|r| *r > 0),
+                tokens: 0.0,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+    /// change the limit, connections waiting on the old one finish their wait
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut b = self.bucket.lock().unwrap();
+        b.rate = rate.filter(|r| *r > 0);
+        b.tokens = 0.0;
+        b.refilled = Instant::now();
+    }
+    /// wait until `bytes` may be transferred
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut b = self.bucket.lock().unwrap();
+            let rate = match b.rate {
+                Some(r) => r as f64,
+                None => return,
+            };
+            let now = Instant::now();
+            let elapsed = now.duration_since(b.refilled).as_secs_f64();
+            b.tokens = (b.tokens + elapsed * rate).min(rate);
+            b.refilled = now;
+            b.tokens -= bytes as f64;
+            match b.tokens < 0.0 {
+                true => Duration::from_secs_f64(-b.tokens / rate),
+                false => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
@@ -1,19 +1,108 @@
-use regex::Regex;
+use std::net::SocketAddr;
 
-use crate::file::TorrentFile;
+pub use ::magnet::{ExactTopic, Magnet as MagnetLink, PeerAddress};
 
+use crate::{
+    error::MagnetError,
+    hash::{InfoHash, InfoHashV2},
+    peer::Peer,
+};
+
+/// a torrent known only by its magnet link. `TorrentFile::from_magnet`
+/// turns it into a torrent whose info dictionary is fetched from peers
+/// when it starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Magnet {
-    torrent_file: TorrentFile,
+    pub link: MagnetLink,
+    /// the v1 hash peers know the torrent by
+    pub info_hash: InfoHash,
+    /// the v2 hash of v2 and hybrid links
+    pub info_hash_v2: Option<InfoHashV2>,
 }
 
 impl Magnet {
-    pub fn new(url: &str) -> Result<String, String> {
-        // verify magnet protocol format
-        let r = Regex::new(r"magnet:\?xt=urn:btih:[0-9a-fA-F]{40,}.*").unwrap();
-        match r.is_match(url) {
-            true => Ok("1".to_string()),
-            False => Err("link format error".to_string()),
-            _ => Ok("1".to_string()),
+    /// parse a magnet link naming a v1 or hybrid torrent. links with only
+    /// a v2 hash are refused, peers send the info dictionary but not the
+    /// piece layers a pure v2 torrent needs.
+    pub fn new(url: &str) -> Result<Self, MagnetError> {
+        Self::from_link(parse(url)?)
+    }
+    pub fn from_link(link: MagnetLink) -> Result<Self, MagnetError> {
+        let (v1, v2) = info_hashes(&link)?;
+        let info_hash = match v1 {
+            Some(h) => h,
+            None => return Err(MagnetError::V2Only),
+        };
+        Ok(Self {
+            link,
+            info_hash,
+            info_hash_v2: v2,
+        })
+    }
+    /// the link's trackers, one per BEP 12 tier so that every one is tried
+    pub fn announce_tiers(&self) -> Vec<Vec<String>> {
+        self.link.trackers.iter().map(|t| vec![t.clone()]).collect()
+    }
+    /// whether `info` is the info dictionary the link names, every hash
+    /// the link carries has to match
+    pub fn verify_info(&self, info: &[u8]) -> bool {
+        let v1 = self.link.info_hash().map(InfoHash::new);
+        v1.is_none_or(|h| h == InfoHash::from_info_bytes(info))
+            && self
+                .info_hash_v2
+                .is_none_or(|h| h == InfoHashV2::from_info_bytes(info))
+    }
+    /// the `x.pe` peers, host names are looked up and the ones that do not
+    /// resolve are skipped
+    pub async fn peers(&self) -> Vec<Peer> {
+        let mut peers = vec![];
+        for p in &self.link.peers {
+            let addrs = match tokio::net::lookup_host((p.host.as_str(), p.port)).await {
+                Ok(addrs) => addrs.collect::<Vec<SocketAddr>>(),
+                Err(_) => continue,
+            };
+            peers.extend(addrs.into_iter().map(Peer::new));
         }
+        peers
+    }
+}
+
+/// parse a magnet link
+pub fn parse(url: &str) -> Result<MagnetLink, MagnetError> {
+    Ok(MagnetLink::parse(url)?)
+}
+
+/// the v1 and v2 info hashes of a link, an error when it names neither
+pub fn info_hashes(
+    link: &MagnetLink,
+) -> Result<(Option<InfoHash>, Option<InfoHashV2>), MagnetError> {
+    let v1 = link.info_hash().map(InfoHash::new);
+    let v2 = link.info_hash_v2().map(InfoHashV2::new);
+    if v1.is_none() && v2.is_none() {
+        return Err(MagnetError::MissingInfoHash);
+    }
+    Ok((v1, v2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BTIH: &str = "urn:btih:0123456789abcdef0123456789abcdef01234567";
+    const BTMH: &str =
+        "urn:btmh:12200123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn links_with_only_a_v2_hash_are_refused() {
+        let hybrid = Magnet::new(&format!("magnet:?xt={}&xt={}", BTIH, BTMH)).unwrap();
+        assert_eq!(
+            hybrid.info_hash.to_hex(),
+            "0123456789abcdef0123456789abcdef01234567"
+        );
+        assert!(hybrid.info_hash_v2.is_some());
+        assert_eq!(
+            Magnet::new(&format!("magnet:?xt={}", BTMH)),
+            Err(MagnetError::V2Only)
+        );
     }
 }
@@ -0,0 +1,96 @@
+use sha2::{Digest, Sha256};
+
+/// size of a v2 merkle leaf block, 16 KiB
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+/// a sha256 node of a BEP 52 merkle tree
+pub type MerkleHash = [u8; 32];
+
+/// sha256 of a byte slice.
+pub fn sha256(data: &[u8]) -> MerkleHash {
+    Sha256::digest(data).into()
+}
+
+/// parent node of two children.
+pub fn hash_pair(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+    let mut h = Sha256::new();
+    h.update(left);
+    h.update(right);
+    h.finalize().into()
+}
+
+/// root of a subtree with `2^height` all-zero leaves, used to pad partial trees.
+pub fn pad_hash(height: u32) -> MerkleHash {
+    let mut h = [0; 32];
+    for _ in 0..height {
+        h = hash_pair(&h, &h);
+    }
+    h
+}
+
+/// leaf hashes of `data`, one per 16 KiB block, the last block may be short.
+pub fn block_hashes(data: &[u8]) -> Vec<MerkleHash> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(sha256).collect()
+}
+
+/// root of a tree whose nodes at `height` are `layer`, padded on the right with
+/// zero subtrees up to `width` nodes; `width` is rounded up to a power of two.
+pub fn root_from_layer(layer: &[MerkleHash], height: u32, width: usize) -> MerkleHash {
+    let width = width.max(layer.len()).max(1).next_power_of_two();
+    let mut level: Vec<MerkleHash> = layer.to_vec();
+    let mut pad = pad_hash(height);
+    let mut width = width;
+    while width > 1 {
+        if level.len() % 2 == 1 {
+            level.push(pad);
+        }
+        level = level.chunks(2).map(|c| hash_pair(&c[0], &c[1])).collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    match level.first() {
+        Some(h) => *h,
+        None => pad,
+    }
+}
+
+/// "pieces root" of a file from its leaf block hashes.
+pub fn file_root(leaves: &[MerkleHash]) -> MerkleHash {
+    root_from_layer(leaves, 0, leaves.len())
+}
+
+/// nodes covering `piece_length` bytes each, as stored in "piece layers".
+pub fn piece_layer(leaves: &[MerkleHash], piece_length: usize) -> Vec<MerkleHash> {
+    let blocks_per_piece = (piece_length / MERKLE_BLOCK_SIZE).max(1);
+    leaves
+        .chunks(blocks_per_piece)
+        .map(|c| root_from_layer(c, 0, blocks_per_piece))
+        .collect()
+}
+
+/// check a file's piece layer against its "pieces root".
+pub fn verify_piece_layer(
+    pieces_root: &MerkleHash,
+    layer: &[MerkleHash],
+    piece_length: usize,
+) -> bool {
+    let height = (piece_length / MERKLE_BLOCK_SIZE).max(1).trailing_zeros();
+    root_from_layer(layer, height, layer.len()) == *pieces_root
+}
+
+/// split a concatenated "piece layers" value into 32-byte hashes.
+pub fn split_hashes(bytes: &[u8]) -> Option<Vec<MerkleHash>> {
+    if !bytes.len().is_multiple_of(32) {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks(32)
+            .map(|c| {
+                let mut h = [0; 32];
+                h.copy_from_slice(c);
+                h
+            })
+            .collect(),
+    )
+}
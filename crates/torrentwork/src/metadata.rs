@@ -0,0 +1,382 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    net::TcpStream,
+    time::{Instant, interval, sleep_until},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    error::{Error, PeerError},
+    magnet::Magnet,
+    peer::{
+        ExtendedHandshake, Handshake, HandshakeResult, Message, MetadataMessage, Peer, codec,
+        extension::{
+            CLIENT_NAME, EXTENDED_HANDSHAKE_ID, METADATA_PIECE_SIZE, UT_METADATA, UT_METADATA_ID,
+        },
+    },
+};
+
+/// the largest info dictionary accepted from peers
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// how long a peer may take to answer a metadata request
+const METADATA_REQUEST_TIMEOUT_SEC: u64 = 30;
+/// how often an idle connection looks for a piece to ask for
+const METADATA_POLL_SEC: u64 = 1;
+
+/// the info dictionary of a magnet link, put together from the BEP 9
+/// pieces of every peer that has it and checked against the link's hashes
+#[derive(Debug)]
+pub(crate) struct MetadataFetch {
+    magnet: Magnet,
+    peer_id: [u8; 20],
+    /// the port announced in our extended handshake
+    listen_port: u16,
+    /// cancelled once the dictionary is verified or the task stops, peers
+    /// disconnect
+    stop: CancellationToken,
+    state: Mutex<FetchState>,
+}
+
+#[derive(Debug, Default)]
+struct FetchState {
+    /// the size from the first extended handshake, peers announcing
+    /// another size are dropped until a dictionary of this size failed
+    size: Option<usize>,
+    /// peers that announced `size`
+    advertised: HashSet<SocketAddr>,
+    /// received pieces and the peer that sent each
+    pieces: Vec<Option<(Bytes, SocketAddr)>>,
+    /// pieces asked for and not answered yet
+    requested: HashMap<u32, SocketAddr>,
+    connected: HashSet<SocketAddr>,
+    /// peers that alone sent a dictionary failing the hash check
+    banned: HashSet<IpAddr>,
+    /// the verified dictionary
+    info: Option<Vec<u8>>,
+}
+
+impl MetadataFetch {
+    pub fn new(
+        magnet: Magnet,
+        peer_id: [u8; 20],
+        listen_port: u16,
+        stop: CancellationToken,
+    ) -> Self {
+        Self {
+            magnet,
+            peer_id,
+            listen_port,
+            stop,
+            state: Mutex::new(FetchState::default()),
+        }
+    }
+    /// resolves once the dictionary is verified or the task stops
+    pub async fn finished(&self) {
+        self.stop.cancelled().await
+    }
+    /// disconnect every peer
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+    /// the verified dictionary, once
+    pub fn take_info(&self) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().info.take()
+    }
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connected.len()
+    }
+    /// already connected or banned
+    pub fn is_known(&self, addr: SocketAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        state.connected.contains(&addr) || state.banned.contains(&addr.ip())
+    }
+    /// dial a peer and fetch pieces from it until the dictionary is complete
+    pub async fn connect(&self, peer: Peer) -> Result<(), Error> {
+        let addr = peer.addr;
+        if !self.state.lock().unwrap().connected.insert(addr) {
+            return Err(PeerError::AlreadyConnected(addr).into());
+        }
+        let ours = Handshake::new(self.magnet.info_hash, self.peer_id).with_extension_protocol();
+        let result = match peer.handshake(&ours).await {
+            Ok((theirs, stream)) => self.exchange(addr, theirs, stream).await,
+            Err(e) => Err(e.into()),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.connected.remove(&addr);
+        // what the peer did not answer goes to the others
+        state.requested.retain(|_, a| *a != addr);
+        result
+    }
+    async fn exchange(
+        &self,
+        addr: SocketAddr,
+        theirs: HandshakeResult,
+        stream: TcpStream,
+    ) -> Result<(), Error> {
+        if !theirs.extension_protocol {
+            return Ok(());
+        }
+        let (mut sink, mut messages) = codec::framed(stream).split();
+        let ours = ExtendedHandshake::new()
+            .with_extension(UT_METADATA, UT_METADATA_ID)
+            .with_client(CLIENT_NAME)
+            .with_listen_port(self.listen_port)
+            .with_your_ip(addr.ip());
+        sink.send(Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: ours.to_bytes(),
+        })
+        .await?;
+        let mut poll = interval(Duration::from_secs(METADATA_POLL_SEC));
+        // the peer's ut_metadata id, once its handshake said it has the
+        // dictionary
+        let mut their_id = None;
+        // the piece asked for and when the peer has to have answered
+        let mut asked: Option<(u32, Instant)> = None;
+        loop {
+            if self.is_banned(addr) {
+                return Ok(());
+            }
+            if let Some(id) = their_id
+                && asked.is_none()
+                && let Some(piece) = self.next_piece(addr)
+            {
+                let request = MetadataMessage::Request { piece };
+                sink.send(Message::Extended {
+                    id,
+                    payload: request.to_bytes(),
+                })
+                .await?;
+                let deadline = Instant::now() + Duration::from_secs(METADATA_REQUEST_TIMEOUT_SEC);
+                asked = Some((piece, deadline));
+            }
+            let deadline = asked.map(|(_, d)| d);
+            tokio::select! {
+                m = messages.next() => match m {
+                    Some(Ok(Message::Extended { id: EXTENDED_HANDSHAKE_ID, payload })) => {
+                        let h = ExtendedHandshake::from_bytes(&payload)?;
+                        match (h.id_of(UT_METADATA), h.metadata_size) {
+                            (Some(id), Some(size)) if self.set_size(addr, size) => {
+                                their_id = Some(id)
+                            }
+                            // the peer cannot help with the metadata
+                            _ => return Ok(()),
+                        }
+                    }
+                    Some(Ok(Message::Extended { id: UT_METADATA_ID, payload })) => {
+                        match MetadataMessage::from_bytes(payload)? {
+                            MetadataMessage::Data { piece, data, .. }
+                                if asked.is_some_and(|(p, _)| p == piece) =>
+                            {
+                                asked = None;
+                                self.piece_received(addr, piece, data)?;
+                            }
+                            // the peer does not have it after all
+                            MetadataMessage::Reject { piece }
+                                if asked.is_some_and(|(p, _)| p == piece) =>
+                            {
+                                return Ok(());
+                            }
+                            // we have nothing to serve yet
+                            MetadataMessage::Request { piece } => {
+                                if let Some(id) = their_id {
+                                    let reject = MetadataMessage::Reject { piece };
+                                    sink.send(Message::Extended {
+                                        id,
+                                        payload: reject.to_bytes(),
+                                    })
+                                    .await?;
+                                }
+                            }
+                            // pieces we did not ask for, message types we do
+                            // not know
+                            _ => {}
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(()),
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    return Ok(());
+                }
+                _ = poll.tick() => {}
+                _ = self.stop.cancelled() => return Ok(()),
+            }
+        }
+    }
+    fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.state.lock().unwrap().banned.contains(&addr.ip())
+    }
+    /// agree on the size `addr` announced, the first valid one wins
+    fn set_size(&self, addr: SocketAddr, size: u64) -> bool {
+        let size = match usize::try_from(size) {
+            Ok(s) if s > 0 && s <= MAX_METADATA_SIZE => s,
+            _ => return false,
+        };
+        let mut state = self.state.lock().unwrap();
+        match state.size {
+            Some(s) if s != size => return false,
+            Some(_) => {}
+            None => {
+                state.size = Some(size);
+                state.pieces = vec![None; size.div_ceil(METADATA_PIECE_SIZE)];
+            }
+        }
+        state.advertised.insert(addr);
+        true
+    }
+    /// the first piece nobody sent or was asked for, now asked of `addr`
+    fn next_piece(&self, addr: SocketAddr) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        let piece = (0..state.pieces.len() as u32)
+            .find(|i| state.pieces[*i as usize].is_none() && !state.requested.contains_key(i))?;
+        state.requested.insert(piece, addr);
+        Some(piece)
+    }
+    /// store a piece, and verify the dictionary once every piece is in. a
+    /// dictionary that fails is fetched again, with the size the next peer
+    /// announces, and every peer that announced its size is banned.
+    fn piece_received(&self, addr: SocketAddr, piece: u32, data: Bytes) -> Result<(), PeerError> {
+        let mut state = self.state.lock().unwrap();
+        let size = match state.size {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let begin = piece as usize * METADATA_PIECE_SIZE;
+        if begin >= size || data.len() != METADATA_PIECE_SIZE.min(size - begin) {
+            return Err(PeerError::InvalidExtension(format!(
+                "ut_metadata piece {} has {} bytes",
+                piece,
+                data.len()
+            )));
+        }
+        state.requested.remove(&piece);
+        state.pieces[piece as usize] = Some((data, addr));
+        if state.pieces.iter().any(Option::is_none) {
+            return Ok(());
+        }
+        let mut info = Vec::with_capacity(size);
+        let mut senders = HashSet::new();
+        for (data, from) in state.pieces.iter().flatten() {
+            info.extend_from_slice(data);
+            senders.insert(from.ip());
+        }
+        if self.magnet.verify_info(&info) {
+            state.info = Some(info);
+            self.stop.cancel();
+            return Ok(());
+        }
+        let advertised: Vec<IpAddr> = state.advertised.drain().map(|a| a.ip()).collect();
+        state.banned.extend(advertised);
+        state.banned.extend(senders);
+        state.size = None;
+        state.pieces.clear();
+        state.requested.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::{io::AsyncWriteExt, net::TcpListener, time::timeout};
+
+    use super::*;
+    use crate::{hash::InfoHash, peer::handshake::read_handshake};
+
+    /// the ut_metadata id our stand-in peers announce
+    const THEIR_UT_METADATA_ID: u8 = 3;
+
+    /// a peer on `ip` that announces `size` and serves `info` over
+    /// ut_metadata
+    async fn peer(ip: Ipv4Addr, info: Vec<u8>, size: u64) -> SocketAddr {
+        let listener = TcpListener::bind((ip, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let info = info.clone();
+                tokio::spawn(async move {
+                    let theirs = read_handshake(&mut stream).await.unwrap();
+                    let ours = Handshake::new(theirs.info_hash, [7; 20]).with_extension_protocol();
+                    stream.write_all(&ours.to_bytes()).await.unwrap();
+                    let mut framed = codec::framed(stream);
+                    let h = ExtendedHandshake::new()
+                        .with_extension(UT_METADATA, THEIR_UT_METADATA_ID)
+                        .with_metadata_size(size);
+                    let payload = h.to_bytes();
+                    let _ = framed
+                        .send(Message::Extended {
+                            id: EXTENDED_HANDSHAKE_ID,
+                            payload,
+                        })
+                        .await;
+                    while let Some(Ok(m)) = framed.next().await {
+                        let payload = match m {
+                            Message::Extended {
+                                id: THEIR_UT_METADATA_ID,
+                                payload,
+                            } => payload,
+                            _ => continue,
+                        };
+                        let piece = match MetadataMessage::from_bytes(payload) {
+                            Ok(MetadataMessage::Request { piece }) => piece,
+                            _ => continue,
+                        };
+                        let begin = (piece as usize * METADATA_PIECE_SIZE).min(info.len());
+                        let end = (begin + METADATA_PIECE_SIZE).min(info.len());
+                        let data = MetadataMessage::Data {
+                            piece,
+                            total_size: size,
+                            data: Bytes::copy_from_slice(&info[begin..end]),
+                        };
+                        let payload = data.to_bytes();
+                        let _ = framed
+                            .send(Message::Extended {
+                                id: UT_METADATA_ID,
+                                payload,
+                            })
+                            .await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn a_lying_peer_does_not_lock_in_its_size() {
+        let info = b"d6:lengthi1e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let hash = InfoHash::from_info_bytes(info);
+        let magnet = Magnet::new(&format!("magnet:?xt=urn:btih:{}", hash.to_hex())).unwrap();
+        let fetch = MetadataFetch::new(magnet, [1; 20], 6881, CancellationToken::new());
+        let liar = peer(Ipv4Addr::new(127, 0, 0, 2), vec![b'x'; 100], 100).await;
+        let honest = peer(
+            Ipv4Addr::new(127, 0, 0, 3),
+            info.to_vec(),
+            info.len() as u64,
+        )
+        .await;
+        let wait = Duration::from_secs(10);
+        timeout(wait, fetch.connect(Peer::new(liar)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(fetch.is_known(liar));
+        assert!(!fetch.is_known(honest));
+        timeout(wait, fetch.connect(Peer::new(honest)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetch.take_info().as_deref(), Some(&info[..]));
+    }
+}
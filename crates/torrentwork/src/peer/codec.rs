@@ -0,0 +1,227 @@
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use super::message::{BLOCK_SIZE, Message, MessageError};
+
+/// largest frame accepted by default, enough for the bitfield of a torrent
+/// with eight million pieces
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 1024 * 1024;
+/// largest block a peer may request or send by default, 128 KiB
+pub const DEFAULT_MAX_BLOCK_LENGTH: u32 = 8 * BLOCK_SIZE;
+
+/// tokio codec for length-prefixed peer wire messages
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCodec {
+    max_frame_length: usize,
+    max_block_length: u32,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            max_block_length: DEFAULT_MAX_BLOCK_LENGTH,
+        }
+    }
+    /// reject frames whose length prefix is larger than `max`
+    pub fn max_frame_length(mut self, max: usize) -> Self {
+        self.max_frame_length = max;
+        self
+    }
+    /// reject requests and pieces for blocks larger than `max`
+    pub fn max_block_length(mut self, max: u32) -> Self {
+        self.max_block_length = max;
+        self
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = MessageError;
+
+    /// frames with a message id we do not know are skipped, as BEP 3 asks
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, MessageError> {
+        loop {
+            if src.len() < 4 {
+                return Ok(None);
+            }
+            let length = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+            if length > self.max_frame_length {
+                return Err(MessageError::FrameTooLarge {
+                    length,
+                    max: self.max_frame_length,
+                });
+            }
+            if src.len() < 4 + length {
+                src.reserve(4 + length - src.len());
+                return Ok(None);
+            }
+            src.advance(4);
+            let body = src.split_to(length).freeze();
+            match Message::decode(body, self.max_block_length) {
+                Err(MessageError::UnknownMessage(_)) => continue,
+                r => return r.map(Some),
+            }
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = MessageError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), MessageError> {
+        <Self as Encoder<&Message>>::encode(self, &msg, dst)
+    }
+}
+
+impl Encoder<&Message> for MessageCodec {
+    type Error = MessageError;
+
+    fn encode(&mut self, msg: &Message, dst: &mut BytesMut) -> Result<(), MessageError> {
+        if msg.body_len() > self.max_frame_length {
+            return Err(MessageError::FrameTooLarge {
+                length: msg.body_len(),
+                max: self.max_frame_length,
+            });
+        }
+        msg.encode(dst);
+        Ok(())
+    }
+}
+
+/// wrap any byte stream, a tcp socket or an in-memory duplex, in the message codec
+pub fn framed<T: AsyncRead + AsyncWrite>(io: T) -> Framed<T, MessageCodec> {
+    Framed::new(io, MessageCodec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncWriteExt, duplex};
+
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(7),
+            Message::Bitfield(Bytes::from_static(&[0b1010_0000, 0x01])),
+            Message::Request {
+                index: 1,
+                begin: BLOCK_SIZE,
+                length: BLOCK_SIZE,
+            },
+            Message::Piece {
+                index: 2,
+                begin: 0,
+                block: Bytes::from(vec![9; BLOCK_SIZE as usize]),
+            },
+            Message::Cancel {
+                index: 1,
+                begin: BLOCK_SIZE,
+                length: BLOCK_SIZE,
+            },
+            Message::Port(6881),
+            Message::Extended {
+                id: 0,
+                payload: Bytes::from_static(b"d1:md6:ut_pexi1eee"),
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn every_message_round_trips() {
+        let (a, b) = duplex(64 * 1024);
+        let (mut tx, mut rx) = (framed(a), framed(b));
+        let sent = messages();
+        let writer = tokio::spawn(async move {
+            for m in messages() {
+                tx.send(m).await.unwrap();
+            }
+        });
+        for m in sent {
+            assert_eq!(rx.next().await.unwrap().unwrap(), m);
+        }
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_message_ids_are_skipped() {
+        let (mut a, b) = duplex(1024);
+        // a BEP 6 have all, then an id nobody uses, then a have
+        a.write_all(&[0, 0, 0, 1, 14, 0, 0, 0, 3, 99, 1, 2])
+            .await
+            .unwrap();
+        a.write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 3]).await.unwrap();
+        let mut rx = framed(b);
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::Have(3));
+    }
+
+    #[tokio::test]
+    async fn oversize_frames_are_rejected() {
+        let (mut a, b) = duplex(1024);
+        let length = DEFAULT_MAX_FRAME_LENGTH as u32 + 1;
+        a.write_all(&length.to_be_bytes()).await.unwrap();
+        let mut rx = framed(b);
+        assert!(matches!(
+            rx.next().await,
+            Some(Err(MessageError::FrameTooLarge { length: l, .. })) if l == length as usize
+        ));
+        let mut tx = Framed::new(a, MessageCodec::new().max_frame_length(16));
+        let too_long = Message::Bitfield(Bytes::from(vec![0xff; 16]));
+        assert!(matches!(
+            tx.send(too_long).await,
+            Err(MessageError::FrameTooLarge {
+                length: 17,
+                max: 16
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversize_blocks_are_rejected() {
+        let oversize = [
+            Message::Request {
+                index: 0,
+                begin: 0,
+                length: BLOCK_SIZE + 1,
+            },
+            Message::Piece {
+                index: 0,
+                begin: 0,
+                block: Bytes::from(vec![0; BLOCK_SIZE as usize + 1]),
+            },
+        ];
+        // the stream ends after an error, every message gets a connection
+        for m in oversize {
+            let (a, b) = duplex(64 * 1024);
+            let mut rx = Framed::new(b, MessageCodec::new().max_block_length(BLOCK_SIZE));
+            framed(a).send(m).await.unwrap();
+            assert!(matches!(
+                rx.next().await,
+                Some(Err(MessageError::BlockTooLarge(l))) if l == BLOCK_SIZE + 1
+            ));
+        }
+    }
+
+    #[test]
+    fn fixed_size_messages_check_their_length() {
+        let body = Bytes::from_static(&[4, 0, 0, 1]);
+        assert!(matches!(
+            Message::decode(body, DEFAULT_MAX_BLOCK_LENGTH),
+            Err(MessageError::InvalidLength { id: 4, length: 4 })
+        ));
+    }
+}
@@ -0,0 +1,764 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use bencode::{Decoder, Dict, Value};
+use bytes::Bytes;
+
+use crate::{
+    error::{Error, PeerError},
+    file::TorrentFile,
+    hash::InfoHash,
+    peer::{COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN, Message, Peer},
+};
+
+/// extended message id of the BEP 10 handshake
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// BEP 9 extension name
+pub const UT_METADATA: &str = "ut_metadata";
+/// the id peers send us ut_metadata messages with
+pub const UT_METADATA_ID: u8 = 1;
+/// BEP 54 extension name
+pub const LT_DONTHAVE: &str = "lt_donthave";
+/// the id peers send us lt_donthave messages with
+pub const LT_DONTHAVE_ID: u8 = 2;
+/// BEP 11 extension name
+pub const UT_PEX: &str = "ut_pex";
+/// the id peers send us ut_pex messages with
+pub const UT_PEX_ID: u8 = 3;
+/// BEP 11 flag, the peer is a seed
+pub const PEX_SEED: u8 = 0x02;
+/// BEP 11 flag, the peer accepts incoming connections
+pub const PEX_CONNECTABLE: u8 = 0x10;
+/// most added and most dropped peers in one ut_pex message
+pub const PEX_MAX_PEERS: usize = 50;
+/// the `v` of our extended handshake
+pub const CLIENT_NAME: &str = concat!("torrentwork ", env!("CARGO_PKG_VERSION"));
+/// the `reqq` of our extended handshake, requests a peer may keep in flight
+pub const DEFAULT_MAX_PEER_REQUESTS: usize = 250;
+/// BEP 9 metadata piece size, only the last piece may be shorter
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// the BEP 10 extended handshake, sent once after the regular handshake
+/// when both sides set the extension protocol bit
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// `m`, the extension messages the sender understands and the ids it
+    /// wants them sent with
+    pub extensions: BTreeMap<String, u8>,
+    /// BEP 9 size of the info dictionary, when the sender has it
+    pub metadata_size: Option<u64>,
+    /// `v`, the sender's client name and version
+    pub client: Option<String>,
+    /// `p`, the port the sender listens on
+    pub listen_port: Option<u16>,
+    /// `yourip`, the address the sender sees the receiver at
+    pub your_ip: Option<IpAddr>,
+    /// `reqq`, requests the sender accepts in flight
+    pub request_queue: Option<usize>,
+    /// every other key, with its raw bencoded value
+    pub extra: BTreeMap<String, Vec<u8>>,
+}
+
+impl ExtendedHandshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_extension(mut self, name: impl Into<String>, id: u8) -> Self {
+        self.extensions.insert(name.into(), id);
+        self
+    }
+    pub fn with_metadata_size(mut self, size: u64) -> Self {
+        self.metadata_size = Some(size);
+        self
+    }
+    pub fn with_client(mut self, client: impl Into<String>) -> Self {
+        self.client = Some(client.into());
+        self
+    }
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+    pub fn with_your_ip(mut self, ip: IpAddr) -> Self {
+        self.your_ip = Some(ip);
+        self
+    }
+    pub fn with_request_queue(mut self, requests: usize) -> Self {
+        self.request_queue = Some(requests);
+        self
+    }
+    /// add a key of a third-party extension, `value` must be bencoded. keys
+    /// this type models are ignored.
+    pub fn with_extra(mut self, key: impl Into<String>, value: Vec<u8>) -> Self {
+        let key = key.into();
+        if !HANDSHAKE_KEYS.contains(&key.as_str()) {
+            self.extra.insert(key, value);
+        }
+        self
+    }
+    /// the id to send extension `name` with, `None` if the sender does not
+    /// support it or disabled it with id 0
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        self.extensions.get(name).copied().filter(|id| *id != 0)
+    }
+    pub fn to_bytes(&self) -> Bytes {
+        let mut m = Dict::new();
+        for (name, id) in &self.extensions {
+            m.insert(name, *id as i64);
+        }
+        let mut d = Dict::new();
+        // extra values that are not valid bencoding are left out
+        for (key, value) in &self.extra {
+            if let Ok(v) = bencode::decode(value) {
+                d.insert(key, v);
+            }
+        }
+        d.insert("m", m);
+        if let Some(size) = self.metadata_size {
+            d.insert("metadata_size", size as i64);
+        }
+        if let Some(v) = &self.client {
+            d.insert("v", v.as_str());
+        }
+        if let Some(p) = self.listen_port {
+            d.insert("p", p as i64);
+        }
+        if let Some(ip) = self.your_ip {
+            let ip = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            d.insert("yourip", Value::bytes(ip));
+        }
+        if let Some(reqq) = self.request_queue {
+            d.insert("reqq", reqq as i64);
+        }
+        Value::from(d).to_bytes().into()
+    }
+    /// parse a handshake payload, keys we do not know are ignored
+    pub fn from_bytes(b: &[u8]) -> Result<Self, PeerError> {
+        let root = bencode::decode(b).map_err(|e| invalid(e.to_string()))?;
+        let d = match root.as_dict() {
+            Some(d) => d,
+            None => return Err(invalid("handshake is not a dictionary")),
+        };
+        let mut extensions = BTreeMap::new();
+        if let Some(m) = d.get_dict("m") {
+            for (name, id) in m.iter() {
+                // ids outside a byte are unusable, the extension is skipped
+                if let (Ok(name), Some(id)) = (
+                    std::str::from_utf8(name),
+                    id.as_int().and_then(|i| u8::try_from(i).ok()),
+                ) {
+                    extensions.insert(name.to_string(), id);
+                }
+            }
+        }
+        let your_ip = match d.get_bytes("yourip") {
+            Some(b) => match b.len() {
+                4 => <[u8; 4]>::try_from(b).ok().map(IpAddr::from),
+                16 => <[u8; 16]>::try_from(b).ok().map(IpAddr::from),
+                _ => None,
+            },
+            None => None,
+        };
+        let mut extra = BTreeMap::new();
+        for (key, value) in d.iter() {
+            if let Ok(key) = std::str::from_utf8(key)
+                && !HANDSHAKE_KEYS.contains(&key)
+            {
+                extra.insert(key.to_string(), value.to_bytes());
+            }
+        }
+        // malformed values of the optional keys are treated as absent
+        Ok(Self {
+            extensions,
+            metadata_size: d
+                .get_int("metadata_size")
+                .and_then(|s| u64::try_from(s).ok()),
+            client: d.get_str("v").map(str::to_string),
+            listen_port: d.get_int("p").and_then(|p| u16::try_from(p).ok()),
+            your_ip,
+            request_queue: d.get_int("reqq").and_then(|r| usize::try_from(r).ok()),
+            extra,
+        })
+    }
+}
+
+/// the keys `ExtendedHandshake` models, anything else goes to `extra`
+const HANDSHAKE_KEYS: &[&str] = &["m", "metadata_size", "v", "p", "yourip", "reqq"];
+
+/// a third-party BEP 10 extension. it is offered to the peers of every
+/// torrent it is enabled for and called with the messages they send it.
+/// one instance serves every connection of a session.
+pub trait Extension: Send + Sync + fmt::Debug {
+    /// the key in the `m` dictionary, unique in a registry
+    fn name(&self) -> &str;
+    /// whether to offer the extension to the peers of `tf`
+    fn enabled_for(&self, _tf: &TorrentFile) -> bool {
+        true
+    }
+    /// add keys to our extended handshake with `with_extra`
+    fn add_handshake(&self, h: ExtendedHandshake) -> ExtendedHandshake {
+        h
+    }
+    /// the peer's extended handshake arrived and names this extension
+    fn on_handshake(&self, _ctx: &mut ExtensionContext<'_>) -> Result<(), PeerError> {
+        Ok(())
+    }
+    /// a message the peer sent this extension, an error disconnects it
+    fn on_message(&self, ctx: &mut ExtensionContext<'_>, payload: Bytes) -> Result<(), PeerError>;
+}
+
+/// the connection an extension is called for
+#[derive(Debug)]
+pub struct ExtensionContext<'a> {
+    pub info_hash: InfoHash,
+    pub addr: SocketAddr,
+    /// the peer's extended handshake
+    pub handshake: &'a ExtendedHandshake,
+    name: &'a str,
+    out: &'a mut Vec<Message>,
+}
+
+impl<'a> ExtensionContext<'a> {
+    pub(crate) fn new(
+        info_hash: InfoHash,
+        addr: SocketAddr,
+        handshake: &'a ExtendedHandshake,
+        name: &'a str,
+        out: &'a mut Vec<Message>,
+    ) -> Self {
+        Self {
+            info_hash,
+            addr,
+            handshake,
+            name,
+            out,
+        }
+    }
+    /// queue a message of this extension for the peer, false when the peer
+    /// did not name it in its handshake
+    pub fn send(&mut self, payload: impl Into<Bytes>) -> bool {
+        match self.handshake.id_of(self.name) {
+            Some(id) => {
+                self.out.push(Message::Extended {
+                    id,
+                    payload: payload.into(),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// an extension and the id peers send its messages with
+#[derive(Debug, Clone)]
+pub struct RegisteredExtension {
+    pub id: u8,
+    pub name: String,
+    /// `None` for the extensions built into the download task
+    pub handler: Option<Arc<dyn Extension>>,
+}
+
+/// the extensions offered to peers by the message id each is registered
+/// with. ut_metadata, lt_donthave and ut_pex are built in, third-party
+/// extensions get the next free ids.
+#[derive(Debug, Clone)]
+pub struct ExtensionRegistry {
+    extensions: Vec<RegisteredExtension>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        let builtin = |id, name: &str| RegisteredExtension {
+            id,
+            name: name.to_string(),
+            handler: None,
+        };
+        Self {
+            extensions: vec![
+                builtin(UT_METADATA_ID, UT_METADATA),
+                builtin(LT_DONTHAVE_ID, LT_DONTHAVE),
+                builtin(UT_PEX_ID, UT_PEX),
+            ],
+        }
+    }
+    /// add a third-party extension, returning its message id
+    pub fn register(&mut self, ext: Arc<dyn Extension>) -> Result<u8, Error> {
+        let name = ext.name().to_string();
+        if self.by_name(&name).is_some() {
+            return Err(Error::InvalidState(format!(
+                "extension {} is already registered",
+                name
+            )));
+        }
+        let id = match (1..=u8::MAX).find(|id| self.get(*id).is_none()) {
+            Some(id) => id,
+            None => return Err(Error::InvalidState("no extension ids left".to_string())),
+        };
+        self.extensions.push(RegisteredExtension {
+            id,
+            name,
+            handler: Some(ext),
+        });
+        Ok(id)
+    }
+    /// the extension peers send messages with `id` to
+    pub fn get(&self, id: u8) -> Option<&RegisteredExtension> {
+        self.extensions.iter().find(|e| e.id == id)
+    }
+    pub fn by_name(&self, name: &str) -> Option<&RegisteredExtension> {
+        self.extensions.iter().find(|e| e.name == name)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &RegisteredExtension> {
+        self.extensions.iter()
+    }
+    /// our extended handshake for the peers of `tf`, naming every extension
+    /// enabled for the torrent. ut_metadata is only offered for public
+    /// torrents whose metadata we have and ut_pex for public torrents, as
+    /// private trackers hand out every peer themselves. `yourip` is left to
+    /// the caller.
+    pub fn handshake(&self, tf: &TorrentFile, port: u16) -> ExtendedHandshake {
+        let private = tf.is_private();
+        let mut h = ExtendedHandshake::new()
+            .with_client(CLIENT_NAME)
+            .with_listen_port(port)
+            .with_request_queue(DEFAULT_MAX_PEER_REQUESTS);
+        for e in &self.extensions {
+            let enabled = match (&e.handler, e.name.as_str()) {
+                (Some(ext), _) => ext.enabled_for(tf),
+                (None, UT_METADATA) => !private && tf.has_metadata(),
+                (None, UT_PEX) => !private,
+                (None, _) => true,
+            };
+            if !enabled {
+                continue;
+            }
+            h = h.with_extension(e.name.clone(), e.id);
+            if let Some(ext) = &e.handler {
+                h = ext.add_handshake(h);
+            }
+        }
+        if h.id_of(UT_METADATA).is_some()
+            && let Some(info) = tf.info_bytes()
+        {
+            h = h.with_metadata_size(info.len() as u64);
+        }
+        h
+    }
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// a BEP 11 ut_pex message, the change in the sender's peers since its
+/// last message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    /// peers connected since, where they accept connections and their flags
+    pub added: Vec<(SocketAddr, u8)>,
+    /// peers disconnected since
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+    pub fn to_bytes(&self) -> Bytes {
+        let mut d = Dict::new();
+        for (v6, suffix) in [(false, ""), (true, "6")] {
+            let mut added = vec![];
+            let mut flags = vec![];
+            for (addr, f) in self.added.iter().filter(|(a, _)| a.is_ipv6() == v6) {
+                added.extend(Peer::new(*addr).to_compact());
+                flags.push(*f);
+            }
+            let mut dropped = vec![];
+            for addr in self.dropped.iter().filter(|a| a.is_ipv6() == v6) {
+                dropped.extend(Peer::new(*addr).to_compact());
+            }
+            d.insert(&format!("added{}", suffix), Value::bytes(added));
+            d.insert(&format!("added{}.f", suffix), Value::bytes(flags));
+            d.insert(&format!("dropped{}", suffix), Value::bytes(dropped));
+        }
+        Value::from(d).to_bytes().into()
+    }
+    /// parse a message, missing lists are empty and missing flags zero
+    pub fn from_bytes(b: &[u8]) -> Result<Self, PeerError> {
+        let root = bencode::decode(b).map_err(|e| invalid(e.to_string()))?;
+        let d = match root.as_dict() {
+            Some(d) => d,
+            None => return Err(invalid("ut_pex message is not a dictionary")),
+        };
+        let mut m = PexMessage::default();
+        for (suffix, len) in [("", COMPACT_PEER_V4_LEN), ("6", COMPACT_PEER_V6_LEN)] {
+            let list = |key: String| {
+                Peer::from_compact_list(d.get_bytes(&key).unwrap_or_default(), len)
+                    .into_iter()
+                    .map(|p| p.addr)
+            };
+            let flags = d
+                .get_bytes(&format!("added{}.f", suffix))
+                .unwrap_or_default();
+            m.added.extend(
+                list(format!("added{}", suffix))
+                    .enumerate()
+                    .map(|(i, a)| (a, flags.get(i).copied().unwrap_or(0))),
+            );
+            m.dropped.extend(list(format!("dropped{}", suffix)));
+        }
+        Ok(m)
+    }
+}
+
+/// a BEP 9 ut_metadata message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    /// ask for a 16 KiB piece of the info dictionary
+    Request { piece: u32 },
+    /// a piece of the info dictionary, `total_size` is the whole dictionary
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Bytes,
+    },
+    /// the sender will not send this piece
+    Reject { piece: u32 },
+    /// a message type added after BEP 9, to be ignored
+    Unknown { msg_type: i64, piece: u32 },
+}
+
+impl MetadataMessage {
+    pub fn piece(&self) -> u32 {
+        match self {
+            MetadataMessage::Request { piece }
+            | MetadataMessage::Data { piece, .. }
+            | MetadataMessage::Reject { piece }
+            | MetadataMessage::Unknown { piece, .. } => *piece,
+        }
+    }
+    /// the message payload, a data message carries the piece after the
+    /// dictionary
+    pub fn to_bytes(&self) -> Bytes {
+        let mut d = Dict::new();
+        let msg_type: i64 = match self {
+            MetadataMessage::Request { .. } => 0,
+            MetadataMessage::Data { .. } => 1,
+            MetadataMessage::Reject { .. } => 2,
+            MetadataMessage::Unknown { msg_type, .. } => *msg_type,
+        };
+        d.insert("msg_type", msg_type);
+        d.insert("piece", self.piece() as i64);
+        if let MetadataMessage::Data { total_size, .. } = self {
+            d.insert("total_size", *total_size as i64);
+        }
+        let mut b = Value::from(d).to_bytes();
+        if let MetadataMessage::Data { data, .. } = self {
+            b.extend_from_slice(data);
+        }
+        b.into()
+    }
+    pub fn from_bytes(b: Bytes) -> Result<Self, PeerError> {
+        let mut decoder = Decoder::new(&b);
+        let root = decoder
+            .decode_prefix()
+            .map_err(|e| invalid(e.to_string()))?;
+        let d = match root.as_dict() {
+            Some(d) => d,
+            None => return Err(invalid("ut_metadata message is not a dictionary")),
+        };
+        let field = |key: &str| {
+            d.get_int(key)
+                .ok_or_else(|| invalid(format!("ut_metadata message without {}", key)))
+        };
+        let piece = u32::try_from(field("piece")?).map_err(|_| invalid("negative piece"))?;
+        Ok(match field("msg_type")? {
+            0 => MetadataMessage::Request { piece },
+            1 => MetadataMessage::Data {
+                piece,
+                total_size: u64::try_from(field("total_size")?)
+                    .map_err(|_| invalid("negative total_size"))?,
+                data: b.slice(decoder.position()..),
+            },
+            2 => MetadataMessage::Reject { piece },
+            msg_type => MetadataMessage::Unknown { msg_type, piece },
+        })
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> PeerError {
+    PeerError::InvalidExtension(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// a single-file torrent with its metadata, private or public
+    fn torrent(private: bool) -> TorrentFile {
+        let private = if private { "7:privatei1e" } else { "" };
+        let buf = format!(
+            "d4:infod6:lengthi1e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa{}ee",
+            private
+        );
+        TorrentFile::from_bytes(buf.into_bytes()).unwrap()
+    }
+
+    /// answers every message with its payload reversed and remembers what
+    /// it was sent
+    #[derive(Debug, Default)]
+    struct Echo {
+        received: Mutex<Vec<Bytes>>,
+    }
+
+    impl Extension for Echo {
+        fn name(&self) -> &str {
+            "x_echo"
+        }
+        fn enabled_for(&self, tf: &TorrentFile) -> bool {
+            !tf.is_private()
+        }
+        fn add_handshake(&self, h: ExtendedHandshake) -> ExtendedHandshake {
+            h.with_extra("x_echo_version", b"i2e".to_vec())
+        }
+        fn on_message(
+            &self,
+            ctx: &mut ExtensionContext<'_>,
+            payload: Bytes,
+        ) -> Result<(), PeerError> {
+            let reversed: Vec<u8> = payload.iter().rev().copied().collect();
+            self.received.lock().unwrap().push(payload);
+            ctx.send(reversed);
+            Ok(())
+        }
+    }
+
+    /// an extension that only has a name
+    #[derive(Debug)]
+    struct Named(String);
+
+    impl Extension for Named {
+        fn name(&self) -> &str {
+            &self.0
+        }
+        fn on_message(&self, _: &mut ExtensionContext<'_>, _: Bytes) -> Result<(), PeerError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn extended_handshake_round_trips() {
+        let h = ExtendedHandshake::new()
+            .with_extension(UT_METADATA, 3)
+            .with_extension("lt_donthave", 0)
+            .with_metadata_size(31235)
+            .with_client("client 1.0")
+            .with_listen_port(6881)
+            .with_your_ip(IpAddr::from([192, 0, 2, 1]))
+            .with_request_queue(500)
+            .with_extra("upload_only", b"i1e".to_vec())
+            .with_extra("m", b"i1e".to_vec());
+        let parsed = ExtendedHandshake::from_bytes(&h.to_bytes()).unwrap();
+        assert_eq!(parsed, h);
+        assert_eq!(parsed.id_of(UT_METADATA), Some(3));
+        // id 0 switches an extension off
+        assert_eq!(parsed.id_of("lt_donthave"), None);
+        assert_eq!(parsed.id_of(UT_PEX), None);
+        assert_eq!(parsed.extra.keys().collect::<Vec<_>>(), ["upload_only"]);
+        let v6 = ExtendedHandshake::new().with_your_ip("2001:db8::1".parse().unwrap());
+        assert_eq!(ExtendedHandshake::from_bytes(&v6.to_bytes()).unwrap(), v6);
+    }
+
+    #[test]
+    fn malformed_handshake_keys_are_absent() {
+        let h = ExtendedHandshake::from_bytes(
+            b"d1:md4:goodi4e2:ok3:abc6:ut_pexi300ee1:pi-1e4:reqqi-5e6:yourip3:abce",
+        )
+        .unwrap();
+        assert_eq!(
+            h.extensions.into_iter().collect::<Vec<_>>(),
+            [("good".to_string(), 4)]
+        );
+        assert_eq!(
+            (h.listen_port, h.request_queue, h.your_ip),
+            (None, None, None)
+        );
+        assert!(ExtendedHandshake::from_bytes(b"li1ee").is_err());
+    }
+
+    #[test]
+    fn registry_hands_out_free_ids() {
+        let mut r = ExtensionRegistry::new();
+        let ids: Vec<(u8, &str)> = r.iter().map(|e| (e.id, e.name.as_str())).collect();
+        assert_eq!(
+            ids,
+            [
+                (UT_METADATA_ID, UT_METADATA),
+                (LT_DONTHAVE_ID, LT_DONTHAVE),
+                (UT_PEX_ID, UT_PEX)
+            ]
+        );
+        let echo = Arc::new(Echo::default());
+        assert_eq!(r.register(echo.clone()).unwrap(), 4);
+        assert!(r.register(echo).is_err());
+        assert_eq!(r.by_name("x_echo").map(|e| e.id), Some(4));
+        for n in 5..=u8::MAX {
+            assert_eq!(r.register(Arc::new(Named(format!("x_{}", n)))).unwrap(), n);
+        }
+        assert!(r.register(Arc::new(Named("x_last".to_string()))).is_err());
+    }
+
+    #[test]
+    fn messages_reach_the_registered_extension() {
+        let mut r = ExtensionRegistry::new();
+        let echo = Arc::new(Echo::default());
+        let id = r.register(echo.clone()).unwrap();
+        let theirs = ExtendedHandshake::new().with_extension("x_echo", 9);
+        let mut out = vec![];
+        let e = r.get(id).unwrap();
+        let handler = e.handler.as_ref().unwrap();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut ctx =
+            ExtensionContext::new(InfoHash::new([1; 20]), addr, &theirs, &e.name, &mut out);
+        handler
+            .on_message(&mut ctx, Bytes::from_static(b"abc"))
+            .unwrap();
+        assert_eq!(*echo.received.lock().unwrap(), [Bytes::from_static(b"abc")]);
+        // the answer goes out with the id the peer asked for
+        assert_eq!(
+            out,
+            [Message::Extended {
+                id: 9,
+                payload: Bytes::from_static(b"cba")
+            }]
+        );
+        // nothing is sent to a peer that does not know the extension
+        let none = ExtendedHandshake::new();
+        let mut out = vec![];
+        let mut ctx = ExtensionContext::new(InfoHash::new([1; 20]), addr, &none, &e.name, &mut out);
+        assert!(!ctx.send(Bytes::from_static(b"x")));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn handshake_names_the_enabled_extensions() {
+        let mut r = ExtensionRegistry::new();
+        r.register(Arc::new(Echo::default())).unwrap();
+        let public = torrent(false);
+        let h = r.handshake(&public, 6881);
+        let names: Vec<&str> = h.extensions.keys().map(String::as_str).collect();
+        assert_eq!(names, [LT_DONTHAVE, UT_METADATA, UT_PEX, "x_echo"]);
+        assert_eq!(
+            h.metadata_size,
+            Some(public.info_bytes().unwrap().len() as u64)
+        );
+        assert_eq!(
+            h.extra.get("x_echo_version").map(|v| &v[..]),
+            Some(&b"i2e"[..])
+        );
+        assert_eq!(h.listen_port, Some(6881));
+        assert_eq!(h.client.as_deref(), Some(CLIENT_NAME));
+        assert_eq!(h.request_queue, Some(DEFAULT_MAX_PEER_REQUESTS));
+        let h = r.handshake(&torrent(true), 6881);
+        let names: Vec<&str> = h.extensions.keys().map(String::as_str).collect();
+        assert_eq!(names, [LT_DONTHAVE]);
+        assert_eq!((h.metadata_size, h.extra.len()), (None, 0));
+    }
+
+    #[test]
+    fn metadata_messages_round_trip() {
+        let messages = [
+            MetadataMessage::Request { piece: 1 },
+            MetadataMessage::Data {
+                piece: 2,
+                total_size: 3,
+                data: Bytes::from_static(b"abc"),
+            },
+            MetadataMessage::Reject { piece: 4 },
+        ];
+        for m in messages {
+            assert_eq!(MetadataMessage::from_bytes(m.to_bytes()).unwrap(), m);
+        }
+    }
+
+    #[test]
+    fn unknown_metadata_message_types_are_not_errors() {
+        let m = MetadataMessage::from_bytes(Bytes::from_static(b"d8:msg_typei7e5:piecei0ee"));
+        assert_eq!(
+            m.unwrap(),
+            MetadataMessage::Unknown {
+                msg_type: 7,
+                piece: 0
+            }
+        );
+    }
+
+    #[test]
+    fn pex_messages_round_trip() {
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "[2001:db8::1]:6882".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:6883".parse().unwrap();
+        let d: SocketAddr = "[2001:db8::4]:6884".parse().unwrap();
+        let m = PexMessage {
+            added: vec![
+                (a, PEX_SEED | PEX_CONNECTABLE),
+                (c, 0),
+                (b, PEX_CONNECTABLE),
+            ],
+            dropped: vec![c, d],
+        };
+        let parsed = PexMessage::from_bytes(&m.to_bytes()).unwrap();
+        // ipv4 peers come before ipv6 ones
+        assert_eq!(
+            parsed.added,
+            [
+                (a, PEX_SEED | PEX_CONNECTABLE),
+                (c, 0),
+                (b, PEX_CONNECTABLE)
+            ]
+        );
+        assert_eq!(parsed.dropped, [c, d]);
+        assert!(PexMessage::default().is_empty());
+        assert!(
+            PexMessage::from_bytes(&PexMessage::default().to_bytes())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn pex_flags_may_be_missing() {
+        let mut d = Dict::new();
+        d.insert(
+            "added",
+            Value::bytes(b"\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2"),
+        );
+        d.insert("added.f", Value::bytes([PEX_SEED]));
+        let m = PexMessage::from_bytes(&Value::from(d).to_bytes()).unwrap();
+        let flags: Vec<u8> = m.added.iter().map(|(_, f)| *f).collect();
+        assert_eq!(flags, [PEX_SEED, 0]);
+        assert!(PexMessage::from_bytes(b"le").is_err());
+    }
+
+    #[test]
+    fn pex_is_off_for_private_torrents() {
+        let r = ExtensionRegistry::new();
+        assert_eq!(
+            r.handshake(&torrent(false), 6881).id_of(UT_PEX),
+            Some(UT_PEX_ID)
+        );
+        assert_eq!(r.handshake(&torrent(true), 6881).id_of(UT_PEX), None);
+    }
+}
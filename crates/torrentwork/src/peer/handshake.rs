@@ -0,0 +1,305 @@
+use std::{fmt, io, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+
+use crate::hash::InfoHash;
+
+/// protocol identifier, always "BitTorrent protocol", 19 bytes.
+pub const BITTORRENT_PROTOCOL_IDENTIFIER: &[u8; 19] = b"BitTorrent protocol";
+/// pstrlen + pstr + reserved + info hash + peer id
+pub const HANDSHAKE_LEN: usize = 68;
+/// how long the remote side gets to answer our handshake
+pub const HANDSHAKE_TIMEOUT_SEC: u64 = 10;
+
+/// reserved[5] bit announcing the BEP 10 extension protocol
+const RESERVED_EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+/// reserved[7] bit announcing the BEP 6 fast extension
+const RESERVED_FAST: (usize, u8) = (7, 0x04);
+/// reserved[7] bit announcing BEP 5 DHT support
+const RESERVED_DHT: (usize, u8) = (7, 0x01);
+/// reserved[7] bit announcing BEP 52 v2 upgrade support
+const RESERVED_V2: (usize, u8) = (7, 0x10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    /// reserved fields, 8 bytes.
+    pub reserved: [u8; 8],
+    /// torrent file metadata "Info" field Sha1 hash value, 20 bytes.
+    pub info_hash: InfoHash,
+    /// custom peer id, 20 bytes.
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: InfoHash, peer_id: [u8; 20]) -> Self {
+        Self {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+    /// announce BEP 10 extension protocol support
+    pub fn with_extension_protocol(mut self) -> Self {
+        set_bit(&mut self.reserved, RESERVED_EXTENSION_PROTOCOL);
+        self
+    }
+    /// announce BEP 5 DHT support
+    pub fn with_dht(mut self) -> Self {
+        set_bit(&mut self.reserved, RESERVED_DHT);
+        self
+    }
+    /// announce BEP 52 v2 support
+    pub fn with_v2(mut self) -> Self {
+        set_bit(&mut self.reserved, RESERVED_V2);
+        self
+    }
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut b = [0; HANDSHAKE_LEN];
+        b[0] = BITTORRENT_PROTOCOL_IDENTIFIER.len() as u8;
+        b[1..20].copy_from_slice(BITTORRENT_PROTOCOL_IDENTIFIER);
+        b[20..28].copy_from_slice(&self.reserved);
+        b[28..48].copy_from_slice(self.info_hash.as_bytes());
+        b[48..68].copy_from_slice(&self.peer_id);
+        b
+    }
+    pub fn from_bytes(b: &[u8; HANDSHAKE_LEN]) -> Result<Self, HandshakeError> {
+        if b[0] as usize != BITTORRENT_PROTOCOL_IDENTIFIER.len()
+            || &b[1..20] != BITTORRENT_PROTOCOL_IDENTIFIER
+        {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+        let mut reserved = [0; 8];
+        let mut peer_id = [0; 20];
+        reserved.copy_from_slice(&b[20..28]);
+        peer_id.copy_from_slice(&b[48..68]);
+        Ok(Self {
+            reserved,
+            // slice is exactly 20 bytes
+            info_hash: InfoHash::from_slice(&b[28..48]).unwrap_or_default(),
+            peer_id,
+        })
+    }
+    pub fn supports_extension_protocol(&self) -> bool {
+        has_bit(&self.reserved, RESERVED_EXTENSION_PROTOCOL)
+    }
+    pub fn supports_fast(&self) -> bool {
+        has_bit(&self.reserved, RESERVED_FAST)
+    }
+    pub fn supports_dht(&self) -> bool {
+        has_bit(&self.reserved, RESERVED_DHT)
+    }
+    pub fn supports_v2(&self) -> bool {
+        has_bit(&self.reserved, RESERVED_V2)
+    }
+}
+
+fn set_bit(reserved: &mut [u8; 8], (byte, mask): (usize, u8)) {
+    reserved[byte] |= mask;
+}
+
+fn has_bit(reserved: &[u8; 8], (byte, mask): (usize, u8)) -> bool {
+    reserved[byte] & mask != 0
+}
+
+/// what the remote side told us in its handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeResult {
+    /// the remote peer id
+    pub peer_id: [u8; 20],
+    /// the remote reserved bits
+    pub reserved: [u8; 8],
+    /// the info hash the remote side answered with
+    pub info_hash: InfoHash,
+    pub extension_protocol: bool,
+    pub fast: bool,
+    pub dht: bool,
+}
+
+impl From<Handshake> for HandshakeResult {
+    fn from(h: Handshake) -> Self {
+        Self {
+            peer_id: h.peer_id,
+            reserved: h.reserved,
+            info_hash: h.info_hash,
+            extension_protocol: h.supports_extension_protocol(),
+            fast: h.supports_fast(),
+            dht: h.supports_dht(),
+        }
+    }
+}
+
+/// read one 68-byte handshake from the stream.
+pub async fn read_handshake<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Handshake, HandshakeError> {
+    let mut buf = [0; HANDSHAKE_LEN];
+    match timeout(
+        Duration::from_secs(HANDSHAKE_TIMEOUT_SEC),
+        stream.read_exact(&mut buf),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Handshake::from_bytes(&buf),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(HandshakeError::ConnectionClosed)
+        }
+        Ok(Err(e)) => Err(HandshakeError::Io(e)),
+        Err(_) => Err(HandshakeError::Timeout),
+    }
+}
+
+/// send our handshake and check the answer against the info hash we asked
+/// for and our own peer id.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    ours: &Handshake,
+) -> Result<HandshakeResult, HandshakeError> {
+    stream.write_all(&ours.to_bytes()).await?;
+    let theirs = read_handshake(stream).await?;
+    if theirs.info_hash != ours.info_hash {
+        return Err(HandshakeError::InfoHashMismatch {
+            expected: ours.info_hash,
+            got: theirs.info_hash,
+        });
+    }
+    if theirs.peer_id == ours.peer_id {
+        return Err(HandshakeError::OwnPeerId);
+    }
+    Ok(theirs.into())
+}
+
+/// why a handshake failed
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    /// the remote side did not answer in time
+    Timeout,
+    /// the remote side closed the connection before sending 68 bytes
+    ConnectionClosed,
+    /// not a "BitTorrent protocol" handshake
+    InvalidProtocol,
+    /// the remote side answered for a different torrent
+    InfoHashMismatch {
+        expected: InfoHash,
+        got: InfoHash,
+    },
+    /// the remote peer id differs from the one the tracker announced
+    PeerIdMismatch,
+    /// the remote side sent our own peer id, we connected to ourselves
+    OwnPeerId,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "handshake io error: {}", e),
+            HandshakeError::Timeout => f.write_str("handshake timed out"),
+            HandshakeError::ConnectionClosed => f.write_str("connection closed during handshake"),
+            HandshakeError::InvalidProtocol => f.write_str("not a bittorrent handshake"),
+            HandshakeError::InfoHashMismatch { expected, got } => {
+                write!(f, "info hash mismatch, expected {} got {}", expected, got)
+            }
+            HandshakeError::PeerIdMismatch => f.write_str("peer id mismatch"),
+            HandshakeError::OwnPeerId => f.write_str("connected to ourselves"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    fn hash(n: u8) -> InfoHash {
+        InfoHash::new([n; 20])
+    }
+
+    #[test]
+    fn handshake_layout() {
+        let h = Handshake::new(hash(1), [2; 20])
+            .with_extension_protocol()
+            .with_dht();
+        let b = h.to_bytes();
+        assert_eq!(b.len(), HANDSHAKE_LEN);
+        assert_eq!(b[0], 19);
+        assert_eq!(&b[1..20], b"BitTorrent protocol");
+        assert_eq!(b[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert_eq!((&b[28..48], &b[48..68]), (&[1; 20][..], &[2; 20][..]));
+        assert_eq!(Handshake::from_bytes(&b).unwrap(), h);
+        let mut other = b;
+        other[0] = 18;
+        assert!(matches!(
+            Handshake::from_bytes(&other),
+            Err(HandshakeError::InvalidProtocol)
+        ));
+    }
+
+    #[test]
+    fn reserved_bits() {
+        let plain = Handshake::new(hash(1), [2; 20]);
+        assert!(!plain.supports_extension_protocol() && !plain.supports_dht());
+        assert!(!plain.supports_fast() && !plain.supports_v2());
+        let all = plain.with_extension_protocol().with_dht().with_v2();
+        assert!(all.supports_extension_protocol() && all.supports_dht() && all.supports_v2());
+        let mut b = plain.to_bytes();
+        b[27] |= 0x04;
+        let fast = HandshakeResult::from(Handshake::from_bytes(&b).unwrap());
+        assert!(fast.fast && !fast.dht && !fast.extension_protocol);
+    }
+
+    /// run `handshake` against a remote side that answers with `theirs`
+    async fn answered_with(
+        ours: Handshake,
+        theirs: Handshake,
+    ) -> Result<HandshakeResult, HandshakeError> {
+        let (mut local, mut remote) = duplex(256);
+        let answer = tokio::spawn(async move {
+            let got = read_handshake(&mut remote).await.unwrap();
+            remote.write_all(&theirs.to_bytes()).await.unwrap();
+            got
+        });
+        let result = handshake(&mut local, &ours).await;
+        assert_eq!(answer.await.unwrap(), ours);
+        result
+    }
+
+    #[tokio::test]
+    async fn handshake_checks_the_answer() {
+        let ours = Handshake::new(hash(1), [2; 20]);
+        let theirs = Handshake::new(hash(1), [3; 20]).with_extension_protocol();
+        let r = answered_with(ours, theirs).await.unwrap();
+        assert_eq!((r.peer_id, r.info_hash), ([3; 20], hash(1)));
+        assert!(r.extension_protocol);
+        assert!(matches!(
+            answered_with(ours, Handshake::new(hash(9), [3; 20])).await,
+            Err(HandshakeError::InfoHashMismatch { expected, got })
+                if expected == hash(1) && got == hash(9)
+        ));
+        assert!(matches!(
+            answered_with(ours, Handshake::new(hash(1), [2; 20])).await,
+            Err(HandshakeError::OwnPeerId)
+        ));
+    }
+
+    #[tokio::test]
+    async fn short_handshakes_are_reported() {
+        let (mut local, remote) = duplex(256);
+        drop(remote);
+        assert!(matches!(
+            read_handshake(&mut local).await,
+            Err(HandshakeError::ConnectionClosed)
+        ));
+    }
+}
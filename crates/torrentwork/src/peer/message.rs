@@ -0,0 +1,288 @@
+use std::{fmt, io};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// standard block size requested from peers, 16 KiB
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// peer wire message ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    MsgChoke = 0,
+    MsgUnchoke = 1,
+    MsgInterested = 2,
+    MsgNotInterested = 3,
+    MsgHave = 4,
+    MsgBitfield = 5,
+    MsgRequest = 6,
+    MsgPiece = 7,
+    MsgCancel = 8,
+    MsgPort = 9,
+    /// BEP 10 extension protocol
+    MsgExtended = 20,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = MessageError;
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        Ok(match id {
+            0 => MessageType::MsgChoke,
+            1 => MessageType::MsgUnchoke,
+            2 => MessageType::MsgInterested,
+            3 => MessageType::MsgNotInterested,
+            4 => MessageType::MsgHave,
+            5 => MessageType::MsgBitfield,
+            6 => MessageType::MsgRequest,
+            7 => MessageType::MsgPiece,
+            8 => MessageType::MsgCancel,
+            9 => MessageType::MsgPort,
+            20 => MessageType::MsgExtended,
+            _ => return Err(MessageError::UnknownMessage(id)),
+        })
+    }
+}
+
+/// a length-prefixed peer wire message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// zero-length frame keeping the connection open
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// the sender finished and verified this piece
+    Have(u32),
+    /// pieces the sender has, high bit of the first byte is piece 0
+    Bitfield(Bytes),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// the sender's DHT port
+    Port(u16),
+    /// a BEP 10 extension message, `id` 0 is the extended handshake and
+    /// other ids are the ones the receiver announced in its handshake
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+}
+
+impl Message {
+    /// the wire id, `None` for keep-alive
+    pub fn message_type(&self) -> Option<MessageType> {
+        Some(match self {
+            Message::KeepAlive => return None,
+            Message::Choke => MessageType::MsgChoke,
+            Message::Unchoke => MessageType::MsgUnchoke,
+            Message::Interested => MessageType::MsgInterested,
+            Message::NotInterested => MessageType::MsgNotInterested,
+            Message::Have(_) => MessageType::MsgHave,
+            Message::Bitfield(_) => MessageType::MsgBitfield,
+            Message::Request { .. } => MessageType::MsgRequest,
+            Message::Piece { .. } => MessageType::MsgPiece,
+            Message::Cancel { .. } => MessageType::MsgCancel,
+            Message::Port(_) => MessageType::MsgPort,
+            Message::Extended { .. } => MessageType::MsgExtended,
+        })
+    }
+    /// length of the frame body, without the 4-byte length prefix
+    pub fn body_len(&self) -> usize {
+        match self {
+            Message::KeepAlive => 0,
+            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => 1,
+            Message::Have(_) => 5,
+            Message::Bitfield(b) => 1 + b.len(),
+            Message::Request { .. } | Message::Cancel { .. } => 13,
+            Message::Piece { block, .. } => 9 + block.len(),
+            Message::Port(_) => 3,
+            Message::Extended { payload, .. } => 2 + payload.len(),
+        }
+    }
+    /// append the length-prefixed frame to `dst`
+    pub fn encode(&self, dst: &mut BytesMut) {
+        dst.reserve(4 + self.body_len());
+        dst.put_u32(self.body_len() as u32);
+        if let Some(t) = self.message_type() {
+            dst.put_u8(t as u8);
+        }
+        match self {
+            Message::Have(index) => dst.put_u32(*index),
+            Message::Bitfield(b) => dst.put_slice(b),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_slice(block);
+            }
+            Message::Port(port) => dst.put_u16(*port),
+            Message::Extended { id, payload } => {
+                dst.put_u8(*id);
+                dst.put_slice(payload);
+            }
+            _ => {}
+        }
+    }
+    /// parse a frame body (the bytes after the length prefix)
+    pub fn decode(mut body: Bytes, max_block_length: u32) -> Result<Self, MessageError> {
+        if body.is_empty() {
+            return Ok(Message::KeepAlive);
+        }
+        let id = body[0];
+        let t = MessageType::try_from(id)?;
+        let expect = |len: usize| {
+            if body.len() == len {
+                Ok(())
+            } else {
+                Err(MessageError::InvalidLength {
+                    id,
+                    length: body.len(),
+                })
+            }
+        };
+        let u32_at = |b: &Bytes, i: usize| u32::from_be_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Ok(match t {
+            MessageType::MsgChoke => expect(1).map(|_| Message::Choke)?,
+            MessageType::MsgUnchoke => expect(1).map(|_| Message::Unchoke)?,
+            MessageType::MsgInterested => expect(1).map(|_| Message::Interested)?,
+            MessageType::MsgNotInterested => expect(1).map(|_| Message::NotInterested)?,
+            MessageType::MsgHave => {
+                expect(5)?;
+                Message::Have(u32_at(&body, 1))
+            }
+            MessageType::MsgBitfield => Message::Bitfield(body.split_off(1)),
+            MessageType::MsgRequest | MessageType::MsgCancel => {
+                expect(13)?;
+                let (index, begin, length) = (u32_at(&body, 1), u32_at(&body, 5), u32_at(&body, 9));
+                if length > max_block_length {
+                    return Err(MessageError::BlockTooLarge(length));
+                }
+                if t == MessageType::MsgRequest {
+                    Message::Request {
+                        index,
+                        begin,
+                        length,
+                    }
+                } else {
+                    Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    }
+                }
+            }
+            MessageType::MsgPiece => {
+                if body.len() < 9 {
+                    return Err(MessageError::InvalidLength {
+                        id,
+                        length: body.len(),
+                    });
+                }
+                let (index, begin) = (u32_at(&body, 1), u32_at(&body, 5));
+                let block = body.split_off(9);
+                if block.len() > max_block_length as usize {
+                    return Err(MessageError::BlockTooLarge(block.len() as u32));
+                }
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                }
+            }
+            MessageType::MsgPort => {
+                expect(3)?;
+                Message::Port(u16::from_be_bytes([body[1], body[2]]))
+            }
+            MessageType::MsgExtended => {
+                if body.len() < 2 {
+                    return Err(MessageError::InvalidLength {
+                        id,
+                        length: body.len(),
+                    });
+                }
+                let ext = body[1];
+                Message::Extended {
+                    id: ext,
+                    payload: body.split_off(2),
+                }
+            }
+        })
+    }
+}
+
+/// a malformed or oversized peer wire frame
+#[derive(Debug)]
+pub enum MessageError {
+    Io(io::Error),
+    /// the length prefix exceeds the codec's maximum frame length
+    FrameTooLarge {
+        length: usize,
+        max: usize,
+    },
+    /// a message id we do not understand, the codec skips these frames
+    UnknownMessage(u8),
+    /// a fixed-size message with the wrong body length
+    InvalidLength {
+        id: u8,
+        length: usize,
+    },
+    /// a request, cancel or piece for more than the maximum block length
+    BlockTooLarge(u32),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Io(e) => write!(f, "peer connection error: {}", e),
+            MessageError::FrameTooLarge { length, max } => {
+                write!(
+                    f,
+                    "message of {} bytes exceeds the {} byte limit",
+                    length, max
+                )
+            }
+            MessageError::UnknownMessage(id) => write!(f, "unknown message id {}", id),
+            MessageError::InvalidLength { id, length } => {
+                write!(f, "message id {} has invalid length {}", id, length)
+            }
+            MessageError::BlockTooLarge(len) => write!(f, "block of {} bytes is too large", len),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<io::Error> for MessageError {
+    fn from(e: io::Error) -> Self {
+        MessageError::Io(e)
+    }
+}
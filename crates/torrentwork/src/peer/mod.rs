@@ -0,0 +1,115 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use rand::Rng;
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::hash::InfoHash;
+
+pub mod codec;
+pub mod extension;
+pub mod handshake;
+pub mod message;
+
+pub use extension::{
+    ExtendedHandshake, Extension, ExtensionContext, ExtensionRegistry, MetadataMessage, PexMessage,
+};
+pub use handshake::{Handshake, HandshakeError, HandshakeResult};
+pub use message::{Message, MessageType};
+
+/// azureus-style client prefix of our peer id
+const PEER_ID_PREFIX: &[u8; 8] = b"-TW0010-";
+/// how long to wait for a tcp connection to a peer
+const PEER_CONNECT_TIMEOUT_SEC: u64 = 10;
+/// BEP 23 compact ipv4 peer, address and port
+pub const COMPACT_PEER_V4_LEN: usize = 6;
+/// BEP 7 compact ipv6 peer, address and port
+pub const COMPACT_PEER_V6_LEN: usize = 18;
+
+/// this process' peer id, "-TW0010-" followed by 12 random alphanumerics,
+/// generated once and used for every tracker announce and handshake.
+pub fn local_peer_id() -> [u8; 20] {
+    static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+    *PEER_ID.get_or_init(|| {
+        let mut id = [0; 20];
+        id[..8].copy_from_slice(PEER_ID_PREFIX);
+        let mut rng = rand::thread_rng();
+        for b in id[8..].iter_mut() {
+            *b = rng.sample(rand::distributions::Alphanumeric);
+        }
+        id
+    })
+}
+
+/// a remote peer as announced by a tracker, pex or the dht
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// only known from the tracker's dictionary model or after a handshake
+    pub peer_id: Option<[u8; 20]>,
+    pub info_hash: InfoHash,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            peer_id: None,
+            info_hash: InfoHash::default(),
+        }
+    }
+    /// parse one compact entry, 4 or 16 address bytes followed by the port
+    pub fn from_compact(b: &[u8]) -> Option<Self> {
+        let ip: IpAddr = match b.len() {
+            COMPACT_PEER_V4_LEN => <[u8; 4]>::try_from(&b[..4]).ok()?.into(),
+            COMPACT_PEER_V6_LEN => <[u8; 16]>::try_from(&b[..16]).ok()?.into(),
+            _ => return None,
+        };
+        let port = u16::from_be_bytes([b[b.len() - 2], b[b.len() - 1]]);
+        Some(Self::new(SocketAddr::new(ip, port)))
+    }
+    /// parse a BEP 23 `peers` (6 bytes each) or BEP 7 `peers6` (18 bytes
+    /// each) string, a trailing partial entry is ignored.
+    pub fn from_compact_list(b: &[u8], entry_len: usize) -> Vec<Self> {
+        b.chunks_exact(entry_len)
+            .filter_map(Self::from_compact)
+            .collect()
+    }
+    /// the compact form of this peer's address
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut b = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        b.extend_from_slice(&self.addr.port().to_be_bytes());
+        b
+    }
+    /// connect and exchange handshakes, returning the remote side's handshake
+    /// and the connection ready for wire messages.
+    pub async fn handshake(
+        &self,
+        ours: &Handshake,
+    ) -> Result<(HandshakeResult, TcpStream), HandshakeError> {
+        let mut stream = match timeout(
+            Duration::from_secs(PEER_CONNECT_TIMEOUT_SEC),
+            TcpStream::connect(self.addr),
+        )
+        .await
+        {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => return Err(HandshakeError::Io(e)),
+            Err(_) => return Err(HandshakeError::Timeout),
+        };
+        let result = handshake::handshake(&mut stream, ours).await?;
+        // the tracker's dictionary model tells us who should answer
+        if let Some(id) = self.peer_id
+            && id != result.peer_id
+        {
+            return Err(HandshakeError::PeerIdMismatch);
+        }
+        Ok((result, stream))
+    }
+}
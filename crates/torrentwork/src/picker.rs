@@ -0,0 +1,553 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+};
+
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+use crate::{bitfield::Bitfield, peer::message::BLOCK_SIZE};
+
+/// outstanding requests kept in flight per peer
+pub const DEFAULT_REQUEST_QUEUE: usize = 16;
+/// pieces picked at random before switching to rarest-first, so a new
+/// download quickly has something to trade
+const RANDOM_FIRST_PIECES: usize = 4;
+
+/// download priority of a piece or file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// do not download
+    Skip = 0,
+    Low = 1,
+    #[default]
+    Normal = 4,
+    High = 7,
+}
+
+/// a block request, at most 16 KiB of one piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Open,
+    /// requested from these peers, more than one only in endgame
+    Requested(Vec<SocketAddr>),
+    Received,
+}
+
+/// what `block_received` tells the caller to do next
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockReceived {
+    /// every block of the piece arrived, it is ready to be verified
+    pub piece_complete: bool,
+    /// endgame duplicates of the block that should be cancelled
+    pub cancels: Vec<(SocketAddr, Block)>,
+}
+
+/// chooses which blocks to request from which peer. pieces are picked
+/// rarest-first by availability after a few random ones, higher priorities
+/// first, and started pieces are finished before new ones are begun. once
+/// every missing block is requested the picker enters endgame and requests
+/// the remaining blocks from every peer that has them.
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    piece_length: u32,
+    total_length: u64,
+    have: Bitfield,
+    /// number of connected peers having each piece
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    peers: HashMap<SocketAddr, Bitfield>,
+    /// pieces with at least one requested block, ordered so picks are
+    /// reproducible under a seed
+    partial: BTreeMap<u32, Vec<BlockState>>,
+    request_queue: usize,
+    rng: StdRng,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize, piece_length: u32, total_length: u64) -> Self {
+        Self {
+            piece_length,
+            total_length,
+            have: Bitfield::new(piece_count),
+            availability: vec![0; piece_count],
+            priorities: vec![Priority::Normal; piece_count],
+            peers: HashMap::new(),
+            partial: BTreeMap::new(),
+            request_queue: DEFAULT_REQUEST_QUEUE,
+            rng: StdRng::from_entropy(),
+        }
+    }
+    /// make every random choice reproducible
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+    /// number of requests kept in flight per peer
+    pub fn with_request_queue(mut self, request_queue: usize) -> Self {
+        self.request_queue = request_queue.max(1);
+        self
+    }
+    pub fn piece_count(&self) -> usize {
+        self.have.len()
+    }
+    /// length of piece `i`, the last one may be short
+    pub fn piece_size(&self, i: u32) -> u32 {
+        let start = i as u64 * self.piece_length as u64;
+        self.total_length
+            .saturating_sub(start)
+            .min(self.piece_length as u64) as u32
+    }
+    pub fn block_count(&self, i: u32) -> usize {
+        self.piece_size(i).div_ceil(BLOCK_SIZE) as usize
+    }
+    /// the `b`-th block of piece `i`
+    pub fn block(&self, i: u32, b: usize) -> Block {
+        let begin = b as u32 * BLOCK_SIZE;
+        Block {
+            piece: i,
+            begin,
+            length: self.piece_size(i).saturating_sub(begin).min(BLOCK_SIZE),
+        }
+    }
+    /// the verified pieces
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+    pub fn has_piece(&self, i: u32) -> bool {
+        self.have.get(i as usize)
+    }
+    /// every piece that is not skipped is verified
+    pub fn is_complete(&self) -> bool {
+        (0..self.piece_count()).all(|i| self.have.get(i) || self.priorities[i] == Priority::Skip)
+    }
+    /// mark piece `i` verified
+    pub fn set_have(&mut self, i: u32) {
+        self.have.set(i as usize);
+        self.partial.remove(&i);
+    }
+    /// piece `i` failed the hash check, download it again from scratch
+    pub fn piece_failed(&mut self, i: u32) {
+        self.have.clear(i as usize);
+        self.partial.remove(&i);
+    }
+    pub fn piece_priority(&self, i: u32) -> Priority {
+        self.priorities
+            .get(i as usize)
+            .copied()
+            .unwrap_or(Priority::Skip)
+    }
+    pub fn set_piece_priority(&mut self, i: u32, p: Priority) {
+        if let Some(prio) = self.priorities.get_mut(i as usize) {
+            *prio = p;
+        }
+    }
+    /// set piece priorities from file priorities, `files` are the lengths
+    /// and priorities of the files in torrent order. a piece shared by
+    /// several files gets the highest of their priorities.
+    pub fn set_file_priorities(&mut self, files: &[(u64, Priority)]) {
+        self.priorities.iter_mut().for_each(|p| *p = Priority::Skip);
+        let mut offset = 0u64;
+        for (length, prio) in files {
+            if *length > 0 && self.piece_length > 0 {
+                let first = offset / self.piece_length as u64;
+                let last = (offset + length - 1) / self.piece_length as u64;
+                for i in first..=last {
+                    if let Some(p) = self.priorities.get_mut(i as usize) {
+                        *p = (*p).max(*prio);
+                    }
+                }
+            }
+            offset += length;
+        }
+    }
+    /// a peer sent its bitfield, replaces what we knew about it
+    pub fn add_peer(&mut self, addr: SocketAddr, bitfield: Bitfield) {
+        self.remove_peer(addr);
+        for i in bitfield.iter_set() {
+            if let Some(a) = self.availability.get_mut(i) {
+                *a += 1;
+            }
+        }
+        self.peers.insert(addr, bitfield);
+    }
+    /// a peer sent a have message
+    pub fn peer_have(&mut self, addr: SocketAddr, i: u32) {
+        let n = self.piece_count();
+        let bitfield = self.peers.entry(addr).or_insert_with(|| Bitfield::new(n));
+        if (i as usize) < n && !bitfield.get(i as usize) {
+            bitfield.set(i as usize);
+            self.availability[i as usize] += 1;
+        }
+    }
+    /// a peer sent a BEP 54 lt_donthave, it no longer has the piece and
+    /// will not answer requests for it
+    pub fn peer_dont_have(&mut self, addr: SocketAddr, i: u32) {
+        if let Some(bitfield) = self.peers.get_mut(&addr)
+            && (i as usize) < bitfield.len()
+            && bitfield.get(i as usize)
+        {
+            bitfield.clear(i as usize);
+            self.availability[i as usize] -= 1;
+        }
+        if let Some(blocks) = self.partial.get_mut(&i) {
+            for state in blocks.iter_mut() {
+                release(state, addr);
+            }
+        }
+    }
+    /// a peer disconnected
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        if let Some(bitfield) = self.peers.remove(&addr) {
+            for i in bitfield.iter_set() {
+                if let Some(a) = self.availability.get_mut(i) {
+                    *a = a.saturating_sub(1);
+                }
+            }
+        }
+        self.release_requests(addr);
+    }
+    /// a peer choked us, its outstanding requests will not be answered
+    pub fn release_requests(&mut self, addr: SocketAddr) {
+        for blocks in self.partial.values_mut() {
+            for state in blocks.iter_mut() {
+                release(state, addr);
+            }
+        }
+    }
+    /// a peer rejected a request or it timed out
+    pub fn request_failed(&mut self, addr: SocketAddr, block: Block) {
+        if let Some(state) = self.block_state(block) {
+            release(state, addr);
+        }
+    }
+    /// number of connected peers having piece `i`
+    pub fn availability(&self, i: u32) -> u32 {
+        self.availability.get(i as usize).copied().unwrap_or(0)
+    }
+    /// the peer has a piece we want
+    pub fn is_interesting(&self, addr: SocketAddr) -> bool {
+        match self.peers.get(&addr) {
+            Some(b) => b.iter_set().any(|i| self.wanted(i as u32)),
+            None => false,
+        }
+    }
+    /// the peer has every piece
+    pub fn peer_is_seed(&self, addr: SocketAddr) -> bool {
+        self.peers.get(&addr).is_some_and(|b| b.all())
+    }
+    /// requests in flight to a peer
+    pub fn outstanding(&self, addr: SocketAddr) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        for (i, states) in self.partial.iter() {
+            for (b, state) in states.iter().enumerate() {
+                if let BlockState::Requested(peers) = state
+                    && peers.contains(&addr)
+                {
+                    blocks.push(self.block(*i, b));
+                }
+            }
+        }
+        blocks
+    }
+    /// every missing block is requested from some peer
+    pub fn is_endgame(&self) -> bool {
+        let open = self
+            .partial
+            .values()
+            .flatten()
+            .any(|s| *s == BlockState::Open);
+        !open
+            && !self.is_complete()
+            && (0..self.piece_count() as u32)
+                .filter(|i| self.wanted(*i))
+                .all(|i| self.partial.contains_key(&i))
+    }
+    /// the blocks to request from `addr` to fill its request queue, they are
+    /// recorded as requested from that peer.
+    pub fn pick(&mut self, addr: SocketAddr) -> Vec<Block> {
+        let Some(bitfield) = self.peers.get(&addr).cloned() else {
+            return vec![];
+        };
+        let want = self
+            .request_queue
+            .saturating_sub(self.outstanding(addr).len());
+        let mut picked = Vec::new();
+        if want == 0 {
+            return picked;
+        }
+        // finish started pieces first
+        let partial: Vec<u32> = self.partial.keys().copied().collect();
+        for i in partial {
+            if bitfield.get(i as usize) {
+                self.pick_open(i, addr, want, &mut picked);
+            }
+        }
+        // then start new ones
+        while picked.len() < want {
+            let Some(i) = self.next_piece(&bitfield) else {
+                break;
+            };
+            self.partial
+                .insert(i, vec![BlockState::Open; self.block_count(i)]);
+            self.pick_open(i, addr, want, &mut picked);
+        }
+        if picked.len() < want && self.is_endgame() {
+            self.pick_endgame(&bitfield, addr, want, &mut picked);
+        }
+        picked
+    }
+    /// a block arrived, `None` if we did not ask for it or already have it
+    pub fn block_received(&mut self, addr: SocketAddr, block: Block) -> Option<BlockReceived> {
+        let expected = self.block(block.piece, (block.begin / BLOCK_SIZE) as usize);
+        if block.length == 0 || block != expected {
+            return None;
+        }
+        let state = self.block_state(block)?;
+        let others = match state {
+            BlockState::Received => return None,
+            BlockState::Open => vec![],
+            BlockState::Requested(peers) => peers.iter().filter(|p| **p != addr).copied().collect(),
+        };
+        *state = BlockState::Received;
+        let piece_complete = self.partial[&block.piece]
+            .iter()
+            .all(|s| *s == BlockState::Received);
+        Some(BlockReceived {
+            piece_complete,
+            cancels: others.into_iter().map(|p| (p, block)).collect(),
+        })
+    }
+    fn wanted(&self, i: u32) -> bool {
+        !self.has_piece(i) && self.piece_priority(i) != Priority::Skip
+    }
+    fn block_state(&mut self, block: Block) -> Option<&mut BlockState> {
+        if !block.begin.is_multiple_of(BLOCK_SIZE) {
+            return None;
+        }
+        self.partial
+            .get_mut(&block.piece)?
+            .get_mut((block.begin / BLOCK_SIZE) as usize)
+    }
+    /// request the open blocks of piece `i` until `picked` holds `want`
+    fn pick_open(&mut self, i: u32, addr: SocketAddr, want: usize, picked: &mut Vec<Block>) {
+        let blocks: Vec<usize> = self.partial[&i]
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| **s == BlockState::Open)
+            .map(|(b, _)| b)
+            .collect();
+        for b in blocks {
+            if picked.len() >= want {
+                return;
+            }
+            self.partial.get_mut(&i).unwrap()[b] = BlockState::Requested(vec![addr]);
+            picked.push(self.block(i, b));
+        }
+    }
+    /// duplicate requests for blocks in flight to other peers
+    fn pick_endgame(
+        &mut self,
+        bitfield: &Bitfield,
+        addr: SocketAddr,
+        want: usize,
+        picked: &mut Vec<Block>,
+    ) {
+        let mut candidates = Vec::new();
+        for (i, states) in self.partial.iter() {
+            if !bitfield.get(*i as usize) {
+                continue;
+            }
+            for (b, state) in states.iter().enumerate() {
+                if let BlockState::Requested(peers) = state
+                    && !peers.contains(&addr)
+                {
+                    candidates.push((*i, b));
+                }
+            }
+        }
+        // spread duplicates so peers do not all race for the same block
+        candidates.shuffle(&mut self.rng);
+        for (i, b) in candidates.into_iter().take(want - picked.len()) {
+            if let BlockState::Requested(peers) = &mut self.partial.get_mut(&i).unwrap()[b] {
+                peers.push(addr);
+            }
+            picked.push(self.block(i, b));
+        }
+    }
+    /// the next piece to start, highest priority first, then random for
+    /// the first few pieces and rarest after that, ties broken at random.
+    fn next_piece(&mut self, bitfield: &Bitfield) -> Option<u32> {
+        let candidates: Vec<u32> = bitfield
+            .iter_set()
+            .map(|i| i as u32)
+            .filter(|i| self.wanted(*i) && !self.partial.contains_key(i))
+            .collect();
+        let top = candidates.iter().map(|i| self.piece_priority(*i)).max()?;
+        let mut candidates: Vec<u32> = candidates
+            .into_iter()
+            .filter(|i| self.piece_priority(*i) == top)
+            .collect();
+        if self.have.count() < RANDOM_FIRST_PIECES {
+            let n = self.rng.gen_range(0..candidates.len());
+            return Some(candidates[n]);
+        }
+        candidates.shuffle(&mut self.rng);
+        candidates
+            .into_iter()
+            .min_by_key(|i| self.availability[*i as usize])
+    }
+}
+
+/// forget that `addr` was asked for the block
+fn release(state: &mut BlockState, addr: SocketAddr) {
+    if let BlockState::Requested(peers) = state {
+        peers.retain(|p| *p != addr);
+        if peers.is_empty() {
+            *state = BlockState::Open;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn bitfield(n: usize, pieces: impl IntoIterator<Item = usize>) -> Bitfield {
+        let mut b = Bitfield::new(n);
+        for i in pieces {
+            b.set(i);
+        }
+        b
+    }
+
+    /// a picker of `n` one-block pieces
+    fn picker(n: usize) -> PiecePicker {
+        PiecePicker::new(n, BLOCK_SIZE, n as u64 * BLOCK_SIZE as u64).with_seed(7)
+    }
+
+    fn receive(p: &mut PiecePicker, from: SocketAddr, blocks: &[Block]) {
+        for b in blocks {
+            if p.block_received(from, *b).is_some_and(|r| r.piece_complete) {
+                p.set_have(b.piece);
+            }
+        }
+    }
+
+    #[test]
+    fn rarest_piece_is_picked_first() {
+        let mut p = picker(10).with_request_queue(1);
+        // past the random first pieces
+        for i in 0..4 {
+            p.set_have(i);
+        }
+        p.add_peer(addr(1), bitfield(10, 0..10));
+        p.add_peer(addr(2), bitfield(10, [4, 5, 6, 8, 9]));
+        p.add_peer(addr(3), bitfield(10, [4, 5, 6]));
+        assert_eq!(p.availability(7), 1);
+        let first = p.pick(addr(1));
+        assert_eq!(first, vec![p.block(7, 0)]);
+        receive(&mut p, addr(1), &first);
+        let second = p.pick(addr(1));
+        assert!(matches!(second[..], [Block { piece: 8 | 9, .. }]));
+        receive(&mut p, addr(1), &second);
+        let third = p.pick(addr(1));
+        assert!(matches!(third[..], [Block { piece: 8 | 9, .. }]));
+        assert_ne!(second[0].piece, third[0].piece);
+    }
+
+    #[test]
+    fn priorities_order_picks_and_skip_is_never_picked() {
+        let mut p = picker(4).with_request_queue(1);
+        p.set_piece_priority(0, Priority::Skip);
+        p.set_piece_priority(2, Priority::High);
+        p.add_peer(addr(1), bitfield(4, 0..4));
+        let first = p.pick(addr(1));
+        assert_eq!(first, vec![p.block(2, 0)]);
+        receive(&mut p, addr(1), &first);
+        let mut picked = vec![];
+        loop {
+            let blocks = p.pick(addr(1));
+            if blocks.is_empty() {
+                break;
+            }
+            picked.extend(blocks.iter().map(|b| b.piece));
+            receive(&mut p, addr(1), &blocks);
+        }
+        picked.sort();
+        assert_eq!(picked, vec![1, 3]);
+        assert!(p.is_complete());
+        assert!(!p.has_piece(0));
+    }
+
+    #[test]
+    fn file_priorities_skip_pieces_of_skipped_files() {
+        let mut p = picker(4);
+        let block = BLOCK_SIZE as u64;
+        // the second file shares piece 1 with the first
+        p.set_file_priorities(&[
+            (block + 1, Priority::Normal),
+            (3 * block - 1, Priority::Skip),
+        ]);
+        assert_eq!(p.piece_priority(0), Priority::Normal);
+        assert_eq!(p.piece_priority(1), Priority::Normal);
+        assert_eq!(p.piece_priority(2), Priority::Skip);
+        assert_eq!(p.piece_priority(3), Priority::Skip);
+    }
+
+    #[test]
+    fn endgame_duplicates_requests_and_cancels_them() {
+        let mut p = picker(2);
+        p.add_peer(addr(1), bitfield(2, 0..2));
+        p.add_peer(addr(2), bitfield(2, 0..2));
+        let first = p.pick(addr(1));
+        assert_eq!(first.len(), 2);
+        assert!(p.is_endgame());
+        let mut dup = p.pick(addr(2));
+        dup.sort_by_key(|b| b.piece);
+        assert_eq!(dup, vec![p.block(0, 0), p.block(1, 0)]);
+        let r = p.block_received(addr(2), p.block(0, 0)).unwrap();
+        assert!(r.piece_complete);
+        assert_eq!(r.cancels, vec![(addr(1), p.block(0, 0))]);
+        // the cancelled duplicate is dropped if it still arrives
+        assert_eq!(p.block_received(addr(1), p.block(0, 0)), None);
+        // a peer is not asked twice for the same block
+        assert!(p.pick(addr(2)).is_empty());
+    }
+
+    #[test]
+    fn failed_piece_is_requested_again() {
+        let mut p = PiecePicker::new(1, 2 * BLOCK_SIZE, 2 * BLOCK_SIZE as u64).with_seed(7);
+        p.add_peer(addr(1), bitfield(1, [0]));
+        let blocks = p.pick(addr(1));
+        assert_eq!(blocks, vec![p.block(0, 0), p.block(0, 1)]);
+        assert!(p.pick(addr(1)).is_empty());
+        assert!(!p.block_received(addr(1), blocks[0]).unwrap().piece_complete);
+        assert!(p.block_received(addr(1), blocks[1]).unwrap().piece_complete);
+        p.piece_failed(0);
+        assert!(!p.has_piece(0));
+        assert_eq!(p.pick(addr(1)), blocks);
+        assert_eq!(p.outstanding(addr(1)), blocks);
+    }
+
+    #[test]
+    fn choke_releases_requests_to_other_peers() {
+        let mut p = picker(1);
+        p.add_peer(addr(1), bitfield(1, [0]));
+        p.add_peer(addr(2), bitfield(1, [0]));
+        assert_eq!(p.pick(addr(1)), vec![p.block(0, 0)]);
+        p.release_requests(addr(1));
+        assert!(p.outstanding(addr(1)).is_empty());
+        assert_eq!(p.pick(addr(2)), vec![p.block(0, 0)]);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{fs, net::SocketAddr, path::PathBuf};
+
+use bencode::{Dict, List, Value};
+
+use crate::{
+    bitfield::Bitfield,
+    error::{Error, StorageError},
+    hash::{InfoHash, InfoHashV2},
+    peer::{COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN, Peer},
+    picker::Priority,
+};
+
+/// "file-format" of libtorrent resume files, kept so the files are recognizable
+const RESUME_FILE_FORMAT: &str = "libtorrent resume file";
+const RESUME_FILE_VERSION: i64 = 1;
+
+/// what a torrent needs to pick up where it left off after a restart,
+/// stored as a bencoded dictionary laid out like libtorrent's resume files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: InfoHash,
+    pub info_hash_v2: Option<InfoHashV2>,
+    pub name: String,
+    pub save_path: PathBuf,
+    /// verified pieces
+    pub pieces: Bitfield,
+    pub file_priorities: Vec<Priority>,
+    /// size and mtime in seconds of every file when the data was saved,
+    /// zeros for files not on disk
+    pub file_sizes: Vec<(u64, i64)>,
+    pub peers: Vec<SocketAddr>,
+    /// tracker tiers
+    pub trackers: Vec<Vec<String>>,
+    pub total_uploaded: u64,
+    pub total_downloaded: u64,
+    pub paused: bool,
+}
+
+impl ResumeData {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut d = Dict::new();
+        d.insert("file-format", RESUME_FILE_FORMAT);
+        d.insert("file-version", RESUME_FILE_VERSION);
+        d.insert(
+            "info-hash",
+            Value::bytes(self.info_hash.as_bytes().to_vec()),
+        );
+        if let Some(v2) = self.info_hash_v2 {
+            d.insert("info-hash2", Value::bytes(v2.as_bytes().to_vec()));
+        }
+        d.insert("name", self.name.as_str());
+        d.insert("save_path", self.save_path.to_string_lossy().to_string());
+        // one byte per piece, bit 0 set when the piece is verified
+        let pieces: Vec<u8> = (0..self.pieces.len())
+            .map(|i| self.pieces.get(i) as u8)
+            .collect();
+        d.insert("pieces", Value::bytes(pieces));
+        let mut prio = List::new();
+        for p in self.file_priorities.iter() {
+            prio.push(*p as i64);
+        }
+        d.insert("file_priority", prio);
+        let mut sizes = List::new();
+        for (size, mtime) in self.file_sizes.iter() {
+            let mut entry = List::new();
+            entry.push(*size as i64);
+            entry.push(*mtime);
+            sizes.push(entry);
+        }
+        d.insert("file sizes", sizes);
+        let (mut peers, mut peers6) = (vec![], vec![]);
+        for addr in self.peers.iter() {
+            match addr {
+                SocketAddr::V4(_) => peers.extend(Peer::new(*addr).to_compact()),
+                SocketAddr::V6(_) => peers6.extend(Peer::new(*addr).to_compact()),
+            }
+        }
+        d.insert("peers", Value::bytes(peers));
+        d.insert("peers6", Value::bytes(peers6));
+        let mut trackers = List::new();
+        for tier in self.trackers.iter() {
+            let mut t = List::new();
+            for url in tier {
+                t.push(url.as_str());
+            }
+            trackers.push(t);
+        }
+        d.insert("trackers", trackers);
+        d.insert("total_uploaded", self.total_uploaded as i64);
+        d.insert("total_downloaded", self.total_downloaded as i64);
+        d.insert("paused", self.paused as i64);
+        Value::Dict(d).to_bytes()
+    }
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, StorageError> {
+        let root = match bencode::decode(buf) {
+            Ok(v) => v,
+            Err(e) => return Err(StorageError::InvalidResume(e.to_string())),
+        };
+        let d = match root.as_dict() {
+            Some(d) => d,
+            None => return Err(StorageError::InvalidResume("not a dictionary".to_string())),
+        };
+        let field = |e: bencode::Error| StorageError::InvalidResume(e.to_string());
+        if d.require_str("file-format").map_err(field)? != RESUME_FILE_FORMAT {
+            return Err(StorageError::InvalidResume("not a resume file".to_string()));
+        }
+        let hash = |e| StorageError::InvalidResume(format!("{}", e));
+        let info_hash =
+            InfoHash::from_slice(d.require_bytes("info-hash").map_err(field)?).map_err(hash)?;
+        let info_hash_v2 = match d.get_bytes("info-hash2") {
+            Some(b) => Some(InfoHashV2::from_slice(b).map_err(hash)?),
+            None => None,
+        };
+        let raw_pieces = d.require_bytes("pieces").map_err(field)?;
+        let mut pieces = Bitfield::new(raw_pieces.len());
+        for (i, b) in raw_pieces.iter().enumerate() {
+            if b & 1 != 0 {
+                pieces.set(i);
+            }
+        }
+        let file_priorities = d
+            .get_list("file_priority")
+            .map(|l| {
+                l.iter()
+                    .map(|p| match p.as_int().unwrap_or(4) {
+                        0 => Priority::Skip,
+                        1..=3 => Priority::Low,
+                        4..=6 => Priority::Normal,
+                        _ => Priority::High,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut file_sizes = vec![];
+        for entry in d.get_list("file sizes").iter().flat_map(|l| l.iter()) {
+            let pair = entry.as_list();
+            let size = pair.and_then(|p| p.get(0)).and_then(|v| v.as_int());
+            let mtime = pair.and_then(|p| p.get(1)).and_then(|v| v.as_int());
+            match (size, mtime) {
+                (Some(s), Some(m)) if s >= 0 => file_sizes.push((s as u64, m)),
+                _ => {
+                    return Err(StorageError::InvalidResume(
+                        "malformed file sizes".to_string(),
+                    ));
+                }
+            }
+        }
+        let mut peers: Vec<SocketAddr> = vec![];
+        if let Some(b) = d.get_bytes("peers") {
+            peers.extend(
+                Peer::from_compact_list(b, COMPACT_PEER_V4_LEN)
+                    .iter()
+                    .map(|p| p.addr),
+            );
+        }
+        if let Some(b) = d.get_bytes("peers6") {
+            peers.extend(
+                Peer::from_compact_list(b, COMPACT_PEER_V6_LEN)
+                    .iter()
+                    .map(|p| p.addr),
+            );
+        }
+        let trackers = d
+            .get_list("trackers")
+            .iter()
+            .flat_map(|l| l.iter())
+            .filter_map(|t| t.as_list())
+            .map(|t| {
+                t.iter()
+                    .filter_map(|u| u.as_str())
+                    .map(|u| u.to_string())
+                    .collect()
+            })
+            .collect();
+        let uint = |k: &str| d.get_int(k).unwrap_or_default().max(0) as u64;
+        Ok(Self {
+            info_hash,
+            info_hash_v2,
+            name: d.get_str("name").unwrap_or_default().to_string(),
+            save_path: PathBuf::from(d.get_str("save_path").unwrap_or(".")),
+            pieces,
+            file_priorities,
+            file_sizes,
+            peers,
+            trackers,
+            total_uploaded: uint("total_uploaded"),
+            total_downloaded: uint("total_downloaded"),
+            paused: d.get_int("paused").unwrap_or_default() != 0,
+        })
+    }
+    /// write the resume file
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+    /// read a resume file
+    pub fn load(path: &str) -> Result<Self, Error> {
+        Ok(Self::from_bytes(&fs::read(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_data_round_trips() {
+        let mut pieces = Bitfield::new(10);
+        pieces.set(0);
+        pieces.set(9);
+        let r = ResumeData {
+            info_hash: InfoHash::new([1; 20]),
+            info_hash_v2: Some(InfoHashV2::new([2; 32])),
+            name: "name".to_string(),
+            save_path: PathBuf::from("/data/torrents"),
+            pieces,
+            file_priorities: vec![Priority::Skip, Priority::Normal, Priority::High],
+            file_sizes: vec![(100, 1_700_000_000), (0, 0)],
+            peers: vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:51413".parse().unwrap(),
+            ],
+            trackers: vec![
+                vec!["udp://a:1".to_string(), "udp://b:2".to_string()],
+                vec!["http://c/announce".to_string()],
+            ],
+            total_uploaded: 123,
+            total_downloaded: 456,
+            paused: true,
+        };
+        assert_eq!(ResumeData::from_bytes(&r.to_bytes()).unwrap(), r);
+    }
+
+    #[test]
+    fn other_files_are_not_resume_data() {
+        for b in [&b"i1e"[..], b"d11:file-format4:nopee", b"d11:file-format"] {
+            assert!(matches!(
+                ResumeData::from_bytes(b),
+                Err(StorageError::InvalidResume(_))
+            ));
+        }
+    }
+}
@@ -1,4 +1,611 @@
-/// torrent server abstraction
-pub struct TorrentServer {}
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-impl TorrentServer {}
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore, broadcast, mpsc},
+    task::JoinHandle,
+    time::sleep,
+};
+
+use crate::{
+    dht::{Dht, DhtSettings},
+    download::{DownloadStatus, DownloadTask},
+    error::Error,
+    event::{DEFAULT_EVENT_CAPACITY, Event, Events},
+    file::TorrentFile,
+    hash::InfoHash,
+    limiter::RateLimiter,
+    magnet::Magnet,
+    peer::{
+        Extension, ExtensionRegistry, Handshake, HandshakeError, HandshakeResult,
+        handshake::read_handshake, local_peer_id,
+    },
+    resume::ResumeData,
+    storage::Storage,
+    tracker::{manager::TrackerManager, tracker::Tracker},
+};
+
+/// the port announced to trackers and listened on for peers
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+/// open peer connections across every torrent of a session
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+/// open peer connections of one torrent
+pub const DEFAULT_MAX_CONNECTIONS_PER_TORRENT: usize = 50;
+
+/// first pause after a failed accept, doubled on every failure in a row
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+/// longest pause between failed accepts, e.g. while out of file descriptors
+const ACCEPT_BACKOFF_MAX_SEC: u64 = 1;
+
+/// what a session is set up with
+#[derive(Debug, Clone)]
+pub struct SessionSettings {
+    pub listen_port: u16,
+    pub peer_id: [u8; 20],
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    /// bytes per second across every torrent, `None` for no limit
+    pub download_rate_limit: Option<u64>,
+    pub upload_rate_limit: Option<u64>,
+    /// events kept for a subscriber that falls behind
+    pub event_capacity: usize,
+    /// run a DHT node on the listening port to find the peers of public
+    /// torrents, `None` for trackers only
+    pub dht: Option<DhtSettings>,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            listen_port: DEFAULT_LISTEN_PORT,
+            peer_id: local_peer_id(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_torrent: DEFAULT_MAX_CONNECTIONS_PER_TORRENT,
+            download_rate_limit: None,
+            upload_rate_limit: None,
+            event_capacity: DEFAULT_EVENT_CAPACITY,
+            dht: Some(DhtSettings::default()),
+        }
+    }
+}
+
+/// what the torrents of a session share, handed to every `DownloadTask`
+#[derive(Debug, Clone)]
+pub struct SessionContext {
+    pub peer_id: [u8; 20],
+    pub listen_port: u16,
+    pub max_connections_per_torrent: usize,
+    /// one permit per open peer connection, across every torrent
+    pub connections: Arc<Semaphore>,
+    pub download_limit: RateLimiter,
+    pub upload_limit: RateLimiter,
+    pub events: Events,
+    /// the BEP 10 extensions offered to peers
+    pub extensions: ExtensionRegistry,
+    /// the session's DHT node, once listening
+    pub dht: Option<Dht>,
+    /// where the listener hands accepted connections, by info hash
+    pub(crate) router: Router,
+}
+
+/// an accepted connection whose handshake named one of our torrents, our
+/// handshake has been sent back
+#[derive(Debug)]
+pub(crate) struct IncomingPeer {
+    pub addr: SocketAddr,
+    pub theirs: HandshakeResult,
+    pub stream: TcpStream,
+    /// the connection's share of the session connection limit
+    pub permit: OwnedSemaphorePermit,
+}
+
+/// running torrents by every info hash they can be reached through
+pub(crate) type Router = Arc<Mutex<HashMap<InfoHash, mpsc::UnboundedSender<IncomingPeer>>>>;
+
+impl SessionContext {
+    pub fn new(settings: &SessionSettings) -> Self {
+        Self {
+            peer_id: settings.peer_id,
+            listen_port: settings.listen_port,
+            max_connections_per_torrent: settings.max_connections_per_torrent,
+            connections: Arc::new(Semaphore::new(settings.max_connections)),
+            download_limit: RateLimiter::new(settings.download_rate_limit),
+            upload_limit: RateLimiter::new(settings.upload_rate_limit),
+            events: Events::new(settings.event_capacity),
+            extensions: ExtensionRegistry::new(),
+            dht: None,
+            router: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Default for SessionContext {
+    fn default() -> Self {
+        Self::new(&SessionSettings::default())
+    }
+}
+
+/// a torrent of the session
+#[derive(Debug)]
+struct SessionTorrent {
+    /// shares progress, status, storage and the stop signal with the
+    /// running copy
+    task: DownloadTask,
+    /// the running copy, which comes back when it stops or finishes
+    running: Option<JoinHandle<(DownloadTask, Result<(), Error>)>>,
+}
+
+/// torrent server abstraction, a session holding many torrents keyed by
+/// info hash that share one peer id, one listening port and one set of
+/// connection and bandwidth limits. must be used inside a tokio runtime.
+#[derive(Debug)]
+pub struct TorrentServer {
+    settings: SessionSettings,
+    context: SessionContext,
+    torrents: HashMap<InfoHash, SessionTorrent>,
+    /// the accept loop, once `listen` was called
+    listener: Option<JoinHandle<()>>,
+}
+
+impl TorrentServer {
+    pub fn new(settings: SessionSettings) -> Self {
+        Self {
+            context: SessionContext::new(&settings),
+            settings,
+            torrents: HashMap::new(),
+            listener: None,
+        }
+    }
+    /// accept incoming peers on the listening port and hand them to the
+    /// torrent their handshake names, and start the DHT node on the same
+    /// udp port. call before adding torrents, with port 0 the port picked by
+    /// the system is the one announced.
+    pub async fn listen(&mut self) -> Result<SocketAddr, Error> {
+        if let Some(l) = self.listener.take() {
+            l.abort();
+        }
+        let listener =
+            TcpListener::bind((Ipv4Addr::UNSPECIFIED, self.settings.listen_port)).await?;
+        let addr = listener.local_addr()?;
+        self.settings.listen_port = addr.port();
+        self.context.listen_port = addr.port();
+        self.listener = Some(tokio::spawn(accept_loop(listener, self.context.clone())));
+        if let Some(settings) = &self.settings.dht
+            && self.context.dht.is_none()
+        {
+            let dht = Dht::bind(addr.port(), settings.clone()).await?;
+            let joining = dht.clone();
+            tokio::spawn(async move { joining.bootstrap().await });
+            self.context.dht = Some(dht);
+        }
+        Ok(addr)
+    }
+    /// offer a BEP 10 extension to the peers of every torrent added from
+    /// now on, returning the message id peers send it with
+    pub fn register_extension(&mut self, ext: Arc<dyn Extension>) -> Result<u8, Error> {
+        self.context.extensions.register(ext)
+    }
+    pub fn settings(&self) -> &SessionSettings {
+        &self.settings
+    }
+    /// the DHT node, once listening
+    pub fn dht(&self) -> Option<&Dht> {
+        self.context.dht.as_ref()
+    }
+    /// receive the events of every torrent in the session
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.context.events.subscribe()
+    }
+    /// add a torrent and start downloading it into its `storage_path`, it
+    /// is seeded once complete until paused or removed
+    pub fn add_torrent(&mut self, tf: TorrentFile) -> Result<InfoHash, Error> {
+        self.insert(tf, None, None)
+    }
+    /// add a torrent by magnet link, its metadata is fetched from peers
+    /// before it downloads into the current directory. `add_torrent` with
+    /// `TorrentFile::from_magnet` stores it elsewhere.
+    pub fn add_magnet(&mut self, url: &str) -> Result<InfoHash, Error> {
+        self.insert(TorrentFile::from_magnet(Magnet::new(url)?), None, None)
+    }
+    /// add a torrent that continues from saved resume data, paused if it
+    /// was paused when the data was saved
+    pub fn add_torrent_with_resume(
+        &mut self,
+        tf: TorrentFile,
+        resume: ResumeData,
+    ) -> Result<InfoHash, Error> {
+        self.insert(tf, Some(resume), None)
+    }
+    /// add a torrent that keeps its payload in `backend` instead of files
+    /// below its `storage_path`
+    pub fn add_torrent_with_storage(
+        &mut self,
+        tf: TorrentFile,
+        backend: Box<dyn Storage>,
+    ) -> Result<InfoHash, Error> {
+        self.insert(tf, None, Some(backend))
+    }
+    fn insert(
+        &mut self,
+        tf: TorrentFile,
+        resume: Option<ResumeData>,
+        backend: Option<Box<dyn Storage>>,
+    ) -> Result<InfoHash, Error> {
+        let info_hash = tf.info_hash;
+        if self.torrents.contains_key(&info_hash) {
+            return Err(Error::InvalidState(format!(
+                "torrent {} is already in the session",
+                info_hash
+            )));
+        }
+        let manager = TrackerManager::new(
+            tf.announce_tiers.clone(),
+            info_hash,
+            self.context.listen_port,
+        )
+        .with_peer_id(self.context.peer_id)
+        .with_events(self.context.events.clone());
+        let tracker = Tracker::with_manager(tf.clone(), manager);
+        let mut task = DownloadTask::new(&tf, tracker)
+            .with_session(self.context.clone())
+            .with_seeding(true);
+        let paused = resume.as_ref().is_some_and(|r| r.paused);
+        if let Some(r) = resume {
+            task.set_resume_data(r)?;
+        }
+        if let Some(b) = backend {
+            task.set_storage(b);
+        }
+        let mut t = SessionTorrent {
+            task,
+            running: None,
+        };
+        self.context.events.send(Event::TorrentAdded { info_hash });
+        match paused {
+            true => t.task.set_status(DownloadStatus::Paused)?,
+            false => t.running = Some(spawn(t.task.clone())),
+        }
+        self.torrents.insert(info_hash, t);
+        Ok(info_hash)
+    }
+    /// stop a torrent, announce stopped and forget it, its files are kept
+    pub async fn remove_torrent(&mut self, info_hash: &InfoHash) -> Result<(), Error> {
+        let mut t = match self.torrents.remove(info_hash) {
+            Some(t) => t,
+            None => return Err(not_found(info_hash)),
+        };
+        halt(&mut t).await;
+        self.context.events.send(Event::TorrentRemoved {
+            info_hash: *info_hash,
+        });
+        Ok(())
+    }
+    /// disconnect a torrent's peers, flush its storage and announce
+    /// stopped, keeping it in the session
+    pub async fn pause(&mut self, info_hash: &InfoHash) -> Result<(), Error> {
+        let t = match self.torrents.get_mut(info_hash) {
+            Some(t) => t,
+            None => return Err(not_found(info_hash)),
+        };
+        if t.task.status() != DownloadStatus::Paused || is_running(t) {
+            halt(t).await;
+            // a torrent that was not running, or failed, is paused here
+            t.task.set_status(DownloadStatus::Paused)?;
+        }
+        Ok(())
+    }
+    /// continue a paused or failed torrent where it stopped
+    pub async fn resume(&mut self, info_hash: &InfoHash) -> Result<(), Error> {
+        let t = match self.torrents.get_mut(info_hash) {
+            Some(t) => t,
+            None => return Err(not_found(info_hash)),
+        };
+        if is_running(t) {
+            return Ok(());
+        }
+        reclaim(t).await;
+        t.task.resume()?;
+        t.running = Some(spawn(t.task.clone()));
+        Ok(())
+    }
+    pub fn is_paused(&self, info_hash: &InfoHash) -> Option<bool> {
+        self.torrents
+            .get(info_hash)
+            .map(|t| t.task.status() == DownloadStatus::Paused)
+    }
+    /// move a torrent's files to a new storage path, a running torrent is
+    /// stopped for the move and started again afterwards
+    pub async fn move_storage(
+        &mut self,
+        info_hash: &InfoHash,
+        to: impl Into<PathBuf>,
+    ) -> Result<(), Error> {
+        let t = match self.torrents.get_mut(info_hash) {
+            Some(t) => t,
+            None => return Err(not_found(info_hash)),
+        };
+        let running = is_running(t);
+        match running {
+            true => halt(t).await,
+            false => reclaim(t).await,
+        }
+        t.task.move_storage(to).await?;
+        if running {
+            t.task.resume()?;
+            t.running = Some(spawn(t.task.clone()));
+        }
+        Ok(())
+    }
+    /// the info hashes of every torrent in the session
+    pub fn torrents(&self) -> Vec<InfoHash> {
+        self.torrents.keys().copied().collect()
+    }
+    pub fn torrent(&self, info_hash: &InfoHash) -> Option<&DownloadTask> {
+        self.torrents.get(info_hash).map(|t| &t.task)
+    }
+    /// change the session wide download limit in bytes per second
+    pub fn set_download_rate_limit(&mut self, rate: Option<u64>) {
+        self.settings.download_rate_limit = rate;
+        self.context.download_limit.set_rate(rate);
+    }
+    /// change the session wide upload limit in bytes per second
+    pub fn set_upload_rate_limit(&mut self, rate: Option<u64>) {
+        self.settings.upload_rate_limit = rate;
+        self.context.upload_limit.set_rate(rate);
+    }
+    /// stop every torrent and announce stopped to their trackers, the DHT
+    /// routing table is saved if a state path is set
+    pub async fn shutdown(mut self) {
+        if let Some(l) = self.listener.take() {
+            l.abort();
+        }
+        for (_, mut t) in self.torrents.drain() {
+            halt(&mut t).await;
+        }
+        if let Some(dht) = self.context.dht.take() {
+            let _ = dht.shutdown();
+        }
+    }
+}
+
+impl Default for TorrentServer {
+    fn default() -> Self {
+        Self::new(SessionSettings::default())
+    }
+}
+
+async fn accept_loop(listener: TcpListener, context: SessionContext) {
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(c) => c,
+            // errors like EMFILE persist for a while, do not spin on them
+            Err(_) => {
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(ACCEPT_BACKOFF_MAX_SEC));
+                continue;
+            }
+        };
+        backoff = ACCEPT_BACKOFF;
+        // over the session limit, the peer will try again later
+        let permit = match Arc::clone(&context.connections).try_acquire_owned() {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        let context = context.clone();
+        tokio::spawn(async move {
+            let _ = route(stream, addr, permit, &context).await;
+        });
+    }
+}
+
+/// read the handshake of an accepted connection and pass it to the torrent
+/// it is for, connections for torrents we do not have and connections from
+/// ourselves are dropped
+async fn route(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    permit: OwnedSemaphorePermit,
+    context: &SessionContext,
+) -> Result<(), HandshakeError> {
+    let theirs = read_handshake(&mut stream).await?;
+    if theirs.peer_id == context.peer_id {
+        return Err(HandshakeError::OwnPeerId);
+    }
+    let torrent = context
+        .router
+        .lock()
+        .unwrap()
+        .get(&theirs.info_hash)
+        .cloned();
+    let torrent = match torrent {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    let ours = Handshake::new(theirs.info_hash, context.peer_id).with_extension_protocol();
+    stream.write_all(&ours.to_bytes()).await?;
+    let _ = torrent.send(IncomingPeer {
+        addr,
+        theirs: theirs.into(),
+        stream,
+        permit,
+    });
+    Ok(())
+}
+
+fn spawn(mut task: DownloadTask) -> JoinHandle<(DownloadTask, Result<(), Error>)> {
+    tokio::spawn(async move {
+        let r = task.start().await;
+        (task, r)
+    })
+}
+
+/// stop the running copy, take it back and announce stopped
+async fn halt(t: &mut SessionTorrent) {
+    t.task.stop();
+    reclaim(t).await;
+    t.task.announce_stopped().await;
+}
+
+/// take back the running copy once it returned
+async fn reclaim(t: &mut SessionTorrent) {
+    if let Some(running) = t.running.take()
+        && let Ok((task, _)) = running.await
+    {
+        t.task = task;
+    }
+}
+
+/// the running copy has not returned yet
+fn is_running(t: &SessionTorrent) -> bool {
+    t.running.as_ref().is_some_and(|r| !r.is_finished())
+}
+
+fn not_found(info_hash: &InfoHash) -> Error {
+    Error::InvalidState(format!("torrent {} is not in the session", info_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::{io::AsyncReadExt, time::timeout};
+
+    use super::*;
+    use crate::{
+        bitfield::Bitfield,
+        creator::{TorrentBuilder, TorrentVersion},
+        storage::{MemoryStorage, StorageLayout},
+    };
+
+    /// a session on a local port without DHT
+    async fn session(peer_id: u8) -> (TorrentServer, SocketAddr) {
+        let mut server = TorrentServer::new(SessionSettings {
+            listen_port: 0,
+            peer_id: [peer_id; 20],
+            dht: None,
+            ..Default::default()
+        });
+        let port = server.listen().await.unwrap().port();
+        (server, SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    /// an http tracker on a local port answering every announce with `peer`
+    async fn tracker(peer: SocketAddr) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut req = vec![];
+                let mut buf = [0; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => req.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+                body.extend(crate::peer::Peer::new(peer).to_compact());
+                body.push(b'e');
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        url
+    }
+
+    /// the handshake a session answers `info_hash` with, `None` when it
+    /// drops the connection
+    async fn answer(addr: SocketAddr, info_hash: InfoHash) -> Option<Handshake> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let ours = Handshake::new(info_hash, [9; 20]);
+        stream.write_all(&ours.to_bytes()).await.unwrap();
+        read_handshake(&mut stream).await.ok()
+    }
+
+    #[tokio::test]
+    async fn peers_are_routed_by_info_hash_and_served_from_verified_pieces() {
+        let dir = std::env::temp_dir().join(format!("torrentwork-server-{}", std::process::id()));
+        let root = dir.join("d");
+        fs::create_dir_all(&root).unwrap();
+        let content = |n: usize, k: usize| (0..n).map(|i| (i * k % 251) as u8).collect::<Vec<u8>>();
+        fs::write(root.join("a"), content(40000, 3)).unwrap();
+        fs::write(root.join("b"), content(30000, 7)).unwrap();
+
+        let (mut seeder, seeder_addr) = session(1).await;
+        let bytes = TorrentBuilder::new(&root)
+            .version(TorrentVersion::Hybrid)
+            .piece_length(16384)
+            .announce(&tracker(seeder_addr).await)
+            .build()
+            .unwrap();
+        let mut tf = TorrentFile::from_bytes(bytes).unwrap();
+        tf.set_storage_path(&dir).unwrap();
+        let info_hash = tf.info_hash;
+        let v2 = tf.info_hash_v2.unwrap().truncated();
+
+        // the seeder claims every piece, its files are hashed when it starts
+        let pieces = StorageLayout::from_torrent(&tf.meta_data)
+            .unwrap()
+            .piece_count();
+        let mut have = Bitfield::new(pieces);
+        (0..pieces).for_each(|i| have.set(i));
+        let resume = ResumeData {
+            info_hash,
+            info_hash_v2: tf.info_hash_v2,
+            name: "d".to_string(),
+            save_path: dir.clone(),
+            pieces: have,
+            file_priorities: vec![],
+            file_sizes: vec![],
+            peers: vec![],
+            trackers: vec![],
+            total_uploaded: 0,
+            total_downloaded: 0,
+            paused: false,
+        };
+        seeder.add_torrent_with_resume(tf.clone(), resume).unwrap();
+        let wait = Duration::from_secs(30);
+        timeout(wait, async {
+            while seeder.torrent(&info_hash).unwrap().status() != DownloadStatus::Seeding {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // both the v1 hash and the truncated v2 hash reach the torrent
+        for h in [info_hash, v2] {
+            let theirs = answer(seeder_addr, h).await.unwrap();
+            assert_eq!((theirs.info_hash, theirs.peer_id), (h, [1; 20]));
+        }
+        assert!(answer(seeder_addr, InfoHash::new([5; 20])).await.is_none());
+
+        let (mut leecher, _) = session(2).await;
+        leecher
+            .add_torrent_with_storage(tf, Box::new(MemoryStorage::new()))
+            .unwrap();
+        timeout(wait, async {
+            while leecher.torrent(&info_hash).unwrap().status() != DownloadStatus::Seeding {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(seeder.torrent(&info_hash).unwrap().uploaded() >= 70000);
+        leecher.shutdown().await;
+        seeder.shutdown().await;
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
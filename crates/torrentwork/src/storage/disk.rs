@@ -0,0 +1,263 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use super::{FileEntry, Storage};
+
+/// zeros written at a time when preallocating
+const PREALLOCATE_CHUNK: usize = 1024 * 1024;
+/// files a backend keeps open at once unless told otherwise
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// how the disk backend reserves space for the torrent's files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    /// files are created on first write at their full length, holes take
+    /// no space on filesystems that support them
+    #[default]
+    Sparse,
+    /// every file is filled with zeros up front, fails early when the
+    /// disk is too small and avoids fragmentation
+    Preallocate,
+}
+
+/// stores the files below a directory on the local filesystem
+#[derive(Debug)]
+pub struct DiskStorage {
+    root: PathBuf,
+    allocation: Allocation,
+    files: Vec<FileEntry>,
+    /// open file handles, by file index
+    handles: HashMap<usize, fs::File>,
+    /// indices of the open files, least recently used first
+    recent: VecDeque<usize>,
+    max_open_files: usize,
+    /// files already grown to their full length
+    sized: HashSet<usize>,
+}
+
+impl DiskStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            allocation: Allocation::default(),
+            files: vec![],
+            handles: HashMap::new(),
+            recent: VecDeque::new(),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            sized: HashSet::new(),
+        }
+    }
+    pub fn with_allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = allocation;
+        self
+    }
+    /// keep at most `n` files open, the least recently used one is closed
+    /// to open another
+    pub fn with_max_open_files(mut self, n: usize) -> Self {
+        self.max_open_files = n.max(1);
+        self
+    }
+    /// the full disk path of file `n`
+    pub fn file_path(&self, n: usize) -> PathBuf {
+        self.root.join(&self.files[n].path)
+    }
+    fn handle(&mut self, n: usize) -> io::Result<&mut fs::File> {
+        if let Some(i) = self.recent.iter().position(|&f| f == n) {
+            self.recent.remove(i);
+        } else {
+            if self.handles.len() >= self.max_open_files
+                && let Some(old) = self.recent.pop_front()
+                && let Some(f) = self.handles.remove(&old)
+            {
+                // what was written has to reach the disk like on `flush`
+                f.sync_data()?;
+            }
+            let path = self.file_path(n);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            self.handles.insert(n, f);
+        }
+        self.recent.push_back(n);
+        Ok(self.handles.get_mut(&n).unwrap())
+    }
+    fn preallocate(&mut self, n: usize) -> io::Result<()> {
+        let length = self.files[n].length;
+        self.sized.insert(n);
+        let f = self.handle(n)?;
+        if f.metadata()?.len() < length {
+            f.set_len(length)?;
+        }
+        let zeros = vec![0; PREALLOCATE_CHUNK];
+        let mut offset = 0u64;
+        let mut buf = vec![0; PREALLOCATE_CHUNK];
+        // keep whatever is already there, only fill what reads as zeros
+        while offset < length {
+            let n = (length - offset).min(PREALLOCATE_CHUNK as u64) as usize;
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(&mut buf[..n])?;
+            if buf[..n] == zeros[..n] {
+                f.seek(SeekFrom::Start(offset))?;
+                f.write_all(&zeros[..n])?;
+            }
+            offset += n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for DiskStorage {
+    fn init(&mut self, files: &[FileEntry]) -> io::Result<()> {
+        // never touch anything outside the root, whoever built the layout
+        for f in files {
+            if !f
+                .path
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsafe file path {:?}", f.path),
+                ));
+            }
+        }
+        self.files = files.to_vec();
+        self.handles.clear();
+        self.recent.clear();
+        self.sized.clear();
+        if self.allocation == Allocation::Preallocate {
+            for n in 0..self.files.len() {
+                if !self.files[n].padding {
+                    self.preallocate(n)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn read_at(&mut self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.handles.contains_key(&file) && !self.file_path(file).exists() {
+            return Ok(0);
+        }
+        let f = self.handle(file)?;
+        f.seek(SeekFrom::Start(offset))?;
+        let mut n = 0;
+        while n < buf.len() {
+            match f.read(&mut buf[n..])? {
+                0 => break,
+                m => n += m,
+            }
+        }
+        Ok(n)
+    }
+    fn write_at(&mut self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        let length = self.files[file].length;
+        let first_write = self.sized.insert(file);
+        let f = self.handle(file)?;
+        // reads leave files alone, the first write gives them their full size
+        if first_write && f.metadata()?.len() < length {
+            f.set_len(length)?;
+        }
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(data)
+    }
+    fn file_info(&mut self, file: usize) -> io::Result<Option<(u64, i64)>> {
+        let meta = match fs::metadata(self.file_path(file)) {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some((0, 0))),
+            Err(e) => return Err(e),
+        };
+        let mtime = match meta.modified()?.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(_) => 0,
+        };
+        Ok(Some((meta.len(), mtime)))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        // empty files are created here as no block ever touches them
+        for n in 0..self.files.len() {
+            if self.files[n].length == 0 && !self.files[n].padding {
+                self.handle(n)?;
+            }
+        }
+        for f in self.handles.values_mut() {
+            f.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// move the files of a torrent from below `from` to below `to`, copying
+/// where a rename is not possible. files that do not exist are skipped and
+/// directories left empty below `from` are removed.
+pub fn move_files(files: &[FileEntry], from: &Path, to: &Path) -> io::Result<()> {
+    for f in files.iter().filter(|f| !f.padding) {
+        let src = from.join(&f.path);
+        if !src.is_file() {
+            continue;
+        }
+        let dst = to.join(&f.path);
+        if let Some(dir) = dst.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if fs::rename(&src, &dst).is_err() {
+            fs::copy(&src, &dst)?;
+            fs::remove_file(&src)?;
+        }
+    }
+    for f in files {
+        let mut dir = from.join(&f.path);
+        while dir.pop() && dir.starts_with(from) && dir != from {
+            if fs::remove_dir(&dir).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_files_are_bounded() {
+        let root = std::env::temp_dir().join(format!("torrentwork-pool-{}", std::process::id()));
+        let files: Vec<FileEntry> = (0..5)
+            .map(|n| FileEntry {
+                path: PathBuf::from(format!("f{}", n)),
+                length: 4,
+                offset: n * 4,
+                padding: false,
+            })
+            .collect();
+        let mut disk = DiskStorage::new(&root).with_max_open_files(2);
+        disk.init(&files).unwrap();
+        for n in 0..5 {
+            disk.write_at(n, 0, &[n as u8; 4]).unwrap();
+            assert!(disk.handles.len() <= 2);
+        }
+        // the most recently used files stay open
+        assert_eq!(disk.recent, [3, 4]);
+        disk.read_at(3, 0, &mut [0; 4]).unwrap();
+        assert_eq!(disk.recent, [4, 3]);
+        for n in 0..5 {
+            let mut buf = [0; 4];
+            assert_eq!(disk.read_at(n, 0, &mut buf).unwrap(), 4);
+            assert_eq!(buf, [n as u8; 4]);
+        }
+        assert_eq!(disk.recent, [3, 4]);
+        disk.flush().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
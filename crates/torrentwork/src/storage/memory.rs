@@ -0,0 +1,57 @@
+use std::io;
+
+use super::{FileEntry, Storage};
+
+/// keeps every file in memory, for tests and short-lived transfers
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    files: Vec<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// the content of file `n`, zeros where nothing was written
+    pub fn file(&self, n: usize) -> Option<&[u8]> {
+        self.files.get(n).map(|f| f.as_slice())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn init(&mut self, files: &[FileEntry]) -> io::Result<()> {
+        self.files = files
+            .iter()
+            .map(|f| match f.padding {
+                true => vec![],
+                false => vec![0; f.length as usize],
+            })
+            .collect();
+        Ok(())
+    }
+    fn read_at(&mut self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let f = match self.files.get(file) {
+            Some(f) => f,
+            None => return Ok(0),
+        };
+        let start = (offset as usize).min(f.len());
+        let n = buf.len().min(f.len() - start);
+        buf[..n].copy_from_slice(&f[start..start + n]);
+        Ok(n)
+    }
+    fn write_at(&mut self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        let f = match self.files.get_mut(file) {
+            Some(f) => f,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+        };
+        let end = offset as usize + data.len();
+        if end > f.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the file",
+            ));
+        }
+        f[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,618 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    net::SocketAddr,
+    ops::Range,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bitfield::Bitfield,
+    error::{Error, MetainfoError, StorageError},
+    merkle::{self, MerkleHash},
+    torrent::Torrent,
+};
+
+pub mod discard;
+pub mod disk;
+pub mod memory;
+pub mod path;
+
+pub use discard::DiscardStorage;
+pub use disk::{Allocation, DiskStorage};
+pub use memory::MemoryStorage;
+
+/// the callback function type reporting (checked pieces, total pieces) during a recheck.
+pub type FnRecheckProgressCallBack = dyn Fn(usize, usize) + Send + Sync;
+/// hashing workers used by a recheck unless told otherwise
+pub const DEFAULT_RECHECK_THREADS: usize = 4;
+
+/// a file of the torrent laid out in the concatenated piece space
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// path relative to the storage root, starting with the torrent name
+    /// for multi-file torrents
+    pub path: PathBuf,
+    pub length: u64,
+    /// offset of the first byte in the piece space
+    pub offset: u64,
+    /// BEP 47 padding, never written to disk and read back as zeros
+    pub padding: bool,
+}
+
+/// a contiguous range of one file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    /// index into `StorageLayout::files`
+    pub file: usize,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// what a piece is checked against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceHash {
+    /// sha1 of the piece, padding included
+    V1([u8; 20]),
+    /// the file's piece layer entry, the merkle root of the piece's 16 KiB
+    /// blocks padded to a full piece
+    V2(MerkleHash),
+    /// the "pieces root" of a file of one piece
+    V2File(MerkleHash),
+}
+
+impl PieceHash {
+    /// whether `data`, a piece of a torrent with `piece_length` byte
+    /// pieces, hashes to this. v2 hashes cover only the file's bytes, not
+    /// the padding aligning the next file.
+    pub fn matches(&self, data: &[u8], piece_length: u64) -> bool {
+        match self {
+            PieceHash::V1(h) => Sha1::digest(data).as_slice() == h,
+            PieceHash::V2(h) => {
+                let leaves = merkle::block_hashes(data);
+                merkle::piece_layer(&leaves, piece_length as usize).first() == Some(h)
+            }
+            PieceHash::V2File(root) => merkle::file_root(&merkle::block_hashes(data)) == *root,
+        }
+    }
+}
+
+/// how the pieces of a torrent map onto its files
+#[derive(Debug, Clone)]
+pub struct StorageLayout {
+    pub files: Vec<FileEntry>,
+    pub piece_length: u64,
+    pub total_length: u64,
+    /// hash of every piece, v1 hashes for v1 and hybrid torrents
+    pub piece_hashes: Vec<PieceHash>,
+}
+
+impl StorageLayout {
+    /// the file layout of a torrent. pure v2 torrents align every file to a
+    /// piece boundary, the gaps become padding files.
+    pub fn from_torrent(t: &Torrent) -> Result<Self, Error> {
+        if !t.is_v1() {
+            return Self::from_v2(t);
+        }
+        if t.info.piece_length <= 0 || !t.info.pieces.len().is_multiple_of(20) {
+            return Err(
+                MetainfoError::invalid("piece length", "malformed piece length or pieces").into(),
+            );
+        }
+        let mut files = vec![];
+        let mut offset = 0u64;
+        let mut push = |path: PathBuf, length: i64, padding: bool| {
+            let length = length.max(0) as u64;
+            files.push(FileEntry {
+                path,
+                length,
+                offset,
+                padding,
+            });
+            offset += length;
+        };
+        let paths = path::file_paths(t)?;
+        match &t.info.files {
+            Some(list) => {
+                for (f, p) in list.iter().zip(paths) {
+                    push(p, f.length, f.is_padding());
+                }
+            }
+            None => {
+                for p in paths {
+                    push(p, t.info.length.unwrap_or_default(), false);
+                }
+            }
+        }
+        let piece_hashes: Vec<PieceHash> = t
+            .info
+            .pieces
+            .chunks_exact(20)
+            .map(|h| PieceHash::V1(<[u8; 20]>::try_from(h).unwrap_or_default()))
+            .collect();
+        let piece_length = t.info.piece_length as u64;
+        if piece_hashes.len() as u64 != offset.div_ceil(piece_length) {
+            return Err(MetainfoError::invalid(
+                "pieces",
+                format!("{} piece hashes for {} bytes", piece_hashes.len(), offset),
+            )
+            .into());
+        }
+        Ok(Self {
+            files,
+            piece_length,
+            total_length: offset,
+            piece_hashes,
+        })
+    }
+    fn from_v2(t: &Torrent) -> Result<Self, Error> {
+        let tree = match &t.info.file_tree {
+            Some(tree) if t.is_v2() => tree,
+            _ => return Err(MetainfoError::NoPieces.into()),
+        };
+        if t.info.piece_length < merkle::MERKLE_BLOCK_SIZE as i64
+            || !(t.info.piece_length as u64).is_power_of_two()
+        {
+            return Err(MetainfoError::invalid("piece length", "malformed piece length").into());
+        }
+        let piece_length = t.info.piece_length as u64;
+        let mut files = vec![];
+        let mut piece_hashes = vec![];
+        let mut offset = 0u64;
+        for (f, path) in tree.iter().zip(path::file_paths(t)?) {
+            let length = f.length.max(0) as u64;
+            let gap = offset.next_multiple_of(piece_length) - offset;
+            if gap > 0 && length > 0 {
+                files.push(FileEntry {
+                    path: PathBuf::from(".pad").join(gap.to_string()),
+                    length: gap,
+                    offset,
+                    padding: true,
+                });
+                offset += gap;
+            }
+            files.push(FileEntry {
+                path,
+                length,
+                offset,
+                padding: false,
+            });
+            offset += length;
+            let (root, pieces) = match f.pieces_root {
+                Some(root) if length > 0 => (root, length.div_ceil(piece_length)),
+                _ => continue,
+            };
+            if pieces == 1 {
+                piece_hashes.push(PieceHash::V2File(root));
+                continue;
+            }
+            match t.piece_layer(&root) {
+                Some(layer) if layer.len() as u64 == pieces => {
+                    piece_hashes.extend(layer.iter().map(|h| PieceHash::V2(*h)));
+                }
+                _ => {
+                    return Err(MetainfoError::invalid(
+                        "piece layers",
+                        format!("no layer of {} hashes for {:?}", pieces, f.path),
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(Self {
+            files,
+            piece_length,
+            total_length: offset,
+            piece_hashes,
+        })
+    }
+    pub fn piece_count(&self) -> usize {
+        self.piece_hashes.len()
+    }
+    /// the bytes of piece `i` its hash covers, which leaves out the padding
+    /// after the last piece of a file in v2 torrents
+    pub fn hashed_size(&self, i: u32) -> u64 {
+        let size = self.piece_size(i);
+        match self.piece_hashes.get(i as usize) {
+            Some(PieceHash::V1(_)) | None => size,
+            Some(_) => {
+                let start = i as u64 * self.piece_length;
+                self.files
+                    .iter()
+                    .find(|f| !f.padding && f.offset <= start && start < f.offset + f.length)
+                    .map_or(size, |f| size.min(f.offset + f.length - start))
+            }
+        }
+    }
+    /// length of piece `i`, the last one may be short
+    pub fn piece_size(&self, i: u32) -> u64 {
+        let start = i as u64 * self.piece_length;
+        self.total_length
+            .saturating_sub(start)
+            .min(self.piece_length)
+    }
+    /// the pieces holding any byte of file `i`, none for empty files
+    pub fn file_pieces(&self, i: usize) -> Range<u32> {
+        let f = &self.files[i];
+        if f.length == 0 {
+            return 0..0;
+        }
+        let first = f.offset / self.piece_length;
+        let last = (f.offset + f.length - 1) / self.piece_length;
+        first as u32..last as u32 + 1
+    }
+    /// the file ranges covering `length` bytes at `begin` within piece `i`
+    pub fn map(&self, i: u32, begin: u64, length: u64) -> Result<Vec<FileSlice>, StorageError> {
+        if begin + length > self.piece_size(i) {
+            return Err(StorageError::OutOfRange {
+                piece: i,
+                begin,
+                length,
+            });
+        }
+        let start = i as u64 * self.piece_length + begin;
+        let end = start + length;
+        // the first file ending after `start`
+        let first = self.files.partition_point(|f| f.offset + f.length <= start);
+        let mut slices = vec![];
+        for (n, f) in self.files.iter().enumerate().skip(first) {
+            if f.offset >= end {
+                break;
+            }
+            if f.length == 0 {
+                continue;
+            }
+            let from = start.max(f.offset);
+            let to = end.min(f.offset + f.length);
+            slices.push(FileSlice {
+                file: n,
+                offset: from - f.offset,
+                length: to - from,
+            });
+        }
+        Ok(slices)
+    }
+}
+
+/// result of checking a completed piece
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PieceCheck {
+    Passed,
+    /// the hash did not match, these peers sent blocks of the piece
+    Failed {
+        peers: Vec<SocketAddr>,
+    },
+}
+
+/// where the bytes of a torrent's files live. file indices refer to the
+/// slice passed to `init`, padding files are never read or written.
+pub trait Storage: fmt::Debug + Send {
+    /// called once with the torrent's files before any read or write
+    fn init(&mut self, files: &[FileEntry]) -> io::Result<()>;
+    /// read file `file` at `offset` into `buf`, returning how many bytes
+    /// exist there, missing data is not an error
+    fn read_at(&mut self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    fn write_at(&mut self, file: usize, offset: u64, data: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// size and mtime in seconds of file `file`, zeros when it does not
+    /// exist, `None` when the backend cannot tell and resume data has to
+    /// be rechecked
+    fn file_info(&mut self, _file: usize) -> io::Result<Option<(u64, i64)>> {
+        Ok(None)
+    }
+    /// false for backends that drop data, their pieces are not hash checked
+    fn keeps_data(&self) -> bool {
+        true
+    }
+}
+
+/// maps pieces onto the files of one torrent and verifies them, the bytes
+/// go through a `Storage` backend
+#[derive(Debug)]
+pub struct TorrentStorage {
+    layout: StorageLayout,
+    backend: Box<dyn Storage>,
+    /// peers that sent blocks of pieces not yet verified
+    contributors: HashMap<u32, HashSet<SocketAddr>>,
+}
+
+impl TorrentStorage {
+    pub fn new(layout: StorageLayout, mut backend: Box<dyn Storage>) -> Result<Self, StorageError> {
+        backend.init(&layout.files)?;
+        Ok(Self {
+            layout,
+            backend,
+            contributors: HashMap::new(),
+        })
+    }
+    pub fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+    /// write a block received from `from`
+    pub fn write_block(
+        &mut self,
+        i: u32,
+        begin: u32,
+        data: &[u8],
+        from: SocketAddr,
+    ) -> Result<(), StorageError> {
+        let mut written = 0usize;
+        for s in self.layout.map(i, begin as u64, data.len() as u64)? {
+            let chunk = &data[written..written + s.length as usize];
+            written += s.length as usize;
+            if self.layout.files[s.file].padding {
+                continue;
+            }
+            self.backend.write_at(s.file, s.offset, chunk)?;
+        }
+        self.contributors.entry(i).or_default().insert(from);
+        Ok(())
+    }
+    /// read `length` bytes at `begin` of piece `i`, missing data reads as zeros
+    pub fn read_block(&mut self, i: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+        let mut buf = vec![0; length as usize];
+        let mut read = 0usize;
+        for s in self.layout.map(i, begin as u64, length as u64)? {
+            let chunk = &mut buf[read..read + s.length as usize];
+            read += s.length as usize;
+            if self.layout.files[s.file].padding {
+                continue;
+            }
+            self.backend.read_at(s.file, s.offset, chunk)?;
+        }
+        Ok(buf)
+    }
+    /// read the whole of piece `i`
+    pub fn read_piece(&mut self, i: u32) -> Result<Vec<u8>, StorageError> {
+        let size = self.layout.piece_size(i) as u32;
+        self.read_block(i, 0, size)
+    }
+    /// hash piece `i` against the torrent's v1 or v2 piece hashes
+    pub fn verify_piece(&mut self, i: u32) -> Result<PieceCheck, StorageError> {
+        let expected = match self.layout.piece_hashes.get(i as usize) {
+            Some(h) => *h,
+            None => {
+                return Err(StorageError::OutOfRange {
+                    piece: i,
+                    begin: 0,
+                    length: 0,
+                });
+            }
+        };
+        let peers = self.contributors.remove(&i).unwrap_or_default();
+        if !self.backend.keeps_data() {
+            return Ok(PieceCheck::Passed);
+        }
+        let data = self.read_block(i, 0, self.layout.hashed_size(i) as u32)?;
+        if expected.matches(&data, self.layout.piece_length) {
+            Ok(PieceCheck::Passed)
+        } else {
+            let mut peers: Vec<SocketAddr> = peers.into_iter().collect();
+            peers.sort();
+            Ok(PieceCheck::Failed { peers })
+        }
+    }
+    /// size and mtime of every file, `None` when the backend cannot tell
+    pub fn file_sizes(&mut self) -> Result<Option<Vec<(u64, i64)>>, StorageError> {
+        let mut sizes = vec![];
+        for n in 0..self.layout.files.len() {
+            match self.backend.file_info(n)? {
+                Some(info) => sizes.push(info),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(sizes))
+    }
+    /// hash every piece with up to `threads` workers, reporting progress as
+    /// (checked pieces, total pieces), and return the pieces that are intact.
+    /// reads go through the backend one at a time, hashing runs in parallel.
+    pub fn recheck(
+        &mut self,
+        threads: usize,
+        progress: &FnRecheckProgressCallBack,
+    ) -> Result<Bitfield, StorageError> {
+        let total = self.layout.piece_count();
+        let hashes = self.layout.piece_hashes.clone();
+        let piece_length = self.layout.piece_length;
+        if !self.backend.keeps_data() {
+            return Ok(Bitfield::new(total));
+        }
+        let next = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        let storage = Mutex::new(&mut *self);
+        let results: Vec<Result<Vec<u32>, StorageError>> = thread::scope(|s| {
+            let handles: Vec<_> = (0..threads.clamp(1, total.max(1)))
+                .map(|_| {
+                    let (next, done, storage, hashes) = (&next, &done, &storage, &hashes);
+                    s.spawn(move || {
+                        let mut good = vec![];
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            if i >= total {
+                                return Ok(good);
+                            }
+                            let data = match storage.lock() {
+                                Ok(mut st) => {
+                                    let size = st.layout.hashed_size(i as u32) as u32;
+                                    st.read_block(i as u32, 0, size)?
+                                }
+                                Err(_) => return Err(StorageError::WorkerPanicked),
+                            };
+                            if hashes[i].matches(&data, piece_length) {
+                                good.push(i as u32);
+                            }
+                            let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+                            progress(n, total);
+                        }
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| match h.join() {
+                    Ok(r) => r,
+                    Err(_) => Err(StorageError::WorkerPanicked),
+                })
+                .collect()
+        });
+        let mut have = Bitfield::new(total);
+        for r in results {
+            for i in r? {
+                have.set(i as usize);
+            }
+        }
+        Ok(have)
+    }
+    /// give up the backend, for the next run of the torrent
+    pub fn into_backend(self) -> Box<dyn Storage> {
+        self.backend
+    }
+    /// flush written data to the backend
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        Ok(self.backend.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, length: u64, offset: u64, padding: bool) -> FileEntry {
+        FileEntry {
+            path: PathBuf::from(path),
+            length,
+            offset,
+            padding,
+        }
+    }
+
+    /// a v1 layout of `files` with 16 byte pieces hashing `content`
+    fn layout(files: Vec<FileEntry>, content: &[u8]) -> StorageLayout {
+        let piece_hashes = content
+            .chunks(16)
+            .map(|p| PieceHash::V1(Sha1::digest(p).into()))
+            .collect();
+        StorageLayout {
+            files,
+            piece_length: 16,
+            total_length: content.len() as u64,
+            piece_hashes,
+        }
+    }
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    #[test]
+    fn map_splits_ranges_across_files() {
+        let files = vec![
+            file("a", 10, 0, false),
+            file("empty", 0, 10, false),
+            file("b", 30, 10, false),
+        ];
+        let l = layout(files, &[0; 40]);
+        let slice = |file, offset, length| FileSlice {
+            file,
+            offset,
+            length,
+        };
+        assert_eq!(
+            l.map(0, 4, 12).unwrap(),
+            vec![slice(0, 4, 6), slice(2, 0, 6)]
+        );
+        assert_eq!(l.map(1, 0, 16).unwrap(), vec![slice(2, 6, 16)]);
+        // the last piece is short
+        assert_eq!(l.piece_size(2), 8);
+        assert_eq!(l.map(2, 0, 8).unwrap(), vec![slice(2, 22, 8)]);
+        assert!(matches!(
+            l.map(2, 4, 8),
+            Err(StorageError::OutOfRange { piece: 2, .. })
+        ));
+        assert_eq!(l.file_pieces(0), 0..1);
+        assert_eq!(l.file_pieces(1), 0..0);
+        assert_eq!(l.file_pieces(2), 0..3);
+    }
+
+    #[test]
+    fn blocks_spanning_files_land_in_each_file() {
+        let content: Vec<u8> = (0..40).collect();
+        let files = vec![file("a", 10, 0, false), file("b", 30, 10, false)];
+        let mut s =
+            TorrentStorage::new(layout(files, &content), Box::new(MemoryStorage::new())).unwrap();
+        for i in 0..3u32 {
+            let piece = &content[i as usize * 16..(i as usize * 16 + 16).min(40)];
+            s.write_block(i, 0, piece, peer(1)).unwrap();
+            assert_eq!(s.verify_piece(i).unwrap(), PieceCheck::Passed);
+        }
+        assert_eq!(s.read_block(0, 8, 4).unwrap(), vec![8, 9, 10, 11]);
+        let mut backend = s.into_backend();
+        let (mut a, mut b) = ([0; 10], [0; 30]);
+        assert_eq!(backend.read_at(0, 0, &mut a).unwrap(), 10);
+        assert_eq!(backend.read_at(1, 0, &mut b).unwrap(), 30);
+        assert_eq!((&a[..], &b[..]), (&content[..10], &content[10..]));
+    }
+
+    #[test]
+    fn padding_is_never_stored_and_reads_as_zeros() {
+        let mut content: Vec<u8> = vec![1; 10];
+        content.extend([0; 6]);
+        content.extend([2; 16]);
+        let files = vec![
+            file("a", 10, 0, false),
+            file(".pad/6", 6, 10, true),
+            file("b", 16, 16, false),
+        ];
+        let mut s =
+            TorrentStorage::new(layout(files, &content), Box::new(MemoryStorage::new())).unwrap();
+        // whatever a peer sends for the padding is dropped
+        let mut piece = vec![1; 10];
+        piece.extend([9; 6]);
+        s.write_block(0, 0, &piece, peer(1)).unwrap();
+        assert_eq!(s.read_piece(0).unwrap(), content[..16]);
+        assert_eq!(s.verify_piece(0).unwrap(), PieceCheck::Passed);
+        let mut backend = s.into_backend();
+        assert_eq!(backend.read_at(1, 0, &mut [0; 6]).unwrap(), 0);
+    }
+
+    #[test]
+    fn failed_piece_names_the_peers_that_sent_it() {
+        let content = [7u8; 32];
+        let files = vec![file("a", 32, 0, false)];
+        let mut s =
+            TorrentStorage::new(layout(files, &content), Box::new(MemoryStorage::new())).unwrap();
+        s.write_block(0, 0, &[7; 8], peer(2)).unwrap();
+        s.write_block(0, 8, &[0; 8], peer(1)).unwrap();
+        s.write_block(1, 0, &[7; 16], peer(3)).unwrap();
+        assert_eq!(
+            s.verify_piece(0).unwrap(),
+            PieceCheck::Failed {
+                peers: vec![peer(1), peer(2)]
+            }
+        );
+        assert_eq!(s.verify_piece(1).unwrap(), PieceCheck::Passed);
+        // the contributors are forgotten once the piece was checked
+        s.write_block(0, 0, &[7; 16], peer(4)).unwrap();
+        assert_eq!(s.verify_piece(0).unwrap(), PieceCheck::Passed);
+        s.write_block(0, 0, &[0; 16], peer(5)).unwrap();
+        assert_eq!(
+            s.verify_piece(0).unwrap(),
+            PieceCheck::Failed {
+                peers: vec![peer(5)]
+            }
+        );
+        assert!(matches!(
+            s.verify_piece(2),
+            Err(StorageError::OutOfRange { piece: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::MetainfoError, file::TorrentFile, torrent::Torrent};
+
+/// longest file or directory name, in bytes, most filesystems allow
+pub const MAX_NAME_LEN: usize = 255;
+
+/// turn one path element from a torrent into a safe file name. `..` and
+/// absolute elements are rejected, empty and `.` elements are dropped
+/// (`None`), separators and characters not allowed on common filesystems
+/// become `_`, and over-long names are shortened keeping the extension.
+pub fn sanitize_component(c: &str) -> Result<Option<String>, MetainfoError> {
+    if c == ".." || c.starts_with('/') || c.starts_with('\\') || has_drive_prefix(c) {
+        return Err(MetainfoError::UnsafePath(c.to_string()));
+    }
+    let name: String = c
+        .chars()
+        .map(|ch| match ch {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            ch if ch.is_control() => '_',
+            ch => ch,
+        })
+        .collect();
+    // windows silently drops trailing dots and spaces
+    let name = name.trim_end_matches([' ', '.']);
+    if name.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(truncate_name(name, MAX_NAME_LEN)))
+}
+
+/// the relative path of every file of a torrent, in `files` order for v1
+/// and hybrid torrents and in file tree order for pure v2 ones. the torrent
+/// name comes first for multi-file torrents, and duplicates are renamed to
+/// `name.1.ext`, `name.2.ext` and so on.
+pub fn file_paths(t: &Torrent) -> Result<Vec<PathBuf>, MetainfoError> {
+    let name = match sanitize_component(t.info.display_name())? {
+        Some(n) => n,
+        None => return Err(MetainfoError::invalid("name", "empty")),
+    };
+    let files: Vec<&[String]> = match (&t.info.files, &t.info.file_tree) {
+        (Some(files), _) => files.iter().map(|f| f.display_path()).collect(),
+        (None, Some(tree)) if !t.is_v1() && TorrentFile::is_multiple_files(t) => {
+            tree.iter().map(|f| &f.path[..]).collect()
+        }
+        _ => return Ok(vec![PathBuf::from(name)]),
+    };
+    let mut used = HashSet::new();
+    let mut dirs = HashSet::new();
+    let mut paths = Vec::with_capacity(files.len());
+    for f in files {
+        let mut path = PathBuf::from(&name);
+        for c in f {
+            if let Some(c) = sanitize_component(c)? {
+                path.push(c);
+            }
+        }
+        if path.as_os_str() == name.as_str() {
+            return Err(MetainfoError::invalid("path", "empty"));
+        }
+        let path = unique_path(path, &used, &dirs);
+        for dir in path.ancestors().skip(1) {
+            dirs.insert(dir.to_path_buf());
+        }
+        used.insert(path.clone());
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// `path`, or the first `stem.N.ext` not already taken by a file or
+/// directory. a directory component that is already a file is renamed the
+/// same way, so later files under the same directory end up together.
+fn unique_path(path: PathBuf, used: &HashSet<PathBuf>, dirs: &HashSet<PathBuf>) -> PathBuf {
+    let mut components: Vec<_> = path.components().collect();
+    let file = match components.pop() {
+        Some(c) => c,
+        None => return path,
+    };
+    let mut dir = PathBuf::new();
+    for c in components {
+        let mut candidate = dir.join(c);
+        let mut n = 1;
+        while used.contains(&candidate) {
+            candidate = numbered(&dir.join(c), n);
+            n += 1;
+        }
+        dir = candidate;
+    }
+    let path = dir.join(file);
+    let taken = |p: &Path| used.contains(p) || dirs.contains(p);
+    let mut candidate = path.clone();
+    let mut n = 1;
+    while taken(&candidate) {
+        candidate = numbered(&path, n);
+        n += 1;
+    }
+    candidate
+}
+
+/// `path` with its last element renamed to `stem.n.ext`
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let suffix = format!(".{}{}", n, ext);
+    let name = format!(
+        "{}{}",
+        truncate_name(&stem, MAX_NAME_LEN.saturating_sub(suffix.len())),
+        suffix
+    );
+    path.with_file_name(name)
+}
+
+/// shorten `name` to at most `max` bytes on a char boundary, keeping a
+/// short extension
+fn truncate_name(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+    let ext = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => &name[i..],
+        _ => "",
+    };
+    let mut end = max.saturating_sub(ext.len());
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], ext)
+}
+
+fn has_drive_prefix(c: &str) -> bool {
+    let b = c.as_bytes();
+    b.len() >= 2 && b[0].is_ascii_alphabetic() && b[1] == b':'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(s: &str) -> String {
+        format!("{}:{}", s.len(), s)
+    }
+
+    fn list(l: &[&str]) -> String {
+        format!("l{}e", l.iter().map(|c| s(c)).collect::<String>())
+    }
+
+    /// a multi-file torrent named `d` holding `files`, each a path and an
+    /// optional "path.utf-8"
+    fn torrent(files: &[(&[&str], Option<&[&str]>)]) -> Torrent {
+        let files: String = files
+            .iter()
+            .map(|(path, utf8)| {
+                let utf8 = utf8.map(|p| format!("{}{}", s("path.utf-8"), list(p)));
+                format!(
+                    "d6:lengthi1e4:path{}{}e",
+                    list(path),
+                    utf8.unwrap_or_default()
+                )
+            })
+            .collect();
+        let buf = format!(
+            "d4:infod5:filesl{}e4:name1:d12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+            files
+        );
+        Torrent::from_bytes(buf.as_bytes()).unwrap()
+    }
+
+    fn paths(files: &[(&[&str], Option<&[&str]>)]) -> Vec<String> {
+        file_paths(&torrent(files))
+            .unwrap()
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn parent_and_absolute_elements_are_rejected() {
+        for c in ["..", "/etc", "\\etc", "C:"] {
+            assert!(matches!(
+                sanitize_component(c),
+                Err(MetainfoError::UnsafePath(_))
+            ));
+        }
+        assert!(matches!(
+            file_paths(&torrent(&[(&["a", "..", "b"], None)])),
+            Err(MetainfoError::UnsafePath(_))
+        ));
+        assert_eq!(sanitize_component(".").unwrap(), None);
+        assert_eq!(
+            sanitize_component("ab:c?").unwrap().as_deref(),
+            Some("ab_c_")
+        );
+    }
+
+    #[test]
+    fn long_names_are_shortened_keeping_the_extension() {
+        let long = format!("{}.mkv", "é".repeat(200));
+        let name = sanitize_component(&long).unwrap().unwrap();
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(name.ends_with("é.mkv"));
+    }
+
+    #[test]
+    fn path_utf8_is_preferred() {
+        assert_eq!(
+            paths(&[(&["x", "raw"], Some(&["y", "utf8"])), (&["z"], None)]),
+            ["d/y/utf8", "d/z"]
+        );
+    }
+
+    #[test]
+    fn duplicate_files_are_numbered() {
+        assert_eq!(
+            paths(&[(&["a.txt"], None), (&["a.txt"], None), (&["a.txt"], None)]),
+            ["d/a.txt", "d/a.1.txt", "d/a.2.txt"]
+        );
+    }
+
+    #[test]
+    fn files_and_directories_of_the_same_name_are_kept_apart() {
+        // a file where a directory already is
+        assert_eq!(
+            paths(&[(&["a", "b"], None), (&["a"], None)]),
+            ["d/a/b", "d/a.1"]
+        );
+        // a directory where a file already is, shared by later files
+        assert_eq!(
+            paths(&[(&["a"], None), (&["a", "b"], None), (&["a", "c"], None)]),
+            ["d/a", "d/a.1/b", "d/a.1/c"]
+        );
+    }
+}
@@ -1,99 +1,468 @@
-use serde::Deserialize;
-use serde::Serialize;
-use serde_bytes::ByteBuf;
-use sha1::{Digest, Sha1};
+use std::collections::HashMap;
 
-use crate::tracker::http::HttpTrackerResponse;
+use bencode::{Dict, Value};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+use crate::{
+    error::MetainfoError,
+    merkle::{self, MerkleHash},
+    tracker::http::HttpTrackerResponse,
+};
+
+#[derive(Clone, Debug, Default)]
 pub struct Torrent {
     pub info: Info,
-    #[serde(default)]
     pub announce: Option<String>,
-    #[serde(default)]
     pub nodes: Option<Vec<Node>>,
-    #[serde(default)]
     pub encoding: Option<String>,
-    #[serde(default)]
     pub httpseeds: Option<Vec<String>>,
-    #[serde(default)]
-    #[serde(rename = "announce-list")]
+    /// "announce-list"
     pub announce_list: Option<Vec<Vec<String>>>,
-    #[serde(default)]
-    #[serde(rename = "creation date")]
+    /// "creation date"
     pub creation_date: Option<i64>,
-    #[serde(rename = "comment")]
     pub comment: Option<String>,
-    #[serde(default)]
-    #[serde(rename = "created by")]
+    /// "created by"
     pub created_by: Option<String>,
+    /// v2 "piece layers", keyed by file "pieces root"
+    pub piece_layers: Option<HashMap<MerkleHash, Vec<MerkleHash>>>,
+}
+
+impl Torrent {
+    /// parse the content of a .torrent file. errors name the key and the
+    /// byte offset of the dictionary holding it.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MetainfoError> {
+        match bencode::decode(buf) {
+            Ok(root) => Self::from_value(&root),
+            Err(e) => Err(MetainfoError::Decode(e.to_string())),
+        }
+    }
+    /// the torrent in a decoded .torrent file, unknown keys are ignored
+    pub fn from_value(root: &Value<'_>) -> Result<Self, MetainfoError> {
+        let d = match root.as_dict() {
+            Some(d) => d,
+            None => return Err(MetainfoError::Decode("not a dictionary".to_string())),
+        };
+        let info = match d.get_dict("info") {
+            Some(info) => info,
+            None => return Err(MetainfoError::MissingInfo),
+        };
+        let mut t = Self {
+            info: Info::from_dict(info)?,
+            announce: optional(d, "announce", text)?,
+            nodes: optional(d, "nodes", |v| list_of(v, node))?,
+            encoding: optional(d, "encoding", text)?,
+            httpseeds: optional(d, "httpseeds", |v| list_of(v, text))?,
+            announce_list: optional(d, "announce-list", |v| {
+                list_of(v, |tier| list_of(tier, text))
+            })?,
+            creation_date: optional(d, "creation date", int)?,
+            comment: optional(d, "comment", text)?,
+            created_by: optional(d, "created by", text)?,
+            piece_layers: None,
+        };
+        t.load_v2(d)?;
+        Ok(t)
+    }
+    /// fill in the BEP 52 fields, the nested "file tree" and the
+    /// binary-keyed "piece layers", and verify each layer against its root.
+    fn load_v2(&mut self, root: &Dict<'_>) -> Result<(), MetainfoError> {
+        if self.info.meta_version != Some(2) {
+            return Ok(());
+        }
+        let tree = match root.get_dict("info").and_then(|i| i.get_dict("file tree")) {
+            Some(t) => t,
+            None => return Err(MetainfoError::invalid("file tree", "missing")),
+        };
+        let mut files = vec![];
+        walk_file_tree(tree, &mut vec![], &mut files)?;
+        let piece_length = self.info.piece_length as usize;
+        let mut layers = HashMap::new();
+        if let Some(pl) = root.get_dict("piece layers") {
+            for (key, value) in pl.iter() {
+                let (root_hash, layer) = match (
+                    <MerkleHash>::try_from(key),
+                    value.as_bytes().and_then(merkle::split_hashes),
+                ) {
+                    (Ok(r), Some(l)) => (r, l),
+                    _ => return Err(MetainfoError::invalid("piece layers", "malformed entry")),
+                };
+                if !merkle::verify_piece_layer(&root_hash, &layer, piece_length) {
+                    return Err(MetainfoError::invalid(
+                        "piece layers",
+                        format!(
+                            "layer does not match pieces root {}",
+                            hex::encode(root_hash)
+                        ),
+                    ));
+                }
+                layers.insert(root_hash, layer);
+            }
+        }
+        // every file spanning more than one piece needs its layer, except in
+        // hybrid torrents which are verified through their v1 hashes and
+        // lack the layers when made from a magnet link's info dictionary
+        for f in files.iter() {
+            if self.info.pieces.is_empty()
+                && f.length as usize > piece_length
+                && let Some(r) = f.pieces_root
+                && !layers.contains_key(&r)
+            {
+                return Err(MetainfoError::invalid(
+                    "piece layers",
+                    format!("missing layer for {:?}", f.path),
+                ));
+            }
+        }
+        self.info.file_tree = Some(files);
+        self.piece_layers = Some(layers);
+        Ok(())
+    }
+    /// has v1 sha1 piece hashes
+    pub fn is_v1(&self) -> bool {
+        !self.info.pieces.is_empty()
+    }
+    /// has a v2 file tree
+    pub fn is_v2(&self) -> bool {
+        self.info.meta_version == Some(2) && self.info.file_tree.is_some()
+    }
+    /// both v1 and v2 metadata, usable by peers of either version
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+    /// the piece layer of a v2 file, `None` for files of at most one piece
+    pub fn piece_layer(&self, pieces_root: &MerkleHash) -> Option<&Vec<MerkleHash>> {
+        self.piece_layers.as_ref()?.get(pieces_root)
+    }
+}
+
+/// flatten a v2 "file tree" into files in tree order, which is sorted by name.
+fn walk_file_tree(
+    dir: &bencode::Dict<'_>,
+    prefix: &mut Vec<String>,
+    out: &mut Vec<TreeFile>,
+) -> Result<(), MetainfoError> {
+    for (name, node) in dir.iter() {
+        let node = match node.as_dict() {
+            Some(n) => n,
+            None => {
+                return Err(MetainfoError::invalid(
+                    "file tree",
+                    format!("malformed node at byte {}", dir.offset()),
+                ));
+            }
+        };
+        let name = match std::str::from_utf8(name) {
+            Ok(n) => n.to_string(),
+            Err(_) => {
+                return Err(MetainfoError::invalid(
+                    "file tree",
+                    format!("name is not utf-8 at byte {}", node.offset()),
+                ));
+            }
+        };
+        prefix.push(name);
+        // a file is a node whose only key is the empty string
+        match node.get("").and_then(|v| v.as_dict()) {
+            Some(attrs) => {
+                let length = match attrs.require_int("length") {
+                    Ok(l) if l >= 0 => l,
+                    _ => {
+                        return Err(MetainfoError::invalid(
+                            "length",
+                            format!("file {:?} has no valid length", prefix),
+                        ));
+                    }
+                };
+                let pieces_root = match attrs.get_bytes("pieces root") {
+                    Some(r) => match <MerkleHash>::try_from(r) {
+                        Ok(r) => Some(r),
+                        Err(_) => {
+                            return Err(MetainfoError::invalid(
+                                "pieces root",
+                                format!("file {:?} has a bad pieces root", prefix),
+                            ));
+                        }
+                    },
+                    None if length > 0 => {
+                        return Err(MetainfoError::invalid(
+                            "pieces root",
+                            format!("file {:?} has no pieces root", prefix),
+                        ));
+                    }
+                    None => None,
+                };
+                out.push(TreeFile {
+                    path: prefix.clone(),
+                    length,
+                    pieces_root,
+                    attr: attrs.get_str("attr").map(|a| a.to_string()),
+                });
+            }
+            None => walk_file_tree(node, prefix, out)?,
+        }
+        prefix.pop();
+    }
+    Ok(())
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct Node(String, i64);
+/// a BEP 5 DHT node of the "nodes" list, host and port
+#[derive(Clone, Debug)]
+pub struct Node(pub String, pub i64);
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug)]
 pub struct File {
+    /// path components, bytes that are not valid UTF-8 are replaced
     pub path: Vec<String>,
+    /// the path in UTF-8 when `path` uses another encoding, "path.utf-8"
+    pub path_utf8: Option<Vec<String>>,
     pub length: i64,
-    #[serde(default)]
     pub md5sum: Option<String>,
-    #[serde(skip)]
+    /// BEP 47 file attributes, "p" marks a padding file
+    pub attr: Option<String>,
     pub tracker_response_list: Option<Vec<HttpTrackerResponse>>,
-    #[serde(skip)]
-    pub info_hash: String,
 }
 
 impl File {
+    fn from_dict(d: &Dict<'_>) -> Result<Self, MetainfoError> {
+        Ok(Self {
+            path: required(d, "path", |v| list_of(v, lossy_text))?,
+            path_utf8: optional(d, "path.utf-8", |v| list_of(v, text))?,
+            length: required(d, "length", int)?,
+            md5sum: optional(d, "md5sum", text)?,
+            attr: optional(d, "attr", text)?,
+            tracker_response_list: None,
+        })
+    }
+    /// padding file inserted to align the next file to a piece boundary
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|a| a.contains('p'))
+    }
+    /// `path.utf-8` if present, else `path`
+    pub fn display_path(&self) -> &[String] {
+        self.path_utf8.as_deref().unwrap_or(&self.path)
+    }
     pub fn set_tracker_response_list(&mut self, l: Option<Vec<HttpTrackerResponse>>) {
         self.tracker_response_list = l;
     }
-    pub fn to_sha1_hash(&self) -> String {
-        // Save the result of sha1(bencode(info)) to send Tracker request.
-        let mut info_bytes = serde_bencode::to_bytes(&self).unwrap();
-        let mut s1 = Sha1::new();
-        s1.update(info_bytes);
-        let mut sha1_hash = hex::encode(s1.finalize().to_vec());
-        sha1_hash
-    }
+}
+
+/// a file of a BitTorrent v2 "file tree"
+#[derive(Clone, Debug)]
+pub struct TreeFile {
+    /// path components below the torrent name
+    pub path: Vec<String>,
+    pub length: i64,
+    /// root of the file's sha256 merkle tree, absent for empty files
+    pub pieces_root: Option<MerkleHash>,
+    pub attr: Option<String>,
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default)]
 pub struct Info {
+    /// bytes that are not valid UTF-8 are replaced
     pub name: String,
-    pub pieces: ByteBuf,
-    #[serde(rename = "piece length")]
+    /// the name in UTF-8 when `name` uses another encoding, "name.utf-8"
+    pub name_utf8: Option<String>,
+    /// concatenated v1 sha1 piece hashes, empty for pure v2 torrents
+    pub pieces: Vec<u8>,
+    /// "piece length"
     pub piece_length: i64,
-    #[serde(default)]
     pub md5sum: Option<String>,
-    #[serde(default)]
     pub length: Option<i64>,
-    #[serde(default)]
     pub files: Option<Vec<File>>,
-    #[serde(default)]
     pub private: Option<u8>,
-    #[serde(default)]
     pub path: Option<Vec<String>>,
-    #[serde(default)]
-    #[serde(rename = "root hash")]
-    pub root_hash: Option<String>,
+    /// "meta version", 2 for v2 and hybrid torrents
+    pub meta_version: Option<i64>,
+    /// the flattened v2 "file tree"
+    pub file_tree: Option<Vec<TreeFile>>,
     pub tracker_response_list: Option<Vec<HttpTrackerResponse>>,
 }
 
 impl Info {
+    fn from_dict(d: &Dict<'_>) -> Result<Self, MetainfoError> {
+        let files = match d.get("files") {
+            Some(v) => {
+                let list = match v.as_list() {
+                    Some(l) => l,
+                    None => return Err(unexpected(d, "files", "a list")),
+                };
+                let mut files = vec![];
+                for f in list.iter() {
+                    match f.as_dict() {
+                        Some(f) => files.push(File::from_dict(f)?),
+                        None => return Err(unexpected(d, "files", "a list of dictionaries")),
+                    }
+                }
+                Some(files)
+            }
+            None => None,
+        };
+        Ok(Self {
+            name: required(d, "name", lossy_text)?,
+            name_utf8: optional(d, "name.utf-8", text)?,
+            pieces: optional(d, "pieces", bytes)?.unwrap_or_default(),
+            piece_length: required(d, "piece length", int)?,
+            md5sum: optional(d, "md5sum", text)?,
+            length: optional(d, "length", int)?,
+            files,
+            private: optional(d, "private", |v| {
+                int(v).and_then(|p| u8::try_from(p).map_err(|_| "0 or 1"))
+            })?,
+            path: optional(d, "path", |v| list_of(v, text))?,
+            meta_version: optional(d, "meta version", int)?,
+            file_tree: None,
+            tracker_response_list: None,
+        })
+    }
+    /// `name.utf-8` if present, else `name`
+    pub fn display_name(&self) -> &str {
+        self.name_utf8.as_deref().unwrap_or(&self.name)
+    }
     pub fn set_tracker_response_list(&mut self, l: Option<Vec<HttpTrackerResponse>>) {
         self.tracker_response_list = l;
     }
     pub fn set_files(&mut self, files: Vec<File>) {
         self.files = Some(files);
     }
-    pub fn to_sha1_hash(&self) -> String {
-        // Save the result of sha1(bencode(info)) to send Tracker request.
-        let mut info_bytes = serde_bencode::to_bytes(&self).unwrap();
-        let mut s1 = Sha1::new();
-        s1.update(info_bytes);
-        let mut sha1_hash = hex::encode(s1.finalize().to_vec());
-        sha1_hash
+}
+
+/// `key` of `d` converted by `f`, which names the expected value when the
+/// key holds something else
+fn required<T>(
+    d: &Dict<'_>,
+    key: &str,
+    f: impl Fn(&Value<'_>) -> Result<T, &'static str>,
+) -> Result<T, MetainfoError> {
+    match optional(d, key, f)? {
+        Some(v) => Ok(v),
+        None => Err(MetainfoError::Decode(format!(
+            "missing {:?} in the dictionary at byte {}",
+            key,
+            d.offset()
+        ))),
+    }
+}
+
+/// like `required`, `None` when `d` has no `key`
+fn optional<T>(
+    d: &Dict<'_>,
+    key: &str,
+    f: impl Fn(&Value<'_>) -> Result<T, &'static str>,
+) -> Result<Option<T>, MetainfoError> {
+    match d.get(key) {
+        Some(v) => f(v)
+            .map(Some)
+            .map_err(|expected| unexpected(d, key, expected)),
+        None => Ok(None),
+    }
+}
+
+fn unexpected(d: &Dict<'_>, key: &str, expected: &str) -> MetainfoError {
+    MetainfoError::Decode(format!(
+        "{:?} in the dictionary at byte {} is not {}",
+        key,
+        d.offset(),
+        expected
+    ))
+}
+
+fn int(v: &Value<'_>) -> Result<i64, &'static str> {
+    v.as_int().ok_or("an integer")
+}
+
+fn bytes(v: &Value<'_>) -> Result<Vec<u8>, &'static str> {
+    v.as_bytes().map(<[u8]>::to_vec).ok_or("a byte string")
+}
+
+fn text(v: &Value<'_>) -> Result<String, &'static str> {
+    v.as_str().map(str::to_string).ok_or("a utf-8 string")
+}
+
+/// a byte string, replacing bytes that are not valid UTF-8
+fn lossy_text(v: &Value<'_>) -> Result<String, &'static str> {
+    match v.as_bytes() {
+        Some(b) => Ok(String::from_utf8_lossy(b).into_owned()),
+        None => Err("a byte string"),
+    }
+}
+
+fn list_of<T>(
+    v: &Value<'_>,
+    f: impl Fn(&Value<'_>) -> Result<T, &'static str>,
+) -> Result<Vec<T>, &'static str> {
+    match v.as_list() {
+        Some(l) => l.iter().map(f).collect(),
+        None => Err("a list"),
+    }
+}
+
+/// a `[host, port]` list
+fn node(v: &Value<'_>) -> Result<Node, &'static str> {
+    match v.as_list().map(|l| (l.len(), l.get(0), l.get(1))) {
+        Some((2, Some(host), Some(port))) => Ok(Node(text(host)?, int(port)?)),
+        _ => Err("a list of host and port"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_torrent_and_skips_unknown_keys() {
+        let buf = b"d8:announce9:udp://a:13:fooi1e13:announce-listll9:udp://a:1el9:udp://b:2ee\
+            4:infod5:filesld6:lengthi3e4:pathl1:xeed4:attr1:p6:lengthi5e4:pathl4:.pad1:5eee\
+            4:name3:d\xff\xfe12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee\
+            5:nodesll4:hosti6881eel2:h2i6882eeee";
+        let t = Torrent::from_bytes(buf).unwrap();
+        assert_eq!(t.announce.as_deref(), Some("udp://a:1"));
+        assert_eq!(
+            t.announce_list,
+            Some(vec![
+                vec!["udp://a:1".to_string()],
+                vec!["udp://b:2".to_string()]
+            ])
+        );
+        assert_eq!(t.info.name, "d\u{fffd}\u{fffd}");
+        assert_eq!((t.info.piece_length, t.info.private), (16, Some(1)));
+        assert!(t.is_v1() && !t.is_v2());
+        let files = t.info.files.unwrap();
+        assert_eq!(files[0].path, ["x"]);
+        assert!(!files[0].is_padding() && files[1].is_padding());
+        let nodes: Vec<(String, i64)> = t.nodes.unwrap().into_iter().map(|n| (n.0, n.1)).collect();
+        assert_eq!(
+            nodes,
+            [("host".to_string(), 6881), ("h2".to_string(), 6882)]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_key_and_offset() {
+        let err = |b: &[u8]| match Torrent::from_bytes(b) {
+            Err(MetainfoError::Decode(s)) => s,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(
+            err(b"d4:infod4:name1:xee"),
+            "missing \"piece length\" in the dictionary at byte 7"
+        );
+        assert_eq!(
+            err(b"d4:infod4:name1:x12:piece length1:xee"),
+            "\"piece length\" in the dictionary at byte 7 is not an integer"
+        );
+        assert_eq!(
+            err(b"d8:announcei1e4:infod4:name1:x12:piece lengthi1eee"),
+            "\"announce\" in the dictionary at byte 0 is not a utf-8 string"
+        );
+        assert_eq!(
+            err(b"d4:infod5:filesld4:pathl1:xeee4:name1:x12:piece lengthi1eee"),
+            "missing \"length\" in the dictionary at byte 16"
+        );
+        assert_eq!(err(b"d4:infod"), "unexpected end of input at byte 8");
+        assert!(matches!(
+            Torrent::from_bytes(b"d8:announce1:xe"),
+            Err(MetainfoError::MissingInfo)
+        ));
     }
 }
@@ -1,17 +1,24 @@
-use std::time::Duration;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
+use bencode::{Dict, Value};
 use reqwest::{Client, StatusCode};
-use serde::{Deserialize, Serialize};
-use serde_bencode::de;
 use url::Url;
 
-use crate::peer::Peer;
+use crate::{
+    error::TrackerError,
+    hash::InfoHash,
+    peer::{COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN, Peer},
+};
 
 const HTTP_REQUEST_TIMEOUT_SEC: u64 = 10;
 
 #[derive(Debug, Clone)]
 pub struct HttpTracker {
-    client: reqwest::Client,
+    /// `None` when the http client could not be set up, every send fails
+    client: Option<reqwest::Client>,
 }
 
 impl HttpTracker {
@@ -21,7 +28,7 @@ impl HttpTracker {
             client: Client::builder()
                 .timeout(Duration::from_secs(HTTP_REQUEST_TIMEOUT_SEC))
                 .build()
-                .unwrap(),
+                .ok(),
         }
     }
     /// http tracker requests
@@ -29,30 +36,51 @@ impl HttpTracker {
         &self,
         url: &str,
         req: HttpTrackerRquest,
-    ) -> Result<HttpTrackerResponse, String> {
-        let curl = Url::parse_with_params(url, req.to_request_params()).unwrap();
-        match self.client.get(curl.to_string()).send().await {
+    ) -> Result<HttpTrackerResponse, TrackerError> {
+        let client = match &self.client {
+            Some(c) => c,
+            None => {
+                return Err(TrackerError::Connection(
+                    "http client unavailable".to_string(),
+                ));
+            }
+        };
+        let mut curl = match Url::parse_with_params(url, req.to_request_params()) {
+            Ok(u) => u,
+            Err(_) => return Err(TrackerError::InvalidUrl(url.to_string())),
+        };
+        // the raw info hash bytes are not valid utf-8, so they are
+        // percent-encoded by hand instead of going through the params above.
+        let query = format!(
+            "{}&info_hash={}",
+            curl.query().unwrap_or_default(),
+            req.info_hash.to_url_encoded()
+        );
+        curl.set_query(Some(&query));
+        match client.get(curl.to_string()).send().await {
             Ok(r) => match r.status() {
-                StatusCode::OK => {
-                    let s = r.text().await.unwrap();
-                    match de::from_str::<HttpTrackerResponse>(&s) {
-                        Ok(thr) => Ok(thr),
-                        Err(e) => Err(format!(
-                            "tracker http response serialization failed {:?}",
-                            e
-                        )),
-                    }
-                }
-                _ => Err(format!("tracker server exception response {:?}", r)),
+                StatusCode::OK => match r.bytes().await {
+                    // compact peer strings are binary, never go through text
+                    Ok(b) => HttpTrackerResponse::from_bytes(&b),
+                    Err(e) => Err(TrackerError::Connection(e.to_string())),
+                },
+                status => Err(TrackerError::HttpStatus(status.as_u16())),
             },
-            Err(e) => Err(format!("tracker server bad request {:?}", e)),
+            Err(e) if e.is_timeout() => Err(TrackerError::Timeout),
+            Err(e) => Err(TrackerError::Connection(e.to_string())),
         }
     }
 }
 
+impl Default for HttpTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HttpTrackerRquest {
-    pub info_hash: String,
+    pub info_hash: InfoHash,
     pub peer_id: String,
     pub port: String,
     pub uploaded: String,
@@ -60,10 +88,13 @@ pub struct HttpTrackerRquest {
     pub left: String,
     pub compact: String,
     pub event: String,
+    /// echoed back once a tracker sent us a `tracker id`
+    pub tracker_id: Option<String>,
 }
 impl HttpTrackerRquest {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        info_hash: String,
+        info_hash: InfoHash,
         peer_id: String,
         port: String,
         uploaded: String,
@@ -72,39 +103,227 @@ impl HttpTrackerRquest {
         compact: String,
         event: String,
     ) -> Self {
-        let req = HttpTrackerRquest {
-            info_hash: info_hash,
-            peer_id: peer_id,
-            port: port,
-            uploaded: uploaded,
-            downloaded: downloaded,
-            left: left,
-            compact: compact,
-            event: event,
-        };
-        req
+        HttpTrackerRquest {
+            info_hash,
+            peer_id,
+            port,
+            uploaded,
+            downloaded,
+            left,
+            compact,
+            event,
+            tracker_id: None,
+        }
     }
     pub fn to_request_params(&self) -> Vec<(&str, String)> {
-        vec![
-            ("info_hash", self.info_hash.clone()),
+        let mut params = vec![
             ("peer_id", self.peer_id.to_string()),
             ("port", self.port.to_string()),
             ("uploaded", self.uploaded.to_string()),
             ("downloaded", self.downloaded.to_string()),
             ("left", self.left.clone()),
             ("compact", self.compact.to_string()),
-            ("event", self.event.to_string()),
-        ]
+        ];
+        // regular re-announces carry no event
+        if !self.event.is_empty() {
+            params.push(("event", self.event.to_string()));
+        }
+        if let Some(id) = &self.tracker_id {
+            params.push(("trackerid", id.clone()));
+        }
+        params
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default)]
 pub struct HttpTrackerResponse {
     pub complete: Option<u64>,
     pub downloaded: Option<u64>,
     pub incomplete: Option<u64>,
     pub interval: Option<u64>,
-    #[serde(rename = "min interval")]
     pub min_interval: Option<u64>,
+    /// peers from `peers` and `peers6`, whatever model the tracker used
     pub peers: Option<Vec<Peer>>,
+    /// the announce failed, no other field is meaningful
+    pub failure_reason: Option<String>,
+    pub warning_message: Option<String>,
+    /// to be sent back as `trackerid` on the next announce
+    pub tracker_id: Option<String>,
+    /// our address as seen by the tracker (BEP 24)
+    pub external_ip: Option<IpAddr>,
+}
+
+impl HttpTrackerResponse {
+    /// parse an announce reply, peers may come in the dictionary model, as a
+    /// BEP 23 compact string or as BEP 7 `peers6`.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, TrackerError> {
+        let root = match bencode::decode(buf) {
+            Ok(v) => v,
+            Err(e) => return Err(TrackerError::InvalidResponse(e.to_string())),
+        };
+        let d = match root.as_dict() {
+            Some(d) => d,
+            None => {
+                return Err(TrackerError::InvalidResponse(
+                    "not a dictionary".to_string(),
+                ));
+            }
+        };
+        let uint = |k: &str| d.get_int(k).and_then(|v| u64::try_from(v).ok());
+        let string = |k: &str| {
+            d.get_bytes(k)
+                .map(|b| String::from_utf8_lossy(b).into_owned())
+        };
+        let mut peers = Vec::new();
+        match d.get("peers") {
+            Some(Value::Bytes(b)) => peers.extend(Peer::from_compact_list(b, COMPACT_PEER_V4_LEN)),
+            Some(Value::List(l)) => peers.extend(
+                l.iter()
+                    .filter_map(|p| p.as_dict())
+                    .filter_map(dictionary_peer),
+            ),
+            _ => {}
+        }
+        if let Some(b) = d.get_bytes("peers6") {
+            peers.extend(Peer::from_compact_list(b, COMPACT_PEER_V6_LEN));
+        }
+        Ok(Self {
+            complete: uint("complete"),
+            downloaded: uint("downloaded"),
+            incomplete: uint("incomplete"),
+            interval: uint("interval"),
+            min_interval: uint("min interval"),
+            peers: if d.contains_key("peers") || d.contains_key("peers6") {
+                Some(peers)
+            } else {
+                None
+            },
+            failure_reason: string("failure reason"),
+            warning_message: string("warning message"),
+            tracker_id: string("tracker id"),
+            external_ip: d.get_bytes("external ip").and_then(|b| match b.len() {
+                4 => <[u8; 4]>::try_from(b).ok().map(IpAddr::from),
+                16 => <[u8; 16]>::try_from(b).ok().map(IpAddr::from),
+                _ => None,
+            }),
+        })
+    }
+}
+
+/// one entry of the dictionary model, trackers may hand out host names in
+/// `ip` which are skipped.
+fn dictionary_peer(d: &Dict) -> Option<Peer> {
+    let ip: IpAddr = d.get_str("ip")?.parse().ok()?;
+    let port = u16::try_from(d.get_int("port")?).ok()?;
+    let mut peer = Peer::new(SocketAddr::new(ip, port));
+    peer.peer_id = d
+        .get_bytes("peer id")
+        .and_then(|b| <[u8; 20]>::try_from(b).ok());
+    Some(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use bencode::List;
+
+    use super::*;
+
+    fn addrs(r: &HttpTrackerResponse) -> Vec<SocketAddr> {
+        r.peers.iter().flatten().map(|p| p.addr).collect()
+    }
+
+    #[test]
+    fn compact_peers_of_both_families_are_read() {
+        let mut d = Dict::new();
+        d.insert("interval", 1800);
+        d.insert("min interval", 60);
+        d.insert("complete", 5);
+        d.insert("incomplete", 2);
+        // a trailing partial entry is ignored
+        d.insert(
+            "peers",
+            Value::bytes(b"\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2\x0a"),
+        );
+        let mut v6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        v6.extend([0; 11]);
+        v6.extend([1, 0xc8, 0xd5]);
+        d.insert("peers6", Value::bytes(v6));
+        let r = HttpTrackerResponse::from_bytes(&Value::from(d).to_bytes()).unwrap();
+        assert_eq!(
+            addrs(&r),
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap(),
+                "[2001:db8::1]:51413".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+        assert_eq!(
+            (r.interval, r.min_interval, r.complete, r.incomplete),
+            (Some(1800), Some(60), Some(5), Some(2))
+        );
+        assert_eq!(r.failure_reason, None);
+    }
+
+    #[test]
+    fn dictionary_model_peers_are_read() {
+        let peer = |ip: &str, port: i64, id: Option<&[u8]>| {
+            let mut p = Dict::new();
+            p.insert("ip", ip);
+            p.insert("port", port);
+            if let Some(id) = id {
+                p.insert("peer id", Value::bytes(id));
+            }
+            p
+        };
+        let mut peers = List::new();
+        peers.push(peer("10.0.0.1", 6881, Some(&[7; 20])));
+        peers.push(peer("::1", 6882, None));
+        // host names and bad ports are skipped
+        peers.push(peer("tracker.example", 6883, None));
+        peers.push(peer("10.0.0.3", 70000, None));
+        let mut d = Dict::new();
+        d.insert("peers", peers);
+        let r = HttpTrackerResponse::from_bytes(&Value::from(d).to_bytes()).unwrap();
+        assert_eq!(
+            addrs(&r),
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:6882".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+        let ids: Vec<_> = r.peers.unwrap().iter().map(|p| p.peer_id).collect();
+        assert_eq!(ids, [Some([7; 20]), None]);
+    }
+
+    #[test]
+    fn failure_warning_tracker_id_and_external_ip_are_read() {
+        let failed =
+            HttpTrackerResponse::from_bytes(b"d14:failure reason12:unregisterede").unwrap();
+        assert_eq!(failed.failure_reason.as_deref(), Some("unregistered"));
+        assert!(failed.peers.is_none());
+        let mut d = Dict::new();
+        d.insert("warning message", "slow down");
+        d.insert("tracker id", "abc");
+        d.insert("external ip", Value::bytes([203, 0, 113, 7]));
+        d.insert("peers", "");
+        let r = HttpTrackerResponse::from_bytes(&Value::from(d).to_bytes()).unwrap();
+        assert_eq!(r.warning_message.as_deref(), Some("slow down"));
+        assert_eq!(r.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(r.external_ip, Some(IpAddr::from([203, 0, 113, 7])));
+        assert_eq!(r.peers.map(|p| p.len()), Some(0));
+        let mut d = Dict::new();
+        d.insert("external ip", Value::bytes([0; 16]));
+        let r = HttpTrackerResponse::from_bytes(&Value::from(d).to_bytes()).unwrap();
+        assert_eq!(r.external_ip, Some(IpAddr::from([0u8; 16])));
+    }
+
+    #[test]
+    fn malformed_replies_are_errors() {
+        for b in [&b"not bencode"[..], b"li1ee"] {
+            assert!(matches!(
+                HttpTrackerResponse::from_bytes(b),
+                Err(TrackerError::InvalidResponse(_))
+            ));
+        }
+    }
 }
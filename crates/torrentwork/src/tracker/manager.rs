@@ -0,0 +1,582 @@
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use crate::{
+    error::TrackerError,
+    event::{Event, Events},
+    hash::InfoHash,
+    peer::Peer,
+    peer::local_peer_id,
+};
+
+use super::{
+    http::{HttpTracker, HttpTrackerResponse, HttpTrackerRquest},
+    upd::{TrackerUDPRquest, UDPTracker, UdpEvent},
+};
+
+/// re-announce interval used when a tracker does not send one
+const DEFAULT_ANNOUNCE_INTERVAL_SEC: u64 = 30 * 60;
+/// lower bound on any interval a tracker asks for
+const MIN_ANNOUNCE_INTERVAL_SEC: u64 = 60;
+/// first retry after a failed announce, doubled on every further failure
+const TRACKER_RETRY_BASE_SEC: u64 = 15;
+/// longest wait between retries of a failing tracker
+const TRACKER_RETRY_MAX_SEC: u64 = 60 * 60;
+/// udp retransmissions per announce, a dead tracker should not hold up its
+/// tier for the full BEP 15 schedule
+const UDP_ANNOUNCE_RETRIES: u32 = 2;
+
+/// announce event, sent at the matching lifecycle point of a torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// a regular re-announce
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// the http tracker `event` parameter, empty for regular announces
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::None => "",
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+impl From<AnnounceEvent> for UdpEvent {
+    fn from(e: AnnounceEvent) -> Self {
+        match e {
+            AnnounceEvent::None => UdpEvent::None,
+            AnnounceEvent::Started => UdpEvent::Started,
+            AnnounceEvent::Completed => UdpEvent::Completed,
+            AnnounceEvent::Stopped => UdpEvent::Stopped,
+        }
+    }
+}
+
+/// transfer totals reported with every announce
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AnnounceStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+/// where a tracker stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerState {
+    /// not contacted yet
+    Idle,
+    /// the last announce succeeded
+    Working,
+    /// the last announce failed, retried after a backoff
+    Failed,
+}
+
+/// the status of one tracker, as exposed to callers
+#[derive(Debug, Clone)]
+pub struct TrackerStatus {
+    pub url: String,
+    /// index of the tier the tracker belongs to
+    pub tier: usize,
+    pub state: TrackerState,
+    /// when the tracker is due for its next announce
+    pub next_announce: Instant,
+    pub last_announce: Option<Instant>,
+    pub interval: Duration,
+    pub min_interval: Option<Duration>,
+    /// consecutive failed announces
+    pub failures: u32,
+    pub last_error: Option<TrackerError>,
+    pub warning_message: Option<String>,
+    pub tracker_id: Option<String>,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    /// peers received on the last successful announce
+    pub peers: usize,
+    /// a started event was accepted, so stopped and completed are owed
+    started: bool,
+    completed_sent: bool,
+}
+
+impl TrackerStatus {
+    fn new(url: String, tier: usize, now: Instant) -> Self {
+        Self {
+            url,
+            tier,
+            state: TrackerState::Idle,
+            next_announce: now,
+            last_announce: None,
+            interval: Duration::from_secs(DEFAULT_ANNOUNCE_INTERVAL_SEC),
+            min_interval: None,
+            failures: 0,
+            last_error: None,
+            warning_message: None,
+            tracker_id: None,
+            seeders: None,
+            leechers: None,
+            peers: 0,
+            started: false,
+            completed_sent: false,
+        }
+    }
+    fn succeeded(&mut self, r: &HttpTrackerResponse, now: Instant) {
+        let floor = |s: u64| Duration::from_secs(s.max(MIN_ANNOUNCE_INTERVAL_SEC));
+        self.state = TrackerState::Working;
+        self.failures = 0;
+        self.last_error = None;
+        self.last_announce = Some(now);
+        self.interval = floor(r.interval.unwrap_or(DEFAULT_ANNOUNCE_INTERVAL_SEC));
+        self.min_interval = r.min_interval.map(floor);
+        self.next_announce = now + self.interval;
+        self.warning_message = r.warning_message.clone();
+        if r.tracker_id.is_some() {
+            self.tracker_id = r.tracker_id.clone();
+        }
+        self.seeders = r.complete;
+        self.leechers = r.incomplete;
+        self.peers = r.peers.as_ref().map_or(0, |p| p.len());
+    }
+    fn failed(&mut self, e: TrackerError, now: Instant) {
+        self.state = TrackerState::Failed;
+        self.failures += 1;
+        self.last_error = Some(e);
+        let backoff = TRACKER_RETRY_BASE_SEC.saturating_mul(1 << (self.failures - 1).min(16));
+        self.next_announce = now + Duration::from_secs(backoff.min(TRACKER_RETRY_MAX_SEC));
+    }
+}
+
+/// BEP 12 multitracker announcer. tiers are shuffled once, within a tier the
+/// first tracker that answers is moved to the front and announced to until
+/// it fails, later tiers are only tried when every tracker of the earlier
+/// tiers failed.
+#[derive(Debug)]
+pub struct TrackerManager {
+    tiers: Vec<Vec<TrackerStatus>>,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    port: u16,
+    /// announce key identifying this client to trackers
+    key: u32,
+    /// announce to one tracker of every tier instead of stopping at the first
+    announce_to_all_tiers: bool,
+    /// `start` was called and `stop` was not since, trackers not told yet
+    /// get a started event once they are due
+    running: bool,
+    /// the torrent finished, trackers not told yet get a completed event
+    completed: bool,
+    /// where replies and errors are reported
+    events: Option<Events>,
+    http: HttpTracker,
+    udp: UDPTracker,
+}
+
+impl TrackerManager {
+    pub fn new(tiers: Vec<Vec<String>>, info_hash: InfoHash, port: u16) -> Self {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let tiers = tiers
+            .into_iter()
+            .filter(|t| !t.is_empty())
+            .enumerate()
+            .map(|(i, mut t)| {
+                t.shuffle(&mut rng);
+                t.into_iter()
+                    .map(|url| TrackerStatus::new(url, i, now))
+                    .collect()
+            })
+            .collect();
+        Self {
+            tiers,
+            info_hash,
+            peer_id: local_peer_id(),
+            port,
+            key: rand::random(),
+            announce_to_all_tiers: false,
+            running: false,
+            completed: false,
+            events: None,
+            http: HttpTracker::new(),
+            udp: UDPTracker::new().with_max_retries(UDP_ANNOUNCE_RETRIES),
+        }
+    }
+    /// announce with `peer_id` instead of the process-wide one
+    pub fn with_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+    /// report every reply and error to `events`
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = Some(events);
+        self
+    }
+    /// keep one working tracker in every tier, as most clients do
+    pub fn with_announce_to_all_tiers(mut self, all: bool) -> Self {
+        self.announce_to_all_tiers = all;
+        self
+    }
+    /// the status of every tracker, in announce order
+    pub fn status(&self) -> Vec<TrackerStatus> {
+        self.tiers.iter().flatten().cloned().collect()
+    }
+    /// some tracker saw the started event and no stopped event since
+    pub fn is_started(&self) -> bool {
+        self.tiers.iter().flatten().any(|t| t.started)
+    }
+    /// `start` was called, whether or not a tracker answered
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+    /// the earliest time `tick` has something to do
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.tiers.iter().flatten().map(|t| t.next_announce).min()
+    }
+    /// pull the next announce of working trackers forward to their
+    /// `min interval`, for when we are short of peers
+    pub fn request_peers(&mut self) {
+        for t in self.tiers.iter_mut().flatten() {
+            if let (TrackerState::Working, Some(last), Some(min)) =
+                (t.state, t.last_announce, t.min_interval)
+            {
+                t.next_announce = t.next_announce.min(last + min);
+            }
+        }
+    }
+    /// send the started event to trackers that are due, the others get it
+    /// once their backoff elapsed. when `stats.left` is zero we are seeding
+    /// and completed will never be sent.
+    pub async fn start(&mut self, stats: AnnounceStats) -> Vec<Peer> {
+        self.running = true;
+        if stats.left == 0 {
+            self.completed = true;
+        }
+        self.announce(stats, false).await
+    }
+    /// the download finished, tell the trackers right away
+    pub async fn complete(&mut self, stats: AnnounceStats) -> Vec<Peer> {
+        self.completed = true;
+        self.announce(stats, true).await
+    }
+    /// re-announce to trackers whose interval or backoff elapsed
+    pub async fn tick(&mut self, stats: AnnounceStats) -> Vec<Peer> {
+        self.announce(stats, false).await
+    }
+    /// send the stopped event to every tracker that saw us start
+    pub async fn stop(&mut self, stats: AnnounceStats) {
+        for i in 0..self.tiers.len() {
+            for j in 0..self.tiers[i].len() {
+                if !self.tiers[i][j].started {
+                    continue;
+                }
+                let _ = self.announce_one(i, j, AnnounceEvent::Stopped, stats).await;
+                let t = &mut self.tiers[i][j];
+                t.started = false;
+                // due for started right away when the torrent runs again
+                t.next_announce = Instant::now();
+            }
+        }
+        self.running = false;
+    }
+    async fn announce(&mut self, stats: AnnounceStats, force: bool) -> Vec<Peer> {
+        let mut peers = Vec::new();
+        for i in 0..self.tiers.len() {
+            if let Some(p) = self.announce_tier(i, stats, force).await {
+                peers.extend(p);
+                if !self.announce_to_all_tiers {
+                    break;
+                }
+            }
+        }
+        for p in peers.iter_mut() {
+            p.info_hash = self.info_hash;
+        }
+        peers
+    }
+    /// announce to the first tracker of the tier that answers, `None` when
+    /// the whole tier failed and the next one should be tried.
+    async fn announce_tier(
+        &mut self,
+        i: usize,
+        stats: AnnounceStats,
+        force: bool,
+    ) -> Option<Vec<Peer>> {
+        let now = Instant::now();
+        let front = &self.tiers[i][0];
+        if !force && front.state == TrackerState::Working && front.next_announce > now {
+            return Some(vec![]);
+        }
+        for j in 0..self.tiers[i].len() {
+            let t = &self.tiers[i][j];
+            if !force && t.next_announce > now {
+                continue;
+            }
+            let event = if !t.started {
+                AnnounceEvent::Started
+            } else if self.completed && !t.completed_sent {
+                AnnounceEvent::Completed
+            } else {
+                AnnounceEvent::None
+            };
+            if let Ok(peers) = self.announce_one(i, j, event, stats).await {
+                let t = &mut self.tiers[i][j];
+                t.started = true;
+                // a seed that starts never owes a completed event
+                t.completed_sent |= self.completed;
+                let t = self.tiers[i].remove(j);
+                self.tiers[i].insert(0, t);
+                return Some(peers);
+            }
+        }
+        None
+    }
+    async fn announce_one(
+        &mut self,
+        i: usize,
+        j: usize,
+        event: AnnounceEvent,
+        stats: AnnounceStats,
+    ) -> Result<Vec<Peer>, TrackerError> {
+        let url = self.tiers[i][j].url.clone();
+        let tracker_id = self.tiers[i][j].tracker_id.clone();
+        let res = match self.send(&url, event, stats, tracker_id).await {
+            Ok(r) => match &r.failure_reason {
+                Some(reason) => Err(TrackerError::Failure(reason.clone())),
+                None => Ok(r),
+            },
+            Err(e) => Err(e),
+        };
+        if let Some(events) = &self.events {
+            events.send(match &res {
+                Ok(r) => Event::TrackerReply {
+                    info_hash: self.info_hash,
+                    url: url.clone(),
+                    peers: r.peers.as_ref().map_or(0, |p| p.len()),
+                },
+                Err(e) => Event::TrackerError {
+                    info_hash: self.info_hash,
+                    url: url.clone(),
+                    error: e.clone(),
+                },
+            });
+        }
+        let now = Instant::now();
+        let t = &mut self.tiers[i][j];
+        match res {
+            Ok(r) => {
+                t.succeeded(&r, now);
+                Ok(r.peers.unwrap_or_default())
+            }
+            Err(e) => {
+                t.failed(e.clone(), now);
+                Err(e)
+            }
+        }
+    }
+    async fn send(
+        &self,
+        url: &str,
+        event: AnnounceEvent,
+        stats: AnnounceStats,
+        tracker_id: Option<String>,
+    ) -> Result<HttpTrackerResponse, TrackerError> {
+        match url.split("://").next() {
+            Some("udp") => {
+                let req = TrackerUDPRquest {
+                    info_hash: self.info_hash,
+                    peer_id: self.peer_id,
+                    downloaded: stats.downloaded,
+                    left: stats.left,
+                    uploaded: stats.uploaded,
+                    event: event.into(),
+                    key: self.key,
+                    num_want: -1,
+                    port: self.port,
+                };
+                self.udp.announce(url, &req).await.map(Into::into)
+            }
+            Some("http") | Some("https") => {
+                let mut req = HttpTrackerRquest::new(
+                    self.info_hash,
+                    String::from_utf8_lossy(&self.peer_id).to_string(),
+                    self.port.to_string(),
+                    stats.uploaded.to_string(),
+                    stats.downloaded.to_string(),
+                    stats.left.to_string(),
+                    "1".to_string(),
+                    event.as_str().to_string(),
+                );
+                req.tracker_id = tracker_id;
+                self.http.send(url, req).await
+            }
+            _ => Err(TrackerError::UnsupportedScheme(url.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const STATS: AnnounceStats = AnnounceStats {
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+    };
+    /// fails at once, the scheme is not supported
+    const FAILING: &str = "wss://127.0.0.1:1/announce";
+
+    /// an http tracker on a local port answering every announce with one
+    /// peer and the given intervals, records the event of every announce
+    async fn tracker(interval: u64, min_interval: u64) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let events = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&events);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = vec![];
+                let mut buf = [0; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        n => req.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let req = String::from_utf8_lossy(&req);
+                let event = req
+                    .split(['?', '&', ' '])
+                    .find_map(|p| p.strip_prefix("event="))
+                    .unwrap_or_default();
+                seen.lock().unwrap().push(event.to_string());
+                let mut body = format!(
+                    "d8:intervali{}e12:min intervali{}e5:peers6:",
+                    interval, min_interval
+                )
+                .into_bytes();
+                body.extend([10, 0, 0, 1, 0x1a, 0xe1, b'e']);
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+        (url, events)
+    }
+
+    fn manager(tiers: Vec<Vec<&str>>) -> TrackerManager {
+        let tiers = tiers
+            .into_iter()
+            .map(|t| t.into_iter().map(str::to_string).collect())
+            .collect();
+        TrackerManager::new(tiers, InfoHash::new([1; 20]), 6881)
+    }
+
+    fn events(e: &Arc<Mutex<Vec<String>>>) -> Vec<String> {
+        e.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn answering_tracker_moves_to_the_front_of_its_tier() {
+        let (url, seen) = tracker(1800, 120).await;
+        let (backup, backup_seen) = tracker(1800, 120).await;
+        let mut m = manager(vec![vec![FAILING, &url], vec![&backup]]);
+        let peers = m.start(STATS).await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].info_hash, InfoHash::new([1; 20]));
+        let status = m.status();
+        assert_eq!(
+            (status[0].url.as_str(), status[0].state),
+            (url.as_str(), TrackerState::Working)
+        );
+        assert_eq!(status[1].url, FAILING);
+        // a later tier is only asked when the earlier ones failed
+        assert_eq!(events(&seen), ["started"]);
+        assert!(events(&backup_seen).is_empty());
+
+        let mut m = manager(vec![vec![FAILING], vec![&backup]]);
+        assert_eq!(m.start(STATS).await.len(), 1);
+        let status = m.status();
+        assert_eq!(status[0].state, TrackerState::Failed);
+        assert_eq!(status[1].state, TrackerState::Working);
+        assert_eq!(events(&backup_seen), ["started"]);
+    }
+
+    #[tokio::test]
+    async fn trackers_are_announced_at_their_interval() {
+        let (url, seen) = tracker(1800, 120).await;
+        let mut m = manager(vec![vec![&url]]);
+        m.start(STATS).await;
+        let t = m.status().remove(0);
+        assert_eq!(t.interval, Duration::from_secs(1800));
+        assert_eq!(t.min_interval, Some(Duration::from_secs(120)));
+        let last = t.last_announce.unwrap();
+        assert_eq!(t.next_announce, last + t.interval);
+        m.tick(STATS).await;
+        // short of peers, the tracker is due at its min interval
+        m.request_peers();
+        assert_eq!(m.status()[0].next_announce, last + Duration::from_secs(120));
+        m.tick(STATS).await;
+        assert_eq!(events(&seen), ["started"]);
+        m.tiers[0][0].next_announce = Instant::now();
+        m.tick(STATS).await;
+        assert_eq!(events(&seen), ["started", ""]);
+        // a stopped torrent that runs again starts right away
+        m.stop(STATS).await;
+        assert!(!m.is_running());
+        m.start(STATS).await;
+        assert_eq!(events(&seen), ["started", "", "stopped", "started"]);
+
+        // intervals below the floor are raised to it
+        let (url, _) = tracker(5, 1).await;
+        let mut m = manager(vec![vec![&url]]);
+        m.start(STATS).await;
+        let floor = Duration::from_secs(MIN_ANNOUNCE_INTERVAL_SEC);
+        assert_eq!(
+            (m.status()[0].interval, m.status()[0].min_interval),
+            (floor, Some(floor))
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_tracker_backs_off() {
+        let mut m = manager(vec![vec![FAILING]]);
+        assert!(m.start(STATS).await.is_empty());
+        assert!(m.is_running() && !m.is_started());
+        let retry = |m: &TrackerManager| m.status()[0].next_announce - Instant::now();
+        let first = retry(&m);
+        assert!(first > Duration::from_secs(TRACKER_RETRY_BASE_SEC - 1));
+        // the download task ticks every few seconds, the backoff holds
+        for _ in 0..3 {
+            m.tick(STATS).await;
+        }
+        assert_eq!(m.status()[0].failures, 1);
+        assert!(matches!(
+            m.status()[0].last_error,
+            Some(TrackerError::UnsupportedScheme(_))
+        ));
+        m.tiers[0][0].next_announce = Instant::now();
+        m.tick(STATS).await;
+        assert_eq!(m.status()[0].failures, 2);
+        assert!(retry(&m) > Duration::from_secs(2 * TRACKER_RETRY_BASE_SEC - 1));
+        // completed is sent at once, backoff or not
+        m.complete(STATS).await;
+        assert_eq!(m.status()[0].failures, 3);
+        assert!(retry(&m) > Duration::from_secs(4 * TRACKER_RETRY_BASE_SEC - 1));
+    }
+}
@@ -1,3 +1,5 @@
 pub mod http;
+pub mod manager;
+#[allow(clippy::module_inception)]
 pub mod tracker;
 pub mod upd;
@@ -1,97 +1,85 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::sync::Arc;
 
-use tokio::{join, task::JoinHandle};
+use tokio::sync::Mutex;
 
-use crate::{download, file::TorrentFile, torrent::File};
+use crate::{
+    error::TrackerError,
+    file::TorrentFile,
+    peer::{Peer, local_peer_id},
+};
 
-use super::http::{HttpTracker, HttpTrackerResponse, HttpTrackerRquest};
+use super::manager::{AnnounceStats, TrackerManager, TrackerStatus};
 
-const HTTP_TRACKER_COMPACT_MODE: (&str, &str) = ("0", "1");
-const HTTP_TRACKER_PORT: &str = "6881";
-const HTTP_TRACKER_EVENT_MODE: (&str, &str, &str) = ("started", "completed", "stopped");
-const PEER_ID: &str = "00112233445566778899";
+const HTTP_TRACKER_PORT: u16 = 6881;
 
 #[derive(Debug, Clone)]
 pub struct Tracker {
     pub torrent_file: TorrentFile,
+    /// shared by every download task of the torrent
+    manager: Arc<Mutex<TrackerManager>>,
 }
 
 impl Tracker {
     pub fn new(tf: TorrentFile) -> Self {
-        Self { torrent_file: tf }
+        Self::with_identity(tf, local_peer_id(), HTTP_TRACKER_PORT)
+    }
+    /// announce as `peer_id` listening on `port`, as the torrents of a
+    /// session do
+    pub fn with_identity(tf: TorrentFile, peer_id: [u8; 20], port: u16) -> Self {
+        let manager = TrackerManager::new(tf.announce_tiers.clone(), tf.info_hash, port)
+            .with_peer_id(peer_id);
+        Self::with_manager(tf, manager)
+    }
+    /// announce through a manager set up by the caller
+    pub fn with_manager(tf: TorrentFile, manager: TrackerManager) -> Self {
+        Self {
+            torrent_file: tf,
+            manager: Arc::new(Mutex::new(manager)),
+        }
     }
-    /// used to send tracker requests
+    /// used to send tracker requests, the first call sends the started
+    /// event, later calls re-announce to trackers that are due and send
+    /// started to those that did not answer yet. call `request_peers`
+    /// first to make them due sooner.
     pub async fn get_peers(
         &mut self,
-        file: File,
+        left: u64,
         downloaded: u64,
         uploaded: u64,
-    ) -> Result<Vec<HttpTrackerResponse>, String> {
-        let res_list: Arc<Mutex<Vec<HttpTrackerResponse>>> =
-            Arc::new(Mutex::new(Vec::<HttpTrackerResponse>::new()));
-        // multiple file processing
-        if self.torrent_file.is_multiple_files {
-            // task pool
-            let mut task_pool: Vec<JoinHandle<_>> = Vec::new();
-            for (_i, u) in self.torrent_file.announces.iter().enumerate() {
-                let protocol_str: Vec<&str> = u.split("://").collect();
-                match protocol_str[0] {
-                    "udp" => {}
-                    "http" | "https" => {
-                        let req = HttpTrackerRquest::new(
-                            self.torrent_file.info_hash.clone(),
-                            PEER_ID.to_string(),
-                            HTTP_TRACKER_PORT.to_string(),
-                            uploaded.to_string(),
-                            downloaded.to_string(),
-                            file.length.to_string(),
-                            HTTP_TRACKER_COMPACT_MODE.0.to_string(),
-                            HTTP_TRACKER_EVENT_MODE.0.to_string(),
-                        );
-                        // url
-                        let url = u.clone();
-                        let arc_list = Arc::clone(&res_list);
-                        task_pool.push(tokio::spawn(async move {
-                            // http tracker
-                            let http_tracker = HttpTracker::new();
-                            match http_tracker.send(&url, req).await {
-                                Ok(r) => {
-                                    let mut list: std::sync::MutexGuard<
-                                        '_,
-                                        Vec<HttpTrackerResponse>,
-                                    > = arc_list.lock().unwrap();
-                                    list.push(r);
-                                }
-                                Err(_e) => {}
-                            }
-                        }));
-                    }
-                    _ => {
-                        continue;
-                    }
-                }
-            }
-            for t in task_pool {
-                join!(t);
-            }
+    ) -> Result<Vec<Peer>, TrackerError> {
+        let stats = AnnounceStats {
+            uploaded,
+            downloaded,
+            left,
+        };
+        let mut manager = self.manager.lock().await;
+        let peers = if manager.is_running() {
+            manager.tick(stats).await
         } else {
-            // try sending tracker requests to all alternate addresses
-            for (_i, url) in self.torrent_file.announces.iter().enumerate() {
-                let protocol_str: Vec<&str> = url.split("://").collect();
-                match protocol_str[0] {
-                    "udp" => {}
-                    "http" | "https" => {}
-                    _ => {
-                        continue;
-                    }
-                }
-            }
+            manager.start(stats).await
+        };
+        if peers.is_empty()
+            && let Some(e) = manager.status().iter().find_map(|t| t.last_error.clone())
+        {
+            return Err(e);
         }
-        let arc_list = Arc::clone(&res_list);
-        let ret_list = arc_list.lock().unwrap().to_owned();
-        Ok(ret_list)
+        Ok(peers)
+    }
+    /// make working trackers due at their `min interval`, for when we are
+    /// short of peers
+    pub async fn request_peers(&self) {
+        self.manager.lock().await.request_peers();
+    }
+    /// the download finished
+    pub async fn completed(&self, stats: AnnounceStats) -> Vec<Peer> {
+        self.manager.lock().await.complete(stats).await
+    }
+    /// the torrent is paused or removed
+    pub async fn stopped(&self, stats: AnnounceStats) {
+        self.manager.lock().await.stop(stats).await
+    }
+    /// per-tracker status
+    pub async fn status(&self) -> Vec<TrackerStatus> {
+        self.manager.lock().await.status()
     }
 }
@@ -1,19 +1,480 @@
-pub struct UDPTracker {}
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    time::timeout,
+};
+use url::Url;
+
+use crate::{
+    error::TrackerError,
+    hash::InfoHash,
+    peer::{COMPACT_PEER_V4_LEN, COMPACT_PEER_V6_LEN, Peer},
+};
+
+use super::http::HttpTrackerResponse;
+
+/// magic constant identifying a connect request
+const UDP_TRACKER_PROTOCOL_ID: u64 = 0x41727101980;
+/// base retransmission timeout, the n-th retry waits 15 * 2^n seconds
+const UDP_TRACKER_TIMEOUT_SEC: u64 = 15;
+/// retries after the first attempt, BEP 15 stops after 15 * 2^8 seconds
+const UDP_TRACKER_MAX_RETRIES: u32 = 8;
+/// info hashes one scrape request may carry
+const UDP_SCRAPE_MAX_HASHES: usize = 74;
+/// how long a connection id may be reused
+const UDP_CONNECTION_ID_TTL_SEC: u64 = 60;
+/// enough for an announce reply with a few hundred ipv6 peers
+const UDP_RECV_BUFFER_LEN: usize = 8192;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// announce event, numbered as on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpEvent {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl UdpEvent {
+    /// map the http tracker event names used by `Tracker`
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "completed" => UdpEvent::Completed,
+            "started" => UdpEvent::Started,
+            "stopped" => UdpEvent::Stopped,
+            _ => UdpEvent::None,
+        }
+    }
+}
+
+/// BEP 15 udp tracker client, connection ids are cached per tracker address
+#[derive(Debug, Clone)]
+pub struct UDPTracker {
+    connections: Arc<Mutex<HashMap<SocketAddr, (u64, Instant)>>>,
+    base_timeout: Duration,
+    max_retries: u32,
+}
 
 impl UDPTracker {
-    /// udp tracker requests
-    async fn udp_request(&self, url: &str) -> Result<(), reqwest::Error> {
-        Ok(())
+    pub fn new() -> Self {
+        Self {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            base_timeout: Duration::from_secs(UDP_TRACKER_TIMEOUT_SEC),
+            max_retries: UDP_TRACKER_MAX_RETRIES,
+        }
+    }
+    /// override the 15 second base of the retransmission schedule
+    pub fn with_timeout(mut self, base_timeout: Duration) -> Self {
+        self.base_timeout = base_timeout;
+        self
+    }
+    /// override the number of retries after the first attempt
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+    /// announce to a `udp://host:port` tracker
+    pub async fn announce(
+        &self,
+        url: &str,
+        req: &TrackerUDPRquest,
+    ) -> Result<TrackerUDPResponse, TrackerError> {
+        let (socket, addr) = self.open(url).await?;
+        let body = req.to_bytes();
+        let reply = self.request(&socket, addr, ACTION_ANNOUNCE, &body).await?;
+        if reply.len() < 12 {
+            return Err(TrackerError::InvalidResponse(format!(
+                "announce reply of {} bytes",
+                reply.len()
+            )));
+        }
+        let peer_len = if addr.is_ipv4() {
+            COMPACT_PEER_V4_LEN
+        } else {
+            COMPACT_PEER_V6_LEN
+        };
+        Ok(TrackerUDPResponse {
+            interval: read_u32(&reply, 0),
+            leechers: read_u32(&reply, 4),
+            seeders: read_u32(&reply, 8),
+            peers: Peer::from_compact_list(&reply[12..], peer_len),
+        })
+    }
+    /// scrape seeders, completed downloads and leechers of torrents, in the
+    /// order of `info_hashes`. a request carries at most 74 hashes, more
+    /// are sent in several requests.
+    pub async fn scrape(
+        &self,
+        url: &str,
+        info_hashes: &[InfoHash],
+    ) -> Result<Vec<TrackerUDPScrape>, TrackerError> {
+        let (socket, addr) = self.open(url).await?;
+        let mut scrapes = Vec::with_capacity(info_hashes.len());
+        for hashes in info_hashes.chunks(UDP_SCRAPE_MAX_HASHES) {
+            let body: Vec<u8> = hashes.iter().flat_map(|h| h.as_bytes().to_vec()).collect();
+            let reply = self.request(&socket, addr, ACTION_SCRAPE, &body).await?;
+            if reply.len() < hashes.len() * 12 {
+                return Err(TrackerError::InvalidResponse(format!(
+                    "scrape reply of {} bytes for {} torrents",
+                    reply.len(),
+                    hashes.len()
+                )));
+            }
+            scrapes.extend(
+                reply
+                    .chunks_exact(12)
+                    .take(hashes.len())
+                    .map(|c| TrackerUDPScrape {
+                        seeders: read_u32(c, 0),
+                        completed: read_u32(c, 4),
+                        leechers: read_u32(c, 8),
+                    }),
+            );
+        }
+        Ok(scrapes)
+    }
+    /// resolve the tracker and bind a socket of the matching address family
+    async fn open(&self, url: &str) -> Result<(UdpSocket, SocketAddr), TrackerError> {
+        let u = match Url::parse(url) {
+            Ok(u) if u.scheme() == "udp" => u,
+            Ok(_) => return Err(TrackerError::UnsupportedScheme(url.to_string())),
+            Err(_) => return Err(TrackerError::InvalidUrl(url.to_string())),
+        };
+        let (host, port) = match (u.host_str(), u.port()) {
+            (Some(h), Some(p)) => (h.trim_matches(|c| c == '[' || c == ']'), p),
+            _ => return Err(TrackerError::InvalidUrl(url.to_string())),
+        };
+        let addr = match lookup_host((host, port)).await {
+            Ok(mut addrs) => match addrs.next() {
+                Some(a) => a,
+                None => return Err(TrackerError::Resolve(host.to_string())),
+            },
+            Err(_) => return Err(TrackerError::Resolve(host.to_string())),
+        };
+        let bind: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = match UdpSocket::bind(bind).await {
+            Ok(s) => s,
+            Err(e) => return Err(TrackerError::Connection(format!("udp bind failed {}", e))),
+        };
+        if let Err(e) = socket.connect(addr).await {
+            return Err(TrackerError::Connection(format!(
+                "udp connect failed {}",
+                e
+            )));
+        }
+        Ok((socket, addr))
+    }
+    /// send `action` with `body`, retransmitting on the BEP 15 schedule, and
+    /// return the reply payload after the action and transaction id.
+    async fn request(
+        &self,
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        action: u32,
+        body: &[u8],
+    ) -> Result<Vec<u8>, TrackerError> {
+        for n in 0..=self.max_retries {
+            let wait = self.retry_timeout(n);
+            // the connection id may expire while we are retrying
+            let connection_id = match self.cached_connection(addr) {
+                Some(id) => id,
+                None => match self
+                    .exchange(socket, wait, ACTION_CONNECT, UDP_TRACKER_PROTOCOL_ID, &[])
+                    .await?
+                {
+                    Some(reply) if reply.len() >= 8 => {
+                        let id = read_u64(&reply, 0);
+                        self.connections
+                            .lock()
+                            .unwrap()
+                            .insert(addr, (id, Instant::now()));
+                        id
+                    }
+                    Some(_) => {
+                        return Err(TrackerError::InvalidResponse(
+                            "connect reply too short".to_string(),
+                        ));
+                    }
+                    None => continue,
+                },
+            };
+            if let Some(reply) = self
+                .exchange(socket, wait, action, connection_id, body)
+                .await?
+            {
+                return Ok(reply);
+            }
+        }
+        self.connections.lock().unwrap().remove(&addr);
+        Err(TrackerError::Timeout)
+    }
+    /// one send and wait, `None` on timeout
+    async fn exchange(
+        &self,
+        socket: &UdpSocket,
+        wait: Duration,
+        action: u32,
+        connection_id: u64,
+        body: &[u8],
+    ) -> Result<Option<Vec<u8>>, TrackerError> {
+        let transaction_id: u32 = rand::thread_rng().r#gen();
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+        if let Err(e) = socket.send(&packet).await {
+            return Err(TrackerError::Connection(format!("udp send failed {}", e)));
+        }
+        let deadline = Instant::now() + wait;
+        let mut buf = vec![0; UDP_RECV_BUFFER_LEN];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let len = match timeout(left, socket.recv(&mut buf)).await {
+                Ok(Ok(len)) => len,
+                Ok(Err(e)) => {
+                    return Err(TrackerError::Connection(format!(
+                        "udp receive failed {}",
+                        e
+                    )));
+                }
+                Err(_) => return Ok(None),
+            };
+            // ignore stray datagrams from earlier attempts
+            if len < 8 || read_u32(&buf, 4) != transaction_id {
+                continue;
+            }
+            return match read_u32(&buf, 0) {
+                a if a == action => Ok(Some(buf[8..len].to_vec())),
+                ACTION_ERROR => Err(TrackerError::Failure(
+                    String::from_utf8_lossy(&buf[8..len]).to_string(),
+                )),
+                a => Err(TrackerError::InvalidResponse(format!(
+                    "unexpected action {}",
+                    a
+                ))),
+            };
+        }
+    }
+    /// how long attempt `n` waits, doubling up to the BEP 15 maximum
+    fn retry_timeout(&self, n: u32) -> Duration {
+        let factor = 1u32 << n.min(UDP_TRACKER_MAX_RETRIES);
+        self.base_timeout.saturating_mul(factor)
+    }
+    fn cached_connection(&self, addr: SocketAddr) -> Option<u64> {
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(&addr) {
+            Some((id, at)) if at.elapsed() < Duration::from_secs(UDP_CONNECTION_ID_TTL_SEC) => {
+                Some(*id)
+            }
+            Some(_) => {
+                connections.remove(&addr);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+impl Default for UDPTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// announce parameters, the part of the packet after connection id, action and transaction id
+#[derive(Debug, Clone)]
+pub struct TrackerUDPRquest {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub event: UdpEvent,
+    /// random key letting the tracker recognise us across ip changes
+    pub key: u32,
+    /// -1 lets the tracker decide
+    pub num_want: i32,
+    pub port: u16,
+}
+
+impl TrackerUDPRquest {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(82);
+        b.extend_from_slice(self.info_hash.as_bytes());
+        b.extend_from_slice(&self.peer_id);
+        b.extend_from_slice(&self.downloaded.to_be_bytes());
+        b.extend_from_slice(&self.left.to_be_bytes());
+        b.extend_from_slice(&self.uploaded.to_be_bytes());
+        b.extend_from_slice(&(self.event as u32).to_be_bytes());
+        // ip address 0, the tracker uses the packet source
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&self.key.to_be_bytes());
+        b.extend_from_slice(&self.num_want.to_be_bytes());
+        b.extend_from_slice(&self.port.to_be_bytes());
+        b
     }
 }
 
-struct TrackerUDPRquest {
-    transaction_id: String,
-    connection_id: String,
-    event: String,
-    num_want: u64,
-    leechers: u64,
-    seeders: u64,
+/// announce reply
+#[derive(Debug, Clone)]
+pub struct TrackerUDPResponse {
+    /// seconds to wait before announcing again
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Vec<Peer>,
+}
+
+impl From<TrackerUDPResponse> for HttpTrackerResponse {
+    fn from(r: TrackerUDPResponse) -> Self {
+        HttpTrackerResponse {
+            complete: Some(r.seeders as u64),
+            downloaded: None,
+            incomplete: Some(r.leechers as u64),
+            interval: Some(r.interval as u64),
+            min_interval: None,
+            peers: Some(r.peers),
+            ..Default::default()
+        }
+    }
+}
+
+/// scrape reply for one torrent
+#[derive(Debug, Clone, Copy)]
+pub struct TrackerUDPScrape {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+fn read_u32(b: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+fn read_u64(b: &[u8], i: usize) -> u64 {
+    let mut a = [0; 8];
+    a.copy_from_slice(&b[i..i + 8]);
+    u64::from_be_bytes(a)
 }
 
-struct TrackerUDPResponse {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONNECTION_ID: u64 = 0x1122334455667788;
+
+    /// a tracker on a local port answering connect, announce with one peer
+    /// and scrape with the index of every hash. counts scrape requests.
+    async fn tracker() -> (String, Arc<Mutex<usize>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let scrapes = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&scrapes);
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let (action, transaction) = (read_u32(&buf, 8), read_u32(&buf, 12));
+                let mut out = vec![];
+                out.extend(action.to_be_bytes());
+                out.extend(transaction.to_be_bytes());
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(read_u64(&buf, 0), UDP_TRACKER_PROTOCOL_ID);
+                        out.extend(CONNECTION_ID.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(read_u64(&buf, 0), CONNECTION_ID);
+                        for n in [1800u32, 3, 5] {
+                            out.extend(n.to_be_bytes());
+                        }
+                        out.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+                    }
+                    ACTION_SCRAPE => {
+                        let hashes = (len - 16) / 20;
+                        assert!(hashes <= UDP_SCRAPE_MAX_HASHES);
+                        *counter.lock().unwrap() += 1;
+                        for h in 0..hashes {
+                            let first = buf[16 + h * 20] as u32;
+                            for n in [first, 0, 1] {
+                                out.extend(n.to_be_bytes());
+                            }
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                socket.send_to(&out, from).await.unwrap();
+            }
+        });
+        (url, scrapes)
+    }
+
+    #[tokio::test]
+    async fn announce_reads_the_peers() {
+        let (url, _) = tracker().await;
+        let req = TrackerUDPRquest {
+            info_hash: InfoHash::new([1; 20]),
+            peer_id: [2; 20],
+            downloaded: 0,
+            left: 100,
+            uploaded: 0,
+            event: UdpEvent::Started,
+            key: 7,
+            num_want: -1,
+            port: 6881,
+        };
+        let r = UDPTracker::new().announce(&url, &req).await.unwrap();
+        assert_eq!((r.interval, r.leechers, r.seeders), (1800, 3, 5));
+        let addrs: Vec<SocketAddr> = r.peers.iter().map(|p| p.addr).collect();
+        assert_eq!(addrs, vec![SocketAddr::from(([10, 0, 0, 1], 6881))]);
+    }
+
+    #[tokio::test]
+    async fn scrape_splits_hashes_into_requests_of_74() {
+        let (url, requests) = tracker().await;
+        let hashes: Vec<InfoHash> = (0..100u8).map(|i| InfoHash::new([i; 20])).collect();
+        let scrapes = UDPTracker::new().scrape(&url, &hashes).await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), 2);
+        let seeders: Vec<u32> = scrapes.iter().map(|s| s.seeders).collect();
+        assert_eq!(seeders, (0..100).collect::<Vec<u32>>());
+    }
+
+    #[tokio::test]
+    async fn silent_tracker_times_out() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", silent.local_addr().unwrap());
+        let udp = UDPTracker::new()
+            .with_timeout(Duration::from_millis(10))
+            .with_max_retries(1);
+        let r = udp.scrape(&url, &[InfoHash::new([1; 20])]).await;
+        assert!(matches!(r, Err(TrackerError::Timeout)));
+    }
+
+    #[test]
+    fn retry_timeout_stops_doubling() {
+        let udp = UDPTracker::new();
+        assert_eq!(udp.retry_timeout(0), Duration::from_secs(15));
+        assert_eq!(udp.retry_timeout(3), Duration::from_secs(120));
+        assert_eq!(udp.retry_timeout(8), Duration::from_secs(15 * 256));
+        assert_eq!(udp.retry_timeout(40), Duration::from_secs(15 * 256));
+        let long = UDPTracker::new().with_timeout(Duration::MAX);
+        assert_eq!(long.retry_timeout(8), Duration::MAX);
+    }
+}